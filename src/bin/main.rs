@@ -1,44 +1,391 @@
 //! A binary written as an in-place replacement for ofelia with a few different
 //! configuration options and a lower memory footprint.
-use std::process::exit;
+use std::{path::PathBuf, process::exit, sync::Arc, time::Instant};
 
-use cfc::{context::ApplicationContext, utils::is_docker_env, loader::{load_labels, load_file}};
+use anyhow::Result;
+use cfc::{clock, context::{ApplicationContext, ContainerEngine, OfeliaSource, OFELIA_CONFIG_PATHS}, health, job::{parse_duration, running_executions, ErrorCategory, GroupLocks, JobInfo, JobState, StopDaemonError}, loader::{cache::LabelCache, discovery::Discovery, follow_labels, load_file, load_labels, migrate_config, parse_docker_filter, ConfigFormat, ContainerEvent, STDIN_PATH, lock::{config_checksum, export_jobs, load_lock}, watch::FileWatcher}, report::{BatchReport, JobRunReport}, scheduler::{Scheduler, SpawnBudget}, slack, state::{StateStore, VolumeStateStore}, utils::{is_docker_env, Elided}, webhook};
 use clap::{ArgAction, Parser, Subcommand, Args};
-use tokio::{task::JoinSet, time::{sleep, Duration}};
+use tokio::{signal::unix::{signal, Signal, SignalKind}, time::{interval, sleep, Duration}};
 use tracing::{debug, error, info, instrument, trace, warn, Level};
-use tracing_subscriber;
 
-/// Arguments supported when running as a daemon
+/// Arguments that select where the jobs are loaded from
 #[derive(Args, Debug)]
-struct DaemonArgs {
+struct SourceArgs {
     /// Whether the configuration should be obtained from docker labels or from a configuration file
     #[arg(short, long, help = "Extract configuration from docker labels", default_value = "false")]
     docker: bool,
-    /// If the configuration is obtained from docker labels, the filter to use to find managed containers
-    #[arg(short, long = "docker-filter", help = "Filter used to select valid docker containers")]
-    filter: Option<String>,
+    /// If the configuration is obtained from docker labels, the filters to use to find managed containers
+    #[arg(short, long = "docker-filter", help = "Filter used to select valid docker containers, e.g. label=env=prod or compose-project=app. May be provided more than once.", value_parser = parse_docker_filter)]
+    filters: Vec<(String, String)>,
+    /// Whether the replicas of a Compose service declare a single job for the service
+    #[arg(long = "compose-services", help = "Declare the jobs found in the labels of a Compose service's replicas once for the service, instead of once per container", default_value = "false")]
+    compose_services: bool,
     /// The path to the container manager's socket handle
     #[arg(long = "socket-path", help = "Configure the path to the docker socket")]
     socket_path: Option<String>,
+    /// The address of a remote container manager
+    #[arg(long = "host", help = "Connect to a remote docker daemon (e.g. tcp://docker.example.com:2376)")]
+    host: Option<String>,
+    /// The client certificate used to connect to the container manager
+    #[arg(long = "tls-cert", help = "Path to the TLS client certificate", requires_all = ["tls_key", "tls_ca"])]
+    tls_cert: Option<String>,
+    /// The client key used to connect to the container manager
+    #[arg(long = "tls-key", help = "Path to the TLS client key", requires_all = ["tls_cert", "tls_ca"])]
+    tls_key: Option<String>,
+    /// The certificate authority used to verify the container manager
+    #[arg(long = "tls-ca", help = "Path to the TLS certificate authority", requires_all = ["tls_cert", "tls_key"])]
+    tls_ca: Option<String>,
     /// The target prefixes to use when looking for container jobs
     #[arg(long = "prefix", help = "The label prefix to use when looking for container jobs. May be provided more than once.")]
     label_prefixes: Vec<String>,
     /// When getting configuration from docker labels, how unsafe label configurations should be handled
     #[arg(long = "allow-unsafe-jobs", help = "Register potentially-unsafe jobs when parsing container labels", default_value = "false")]
     allow_unsafe: bool,
+    /// The network of the containers started by run jobs that do not set one
+    #[arg(long = "default-run-network", help = "Network of the containers started by run jobs that do not set one [default: none for jobs declared in labels]")]
+    default_run_network: Option<String>,
+    /// The container engine whose default socket is used when no socket or host is set
+    #[arg(long = "engine", help = "Container engine to connect to when neither --socket-path nor --host is set (docker or podman) [default: docker if reachable, else podman]")]
+    engine: Option<ContainerEngine>,
+    /// Whether several jobs may share a name instead of failing to load the configuration
+    #[arg(long = "allow-duplicate-names", help = "Load jobs that have the same name as another job instead of failing", default_value = "false")]
+    allow_duplicate_names: bool,
+}
+
+/// Arguments supported when running as a daemon
+#[derive(Args, Debug)]
+struct DaemonArgs {
+    /// Where the jobs are loaded from
+    #[command(flatten)]
+    source: SourceArgs,
+    /// Whether the configuration file should be reloaded automatically when it changes
+    #[arg(short, long, help = "Reload the configuration file when it changes", default_value = "false")]
+    watch: bool,
+    /// A lock file generated by the export subcommand to use as the only job source
+    #[arg(long = "from-lock", help = "Load the jobs exclusively from a lock file", conflicts_with = "docker")]
+    from_lock: Option<String>,
+    /// How long running executions may take to end once a shutdown is requested
+    #[arg(long = "shutdown-grace", alias = "shutdown-timeout", help = "Time given to running jobs to end on shutdown before they are aborted", value_parser = parse_duration, default_value = "30s")]
+    shutdown_grace: Duration,
+    /// Whether the daemon stops when a job's scheduler fails instead of restarting it
+    #[arg(long = "fail-fast", help = "Stop all jobs and exit with an error when the scheduler of a job fails, instead of restarting it", default_value = "false")]
+    fail_fast: bool,
+    /// The address on which the Prometheus metrics are served
+    #[cfg(feature = "metrics")]
+    #[arg(long = "metrics-addr", help = "Serve Prometheus metrics on /metrics at this address, e.g. 0.0.0.0:9090")]
+    metrics_addr: Option<std::net::SocketAddr>,
+    /// The job annotations exported as metric labels
+    #[cfg(feature = "metrics")]
+    #[arg(long = "metrics-annotation", help = "Export this job annotation as a label of the cfc_job_annotations metric, may be repeated up to 8 times")]
+    metrics_annotations: Vec<String>,
+    /// The address on which the health endpoints are served
+    #[cfg(feature = "health")]
+    #[arg(long = "health-addr", help = "Serve the /healthz and /readyz health checks at this address, e.g. 0.0.0.0:8080")]
+    health_addr: Option<std::net::SocketAddr>,
+    /// The SQLite database the completed executions are recorded in
+    #[cfg(feature = "history")]
+    #[arg(long = "history-db", help = "Record the completed executions in this SQLite database, created if needed")]
+    history_db: Option<std::path::PathBuf>,
+    /// The age after which the executions recorded in the history database are pruned
+    #[cfg(feature = "history")]
+    #[arg(long = "history-retention", help = "Age after which the executions recorded with --history-db are deleted", value_parser = parse_duration, default_value = "30d")]
+    history_retention: Duration,
+    /// The maximum number of executions that run at the same time
+    #[arg(long = "max-parallel", help = "Maximum number of job executions that may run at the same time across all jobs")]
+    max_parallel: Option<std::num::NonZeroUsize>,
+    /// How long an execution waits for a permit before it is admitted before higher-priority ones
+    #[arg(long = "priority-promotion-delay", help = "Time after which an execution waiting for a --max-parallel permit is admitted before higher-priority ones", value_parser = parse_duration, default_value = "5m")]
+    priority_promotion_delay: Duration,
+    /// The maximum number of containers created per hour by the jobs
+    #[arg(long = "max-spawns-per-hour", help = "Maximum number of containers created by job-run and job-service-run jobs over the last hour, the runs beyond it are skipped")]
+    max_spawns_per_hour: Option<std::num::NonZeroUsize>,
+    /// How often the containers are listed again when their events can not be listened to
+    #[arg(long = "rescan-interval", help = "Delay between two rescans of the containers when the container engine does not support events", value_parser = parse_duration, default_value = "60s")]
+    rescan_interval: Duration,
+    /// The volume the last run of each label job is written to
+    #[arg(long = "state-volume", help = "Write the last run of each job discovered with --docker as a JSON file to this volume, created if needed, e.g. cfc.state")]
+    state_volume: Option<String>,
+    /// The image of the container used to write to the state volume
+    #[arg(long = "state-image", help = "Image of the stopped container that mounts the --state-volume to write to it", default_value = cfc::state::DEFAULT_STATE_IMAGE)]
+    state_image: String,
+    /// Whether the triggered jobs are only logged instead of being executed
+    #[arg(long = "dry-run", help = "Schedule the jobs but only log what they would execute when triggered, without running anything", default_value = "false")]
+    dry_run: bool,
+}
+
+/// Arguments supported when listing the configured jobs
+#[derive(Args, Debug)]
+struct ListArgs {
+    /// Where the jobs are loaded from
+    #[command(flatten)]
+    source: SourceArgs,
+    /// Whether the list should be printed as JSON
+    #[arg(long, help = "Print the job list as JSON", default_value = "false")]
+    json: bool,
+}
+
+/// Arguments supported when running a single job
+#[derive(Args, Debug)]
+struct RunNowArgs {
+    /// Where the jobs are loaded from
+    #[command(flatten)]
+    source: SourceArgs,
+    /// The name of the job to run
+    #[arg(help = "The name of the job to run")]
+    name: String,
+    /// The file the JSON report of the execution is written to
+    #[arg(long = "report-file", help = "Write a JSON report of the execution to a file")]
+    report_file: Option<PathBuf>,
+}
+
+/// Arguments supported when running every job once
+#[derive(Args, Debug)]
+struct OnceArgs {
+    /// Where the jobs are loaded from
+    #[command(flatten)]
+    source: SourceArgs,
+    /// The file the JSON report of the executions is written to
+    #[arg(long = "report-file", help = "Write a JSON report of the executions to a file")]
+    report_file: Option<PathBuf>,
+}
+
+/// Arguments supported when exporting the job set
+#[derive(Args, Debug)]
+struct ExportArgs {
+    /// Where the jobs are loaded from
+    #[command(flatten)]
+    source: SourceArgs,
+    /// The file to write the lock to
+    #[arg(short, long, help = "Write the lock to a file instead of the standard output")]
+    output: Option<String>,
 }
 
 /// Arguments supported when running a configuration file validation check
 #[derive(Args, Debug)]
-struct ValidateArgs {}
+struct ValidateArgs {
+    /// The delay after which a job's next occurence is considered suspicious
+    #[arg(long, help = "Warn about jobs whose next occurence is further away than this duration", value_parser = parse_duration, default_value = "P366D")]
+    horizon: Duration,
+    /// Print the configuration migrated to the current version of the format instead of validating it
+    #[arg(long, help = "Print the configuration with its renamed keys migrated, in its original format")]
+    migrate: bool,
+    /// Whether the container engine should also be checked
+    #[arg(long, help = "Also check that the container engine is reachable and that the state volume can be written")]
+    deep: bool,
+    /// The state volume checked by a deep validation
+    #[arg(long = "state-volume", help = "Volume whose writability is checked with --deep", default_value = cfc::state::DEFAULT_STATE_VOLUME)]
+    state_volume: String,
+    /// The image of the container used to write to the state volume
+    #[arg(long = "state-image", help = "Image of the stopped container that mounts the --state-volume to write to it", default_value = cfc::state::DEFAULT_STATE_IMAGE)]
+    state_image: String,
+}
+
+/// Load the jobs from the source configured on the command-line
+async fn load_jobs(source_args: &SourceArgs, ctx: &mut ApplicationContext, cache: &mut LabelCache) -> Result<Vec<JobInfo>> {
+    if source_args.docker {
+        load_labels(ctx, cache).await
+    } else {
+        load_file(&ctx.config_path.clone(), ctx).await
+    }
+}
+
+/// Load the jobs the daemon should run
+async fn load_daemon_jobs(daemon_args: &DaemonArgs, ctx: &mut ApplicationContext, cache: &mut LabelCache) -> Result<Vec<JobInfo>> {
+    match daemon_args.from_lock.as_ref() {
+        Some(lock) => load_lock(lock).await,
+        None => load_jobs(&daemon_args.source, ctx, cache).await,
+    }
+}
+
+/// Print the jobs with their next run time, as a table or as JSON
+fn print_jobs(mut jobs: Vec<JobInfo>, as_json: bool) {
+    jobs.sort_by(|a, b| a.name().cmp(b.name()));
+    let now = chrono::Local::now();
+    let next_runs: Vec<Option<String>> = jobs.iter()
+        .map(|job| job.next_occurrence(&now).ok().flatten().map(|n| n.to_rfc3339()))
+        .collect();
+    if as_json {
+        let entries: Vec<serde_json::Value> = jobs.iter().zip(next_runs).map(|(job, next_run)| serde_json::json!({
+            "name": job.name(),
+            "kind": job.kind(),
+            "schedule": job.as_job().schedule().to_string(),
+            "command": (!job.commands().is_empty()).then(|| job.commands().join("; ")),
+            "next_run": next_run,
+        })).collect();
+        println!("{}", serde_json::Value::Array(entries));
+        return;
+    }
+    let header = ["NAME", "KIND", "SCHEDULE", "NEXT RUN", "COMMAND"].map(|h| h.to_string());
+    let rows: Vec<[String; 5]> = jobs.iter().zip(next_runs).map(|(job, next_run)| [
+        job.name().clone(),
+        job.kind().to_string(),
+        job.as_job().schedule().to_string(),
+        next_run.unwrap_or_else(|| "never".to_string()),
+        if job.commands().is_empty() { "-".to_string() } else { job.commands().join("; ") },
+    ]).collect();
+    let mut widths = header.clone().map(|h| h.len());
+    for row in &rows {
+        for (w, c) in widths.iter_mut().zip(row) {
+            *w = (*w).max(c.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line: Vec<String> = row.iter().zip(widths).map(|(c, w)| format!("{:w$}", c, w = w)).collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Run the enabled jobs once, all at the same time, and wait for them to end.
+/// The output of each job is forwarded as soon as the job ends.
+async fn run_jobs_once(jobs: Vec<JobInfo>, handle: &bollard::Docker) -> BatchReport {
+    let runs = jobs.into_iter()
+        .filter(|job| {
+            if job.options().disabled {
+                info!["Job {} is disabled, it is not run", job.name()];
+            }
+            !job.options().disabled
+        })
+        .map(|job| async move {
+            let (summary, merge_streams) = (job.clone(), job.options().merge_streams);
+            let start_time = Instant::now();
+            let result = job.run_once(handle).await;
+            match &result {
+                Ok(report) => {
+                    if let Err(e) = report.write_output(merge_streams, &mut std::io::stdout(), &mut std::io::stderr()) {
+                        error!["Failed to forward the output of job {}: {}", summary.name(), e];
+                    }
+                    match report.timed_out {
+                        true => error![category = %ErrorCategory::Timeout, "Job {} timed out", summary.name()],
+                        false if report.retval != 0 => error!["Job {} exited with code {}", summary.name(), report.retval],
+                        false => info!["Job {} exited with code 0", summary.name()],
+                    }
+                },
+                Err(e) => error![category = %ErrorCategory::of(e), "Failed to run job {}: {}", summary.name(), e],
+            }
+            JobRunReport::new(&summary, result, start_time.elapsed().as_secs_f64())
+        });
+    let mut reports = futures_util::future::join_all(runs).await;
+    reports.sort_by(|a, b| a.name.cmp(&b.name));
+    BatchReport::new(reports)
+}
+
+/// Replace the scheduled jobs with the ones of the current configuration.
+/// Errors are logged and the current jobs are kept running.
+async fn reload_jobs(args: &CliArgs, daemon_args: &DaemonArgs, scheduler: &mut Scheduler) {
+    let mut reload_context = args.get_context();
+    match load_daemon_jobs(daemon_args, &mut reload_context, scheduler.label_cache()).await {
+        Ok(targets) if targets.is_empty() => error!("No valid job could be found in the new configuration, keeping the current jobs"),
+        Ok(targets) => {
+            if let Err(e) = slack::configure(reload_context.slack_webhook.clone(), reload_context.slack_collapse_window) {
+                error!("Failed to configure the Slack notifications: {}", e);
+            }
+            let checksum = config_checksum(&targets);
+            match cfc::metrics::set_config_checksum(checksum.clone()) {
+                Some(previous) if previous == checksum => debug!("The configuration checksum is unchanged: {}", checksum),
+                Some(previous) => info!("The configuration checksum changed from {} to {}", previous, checksum),
+                None => info!("Configuration checksum: {}", checksum),
+            }
+            scheduler.reload(targets);
+        },
+        Err(e) => error!("Failed to reload the configuration, keeping the current jobs: {}", e),
+    }
+}
+
+/// The number of upcoming runs shown for each job at startup and during validation
+const PREVIEWED_OCCURRENCES: usize = 3;
+
+/// The number of upcoming runs printed for each job by the validate subcommand
+const VALIDATED_OCCURRENCES: usize = 5;
+
+/// Format a list of run dates for display
+fn format_occurrences(occurrences: &[chrono::DateTime<chrono::Local>]) -> String {
+    if occurrences.is_empty() {
+        return "never".to_string();
+    }
+    occurrences.iter().map(|o| o.to_rfc3339()).collect::<Vec<_>>().join(", ")
+}
+
+/// Log the next runs of a job so that a miswritten schedule is spotted early
+fn log_next_occurrences(job: &JobInfo) {
+    info!("Job {} will {} and next run on {}", job.name(), job.describe_action(), format_occurrences(&job.next_occurrences(PREVIEWED_OCCURRENCES)));
+}
+
+/// Stop scheduling the jobs after a shutdown signal and exit once their running executions
+/// ended, or once the grace period elapsed or another shutdown signal is received
+async fn shutdown_gracefully(scheduler: &mut Scheduler, grace: Duration, received: &str, interrupt: &mut Signal, terminate: &mut Signal) -> ! {
+    warn!("Received {}, waiting up to {:?} for running jobs to end", received, grace);
+    tokio::select! {
+        _ = scheduler.stop(grace) => {},
+        _ = interrupt.recv() => warn!("Received a second shutdown signal, aborting all jobs"),
+        _ = terminate.recv() => warn!("Received a second shutdown signal, aborting all jobs"),
+    }
+    scheduler.shutdown().await;
+    cfc::job::flush_hooks(cfc::job::HOOK_FLUSH_TIMEOUT).await;
+    webhook::flush(webhook::DELIVERY_TIMEOUT).await;
+    exit(0);
+}
+
+/// Log the scheduled jobs and their running executions
+fn log_job_table(scheduler: &Scheduler) {
+    let mut jobs: Vec<&JobInfo> = scheduler.jobs().collect();
+    jobs.sort_by(|a, b| a.name().cmp(b.name()));
+    warn!("{} jobs are scheduled", jobs.len());
+    for job in jobs {
+        let next = job.next_occurrence(&chrono::Local::now()).ok().flatten().map_or_else(|| "never".to_string(), |n| n.to_rfc3339());
+        let annotations: Vec<String> = job.options().annotations.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        match annotations.is_empty() {
+            true => warn!("Job {} ({}): next run on {}, {}", job.name(), job.kind(), next, job.describe_action()),
+            false => warn!("Job {} ({}): next run on {}, {} [{}]", job.name(), job.kind(), next, job.describe_action(), annotations.join(", ")),
+        }
+    }
+    if let Some(budget) = scheduler.spawn_budget() {
+        warn!(
+            "{} of {} containers were created in the last hour, {} runs were skipped as the budget was exhausted",
+            budget.used(tokio::time::Instant::now()), budget.limit(), budget.skipped(),
+        );
+    }
+    let executions = running_executions();
+    warn!("{} executions are running", executions.len());
+    for (name, started_at) in executions {
+        warn!("Execution of job {} running since {}", name, started_at.to_rfc3339());
+    }
+}
+
+/// Log the last executions of each scheduled job
+fn log_histories(scheduler: &Scheduler) {
+    let mut histories: Vec<_> = scheduler.histories().collect();
+    histories.sort_by(|(a, _), (b, _)| a.name().cmp(b.name()));
+    for (job, history) in histories {
+        let entries = history.entries();
+        warn!("Job {}: {} executions recorded in its history (history-size {})", job.name(), entries.len(), history.capacity());
+        for entry in entries {
+            warn!(
+                run.id = entry.report.run_id,
+                "Job {} started at {}: {} in {:.3} seconds with exit code {}{}",
+                job.name(), entry.started_at.to_rfc3339(), if entry.success { "succeeded" } else { "failed" },
+                entry.duration.as_secs_f64(), entry.report.retval, if entry.report.timed_out { " (timed out)" } else { "" },
+            );
+        }
+    }
+}
 
 /// The commands supported by the executable
 #[derive(Subcommand, Debug)]
 enum SubCommands {
     #[command(about="Run as a simple process")]
-    Daemon(DaemonArgs),
+    Daemon(Box<DaemonArgs>),
     #[command(about="Validate the configuration files")]
-    Validate(ValidateArgs)
+    Validate(ValidateArgs),
+    #[command(about="Write the resolved job set to a lock file")]
+    Export(ExportArgs),
+    #[command(about="List the configured jobs and their next run time")]
+    List(ListArgs),
+    #[command(about="Run a configured job once and exit with its return code")]
+    RunNow(RunNowArgs),
+    #[command(about="Run every configured job once, at the same time, and exit with an error if one of them failed")]
+    Once(OnceArgs),
+    #[command(about="Print the keys accepted by each job kind as JSON")]
+    Schema,
 }
 
 /// The argument parser's output representation
@@ -48,17 +395,35 @@ struct CliArgs {
     /// Command-specific parameters
     #[command(subcommand)]
     command: SubCommands,
-    /// The path to the configuration file
-    #[arg(short, long, help = "Path to the configuration file to use", global = true)]
+    /// The path to the configuration file, or to a directory of configuration files.
+    /// The configuration is read from the standard input if the path is "-".
+    #[arg(short, long, help = "Path to the configuration file or directory to use, or - to read the standard input", global = true)]
     config: Option<String>,
+    /// The format of the configuration, which is otherwise guessed from the file extensions
+    #[arg(long = "config-format", help = "Format of the configuration (ini or yaml)", global = true)]
+    config_format: Option<ConfigFormat>,
     /// Whether to run in ofelia-compatibility mode.
     /// 
-    /// This is equivalent to providing "--config /etc/ofelia.conf" in general,
-    /// with "--allow-unsafe-jobs --prefix ofelia" with the daemon subcommand.
+    /// This is equivalent to providing "--config" with the first of "/etc/ofelia.conf" and
+    /// "/etc/ofelia/config.ini" that exists in general, with "--allow-unsafe-jobs --prefix ofelia"
+    /// with the daemon subcommand. When none of the files exist, the jobs may still be read
+    /// from container labels with "--docker".
     /// 
     /// *Note that if --prefix or --config is used, the provided value will take precedence.*
     #[arg(long, help = "Run in ofelia compatibility mode.", global = true)]
     ofelia: bool,
+    /// The maximum size of the configuration file
+    #[arg(long = "max-config-size", help = "Maximum size of the configuration file, in bytes", global = true)]
+    max_config_size: Option<usize>,
+    /// The maximum number of jobs in the configuration file
+    #[arg(long = "max-jobs", help = "Maximum number of jobs declared in the configuration file", global = true)]
+    max_jobs: Option<usize>,
+    /// The maximum length of a key or value in the configuration file
+    #[arg(long = "max-scalar-length", help = "Maximum length of a key or value in the configuration file, in bytes", global = true)]
+    max_scalar_length: Option<usize>,
+    /// The maximum length of a container label's value
+    #[arg(long = "max-label-value-length", help = "Maximum length of a container label's value, in bytes", global = true)]
+    max_label_value_length: Option<usize>,
     /// The verbosity level
     #[arg(short, help = "Increase verbosity", action = ArgAction::Count, global = true)]
     verbosity: u8,
@@ -68,33 +433,74 @@ impl CliArgs {
     pub fn get_context(&self) -> ApplicationContext {
         let mut global_context = ApplicationContext::default();
 
-        global_context.config_path = self.config.as_ref()
-            .and_then(|c| Some(c.clone()))
-            .unwrap_or_else(|| {
-                if self.ofelia {"/etc/ofelia.conf".to_string()}
-                else {global_context.config_path}
-            });
-        match &self.command {
-            SubCommands::Daemon(daemon_args) => {
-                global_context.unsafe_labels = daemon_args.allow_unsafe;
-                global_context.socket = daemon_args.socket_path.clone();
-                if self.ofelia {
-                    let ofelia_label = "ofelia".to_string();
-                    if !global_context.label_prefixes.contains(&ofelia_label) {
-                        global_context.label_prefixes.push(ofelia_label);
-                    }
-                    global_context.unsafe_labels = true;
-                }
-                for p in &daemon_args.label_prefixes {
-                    if !global_context.label_prefixes.contains(p) {
-                        global_context.label_prefixes.push(p.clone());
-                    }
+        let source_args = match &self.command {
+            SubCommands::Daemon(daemon_args) => Some(&daemon_args.source),
+            SubCommands::Export(export_args) => Some(&export_args.source),
+            SubCommands::List(list_args) => Some(&list_args.source),
+            SubCommands::RunNow(run_args) => Some(&run_args.source),
+            SubCommands::Once(once_args) => Some(&once_args.source),
+            SubCommands::Validate(_) | SubCommands::Schema => None,
+        };
+        global_context.config_path = match (self.config.clone(), self.ofelia) {
+            (Some(path), _) => path,
+            (None, false) => global_context.config_path,
+            (None, true) => match OfeliaSource::probe(&OFELIA_CONFIG_PATHS, source_args.is_some_and(|s| s.docker)) {
+                OfeliaSource::File(path) => {
+                    info!("Using the ofelia configuration file {}", path);
+                    path
+                },
+                OfeliaSource::Labels => {
+                    info!("No ofelia configuration file found in {:?}, reading the jobs from container labels only", OFELIA_CONFIG_PATHS);
+                    OFELIA_CONFIG_PATHS[0].to_string()
+                },
+                OfeliaSource::Missing => {
+                    warn!("No ofelia configuration file found in {:?}, use --config or --docker to select the job source", OFELIA_CONFIG_PATHS);
+                    OFELIA_CONFIG_PATHS[0].to_string()
+                },
+            },
+        };
+        global_context.config_format = self.config_format;
+        let limits = &mut global_context.parser_limits;
+        limits.max_input_size = self.max_config_size.unwrap_or(limits.max_input_size);
+        limits.max_jobs = self.max_jobs.unwrap_or(limits.max_jobs);
+        limits.max_scalar_length = self.max_scalar_length.unwrap_or(limits.max_scalar_length);
+        limits.max_label_value_length = self.max_label_value_length.unwrap_or(limits.max_label_value_length);
+        if let SubCommands::Daemon(daemon_args) = &self.command {
+            global_context.promotion_delay = daemon_args.priority_promotion_delay;
+            if let Some(max_parallel) = daemon_args.max_parallel {
+                global_context.set_max_parallel(max_parallel);
+            }
+            global_context.dry_run = daemon_args.dry_run;
+        }
+        if let Some(source_args) = source_args {
+            global_context.unsafe_labels = source_args.allow_unsafe;
+            global_context.socket = source_args.socket_path.clone();
+            global_context.host = source_args.host.clone();
+            global_context.tls_cert = source_args.tls_cert.clone();
+            global_context.tls_key = source_args.tls_key.clone();
+            global_context.tls_ca = source_args.tls_ca.clone();
+            global_context.default_run_network = source_args.default_run_network.clone();
+            global_context.allow_duplicate_names = source_args.allow_duplicate_names;
+            global_context.engine = source_args.engine;
+            for (filter, value) in &source_args.filters {
+                global_context.docker_filters.entry(filter.clone()).or_default().push(value.clone());
+            }
+            global_context.compose_services = source_args.compose_services;
+            if self.ofelia {
+                let ofelia_label = "ofelia".to_string();
+                if !global_context.label_prefixes.contains(&ofelia_label) {
+                    global_context.label_prefixes.push(ofelia_label);
                 }
-                if global_context.label_prefixes.is_empty() {
-                    global_context.label_prefixes.push("cfc".to_string());
+                global_context.unsafe_labels = true;
+            }
+            for p in &source_args.label_prefixes {
+                if !global_context.label_prefixes.contains(p) {
+                    global_context.label_prefixes.push(p.clone());
                 }
-            },
-            SubCommands::Validate(_) => {},
+            }
+            if global_context.label_prefixes.is_empty() {
+                global_context.label_prefixes.push("cfc".to_string());
+            }
         }
         global_context
     }
@@ -113,55 +519,424 @@ async fn main() {
                 3 => Level::DEBUG,
                 _ => Level::TRACE,
             }
-        ).init();
+        )
+        .with_writer(std::io::stderr)
+        .init();
     debug!("{:?}", args);
 
-    let global_context = args.get_context();
+    let mut global_context = args.get_context();
 
-    match args.command {
+    match &args.command {
         SubCommands::Daemon(daemon_args) => {
             // Add delay so docker has time to finish initializing container state
             if is_docker_env() {
                 sleep(Duration::from_secs(1)).await;
             }
-            let targets = if daemon_args.docker {
-                load_labels(&global_context).await.unwrap()
-            } else {
-                load_file(&global_context.config_path, &global_context).await.unwrap()
+            #[cfg(feature = "health")]
+            if let Some(addr) = daemon_args.health_addr {
+                let server = match global_context.get_handle() {
+                    Ok(handle) => cfc::health::HealthServer::bind(addr, handle).await,
+                    Err(e) => Err(e),
+                };
+                match server {
+                    Ok(server) => {
+                        info!("Serving health checks on http://{}/healthz and http://{}/readyz", addr, addr);
+                        tokio::spawn(server.run());
+                    },
+                    Err(e) => {
+                        error!("{}", e);
+                        exit(1);
+                    },
+                }
+            }
+            let mut label_cache = LabelCache::default();
+            let targets = match load_daemon_jobs(daemon_args, &mut global_context, &mut label_cache).await {
+                Ok(targets) => targets,
+                Err(e) => {
+                    error!("Failed to load the jobs: {}", e);
+                    exit(1);
+                },
             };
-            trace!("Generated jobs list: {:?}", targets);
+            trace!("Generated jobs list: {:?}", Elided(&targets.iter().map(|t| t.to_parameters()).collect::<Vec<_>>()));
             if targets.is_empty() {
                 error!("No valid job could be found, stopping with an error");
                 exit(1);
             }
+            let checksum = config_checksum(&targets);
+            info!("Loaded {} jobs, configuration checksum: {}", targets.len(), checksum);
+            cfc::metrics::set_config_checksum(checksum);
+            #[cfg(feature = "metrics")]
+            if let Err(e) = cfc::metrics::set_annotation_labels(daemon_args.metrics_annotations.clone()) {
+                error!("{}", e);
+                exit(1);
+            }
+            #[cfg(feature = "metrics")]
+            if let Some(addr) = daemon_args.metrics_addr {
+                match cfc::metrics::MetricsServer::bind(addr).await {
+                    Ok(server) => {
+                        info!("Serving metrics on http://{}/metrics", addr);
+                        tokio::spawn(server.run());
+                    },
+                    Err(e) => {
+                        error!("{}", e);
+                        exit(1);
+                    },
+                }
+            }
+            #[cfg(feature = "history")]
+            if let Some(path) = &daemon_args.history_db {
+                match cfc::history_db::configure(Some(path), daemon_args.history_retention) {
+                    Ok(()) => info!("Recording the executions in {}, for {} seconds", path.display(), daemon_args.history_retention.as_secs()),
+                    Err(e) => {
+                        error!("{}", e);
+                        exit(1);
+                    },
+                }
+            }
 
-            let mut set = JoinSet::new();
+            match (&daemon_args.state_volume, daemon_args.source.docker) {
+                (Some(volume), true) => {
+                    let store = VolumeStateStore::new(global_context.get_handle().unwrap(), volume, &daemon_args.state_image);
+                    match cfc::state::configure(Some(Arc::new(store))).await {
+                        Ok(()) => info!("Writing the last run of the jobs to the volume {}", volume),
+                        Err(e) => {
+                            error!("{}", e);
+                            exit(1);
+                        },
+                    }
+                },
+                (Some(_), false) => warn!("The state volume only holds the jobs discovered from docker labels, ignoring --state-volume"),
+                (None, _) => {},
+            }
 
+            if let Err(e) = slack::configure(global_context.slack_webhook.clone(), global_context.slack_collapse_window) {
+                error!("Failed to configure the Slack notifications: {}", e);
+                exit(1);
+            }
+            let (engine, endpoint) = global_context.engine_endpoint();
+            info!("Using the {} container engine at {}", engine, endpoint);
             trace!("Registering all jobs for run");
-            let base_handle = global_context.get_handle().unwrap();
+            let mut scheduler = Scheduler::new(global_context.get_handle().unwrap());
+            scheduler.set_restart_failed(!daemon_args.fail_fast);
+            scheduler.set_execution_permits(global_context.execution_permits.clone());
+            // Jobs from files and labels share the same groups
+            scheduler.set_group_locks(GroupLocks::default());
+            let spawn_budget = daemon_args.max_spawns_per_hour.map(SpawnBudget::new);
+            cfc::metrics::set_spawn_budget(spawn_budget.clone());
+            scheduler.set_spawn_budget(spawn_budget);
+            if global_context.dry_run {
+                warn!("Running in dry-run mode: the triggered jobs are only logged and nothing is executed");
+                scheduler.set_dry_run(true);
+            }
+            *scheduler.label_cache() = label_cache;
             for target in targets {
-                let handle = base_handle.clone();
-                set.spawn(async move {target.start(handle).await});
+                if !target.options().disabled {
+                    log_next_occurrences(&target);
+                }
+                scheduler.start(target);
             }
+            health::set_ready(true);
 
             trace!("Registering interrupt handler");
+            let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+            let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+            let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+            let mut quit = signal(SignalKind::quit()).expect("Failed to listen for SIGQUIT");
+            let mut pause = signal(SignalKind::user_defined1()).expect("Failed to listen for SIGUSR1");
+            let mut resume = signal(SignalKind::user_defined2()).expect("Failed to listen for SIGUSR2");
+            let watched_path = match (&daemon_args.from_lock, daemon_args.source.docker) {
+                (Some(lock), _) => Some(lock.clone()),
+                (None, false) => Some(global_context.config_path.clone()),
+                (None, true) => None,
+            };
+            let mut watcher = match (daemon_args.watch, watched_path) {
+                (true, None) => {
+                    warn!("The configuration file is not used when extracting configuration from docker labels, ignoring --watch");
+                    None
+                },
+                (true, Some(path)) if path == STDIN_PATH => {
+                    warn!("The configuration is read from the standard input, ignoring --watch");
+                    None
+                },
+                (true, Some(path)) => Some(FileWatcher::new(path, Duration::from_secs(1), Duration::from_millis(500))),
+                _ => None,
+            };
+
+            let mut label_discovery = (daemon_args.source.docker && daemon_args.from_lock.is_none())
+                .then(|| follow_labels(&global_context, daemon_args.rescan_interval));
 
             info!("Start running all jobs");
-            tokio::select! {
-                interrupt = tokio::signal::ctrl_c() => {
-                    interrupt.expect("Failed to listen for event");
-                    warn!("Received shutdown signal, stopping all tasks before exiting");
-                    set.shutdown().await;
-                    exit(0);
-                },
-                r = set.join_next() => debug!("A job ended unexpectedly {:?}", r),
+            let mut heartbeat = interval(health::HEARTBEAT_INTERVAL);
+            let mut clock_check = interval(clock::CLOCK_CHECK_INTERVAL);
+            let mut clock_watch = clock::ClockWatch::new(clock::CLOCK_JUMP_THRESHOLD);
+            loop {
+                tokio::select! {
+                    _ = heartbeat.tick() => health::heartbeat(),
+                    _ = clock_check.tick() => if let Some(jump) = clock_watch.check() {
+                        clock::log_jump(&jump);
+                        scheduler.clock_jumped(jump);
+                    },
+                    _ = interrupt.recv() => shutdown_gracefully(&mut scheduler, daemon_args.shutdown_grace, "SIGINT", &mut interrupt, &mut terminate).await,
+                    _ = terminate.recv() => shutdown_gracefully(&mut scheduler, daemon_args.shutdown_grace, "SIGTERM", &mut interrupt, &mut terminate).await,
+                    _ = quit.recv() => {
+                        log_job_table(&scheduler);
+                        if let Some(discovery) = label_discovery.as_ref() {
+                            warn!("Containers are discovered with {}", discovery.mode());
+                        }
+                        log_histories(&scheduler);
+                        shutdown_gracefully(&mut scheduler, daemon_args.shutdown_grace, "SIGQUIT", &mut interrupt, &mut terminate).await
+                    },
+                    _ = pause.recv() => {
+                        let paused = scheduler.set_state(JobState::Paused);
+                        warn!("Received SIGUSR1, pausing {} jobs until SIGUSR2 is received", paused);
+                    },
+                    _ = resume.recv() => {
+                        let resumed = scheduler.set_state(JobState::Active);
+                        info!("Received SIGUSR2, resuming {} jobs", resumed);
+                    },
+                    _ = hangup.recv() => {
+                        info!("Received SIGHUP, reloading the configuration");
+                        reload_jobs(&args, daemon_args, &mut scheduler).await;
+                    },
+                    _ = async { match watcher.as_mut() { Some(w) => w.changed().await, None => std::future::pending().await } } => {
+                        info!("The configuration file changed, reloading the configuration");
+                        reload_jobs(&args, daemon_args, &mut scheduler).await;
+                    },
+                    discovery = async { match label_discovery.as_mut() { Some(d) => d.next().await, None => std::future::pending().await } } => match discovery {
+                        Discovery::Event(ContainerEvent::Started(id)) => {
+                            info!("Container {} started, reloading the jobs declared in labels", id);
+                            reload_jobs(&args, daemon_args, &mut scheduler).await;
+                        },
+                        Discovery::Event(ContainerEvent::Stopped(id)) => {
+                            info!("Container {} stopped, reloading the jobs declared in labels", id);
+                            reload_jobs(&args, daemon_args, &mut scheduler).await;
+                        },
+                        Discovery::Rescan => {
+                            debug!("Rescanning the containers for jobs declared in labels");
+                            reload_jobs(&args, daemon_args, &mut scheduler).await;
+                        },
+                    },
+                    r = scheduler.join_next() => match r {
+                        Some((name, Ok(Some(true)))) => debug!("The job {} ended its scheduling", name),
+                        Some((name, Ok(Some(false)))) => warn!("The job {} can not be scheduled anymore", name),
+                        Some((name, Ok(None))) => warn!("The job {} ended its scheduling unexpectedly", name),
+                        Some((_, Err(e))) if e.is::<StopDaemonError>() => {
+                            error!("{}, stopping all jobs", e);
+                            scheduler.stop(daemon_args.shutdown_grace).await;
+                            cfc::job::flush_hooks(cfc::job::HOOK_FLUSH_TIMEOUT).await;
+                            webhook::flush(webhook::DELIVERY_TIMEOUT).await;
+                            exit(1);
+                        },
+                        Some((name, Err(e))) if daemon_args.fail_fast => {
+                            error!("The scheduler of job {} failed, stopping all jobs: {}", name, e);
+                            scheduler.shutdown().await;
+                            exit(1);
+                        },
+                        Some((name, Err(e))) => error!("The scheduler of job {} failed: {}", name, e),
+                        None => {
+                            info!("All jobs ended their scheduling, stopping");
+                            cfc::job::flush_hooks(cfc::job::HOOK_FLUSH_TIMEOUT).await;
+                            webhook::flush(webhook::DELIVERY_TIMEOUT).await;
+                            exit(0);
+                        },
+                    },
+                }
             }
-            error!("Stopping. This should never happen");
         }
-        SubCommands::Validate(_) => {
-            match load_file(&global_context.config_path, &global_context).await {
-                Ok(_) => {
-                    info!["Successfully loaded configuration file"];
+        SubCommands::List(list_args) => {
+            match load_jobs(&list_args.source, &mut global_context, &mut LabelCache::default()).await {
+                Ok(jobs) => print_jobs(jobs, list_args.json),
+                Err(e) => {
+                    error!["Failed to load the jobs: {}", e];
+                    exit(1);
+                },
+            }
+        },
+        SubCommands::RunNow(run_args) => {
+            let jobs = match load_jobs(&run_args.source, &mut global_context, &mut LabelCache::default()).await {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    error!["Failed to load the jobs: {}", e];
+                    exit(1);
+                },
+            };
+            let mut names: Vec<String> = jobs.iter().map(|j| j.name().clone()).collect();
+            let Some(job) = jobs.into_iter().find(|j| j.name() == &run_args.name) else {
+                names.sort();
+                error!["No job is named '{}', available jobs are: {}", run_args.name, names.join(", ")];
+                exit(1);
+            };
+            let handle = match global_context.get_handle() {
+                Ok(handle) => handle,
+                Err(e) => {
+                    error!["Failed to connect to the container engine: {}", e];
+                    exit(1);
+                },
+            };
+            let merge_streams = job.options().merge_streams;
+            let summary = job.clone();
+            let start_time = Instant::now();
+            let result = job.run_once(&handle).await;
+            let duration = start_time.elapsed().as_secs_f64();
+            let mut code = match &result {
+                Ok(report) => {
+                    if let Err(e) = report.write_output(merge_streams, &mut std::io::stdout(), &mut std::io::stderr()) {
+                        error!["Failed to forward the output of job {}: {}", run_args.name, e];
+                    }
+                    if report.timed_out {
+                        error![category = %ErrorCategory::Timeout, "Job {} timed out", run_args.name];
+                        // Same exit code as timeout(1)
+                        124
+                    } else {
+                        info!["Job {} exited with code {}", run_args.name, report.retval];
+                        i32::try_from(report.retval).unwrap_or(1)
+                    }
+                },
+                Err(e) => {
+                    error![category = %ErrorCategory::of(e), "Failed to run job {}: {}", run_args.name, e];
+                    1
+                },
+            };
+            if let Some(path) = run_args.report_file.as_ref() {
+                let report = BatchReport::new(vec![JobRunReport::new(&summary, result, duration)]);
+                if let Err(e) = report.write(path) {
+                    error!["{}", e];
+                    if code == 0 {
+                        code = 1;
+                    }
+                }
+            }
+            exit(code);
+        },
+        SubCommands::Once(once_args) => {
+            let jobs = match load_jobs(&once_args.source, &mut global_context, &mut LabelCache::default()).await {
+                Ok(jobs) if jobs.is_empty() => {
+                    error!["No valid job could be found, stopping with an error"];
+                    exit(1);
+                },
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    error!["Failed to load the jobs: {}", e];
+                    exit(1);
+                },
+            };
+            let handle = match global_context.get_handle() {
+                Ok(handle) => handle,
+                Err(e) => {
+                    error!["Failed to connect to the container engine: {}", e];
+                    exit(1);
+                },
+            };
+            let report = run_jobs_once(jobs, &handle).await;
+            let counts = &report.counts;
+            let mut code = match counts.success == counts.total {
+                true => {
+                    info!["All {} jobs succeeded", counts.total];
+                    0
+                },
+                false => {
+                    error![
+                        "{} of {} jobs did not succeed: {} failed, {} timed out and {} could not be run",
+                        counts.total - counts.success, counts.total, counts.failure, counts.timeout, counts.error,
+                    ];
+                    1
+                },
+            };
+            if let Some(path) = once_args.report_file.as_ref() {
+                if let Err(e) = report.write(path) {
+                    error!["{}", e];
+                    code = 1;
+                }
+            }
+            exit(code);
+        },
+        SubCommands::Export(export_args) => {
+            let content = load_jobs(&export_args.source, &mut global_context, &mut LabelCache::default()).await
+                .and_then(|jobs| export_jobs(&jobs));
+            let result = match (content, export_args.output.as_ref()) {
+                (Ok(content), Some(output)) => tokio::fs::write(output, content).await.map_err(anyhow::Error::new),
+                (Ok(content), None) => {
+                    print!("{}", content);
+                    Ok(())
+                },
+                (Err(e), _) => Err(e),
+            };
+            if let Err(e) = result {
+                error!["Failed to export the job set: {}", e];
+                exit(1);
+            }
+        },
+        SubCommands::Validate(validate_args) if validate_args.migrate => {
+            match migrate_config(&global_context.config_path, &global_context).await {
+                Ok(files) if files.len() == 1 => print!("{}", files[0].1),
+                Ok(files) => for (i, (path, content)) in files.iter().enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    println!("# {}", path.display());
+                    print!("{}", content);
+                },
+                Err(e) => {
+                    error!["Failed to migrate the configuration: {}", e];
+                    exit(1);
+                },
+            }
+        },
+        SubCommands::Validate(validate_args) => {
+            match load_file(&global_context.config_path.clone(), &mut global_context).await {
+                Ok(jobs) => {
+                    info!["Successfully loaded configuration file, checksum: {}", config_checksum(&jobs)];
+                    let now = chrono::Local::now();
+                    let horizon = chrono::Duration::from_std(validate_args.horizon).unwrap_or(chrono::Duration::max_value());
+                    let mut never_triggered = vec![];
+                    for job in jobs {
+                        if job.options().disabled {
+                            println!("{}: disabled, it will not be scheduled", job.name());
+                            continue;
+                        }
+                        let schedule = job.as_job().get_schedule();
+                        match schedule.fields() {
+                            Some([second, minute, hour, dom, month, dow]) => println!(
+                                "{}: '{}' is read as second={} minute={} hour={} dom={} month={} dow={}",
+                                job.name(), schedule, second, minute, hour, dom, month, dow,
+                            ),
+                            None if schedule.after_completion().is_some() => println!("{}: '{}' is read as a delay after the end of the previous run", job.name(), schedule),
+                            None => println!("{}: '{}' is read as a fixed interval", job.name(), schedule),
+                        }
+                        println!("{}: next runs on {}", job.name(), format_occurrences(&job.next_occurrences(VALIDATED_OCCURRENCES)));
+                        match job.next_occurrence(&now) {
+                            Ok(Some(next)) if next - now > horizon => warn![
+                                "The next occurence of job {} is on {}, check its schedule for typos",
+                                job.name(), next,
+                            ],
+                            Ok(Some(_)) => {},
+                            Ok(None) => warn!["Job {} has no occurence left before its not-after date", job.name()],
+                            Err(e) => {
+                                error!["Job {} will never be triggered: {}", job.name(), e];
+                                never_triggered.push(job.name().clone());
+                            },
+                        }
+                    }
+                    if !never_triggered.is_empty() {
+                        error!["The schedule of jobs {} can never fire", never_triggered.join(", ")];
+                        exit(1);
+                    }
+                    if validate_args.deep {
+                        let store = match global_context.get_handle() {
+                            Ok(handle) => VolumeStateStore::new(handle, &validate_args.state_volume, &validate_args.state_image),
+                            Err(e) => {
+                                error!["Failed to connect to the container engine: {}", e];
+                                exit(1);
+                            },
+                        };
+                        match store.ensure().await {
+                            Ok(()) => info!["The state volume {} can be written", validate_args.state_volume],
+                            Err(e) => {
+                                error!["{}", e];
+                                exit(1);
+                            },
+                        }
+                    }
                 },
                 Err(e) => {
                     error!["Failed to load the configuration file: {}", e];
@@ -169,5 +944,6 @@ async fn main() {
                 },
             }
         },
+        SubCommands::Schema => println!("{}", cfc::job::schema()),
     }
 }
@@ -0,0 +1,108 @@
+//! Detection of the discontinuities of the wall clock
+//!
+//! Timers are measured with the monotonic clock, which does not advance while the host is
+//! suspended, while schedules are expressed in wall-clock time. When both clocks drift apart
+//! (suspend, VM migration, large NTP step), the timers armed before the drift fire at the wrong
+//! time and must be re-armed.
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use tracing::warn;
+
+/// How often the clocks are compared
+pub const CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The minimum drift between the clocks for it to be considered a jump
+pub const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(60);
+
+/// A discontinuity of the wall clock
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockJump {
+    /// The wall-clock time of the last check before the jump
+    pub before: DateTime<Local>,
+    /// The wall-clock time at which the jump was detected
+    pub after: DateTime<Local>,
+    /// How much further the wall clock advanced than the monotonic clock, negative if it went back
+    pub gap: chrono::Duration,
+}
+
+impl ClockJump {
+    /// The wall-clock period skipped by the jump, if the clock jumped forward
+    pub fn skipped(&self) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        (self.gap > chrono::Duration::zero()).then_some((self.before, self.after))
+    }
+}
+
+/// Compares the monotonic and wall clocks on each check
+pub struct ClockWatch {
+    last: (Instant, DateTime<Local>),
+    threshold: chrono::Duration,
+}
+
+impl ClockWatch {
+    pub fn new(threshold: Duration) -> Self {
+        ClockWatch {
+            last: (Instant::now(), Local::now()),
+            threshold: chrono::Duration::from_std(threshold).unwrap_or_else(|_| chrono::Duration::max_value()),
+        }
+    }
+
+    /// Compare the clocks with their values at the previous check
+    pub fn check(&mut self) -> Option<ClockJump> {
+        self.check_at(Instant::now(), Local::now())
+    }
+
+    /// Compare the provided clock values with the ones of the previous check
+    pub fn check_at(&mut self, monotonic: Instant, wall: DateTime<Local>) -> Option<ClockJump> {
+        let (last_monotonic, last_wall) = std::mem::replace(&mut self.last, (monotonic, wall));
+        let monotonic_elapsed = chrono::Duration::from_std(monotonic.saturating_duration_since(last_monotonic)).ok()?;
+        let gap = (wall - last_wall) - monotonic_elapsed;
+        (gap.abs() >= self.threshold).then_some(ClockJump { before: last_wall, after: wall, gap })
+    }
+}
+
+/// Format a gap between the clocks for logs
+pub fn format_gap(gap: chrono::Duration) -> String {
+    let seconds = gap.num_seconds().abs();
+    format!(
+        "{} by {}h{:02}m{:02}s",
+        if gap < chrono::Duration::zero() { "backward" } else { "forward" },
+        seconds / 3600, seconds / 60 % 60, seconds % 60,
+    )
+}
+
+/// Log a clock jump prominently
+pub fn log_jump(jump: &ClockJump) {
+    warn!(
+        "The system clock jumped {} between {} and {} (suspend, VM migration or time step), re-evaluating the schedule of all jobs",
+        format_gap(jump.gap), jump.before.to_rfc3339(), jump.after.to_rfc3339(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use chrono::Local;
+
+    use super::{format_gap, ClockWatch};
+
+    #[test]
+    fn detect_clock_jumps() {
+        let mut watch = ClockWatch::new(Duration::from_secs(60));
+        let (monotonic, wall) = (Instant::now(), Local::now());
+        assert_eq!(watch.check_at(monotonic, wall), None);
+        // Both clocks advanced alike
+        assert_eq!(watch.check_at(monotonic + Duration::from_secs(30), wall + chrono::Duration::seconds(31)), None);
+        // The host was suspended overnight: the monotonic clock advanced 30 seconds, the wall clock 10 hours
+        let jump = watch.check_at(monotonic + Duration::from_secs(60), wall + chrono::Duration::hours(10)).unwrap();
+        assert_eq!(jump.gap, chrono::Duration::hours(10) - chrono::Duration::seconds(61));
+        assert_eq!(jump.skipped(), Some((wall + chrono::Duration::seconds(31), wall + chrono::Duration::hours(10))));
+        // The clock was stepped back
+        let jump = watch.check_at(monotonic + Duration::from_secs(90), wall + chrono::Duration::hours(9)).unwrap();
+        assert!(jump.gap < chrono::Duration::zero());
+        assert_eq!(jump.skipped(), None);
+        assert_eq!(format_gap(chrono::Duration::seconds(36061)), "forward by 10h01m01s");
+        assert_eq!(format_gap(chrono::Duration::seconds(-90)), "backward by 0h01m30s");
+    }
+}
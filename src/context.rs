@@ -1,12 +1,205 @@
+use std::{collections::HashMap, fmt::{Display, Formatter}, num::NonZeroUsize, path::{Path, PathBuf}, str::FromStr, time::Duration};
+
 use anyhow::{Error, Result};
 use bollard::{Docker, API_DEFAULT_VERSION};
-use tracing::error;
+use tracing::{debug, error, warn};
+
+use crate::{job::{parse_duration, validate_schedule_alias, ExecutionPermits, JobTimezone, SandboxKind, DEFAULT_PROMOTION_DELAY}, loader::{ConfigFormat, ParserLimits}, slack, take_one, webhook::WebhookUrl};
+
+/// The timeout of requests sent to the container engine, in seconds
+const DOCKER_TIMEOUT: u64 = 120;
+
+/// The client certificate, client key and certificate authority used to connect to the container engine
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub ca: PathBuf,
+}
+
+impl TlsFiles {
+    /// Get the `cert.pem`, `key.pem` and `ca.pem` files of a directory, as the docker client does
+    /// for `DOCKER_CERT_PATH`
+    pub fn in_directory(dir: &Path) -> Self {
+        TlsFiles { cert: dir.join("cert.pem"), key: dir.join("key.pem"), ca: dir.join("ca.pem") }
+    }
+
+    /// Get the files set by `DOCKER_CERT_PATH`, or the ones of `~/.docker` like the docker client
+    fn from_env() -> Result<Self> {
+        match std::env::var("DOCKER_CERT_PATH").ok().filter(|p| !p.is_empty()) {
+            Some(dir) => Ok(Self::in_directory(Path::new(&dir))),
+            None => std::env::var("HOME").ok().filter(|h| !h.is_empty())
+                .map(|home| Self::in_directory(&Path::new(&home).join(".docker")))
+                .ok_or_else(|| Error::msg("DOCKER_TLS_VERIFY is set but neither DOCKER_CERT_PATH nor HOME is")),
+        }
+    }
+}
+
+/// Connect to the container engine listening at a `DOCKER_HOST` address, with TLS if files are given
+fn connect_to_host(host: &str, tls: Option<&TlsFiles>) -> Result<Docker> {
+    match (host.split_once("://").map_or("", |(scheme, _)| scheme), tls) {
+        ("unix", _) => Docker::connect_with_unix(host, DOCKER_TIMEOUT, API_DEFAULT_VERSION).map_err(Error::new),
+        ("tcp" | "http", None) => Docker::connect_with_http(host, DOCKER_TIMEOUT, API_DEFAULT_VERSION).map_err(Error::new),
+        ("tcp" | "https", Some(tls)) => Docker::connect_with_ssl(host, &tls.key, &tls.cert, &tls.ca, DOCKER_TIMEOUT, API_DEFAULT_VERSION)
+            .map_err(|e| Error::msg(format!("Cannot connect to {} with TLS: {}", host, e))),
+        ("https", None) => Err(Error::msg(format!(
+            "Cannot connect to {}: TLS connections require --tls-cert, --tls-key and --tls-ca, or DOCKER_TLS_VERIFY", host,
+        ))),
+        ("http", Some(_)) => Err(Error::msg(format!("Cannot connect to {} with TLS, use a tcp:// or https:// address", host))),
+        ("ssh", _) => Err(Error::msg(format!(
+            "Cannot connect to {}: SSH connections are not supported, forward the remote socket with 'ssh -L' and use its local path instead", host,
+        ))),
+        _ => Err(Error::msg(format!("Unsupported DOCKER_HOST value '{}', expected a unix://, tcp://, http:// or https:// address", host))),
+    }
+}
+
+/// The socket of the Docker engine
+pub const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// The socket of the rootful podman service
+pub const PODMAN_SOCKET: &str = "/run/podman/podman.sock";
+
+/// The container engines whose default sockets are known
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContainerEngine {
+    Docker,
+    Podman,
+}
+
+impl ContainerEngine {
+    /// The sockets the engine listens on by default, in the order they are probed.
+    /// Podman's rootless socket is in the user's runtime directory.
+    pub fn default_sockets(&self) -> Vec<String> {
+        match self {
+            ContainerEngine::Docker => vec![DOCKER_SOCKET.to_string()],
+            ContainerEngine::Podman => std::env::var("XDG_RUNTIME_DIR").ok()
+                .filter(|d| !d.is_empty())
+                .map(|d| format!("{}/podman/podman.sock", d.trim_end_matches('/')))
+                .into_iter()
+                .chain([PODMAN_SOCKET.to_string()])
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for ContainerEngine {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "docker" => Ok(ContainerEngine::Docker),
+            "podman" => Ok(ContainerEngine::Podman),
+            _ => Err(Error::msg(format!("Unsupported container engine '{}', expected docker or podman", s))),
+        }
+    }
+}
+
+impl Display for ContainerEngine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerEngine::Docker => write!(f, "docker"),
+            ContainerEngine::Podman => write!(f, "podman"),
+        }
+    }
+}
+
+/// Whether something accepts connections on a unix socket
+fn is_reachable(path: &str) -> bool {
+    std::os::unix::net::UnixStream::connect(path).is_ok()
+}
+
+/// Select the socket to connect to when none is configured: the first reachable default socket
+/// of the engine if one is forced, else Docker's, else podman's. When none is reachable, the
+/// first candidate is returned so that the connection error names it.
+fn select_socket(engine: Option<ContainerEngine>, reachable: impl Fn(&str) -> bool) -> (ContainerEngine, String) {
+    let engines = match engine {
+        Some(engine) => vec![engine],
+        None => vec![ContainerEngine::Docker, ContainerEngine::Podman],
+    };
+    for engine in &engines {
+        if let Some(socket) = engine.default_sockets().into_iter().find(|s| reachable(s)) {
+            return (*engine, socket);
+        }
+    }
+    (engines[0], engines[0].default_sockets().remove(0))
+}
+
+/// The configuration files read by ofelia, in the order they are looked for
+pub const OFELIA_CONFIG_PATHS: [&str; 2] = ["/etc/ofelia.conf", "/etc/ofelia/config.ini"];
+
+/// Where the jobs are read from in ofelia-compatibility mode when no configuration file is provided
+#[derive(Debug, PartialEq)]
+pub enum OfeliaSource {
+    /// The first well-known configuration file that exists
+    File(String),
+    /// No configuration file exists, the jobs are only read from container labels
+    Labels,
+    /// No configuration file exists and container labels are not used
+    Missing,
+}
+
+impl OfeliaSource {
+    /// Look for the first existing configuration file among the candidates
+    pub fn probe(candidates: &[&str], docker: bool) -> Self {
+        match candidates.iter().find(|p| std::path::Path::new(p).is_file()) {
+            Some(path) => OfeliaSource::File(path.to_string()),
+            None if docker => OfeliaSource::Labels,
+            None => OfeliaSource::Missing,
+        }
+    }
+}
 
 pub struct ApplicationContext {
     pub label_prefixes: Vec<String>,
     pub socket: Option<String>,
     pub unsafe_labels: bool,
     pub config_path: String,
+    /// The format of the configuration files, guessed from their extension if unset
+    pub config_format: Option<ConfigFormat>,
+    /// The timezone used when evaluating the schedules of jobs that do not configure one
+    pub timezone: Option<JobTimezone>,
+    /// The sandbox used by local jobs that do not configure one
+    pub sandbox: Option<SandboxKind>,
+    /// The number of bytes of each output stream kept by the exec and local jobs that do not set `max-output-size`
+    pub max_output_size: Option<usize>,
+    /// The limits applied when parsing configuration files
+    pub parser_limits: ParserLimits,
+    /// Custom `@` schedules that jobs may use, mapped to the schedule they stand for
+    pub schedule_aliases: HashMap<String, String>,
+    /// Whether cron expressions that do not match their job's seconds hint are rejected
+    pub strict_schedule: bool,
+    /// Whether container jobs may forward variables from cfc's environment with bare `NAME` entries
+    pub pass_environment: bool,
+    /// The address of a remote container engine, takes precedence over `DOCKER_HOST`
+    pub host: Option<String>,
+    /// The path to the client certificate used to connect to the container engine
+    pub tls_cert: Option<String>,
+    /// The path to the client key used to connect to the container engine
+    pub tls_key: Option<String>,
+    /// The path to the certificate authority used to verify the container engine
+    pub tls_ca: Option<String>,
+    /// Limits the number of executions that run at the same time across all jobs
+    pub execution_permits: Option<ExecutionPermits>,
+    /// How long an execution waits for a permit before it is admitted before higher-priority ones
+    pub promotion_delay: Duration,
+    /// The network of the containers started by run jobs that do not set one, see [`LABEL_RUN_NETWORK`][crate::loader::LABEL_RUN_NETWORK]
+    pub default_run_network: Option<String>,
+    /// The URL the execution reports of the jobs that do not set one are posted to
+    pub webhook_url: Option<WebhookUrl>,
+    /// The Slack incoming webhook that job failures are notified to
+    pub slack_webhook: Option<WebhookUrl>,
+    /// The delay during which the repeated failures of a job are collapsed in a single Slack notification
+    pub slack_collapse_window: Duration,
+    /// Whether the daemon only logs what the triggered jobs would execute instead of running them
+    pub dry_run: bool,
+    /// Whether several jobs may have the same name, which is otherwise a configuration error
+    pub allow_duplicate_names: bool,
+    /// The container engine whose default socket is used, probed if unset
+    pub engine: Option<ContainerEngine>,
+    /// The filters of the engine's container list that the containers declaring jobs in their labels must also match
+    pub docker_filters: HashMap<String, Vec<String>>,
+    /// Whether the replicas of a Compose service declare a single job for the service instead of one job each
+    pub compose_services: bool,
 }
 
 impl Default for ApplicationContext {
@@ -16,17 +209,306 @@ impl Default for ApplicationContext {
             socket: None,
             unsafe_labels: false,
             config_path: "/etc/cfc.conf".to_string(),
+            config_format: None,
+            timezone: None,
+            sandbox: None,
+            max_output_size: None,
+            parser_limits: ParserLimits::default(),
+            schedule_aliases: HashMap::new(),
+            strict_schedule: false,
+            pass_environment: false,
+            host: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca: None,
+            execution_permits: None,
+            promotion_delay: DEFAULT_PROMOTION_DELAY,
+            default_run_network: None,
+            webhook_url: None,
+            slack_webhook: None,
+            slack_collapse_window: slack::DEFAULT_COLLAPSE_WINDOW,
+            dry_run: false,
+            allow_duplicate_names: false,
+            engine: None,
+            docker_filters: HashMap::new(),
+            compose_services: false,
         }
     }
 }
 impl ApplicationContext {
-    pub fn get_handle(self: &Self) -> Result<Docker> {
-        match self.socket.as_ref() {
-            Some(path) => Docker::connect_with_socket(path, 120, API_DEFAULT_VERSION),
-            None => Docker::connect_with_defaults(),
-        }.map_err(|e| {
-            error!("Failed to connect to Docker: {}", e);
-            Error::new(e)
-        })
+    /// Get the address of the container engine, as a socket path or a `DOCKER_HOST` address
+    fn host(&self) -> Option<String> {
+        self.host.clone().or_else(|| std::env::var("DOCKER_HOST").ok().filter(|h| !h.is_empty()))
+    }
+
+    /// Get the container engine that is connected to and its address.
+    ///
+    /// The configured socket path takes precedence over the `DOCKER_HOST` environment
+    /// variable. If neither is set, the default sockets are probed: Docker's first, then
+    /// podman's, unless an engine is forced.
+    pub fn engine_endpoint(&self) -> (ContainerEngine, String) {
+        let guess = |address: &str| self.engine.unwrap_or(match address.contains("podman") {
+            true => ContainerEngine::Podman,
+            false => ContainerEngine::Docker,
+        });
+        match (self.socket.as_ref(), self.host()) {
+            (Some(path), _) => (guess(path), path.clone()),
+            (None, Some(host)) => (guess(&host), host),
+            (None, None) => select_socket(self.engine, is_reachable),
+        }
+    }
+
+    /// Get a handle to the container engine, see [`engine_endpoint`][Self::engine_endpoint]
+    pub fn get_handle(&self) -> Result<Docker> {
+        let tls = self.check_tls()?;
+        match (self.socket.as_ref(), self.host()) {
+            (Some(path), _) => Docker::connect_with_socket(path, DOCKER_TIMEOUT, API_DEFAULT_VERSION).map_err(Error::new),
+            (None, Some(host)) => match tls {
+                Some(tls) => connect_to_host(&host, Some(&tls)),
+                None if std::env::var("DOCKER_TLS_VERIFY").is_ok_and(|v| !v.is_empty()) => connect_to_host(&host, Some(&TlsFiles::from_env()?)),
+                None => connect_to_host(&host, None),
+            },
+            (None, None) if tls.is_some() => Err(Error::msg("A host is required to connect to the container engine with TLS")),
+            (None, None) => {
+                let (engine, socket) = select_socket(self.engine, is_reachable);
+                debug!("Connecting to the {} socket {}", engine, socket);
+                Docker::connect_with_socket(&socket, DOCKER_TIMEOUT, API_DEFAULT_VERSION).map_err(Error::new)
+            },
+        }.inspect_err(|e| error!("Failed to connect to the container engine: {}", e))
+    }
+
+    /// Limit the number of executions that may run at the same time across all jobs
+    pub fn set_max_parallel(&mut self, max_parallel: NonZeroUsize) {
+        self.execution_permits = Some(ExecutionPermits::new(max_parallel.get(), self.promotion_delay));
+    }
+
+    /// Check the TLS configuration and return the files to connect with, if TLS should be used.
+    /// The certificate, key and certificate authority must be provided together.
+    pub fn check_tls(&self) -> Result<Option<TlsFiles>> {
+        let triplet = [("tls-cert", &self.tls_cert), ("tls-key", &self.tls_key), ("tls-ca", &self.tls_ca)];
+        let missing: Vec<&str> = triplet.iter().filter(|(_, v)| v.is_none()).map(|(k, _)| *k).collect();
+        match missing.len() {
+            3 => Ok(None),
+            0 => {
+                for (key, path) in triplet {
+                    let path = path.as_ref().unwrap();
+                    if std::fs::metadata(path).is_err() {
+                        return Err(Error::msg(format!("The {} file {} can not be read", key, path)));
+                    }
+                }
+                Ok(Some(TlsFiles {
+                    cert: self.tls_cert.clone().unwrap().into(),
+                    key: self.tls_key.clone().unwrap().into(),
+                    ca: self.tls_ca.clone().unwrap().into(),
+                }))
+            },
+            _ => Err(Error::msg(format!("The TLS configuration is incomplete, missing {}", missing.join(", ")))),
+        }
+    }
+
+    /// Merge the settings of a configuration file's `global` section into the context.
+    ///
+    /// Values that were already set (e.g. from the command-line) take precedence over
+    /// the ones found in the configuration file. Unknown keys are ignored with a warning.
+    pub fn apply_global(&mut self, mut global: HashMap<String, Vec<String>>) -> Result<()> {
+        if let Some(prefixes) = global.remove("prefix") {
+            for p in prefixes {
+                if !self.label_prefixes.contains(&p) {
+                    self.label_prefixes.push(p);
+                }
+            }
+        }
+        if let Some(socket) = take_one!(global, "socket-path")? {
+            if self.socket.is_none() {
+                self.socket = Some(socket);
+            } else {
+                debug!("Ignoring the global socket path as one was already provided");
+            }
+        }
+        if let Some(allow_unsafe) = take_one!(global, "allow-unsafe-jobs")? {
+            self.unsafe_labels |= allow_unsafe.parse::<bool>().map_err(Error::new)?;
+        }
+        if let Some(timezone) = take_one!(global, "timezone")? {
+            self.timezone = Some(timezone.parse()?);
+        }
+        if let Some(strict) = take_one!(global, "strict-schedule")? {
+            self.strict_schedule = strict.parse().map_err(Error::new)?;
+        }
+        if let Some(pass) = take_one!(global, "pass-environment")? {
+            self.pass_environment = pass.parse().map_err(Error::new)?;
+        }
+        if let Some(max_parallel) = take_one!(global, "max-parallel")? {
+            if self.execution_permits.is_none() {
+                let max_parallel = max_parallel.parse()
+                    .map_err(|_| Error::msg(format!("The global max-parallel value must be a number greater than 0, got '{}'", max_parallel)))?;
+                self.set_max_parallel(max_parallel);
+            } else {
+                debug!("Ignoring the global max-parallel value as one was already provided");
+            }
+        }
+        if let Some(url) = take_one!(global, "webhook-url")? {
+            self.webhook_url = Some(url.parse()?);
+        }
+        if let Some(url) = take_one!(global, "slack-webhook")? {
+            self.slack_webhook = Some(url.parse()?);
+        }
+        if let Some(window) = take_one!(global, "slack-collapse-window")? {
+            self.slack_collapse_window = parse_duration(&window)?;
+        }
+        if let Some(sandbox) = take_one!(global, "sandbox")? {
+            self.sandbox = Some(sandbox.parse()?);
+        }
+        if let Some(size) = take_one!(global, "max-output-size")? {
+            self.max_output_size = Some(size.trim().parse()
+                .map_err(|_| Error::msg(format!("The global max-output-size value must be a number of bytes, got '{}'", size)))?);
+        }
+        let alias_names: Vec<String> = global.keys().filter(|k| k.starts_with('@')).cloned().collect();
+        for name in alias_names {
+            let pattern = take_one!(global, &name)?.unwrap_or_default();
+            validate_schedule_alias(&name, &pattern)?;
+            self.schedule_aliases.insert(name, pattern);
+        }
+        if !global.is_empty() {
+            warn!("The global configuration has unsupported attributes that will not be used: {:?}", global.keys());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::job::{JobTimezone, SandboxKind};
+
+    use super::{connect_to_host, ApplicationContext, OfeliaSource, TlsFiles};
+
+    #[test]
+    fn select_engine_socket() {
+        use super::{select_socket, ContainerEngine, DOCKER_SOCKET, PODMAN_SOCKET};
+
+        let docker = (ContainerEngine::Docker, DOCKER_SOCKET.to_string());
+        let podman = (ContainerEngine::Podman, PODMAN_SOCKET.to_string());
+        // Docker is preferred, podman is only used when Docker is not reachable
+        assert_eq!(select_socket(None, |_| true), docker);
+        assert_eq!(select_socket(None, |s| s == PODMAN_SOCKET), podman);
+        assert_eq!(select_socket(None, |_| false), docker);
+        // A forced engine is never substituted
+        assert_eq!(select_socket(Some(ContainerEngine::Docker), |s| s == PODMAN_SOCKET), docker);
+        assert_eq!(select_socket(Some(ContainerEngine::Podman), |s| s == PODMAN_SOCKET), podman);
+        assert_eq!(select_socket(Some(ContainerEngine::Podman), |_| false).0, ContainerEngine::Podman);
+        // The rootless socket, if any, is probed before the rootful one
+        assert_eq!(ContainerEngine::Podman.default_sockets().last().unwrap(), PODMAN_SOCKET);
+        assert_eq!(" Podman".parse::<ContainerEngine>().unwrap(), ContainerEngine::Podman);
+        assert!("lxc".parse::<ContainerEngine>().is_err());
+    }
+
+    #[test]
+    fn probe_ofelia_source() {
+        let dir = std::env::temp_dir().join(format!("cfc-ofelia-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first, second) = (dir.join("ofelia.conf"), dir.join("config.ini"));
+        let candidates = [first.to_str().unwrap(), second.to_str().unwrap()];
+        assert_eq!(OfeliaSource::probe(&candidates, false), OfeliaSource::Missing);
+        assert_eq!(OfeliaSource::probe(&candidates, true), OfeliaSource::Labels);
+        std::fs::write(&second, "").unwrap();
+        assert_eq!(OfeliaSource::probe(&candidates, true), OfeliaSource::File(candidates[1].to_string()));
+        std::fs::write(&first, "").unwrap();
+        assert_eq!(OfeliaSource::probe(&candidates, false), OfeliaSource::File(candidates[0].to_string()));
+        // Directories are not configuration files
+        std::fs::remove_file(&first).unwrap();
+        std::fs::create_dir(&first).unwrap();
+        assert_eq!(OfeliaSource::probe(&candidates, false), OfeliaSource::File(candidates[1].to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_global_section() {
+        let mut ctx = ApplicationContext {
+            socket: Some("/run/docker.sock".into()),
+            ..Default::default()
+        };
+        ctx.apply_global(HashMap::from([
+            ("prefix".into(), vec!["ofelia".into(), "cfc".into()]),
+            ("socket-path".into(), vec!["/run/podman.sock".into()]),
+            ("allow-unsafe-jobs".into(), vec!["true".into()]),
+            ("timezone".into(), vec!["UTC".into()]),
+            ("sandbox".into(), vec!["unshare".into()]),
+            ("max-output-size".into(), vec!["4096".into()]),
+            ("pass-environment".into(), vec!["true".into()]),
+            ("max-parallel".into(), vec!["4".into()]),
+            ("webhook-url".into(), vec!["http://relay/cfc".into()]),
+            ("slack-webhook".into(), vec!["http://relay/slack".into()]),
+            ("slack-collapse-window".into(), vec!["30m".into()]),
+            ("smtp-host".into(), vec!["localhost".into()]),
+            ("@nightly-weekdays".into(), vec!["0 17 3 * * 1-5".into()]),
+        ])).unwrap();
+        assert_eq!(ctx.label_prefixes, vec!["ofelia".to_string(), "cfc".to_string()]);
+        assert_eq!(ctx.socket.as_deref(), Some("/run/docker.sock"));
+        assert!(ctx.unsafe_labels);
+        assert_eq!(ctx.timezone, Some(JobTimezone::Utc));
+        assert_eq!(ctx.sandbox, Some(SandboxKind::Unshare));
+        assert_eq!(ctx.max_output_size, Some(4096));
+        assert!(ctx.pass_environment);
+        assert_eq!(ctx.execution_permits.as_ref().map(|p| p.available_permits()), Some(4));
+        assert_eq!(ctx.webhook_url.as_ref().map(|u| u.to_string()).as_deref(), Some("http://relay/cfc"));
+        assert_eq!(ctx.slack_webhook.as_ref().map(|u| u.to_string()).as_deref(), Some("http://relay/slack"));
+        assert_eq!(ctx.slack_collapse_window, std::time::Duration::from_secs(1800));
+        assert!(ApplicationContext::default().apply_global(HashMap::from([("webhook-url".into(), vec!["ftp://relay/cfc".into()])])).is_err());
+        assert!(ApplicationContext::default().apply_global(HashMap::from([("max-parallel".into(), vec!["0".into()])])).is_err());
+        assert_eq!(ctx.schedule_aliases.get("@nightly-weekdays").map(|s| s.as_str()), Some("0 17 3 * * 1-5"));
+    }
+
+    #[tokio::test]
+    async fn connect_to_docker_host() {
+        assert!(connect_to_host("unix:///run/docker.sock", None).is_ok());
+        assert!(connect_to_host("tcp://127.0.0.1:2375", None).is_ok());
+        assert!(connect_to_host("http://127.0.0.1:2375", None).is_ok());
+        assert!(connect_to_host("https://127.0.0.1:2376", None).is_err());
+        assert!(connect_to_host("ssh://user@host", None).is_err());
+        assert!(connect_to_host("127.0.0.1:2375", None).is_err());
+        // The client certificate and key are only read when the engine asks for them
+        let dir = std::env::temp_dir().join(format!("cfc-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("ca.pem"), "").unwrap();
+        let tls = TlsFiles::in_directory(&dir);
+        assert!(connect_to_host("tcp://127.0.0.1:2376", Some(&tls)).is_ok());
+        assert!(connect_to_host("https://127.0.0.1:2376", Some(&tls)).is_ok());
+        assert!(connect_to_host("http://127.0.0.1:2376", Some(&tls)).is_err());
+        let e = connect_to_host("tcp://127.0.0.1:2376", Some(&TlsFiles::in_directory(&dir.join("missing")))).unwrap_err();
+        assert!(e.to_string().contains("with TLS"), "{}", e);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn check_tls_triplet() {
+        assert!(ApplicationContext::default().check_tls().unwrap().is_none());
+        let partial = ApplicationContext {
+            tls_cert: Some("/etc/hostname".into()),
+            ..Default::default()
+        };
+        let e = partial.check_tls().unwrap_err().to_string();
+        assert!(e.contains("tls-key, tls-ca"), "{}", e);
+        let missing_file = ApplicationContext {
+            tls_cert: Some("/nonexistent/cert.pem".into()),
+            tls_key: Some("/nonexistent/key.pem".into()),
+            tls_ca: Some("/nonexistent/ca.pem".into()),
+            ..Default::default()
+        };
+        assert!(missing_file.check_tls().is_err());
+    }
+
+    #[test]
+    fn apply_global_section_bad_value() {
+        let mut ctx = ApplicationContext::default();
+        assert!(ctx.apply_global(HashMap::from([
+            ("allow-unsafe-jobs".into(), vec!["maybe".into()]),
+        ])).is_err());
+        assert!(ctx.apply_global(HashMap::from([
+            ("@daily".into(), vec!["0 0 0 * * *".into()]),
+        ])).is_err());
+        assert!(ctx.apply_global(HashMap::from([
+            ("timezone".into(), vec!["Europe/Atlantis".into()]),
+        ])).is_err());
     }
 }
@@ -0,0 +1,139 @@
+//! Liveness and readiness of the daemon, served to orchestrators
+//!
+//! The state is always tracked, the HTTP server that exposes it is only
+//! available with the `health` feature.
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+/// How often the daemon's main loop reports that it is alive
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The main loop is considered stuck when it did not report for this long
+pub const LIVENESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Health {
+    /// The last time the main loop reported that it is alive
+    heartbeat: Option<Instant>,
+    /// Whether all jobs were registered
+    ready: bool,
+}
+
+static HEALTH: Mutex<Health> = Mutex::new(Health { heartbeat: None, ready: false });
+
+/// Report that the daemon's main loop is alive
+pub fn heartbeat() {
+    HEALTH.lock().unwrap_or_else(|e| e.into_inner()).heartbeat = Some(Instant::now());
+}
+
+/// Whether the daemon's main loop reported recently
+pub fn is_alive() -> bool {
+    HEALTH.lock().unwrap_or_else(|e| e.into_inner()).heartbeat.is_some_and(|h| h.elapsed() < LIVENESS_TIMEOUT)
+}
+
+/// Set whether all jobs were registered
+pub fn set_ready(ready: bool) {
+    HEALTH.lock().unwrap_or_else(|e| e.into_inner()).ready = ready;
+}
+
+/// Whether all jobs were registered
+pub fn is_ready() -> bool {
+    HEALTH.lock().unwrap_or_else(|e| e.into_inner()).ready
+}
+
+#[cfg(feature = "health")]
+pub use server::HealthServer;
+
+#[cfg(feature = "health")]
+mod server {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use anyhow::Result;
+    use bollard::Docker;
+    use futures_util::future::BoxFuture;
+    use hyper::StatusCode;
+    use tokio::time::{timeout, Duration};
+
+    use crate::http::{HttpServer, Response};
+
+    /// The time given to the container engine to answer a ping
+    const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A minimal HTTP server that answers `GET /healthz` and `GET /readyz`
+    pub struct HealthServer {
+        server: HttpServer,
+    }
+
+    impl HealthServer {
+        /// Listen on an address, `handle` is pinged to check that the container engine is reachable
+        pub async fn bind(addr: SocketAddr, handle: Docker) -> Result<Self> {
+            let handler = Arc::new(move |path: String| -> BoxFuture<'static, Option<Response>> {
+                let handle = handle.clone();
+                Box::pin(async move {
+                    match path.as_str() {
+                        "/healthz" => Some(liveness(&handle).await),
+                        "/readyz" => Some(readiness()),
+                        _ => None,
+                    }
+                })
+            });
+            Ok(HealthServer { server: HttpServer::bind(addr, "health", "text/plain; charset=utf-8", handler).await? })
+        }
+
+        /// Get the address the server listens on
+        pub fn local_addr(&self) -> Result<SocketAddr> {
+            self.server.local_addr()
+        }
+
+        /// Answer requests until the future is dropped
+        pub async fn run(self) {
+            self.server.run().await
+        }
+    }
+
+    async fn liveness(handle: &Docker) -> Response {
+        if !super::is_alive() {
+            return (StatusCode::SERVICE_UNAVAILABLE, "The daemon's main loop is not responding\n".to_string());
+        }
+        match timeout(PING_TIMEOUT, handle.ping()).await {
+            Ok(Ok(_)) => (StatusCode::OK, "ok\n".to_string()),
+            Ok(Err(e)) => (StatusCode::SERVICE_UNAVAILABLE, format!("The container engine is unreachable: {}\n", e)),
+            Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "The container engine did not answer in time\n".to_string()),
+        }
+    }
+
+    fn readiness() -> Response {
+        match super::is_ready() {
+            true => (StatusCode::OK, "ok\n".to_string()),
+            false => (StatusCode::SERVICE_UNAVAILABLE, "The jobs are not registered yet\n".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "health")]
+    #[tokio::test]
+    async fn serve_health() {
+        use bollard::{Docker, API_DEFAULT_VERSION};
+
+        // Nothing listens on this port, so the container engine is never reachable
+        let handle = Docker::connect_with_http("http://127.0.0.1:1", 1, API_DEFAULT_VERSION).unwrap();
+        let server = super::HealthServer::bind("127.0.0.1:0".parse().unwrap(), handle).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let task = tokio::spawn(server.run());
+        let request = |method: &'static str, path: &'static str| crate::http::request(addr, method, path);
+
+        let response = request("GET", "/healthz").await;
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+        assert!(response.ends_with("The daemon's main loop is not responding\n"), "{}", response);
+        super::heartbeat();
+        let response = request("GET", "/healthz").await;
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+        assert!(response.contains("The container engine is unreachable"), "{}", response);
+
+        assert!(request("GET", "/readyz").await.starts_with("HTTP/1.1 503 "));
+        super::set_ready(true);
+        assert!(request("GET", "/readyz").await.starts_with("HTTP/1.1 200 "));
+        assert!(request("GET", "/metrics").await.starts_with("HTTP/1.1 404 "));
+        task.abort();
+    }
+}
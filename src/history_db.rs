@@ -0,0 +1,205 @@
+//! Persistence of the completed executions in a SQLite database
+//!
+//! Unlike the in-memory [`ExecutionHistory`][crate::job::ExecutionHistory], the database outlives
+//! the daemon, so that the runs can be audited later. Each scheduled execution is stored as a
+//! row of the `executions` table once it ended, with the beginning of its output, and the rows
+//! older than the retention are pruned as new ones are added.
+use std::{path::Path, sync::Mutex, time::Duration};
+
+use anyhow::{Error, Result};
+use chrono::{DateTime, Local, TimeZone};
+use rusqlite::{params, Connection};
+use tracing::{debug, warn};
+
+use crate::job::ExecInfo;
+
+/// The number of bytes kept from the beginning of each output stream
+pub const OUTPUT_PREFIX_LENGTH: usize = 4096;
+
+/// The age after which the executions are pruned by default
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(30 * 24 * 3600);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS executions (
+    id INTEGER PRIMARY KEY,
+    job TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    -- Seconds since the Unix epoch
+    started_at INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    -- NULL when the job could not be run, see error
+    exit_code INTEGER,
+    timed_out INTEGER NOT NULL,
+    error TEXT,
+    stdout TEXT,
+    stderr TEXT
+);
+CREATE INDEX IF NOT EXISTS executions_started_at ON executions (started_at);
+";
+
+/// Keep the first `length` bytes of a text, without splitting a character
+fn head(text: &str, length: usize) -> String {
+    let mut end = length.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
+
+/// A completed execution, as it is stored
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredExecution {
+    pub job: String,
+    pub kind: String,
+    pub started_at: DateTime<Local>,
+    pub duration: Duration,
+    /// The exit code of the execution, `None` if the job could not be run
+    pub exit_code: Option<i64>,
+    pub timed_out: bool,
+    /// The reason why the job could not be run
+    pub error: Option<String>,
+    /// The beginning of the execution's stdout
+    pub stdout: Option<String>,
+    /// The beginning of the execution's stderr
+    pub stderr: Option<String>,
+}
+
+impl StoredExecution {
+    /// Describe the result of an execution, or return `None` if nothing was executed (e.g. a skipped run)
+    pub fn new(job: &str, kind: &str, started_at: DateTime<Local>, duration: Duration, result: &Result<ExecInfo>) -> Option<Self> {
+        let (exit_code, timed_out, error, stdout, stderr) = match result {
+            Ok(ExecInfo::Report(report)) => (
+                Some(report.retval),
+                report.timed_out,
+                None,
+                report.stdout.as_deref().map(|s| head(s, OUTPUT_PREFIX_LENGTH)),
+                report.stderr.as_deref().map(|s| head(s, OUTPUT_PREFIX_LENGTH)),
+            ),
+            Ok(_) => return None,
+            Err(e) => (None, false, Some(e.to_string()), None, None),
+        };
+        Some(StoredExecution { job: job.to_string(), kind: kind.to_string(), started_at, duration, exit_code, timed_out, error, stdout, stderr })
+    }
+}
+
+/// A SQLite database in which the completed executions are recorded
+pub struct HistoryDatabase {
+    connection: Connection,
+    retention: Duration,
+}
+
+impl HistoryDatabase {
+    /// Open or create the database at a path, creating its table if needed, and prune the
+    /// executions that are older than `retention`
+    pub fn open(path: &Path, retention: Duration) -> Result<Self> {
+        let connection = Connection::open(path)
+            .and_then(|c| c.execute_batch(SCHEMA).map(|_| c))
+            .map_err(|e| Error::msg(format!("Failed to open the history database {}: {}", path.display(), e)))?;
+        let database = HistoryDatabase { connection, retention };
+        database.prune(Local::now())?;
+        Ok(database)
+    }
+
+    /// Store an execution, then prune the executions that are older than the retention
+    pub fn insert(&self, execution: &StoredExecution) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO executions (job, kind, started_at, duration_ms, exit_code, timed_out, error, stdout, stderr) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                execution.job, execution.kind, execution.started_at.timestamp(), i64::try_from(execution.duration.as_millis()).unwrap_or(i64::MAX),
+                execution.exit_code, execution.timed_out, execution.error, execution.stdout, execution.stderr,
+            ],
+        )?;
+        self.prune(Local::now())?;
+        Ok(())
+    }
+
+    /// Delete the executions that started more than the retention before `now`, and return how many were deleted
+    pub fn prune(&self, now: DateTime<Local>) -> Result<usize> {
+        let retention = i64::try_from(self.retention.as_secs()).unwrap_or(i64::MAX);
+        let deleted = self.connection.execute("DELETE FROM executions WHERE started_at < ?1", [now.timestamp().saturating_sub(retention)])?;
+        if deleted > 0 {
+            debug!("Pruned {} executions from the history database", deleted);
+        }
+        Ok(deleted)
+    }
+
+    /// Get the stored executions, from the oldest to the most recent
+    pub fn executions(&self) -> Result<Vec<StoredExecution>> {
+        let mut statement = self.connection.prepare(
+            "SELECT job, kind, started_at, duration_ms, exit_code, timed_out, error, stdout, stderr FROM executions ORDER BY started_at, id",
+        )?;
+        let rows = statement.query_map([], |row| Ok(StoredExecution {
+            job: row.get(0)?,
+            kind: row.get(1)?,
+            started_at: Local.timestamp_opt(row.get(2)?, 0).single().unwrap_or_default(),
+            duration: Duration::from_millis(row.get::<_, i64>(3)?.try_into().unwrap_or_default()),
+            exit_code: row.get(4)?,
+            timed_out: row.get(5)?,
+            error: row.get(6)?,
+            stdout: row.get(7)?,
+            stderr: row.get(8)?,
+        }))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+static DATABASE: Mutex<Option<HistoryDatabase>> = Mutex::new(None);
+
+/// Set the database that the completed executions are recorded in, or stop recording them
+pub fn configure(path: Option<&Path>, retention: Duration) -> Result<()> {
+    let database = path.map(|p| HistoryDatabase::open(p, retention)).transpose()?;
+    *DATABASE.lock().unwrap_or_else(|e| e.into_inner()) = database;
+    Ok(())
+}
+
+/// Record the result of an execution in the background, if a database is configured
+pub fn record_execution(job: &str, kind: &str, started_at: DateTime<Local>, duration: Duration, result: &Result<ExecInfo>) {
+    if DATABASE.lock().unwrap_or_else(|e| e.into_inner()).is_none() {
+        return;
+    }
+    let Some(execution) = StoredExecution::new(job, kind, started_at, duration, result) else { return };
+    tokio::task::spawn_blocking(move || {
+        let database = DATABASE.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(Err(e)) = database.as_ref().map(|d| d.insert(&execution)) {
+            warn!("Failed to record the execution of job {} in the history database: {}", execution.job, e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Error;
+    use chrono::{Local, TimeZone};
+
+    use crate::job::{ExecInfo, ExecutionReport};
+
+    use super::{head, HistoryDatabase, StoredExecution, OUTPUT_PREFIX_LENGTH};
+
+    #[test]
+    fn store_executions() {
+        let path = std::env::temp_dir().join(format!("cfc-history-{}.db", std::process::id()));
+        let database = HistoryDatabase::open(&path, Duration::from_secs(3600)).unwrap();
+        let now = Local.timestamp_opt(Local::now().timestamp(), 0).unwrap();
+        let report = ExecutionReport { retval: 2, stdout: Some("é".repeat(OUTPUT_PREFIX_LENGTH)), stderr: Some("failed".to_string()), ..Default::default() };
+        let failed = StoredExecution::new("backup", "job-exec", now, Duration::from_millis(1500), &Ok(ExecInfo::Report(report))).unwrap();
+        assert_eq!(failed.stdout.as_ref().unwrap().len(), OUTPUT_PREFIX_LENGTH);
+        let error = StoredExecution::new("backup", "job-exec", now, Duration::ZERO, &Err(Error::msg("No such container"))).unwrap();
+        assert!(StoredExecution::new("backup", "job-exec", now, Duration::ZERO, &Ok(ExecInfo::Skipped)).is_none());
+        database.insert(&failed).unwrap();
+        database.insert(&error).unwrap();
+        // An execution older than the retention is pruned when another one is recorded
+        let old = StoredExecution { started_at: now - chrono::Duration::hours(2), ..failed.clone() };
+        database.insert(&old).unwrap();
+        drop(database);
+
+        let database = HistoryDatabase::open(&path, Duration::from_secs(3600)).unwrap();
+        let executions = database.executions().unwrap();
+        assert_eq!(executions, [failed, error]);
+        assert_eq!(executions[1].error.as_deref(), Some("No such container"));
+        assert_eq!(database.prune(now + chrono::Duration::hours(2)).unwrap(), 2);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(head("aé", 2), "a");
+    }
+}
@@ -0,0 +1,88 @@
+//! A minimal HTTP/1.1 server for the daemon's monitoring endpoints
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use anyhow::{Error, Result};
+use futures_util::future::BoxFuture;
+use http_body_util::Full;
+use hyper::{body::{Bytes, Incoming}, header::CONTENT_TYPE, server::conn::http1, service::service_fn, Method, Request, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use tokio::{net::TcpListener, time::Duration};
+use tracing::{debug, warn};
+
+/// The time given to a client to send the head of its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The status and plain text body of a response
+pub(crate) type Response = (StatusCode, String);
+
+/// Answers a `GET` request to a path, or returns `None` if the path is not served
+pub(crate) type Handler = Arc<dyn Fn(String) -> BoxFuture<'static, Option<Response>> + Send + Sync>;
+
+/// A server that answers `GET` and `HEAD` requests with plain text
+pub(crate) struct HttpServer {
+    listener: TcpListener,
+    content_type: &'static str,
+    handler: Handler,
+}
+
+impl HttpServer {
+    /// Listen on an address, `purpose` describes the served requests in errors
+    pub async fn bind(addr: SocketAddr, purpose: &str, content_type: &'static str, handler: Handler) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await
+            .map_err(|e| Error::msg(format!("Failed to listen on {} for {} requests: {}", addr, purpose, e)))?;
+        Ok(HttpServer { listener, content_type, handler })
+    }
+
+    /// Get the address the server listens on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Answer requests until the future is dropped
+    pub async fn run(self) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, peer)) => {
+                    let (content_type, handler) = (self.content_type, self.handler.clone());
+                    tokio::spawn(async move {
+                        let service = service_fn(move |request| respond(request, content_type, handler.clone()));
+                        let connection = http1::Builder::new()
+                            .timer(TokioTimer::new())
+                            .header_read_timeout(REQUEST_TIMEOUT)
+                            .serve_connection(TokioIo::new(stream), service);
+                        if let Err(e) = connection.await {
+                            debug!("Failed to answer the request of {}: {}", peer, e);
+                        }
+                    });
+                },
+                Err(e) => warn!("Failed to accept a connection: {}", e),
+            }
+        }
+    }
+}
+
+async fn respond(request: Request<Incoming>, content_type: &'static str, handler: Handler) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    // hyper leaves the body out of the responses to HEAD requests
+    let (status, body) = match *request.method() {
+        Method::GET | Method::HEAD => handler(request.uri().path().to_string()).await
+            .unwrap_or_else(|| (StatusCode::NOT_FOUND, "Not found\n".to_string())),
+        _ => (StatusCode::METHOD_NOT_ALLOWED, "Method not allowed\n".to_string()),
+    };
+    let mut response = hyper::Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, content_type.parse().unwrap());
+    Ok(response)
+}
+
+/// Send a request to a server and read the whole response
+#[cfg(test)]
+pub(crate) async fn request(addr: SocketAddr, method: &str, path: &str) -> String {
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", method, path, addr);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
@@ -0,0 +1,195 @@
+//! Admission of the executions when the number of executions running at the same time is limited
+//!
+//! When several executions wait for a permit, the one of the job with the highest `priority`
+//! gets the next permit that is released, and executions of the same priority are admitted in
+//! the order in which they started waiting. An execution that waited longer than the promotion
+//! delay is admitted before all the others so that low-priority jobs are never starved.
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+use anyhow::{Error, Result};
+use tokio::{sync::oneshot, time::Instant};
+use tracing::debug;
+
+/// The default delay after which a waiting execution is admitted before higher-priority ones
+pub const DEFAULT_PROMOTION_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct Waiter {
+    /// The order in which the executions started waiting
+    sequence: u64,
+    priority: i32,
+    since: Instant,
+    /// Receives the number of executions that were waiting before this one when it is admitted
+    admit: oneshot::Sender<usize>,
+}
+
+#[derive(Debug)]
+struct Queue {
+    capacity: usize,
+    available: usize,
+    next_sequence: u64,
+    waiters: Vec<Waiter>,
+}
+
+impl Queue {
+    /// Hand a released permit over to the next waiting execution, or make it available
+    fn release(&mut self, promotion_delay: Duration) {
+        let now = Instant::now();
+        while let Some(next) = self.waiters.iter().enumerate()
+            // Promoted waiters come first, then the highest priority, then the oldest
+            .max_by_key(|(_, w)| (now.saturating_duration_since(w.since) >= promotion_delay, w.priority, std::cmp::Reverse(w.sequence)))
+            .map(|(i, _)| i)
+        {
+            let waiter = self.waiters.swap_remove(next);
+            let jumped = self.waiters.iter().filter(|w| w.sequence < waiter.sequence).count();
+            if waiter.admit.send(jumped).is_ok() {
+                return;
+            }
+        }
+        self.available = (self.available + 1).min(self.capacity);
+    }
+}
+
+/// A permit to run an execution, handed over to the next waiting execution when dropped
+#[derive(Debug)]
+pub struct ExecutionPermit(ExecutionPermits);
+
+impl Drop for ExecutionPermit {
+    fn drop(&mut self) {
+        let delay = self.0.promotion_delay;
+        self.0.queue.lock().unwrap_or_else(|e| e.into_inner()).release(delay);
+    }
+}
+
+/// Removes a waiter that stopped waiting, giving its permit back if it was admitted meanwhile
+struct Waiting<'a> {
+    permits: &'a ExecutionPermits,
+    sequence: u64,
+    admitted: Option<oneshot::Receiver<usize>>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let Some(mut admitted) = self.admitted.take() else { return };
+        let mut queue = self.permits.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.waiters.retain(|w| w.sequence != self.sequence);
+        if admitted.try_recv().is_ok() {
+            queue.release(self.permits.promotion_delay);
+        }
+    }
+}
+
+/// Limits the number of executions that run at the same time across all jobs, admitting the
+/// waiting executions by priority
+#[derive(Clone, Debug)]
+pub struct ExecutionPermits {
+    queue: Arc<Mutex<Queue>>,
+    promotion_delay: Duration,
+}
+
+impl ExecutionPermits {
+    pub fn new(capacity: usize, promotion_delay: Duration) -> Self {
+        let queue = Queue { capacity, available: capacity, next_sequence: 0, waiters: vec![] };
+        ExecutionPermits { queue: Arc::new(Mutex::new(queue)), promotion_delay }
+    }
+
+    /// The number of executions that may start without waiting
+    pub fn available_permits(&self) -> usize {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner()).available
+    }
+
+    /// Wait for a permit to run an execution of a job
+    pub async fn acquire(&self, job: &str, priority: i32) -> Result<ExecutionPermit> {
+        let (sequence, admitted) = {
+            let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+            if queue.available > 0 && queue.waiters.is_empty() {
+                queue.available -= 1;
+                return Ok(ExecutionPermit(self.clone()));
+            }
+            let (admit, admitted) = oneshot::channel();
+            let sequence = queue.next_sequence;
+            queue.next_sequence += 1;
+            queue.waiters.push(Waiter { sequence, priority, since: Instant::now(), admit });
+            // A permit may be free if the executions waiting before this one were cancelled
+            if queue.available > 0 {
+                queue.available -= 1;
+                queue.release(self.promotion_delay);
+            }
+            (sequence, admitted)
+        };
+        let wait_start = Instant::now();
+        let mut waiting = Waiting { permits: self, sequence, admitted: Some(admitted) };
+        let jumped = waiting.admitted.as_mut().unwrap().await
+            .map_err(|_| Error::msg(format!("Job {} stopped waiting for an execution permit", job)))?;
+        waiting.admitted = None;
+        debug!("Job {} waited {:?} for an execution permit with priority {}, ahead of {} jobs that were waiting before it", job, wait_start.elapsed(), priority, jumped);
+        Ok(ExecutionPermit(self.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::time::{sleep, Duration};
+
+    use super::ExecutionPermits;
+
+    #[tokio::test(start_paused = true)]
+    async fn admit_by_priority() {
+        let permits = ExecutionPermits::new(1, Duration::from_secs(300));
+        let order = Arc::new(Mutex::new(vec![]));
+        let running = permits.acquire("running", 0).await.unwrap();
+        let mut tasks = vec![];
+        for (job, priority) in [("low", -1), ("first", 0), ("backup", 10), ("second", 0)] {
+            let (permits, order) = (permits.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = permits.acquire(job, priority).await.unwrap();
+                order.lock().unwrap().push(job);
+                sleep(Duration::from_secs(1)).await;
+            }));
+            sleep(Duration::from_millis(10)).await;
+        }
+        drop(running);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // Ties are admitted in the order in which the jobs started waiting
+        assert_eq!(*order.lock().unwrap(), ["backup", "first", "second", "low"]);
+        assert_eq!(permits.available_permits(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn promote_starved_executions() {
+        let permits = ExecutionPermits::new(1, Duration::from_secs(60));
+        let order = Arc::new(Mutex::new(vec![]));
+        let mut running = Some(permits.acquire("running", 0).await.unwrap());
+        let spawn = |job: &'static str, priority: i32| {
+            let (permits, order) = (permits.clone(), order.clone());
+            tokio::spawn(async move {
+                let _permit = permits.acquire(job, priority).await.unwrap();
+                order.lock().unwrap().push(job);
+                sleep(Duration::from_secs(10)).await;
+            })
+        };
+        let low = spawn("low", -5);
+        sleep(Duration::from_secs(61)).await;
+        let high = spawn("high", 5);
+        sleep(Duration::from_millis(10)).await;
+        running.take();
+        low.await.unwrap();
+        high.await.unwrap();
+        assert_eq!(*order.lock().unwrap(), ["low", "high"]);
+
+        // Cancelled waiters give their place, and their permit, back
+        let running = permits.acquire("running", 0).await.unwrap();
+        let cancelled = spawn("cancelled", 9);
+        sleep(Duration::from_millis(10)).await;
+        cancelled.abort();
+        let _ = cancelled.await;
+        drop(running);
+        assert_eq!(permits.available_permits(), 1);
+        let _permit = permits.acquire("next", 0).await.unwrap();
+        assert_eq!(permits.available_permits(), 0);
+    }
+}
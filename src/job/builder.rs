@@ -0,0 +1,193 @@
+//! Typed construction of jobs
+//!
+//! The loaders build jobs from parameter maps with [`JobInfo::try_from`][crate::job::JobInfo::try_from].
+//! A builder fills the same parameters with typed methods and builds the job through the same
+//! path, so that it is validated like a loaded job. Unlike a loaded job, a job built with keys
+//! that its kind does not use is refused instead of warned about.
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Error, Result};
+
+use super::{schema::suggest_key, ExecJobInfo, StallPolicy};
+
+/// Refuse the parameters that a job did not use
+fn check_unused_keys(kind: &str, job: &str, parameters: &HashMap<String, Vec<String>>) -> Result<()> {
+    let mut keys: Vec<&String> = parameters.keys().collect();
+    keys.sort();
+    match keys.first() {
+        Some(key) => Err(Error::msg(match suggest_key(kind, key) {
+            Some(suggestion) => format!("The key '{}' of job '{}' is not used by {} jobs, did you mean '{}'?", key, job, kind, suggestion),
+            None => format!("The key '{}' of job '{}' is not used by {} jobs", key, job, kind),
+        })),
+        None => Ok(()),
+    }
+}
+
+/// Builds an [`ExecJobInfo`], see [`ExecJobInfo::builder`]
+#[derive(Clone, Debug, Default)]
+pub struct ExecJobBuilder {
+    parameters: HashMap<String, Vec<String>>,
+}
+
+impl ExecJobBuilder {
+    fn set(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.parameters.insert(key.to_string(), vec![value.into()]);
+        self
+    }
+
+    fn push(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.parameters.entry(key.to_string()).or_default().push(value.into());
+        self
+    }
+
+    /// Set the display name of the job
+    pub fn name(self, name: impl Into<String>) -> Self {
+        self.set("name", name)
+    }
+
+    /// Set the schedule of the job, in any of the forms accepted by the `schedule` key
+    pub fn schedule(self, schedule: impl Into<String>) -> Self {
+        self.set("schedule", schedule)
+    }
+
+    /// Add a command to the ones executed, one after the other
+    pub fn command(self, command: impl Into<String>) -> Self {
+        self.push("command", command)
+    }
+
+    /// Run the commands with a shell, such as `/bin/sh`, instead of splitting them in arguments
+    pub fn shell(self, shell: impl Into<String>) -> Self {
+        self.set("shell", shell)
+    }
+
+    /// Set the container in which the commands are executed
+    pub fn container(self, container: impl Into<String>) -> Self {
+        self.set("container", container)
+    }
+
+    /// Set the user that executes the commands
+    pub fn user(self, user: impl Into<String>) -> Self {
+        self.set("user", user)
+    }
+
+    /// Set the directory in which the commands are executed
+    pub fn working_dir(self, dir: impl Into<String>) -> Self {
+        self.set("working-dir", dir)
+    }
+
+    /// Set whether a tty is provisionned for the commands
+    pub fn tty(self, tty: bool) -> Self {
+        self.set("tty", tty.to_string())
+    }
+
+    /// Add a `NAME=value` entry to the environment of the commands
+    pub fn environment(self, entry: impl Into<String>) -> Self {
+        self.push("environment", entry)
+    }
+
+    /// Set the dotenv file read at each execution for more environment variables
+    pub fn environment_file(self, path: impl Into<String>) -> Self {
+        self.set("environment-file", path)
+    }
+
+    /// Set how long the commands may run, in whole seconds
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.set("timeout", format!("{}s", timeout.as_secs()))
+    }
+
+    /// Set how long a command may run without producing output, in whole seconds, and what to do then
+    pub fn first_output_timeout(self, timeout: Duration, on_stall: StallPolicy) -> Self {
+        self.set("first-output-timeout", format!("{}s", timeout.as_secs()))
+            .set("on-stall", on_stall.to_string())
+    }
+
+    /// Add a value to any other key of exec jobs, such as `on-failure` or `max-output-size`
+    pub fn option(self, key: &str, value: impl Into<String>) -> Self {
+        self.push(key, value)
+    }
+
+    /// Build the job, failing if it is incomplete or if one of its keys is invalid or unused by exec jobs
+    pub fn build(self) -> Result<ExecJobInfo> {
+        let mut parameters = self.parameters;
+        if parameters.get("name").is_none_or(|n| n.iter().all(|n| n.trim().is_empty())) {
+            return Err(Error::msg("The job has no name"));
+        }
+        let job = ExecJobInfo::take_from(&mut parameters)?;
+        check_unused_keys(ExecJobInfo::LABEL, &job.name, &parameters)?;
+        Ok(job)
+    }
+}
+
+impl ExecJobInfo {
+    /// Start building an exec job with typed methods.
+    ///
+    /// The job is validated like the ones read from configurations.
+    ///
+    /// ```rust
+    /// use cfc::job::ExecJobInfo;
+    ///
+    /// let job = ExecJobInfo::builder()
+    ///     .name("backup")
+    ///     .schedule("@every 6h")
+    ///     .command("pg_dump -f /backup/db.sql")
+    ///     .container("postgres")
+    ///     .option("on-failure", "stop-job")
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(job.command, ["pg_dump -f /backup/db.sql"]);
+    /// assert!(ExecJobInfo::builder().name("backup").schedule("@every 6h").command("true").build().is_err());
+    /// ```
+    pub fn builder() -> ExecJobBuilder {
+        ExecJobBuilder::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use crate::job::{ExecJobInfo, JobInfo, OnFailure, StallPolicy};
+
+    #[test]
+    fn build_exec_job() {
+        let job = ExecJobInfo::builder()
+            .name("backup")
+            .schedule("@every 90m")
+            .command("sync")
+            .command("backup.sh")
+            .container("db")
+            .user("nobody")
+            .working_dir("/app")
+            .environment("TARGET=/backup")
+            .timeout(Duration::from_secs(600))
+            .first_output_timeout(Duration::from_secs(30), StallPolicy::Kill)
+            .option("on-failure", "stop-job")
+            .build()
+            .unwrap();
+        // The same job as one read from a configuration
+        let loaded = JobInfo::try_from(HashMap::from([
+            ("kind", vec!["job-exec"]),
+            ("name", vec!["backup"]),
+            ("schedule", vec!["@every 90m"]),
+            ("command", vec!["sync", "backup.sh"]),
+            ("container", vec!["db"]),
+            ("user", vec!["nobody"]),
+            ("working-dir", vec!["/app"]),
+            ("environment", vec!["TARGET=/backup"]),
+            ("timeout", vec!["10m"]),
+            ("first-output-timeout", vec!["30s"]),
+            ("on-stall", vec!["kill"]),
+            ("on-failure", vec!["stop-job"]),
+        ].map(|(k, v)| (k.to_string(), v.into_iter().map(str::to_string).collect())))).unwrap();
+        assert!(matches!(loaded, JobInfo::ExecJob(loaded) if *loaded == job));
+        assert_eq!(job.options.on_failure, OnFailure::StopJob);
+
+        let builder = || ExecJobInfo::builder().name("backup").schedule("@daily").command("true").container("db");
+        assert!(builder().build().is_ok());
+        assert!(builder().name(" ").build().unwrap_err().to_string().contains("no name"));
+        assert!(builder().schedule("every day").build().is_err());
+        assert!(builder().option("on-failure", "retry").build().is_err());
+        let e = builder().option("contaner", "db").build().unwrap_err().to_string();
+        assert!(e.contains("did you mean 'container'"), "{}", e);
+    }
+}
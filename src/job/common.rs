@@ -1,12 +1,20 @@
-use std::pin::Pin;
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fmt::{Debug, Display, Formatter}, future::Future, io::Write, pin::Pin, str::FromStr, sync::{Arc, LazyLock, RwLock}, time::Duration};
 
 use anyhow::Error;
+use chrono::{DateTime, FixedOffset, Local, LocalResult, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
 use bollard::container::LogOutput;
 use croner::Cron;
 use futures_util::{Stream, TryStreamExt};
 use regex::Regex;
+use serde::Serialize;
+use tracing::warn;
 
-pub(crate) const UNKNOWN_CONTAINER_LABEL: &'static str = "UNKNOWN";
+use crate::webhook::WebhookUrl;
+
+use super::{history::{DEFAULT_HISTORY_SIZE, MAX_HISTORY_SIZE}, hook::parse_hook, output::{OutputBuffer, OutputLines}};
+
+pub(crate) const UNKNOWN_CONTAINER_LABEL: &str = "UNKNOWN";
 
 
 /// Extract a single value from a HashMap<String, Vec<String>>.
@@ -41,68 +49,1724 @@ macro_rules! require_one {
     };
 }
 
-/// Parse a user-provided string to generate the corresponding cronjob
-pub(crate) fn schedule_to_cron(sched: &str) -> Result<Cron, Error> {
-    // TODO: support multi-keys '@every' (e.g.: 1h30m)
-    let mut sched = sched.trim().to_string();
-    let re = Regex::new("^@every\\s+(?<interval>[0-9]+)(?<unit>s|m|h)$").unwrap();
-    match re.captures(sched.as_str()) {
-        Some(c) => {
-            let interval: i32 = c.name("interval").unwrap().as_str().parse().unwrap();
-            let unit = c.name("unit").unwrap().as_str();
-            match unit {
-                // TODO: add randomization of 0 values
-                "s" => sched = format!("*/{} * * * * *", interval).to_string(),
-                "m" => sched = format!("0 */{} * * * *", interval).to_string(),
-                "h" => sched = format!("0 0 */{} * * *", interval).to_string(),
-                _ => unreachable!("Encountered an unhandled time unit while parsing a schedule"),
+const DURATION_FORMS: &str = "accepted forms are unit suffixes (e.g. '90s', '1h30m', '1h 30m' or '7d'), ISO8601 durations (e.g. 'PT1H30M') and 'HH:MM:SS' (e.g. '01:30:00')";
+
+/// Parse a user-provided duration.
+///
+/// Durations may be written with unit suffixes (`1h30m`, `1h 30m`, `90s`, `2w`, `7d`),
+/// as an ISO8601 duration (`PT1H30M`, `P1DT12H`) or as `HH:MM:SS`.
+pub fn parse_duration(value: &str) -> Result<Duration, Error> {
+    let invalid = || Error::msg(format!("Invalid duration '{}', {}", value, DURATION_FORMS));
+    let value = value.trim();
+    let suffix_re = Regex::new("^(?:[0-9]+\\s*[wdhms]\\s*)+$").unwrap();
+    let iso_re = Regex::new("^P(?:(?<d>[0-9]+)D)?(?:T(?:(?<h>[0-9]+)H)?(?:(?<m>[0-9]+)M)?(?:(?<s>[0-9]+)S)?)?$").unwrap();
+    let clock_re = Regex::new("^(?<h>[0-9]+):(?<m>[0-5][0-9]):(?<s>[0-5][0-9])$").unwrap();
+    let seconds = if suffix_re.is_match(value) {
+        let component_re = Regex::new("(?<n>[0-9]+)\\s*(?<unit>[wdhms])").unwrap();
+        let mut seconds: u64 = 0;
+        for c in component_re.captures_iter(value) {
+            let n: u64 = c["n"].parse().map_err(|_| invalid())?;
+            let factor = match &c["unit"] {
+                "w" => 7 * 86400,
+                "d" => 86400,
+                "h" => 3600,
+                "m" => 60,
+                _ => 1,
+            };
+            seconds = n.checked_mul(factor).and_then(|n| seconds.checked_add(n)).ok_or_else(invalid)?;
+        }
+        seconds
+    } else if let Some(c) = iso_re.captures(value).or_else(|| clock_re.captures(value)) {
+        if value.ends_with('T') || value == "P" {
+            return Err(invalid());
+        }
+        let mut seconds: u64 = 0;
+        for (name, factor) in [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+            if let Some(n) = c.name(name) {
+                let n: u64 = n.as_str().parse().map_err(|_| invalid())?;
+                seconds = n.checked_mul(factor).and_then(|n| seconds.checked_add(n)).ok_or_else(invalid)?;
+            }
+        }
+        seconds
+    } else if Regex::new("^(?:[0-9]+\\s*[a-zA-Z]+\\s*)+$").unwrap().is_match(value) {
+        let unit = Regex::new("[a-zA-Z]+").unwrap().find_iter(value).map(|u| u.as_str()).find(|u| !["w", "d", "h", "m", "s"].contains(u)).unwrap_or_default();
+        return Err(Error::msg(format!("Invalid duration '{}', unsupported unit '{}' (expected w, d, h, m or s), {}", value, unit, DURATION_FORMS)));
+    } else {
+        return Err(invalid());
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+/// The way a [`Schedule`] computes its occurences
+#[derive(Clone)]
+pub(crate) enum Trigger {
+    /// Follow a cron pattern
+    Cron(Box<Cron>),
+    /// Trigger at a fixed interval, aligned on the Unix epoch shifted by an offset
+    Every { interval: Duration, offset: Duration },
+    /// Trigger once the interval elapsed after the end of the previous execution
+    After(Duration),
+}
+
+/// The timezone in which a job's cron expression is evaluated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobTimezone {
+    /// The host's timezone, including its daylight saving time changes
+    Local,
+    Utc,
+    /// A fixed offset from UTC
+    Fixed(FixedOffset),
+    /// A timezone of the IANA database, such as `America/New_York`, including its daylight saving time changes
+    Named(Tz),
+}
+
+impl FromStr for JobTimezone {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s {
+            "Local" | "local" => return Ok(JobTimezone::Local),
+            "UTC" | "utc" | "Etc/UTC" | "Z" => return Ok(JobTimezone::Utc),
+            _ => {},
+        }
+        let re = Regex::new("^(?:UTC)?(?<sign>[+-])(?<hours>[0-9]{1,2})(?::?(?<minutes>[0-9]{2}))?$").unwrap();
+        let offset = re.captures(s).and_then(|c| {
+            let hours: i32 = c["hours"].parse().ok()?;
+            let minutes: i32 = c.name("minutes").map_or(Some(0), |m| m.as_str().parse().ok())?;
+            let seconds = (hours * 60 + minutes) * 60;
+            if &c["sign"] == "-" { FixedOffset::west_opt(seconds) } else { FixedOffset::east_opt(seconds) }
+        });
+        match offset {
+            Some(offset) => Ok(JobTimezone::Fixed(offset)),
+            None => s.parse().map(JobTimezone::Named).map_err(|_| Error::msg(format!(
+                "Invalid timezone '{}', expected UTC, Local, a fixed offset such as +02:00 or an IANA timezone such as Europe/Paris", s,
+            ))),
+        }
+    }
+}
+
+impl Display for JobTimezone {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobTimezone::Local => write!(f, "Local"),
+            JobTimezone::Utc => write!(f, "UTC"),
+            JobTimezone::Fixed(offset) => write!(f, "{}", offset),
+            JobTimezone::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+/// Get the first instant after `from` at which the offset of its timezone differs from its own.
+///
+/// The instant is searched down to the second between `from` and `until`, at which the offset differs.
+fn offset_change<Z: TimeZone>(from: &DateTime<Z>, until: &DateTime<Z>) -> DateTime<Z> {
+    let (offset, tz) = (from.offset().fix(), from.timezone());
+    let (mut before, mut after) = (from.timestamp(), until.timestamp());
+    while after - before > 1 {
+        let middle = before + (after - before) / 2;
+        match tz.timestamp_opt(middle, 0).single() {
+            Some(date) if date.offset().fix() == offset => before = middle,
+            _ => after = middle,
+        }
+    }
+    tz.timestamp_opt(after, 0).single().unwrap_or_else(|| until.clone())
+}
+
+/// Find the first occurence of a cron expression strictly after a date, in the timezone of that date.
+///
+/// Between two offset changes, the occurences are those of the cron expression in a fixed offset.
+/// When the clocks are set forward, an occurence within the skipped local times runs at the change.
+/// When they are set back, the local times that occur twice only trigger the first time, unless
+/// the expression runs every hour: such expressions follow the elapsed time instead.
+fn next_cron_occurrence<Z: TimeZone>(cron: &Cron, from: &DateTime<Z>) -> Result<DateTime<Z>, Error> {
+    let every_hour = cron.pattern.to_string().split_whitespace().rev().nth(3) == Some("*");
+    let tz = from.timezone();
+    let (mut start, mut inclusive) = (from.clone(), false);
+    loop {
+        let offset = start.offset().fix();
+        let next = cron.find_next_occurrence(&start.with_timezone(&offset), inclusive)?.with_timezone(&tz);
+        if next.offset().fix() == offset {
+            match tz.from_local_datetime(&next.naive_local()) {
+                LocalResult::Ambiguous(first, _) if !every_hour && first != next => (start, inclusive) = (next, false),
+                _ => return Ok(next),
+            }
+            continue;
+        }
+        // The offset changed before the occurence, which is then looked for again from the change
+        let change = offset_change(&start, &next);
+        let local = next.with_timezone(&offset).naive_local();
+        if !every_hour && matches!(tz.from_local_datetime(&local), LocalResult::None) {
+            return Ok(change);
+        }
+        (start, inclusive) = (change, true);
+    }
+}
+
+/// A job's parsed schedule
+#[derive(Clone)]
+pub struct Schedule {
+    /// The schedule as it was written in the configuration
+    pub source: String,
+    /// Whether the user expects the cron expression to have a seconds field
+    pub seconds: Option<bool>,
+    /// Whether a mismatch between the cron expression and the seconds hint is an error
+    pub strict: bool,
+    /// Whether `@every` schedules are shifted by an offset specific to the job
+    pub jitter: bool,
+    /// The timezone the cron expression is evaluated in, that of the reference date if unset
+    pub timezone: Option<JobTimezone>,
+    pub(crate) trigger: Trigger,
+}
+
+impl Schedule {
+    /// Get the underlying cron, if the schedule can be represented as one
+    pub fn cron(&self) -> Option<&Cron> {
+        match &self.trigger {
+            Trigger::Cron(c) => Some(c),
+            Trigger::Every { .. } | Trigger::After(_) => None,
+        }
+    }
+
+    /// Get the delay between the end of an execution and the next one, if the schedule
+    /// is measured from the completion of the previous execution
+    pub fn after_completion(&self) -> Option<Duration> {
+        match &self.trigger {
+            Trigger::After(d) => Some(*d),
+            Trigger::Cron(_) | Trigger::Every { .. } => None,
+        }
+    }
+
+    /// Get the second, minute, hour, day of month, month and day of week fields of the
+    /// cron expression the schedule is interpreted as, if it is based on a cron expression
+    pub fn fields(&self) -> Option<[String; 6]> {
+        let pattern = self.cron()?.pattern.to_string();
+        let mut fields: Vec<String> = pattern.split_whitespace().map(|f| f.to_string()).collect();
+        if fields.len() == 5 {
+            fields.insert(0, "0".to_string());
+        }
+        fields.try_into().ok()
+    }
+
+    /// Write the schedule and the keys that alter its interpretation to a job's parameter map
+    pub fn put_into(&self, map: &mut BTreeMap<String, Vec<String>>) {
+        put_one(map, "schedule", Some(&self.source));
+        put_one(map, "seconds", self.seconds);
+        put_one(map, "strict-schedule", self.strict.then_some(true));
+        put_one(map, "jitter", self.jitter.then_some(true));
+        put_one(map, "timezone", self.timezone);
+    }
+
+    /// Find the first occurence of the schedule strictly after the provided time.
+    ///
+    /// Schedules measured from completion consider that an execution ended at that time.
+    pub fn find_next_occurrence<Tz: TimeZone>(&self, from: &DateTime<Tz>) -> Result<DateTime<Tz>, Error> {
+        match &self.trigger {
+            Trigger::Cron(c) => {
+                // The next occurence is computed in the job's timezone, then converted back to an absolute date
+                let tz = from.timezone();
+                match self.timezone {
+                    None => next_cron_occurrence(c, from),
+                    Some(JobTimezone::Local) => next_cron_occurrence(c, &from.with_timezone(&Local)).map(|d| d.with_timezone(&tz)),
+                    Some(JobTimezone::Utc) => next_cron_occurrence(c, &from.with_timezone(&Utc)).map(|d| d.with_timezone(&tz)),
+                    Some(JobTimezone::Fixed(offset)) => next_cron_occurrence(c, &from.with_timezone(&offset)).map(|d| d.with_timezone(&tz)),
+                    Some(JobTimezone::Named(zone)) => next_cron_occurrence(c, &from.with_timezone(&zone)).map(|d| d.with_timezone(&tz)),
+                }
+            },
+            Trigger::Every { interval, offset } => {
+                let (interval, offset) = (i64::try_from(interval.as_secs()).ok(), i64::try_from(offset.as_secs()).ok());
+                interval.zip(offset)
+                    .and_then(|(interval, offset)| {
+                        from.timestamp().checked_sub(offset)?.checked_div_euclid(interval)?
+                            .checked_add(1)?.checked_mul(interval)?.checked_add(offset)
+                    })
+                    .and_then(|next| from.timezone().timestamp_opt(next, 0).single())
+                    .ok_or_else(|| Error::msg(format!("Failed to compute the next occurence of schedule '{}'", self.source)))
+            },
+            Trigger::After(d) => chrono::Duration::from_std(*d).ok()
+                .and_then(|d| from.clone().checked_add_signed(d))
+                .ok_or_else(|| Error::msg(format!("Failed to compute the next occurence of schedule '{}'", self.source))),
+        }
+    }
+}
+
+impl PartialEq for Schedule {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source && self.seconds == other.seconds && self.strict == other.strict && self.jitter == other.jitter && self.timezone == other.timezone
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Debug for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.trigger {
+            Trigger::Cron(c) => write!(f, "{:?} ({})", self.source, c.pattern.to_string()),
+            Trigger::After(d) => write!(f, "{:?} ({}s after completion)", self.source, d.as_secs()),
+            Trigger::Every { interval, offset } if offset.is_zero() => write!(f, "{:?} (every {}s)", self.source, interval.as_secs()),
+            Trigger::Every { interval, offset } => write!(f, "{:?} (every {}s from {}s)", self.source, interval.as_secs(), offset.as_secs()),
+        }
+    }
+}
+
+/// The `@` schedules understood by [`schedule_to_cron`]
+pub const BUILTIN_SCHEDULES: [&str; 9] = ["@yearly", "@annually", "@monthly", "@weekly", "@daily", "@midnight", "@hourly", "@every", "@after"];
+
+/// Whether a schedule starts with a built-in `@` keyword
+fn is_builtin_schedule(sched: &str) -> bool {
+    let keyword = sched.split_whitespace().next().unwrap_or_default();
+    BUILTIN_SCHEDULES.contains(&keyword)
+}
+
+/// Check that a schedule alias can be defined with the provided name and pattern
+pub fn validate_schedule_alias(name: &str, pattern: &str) -> Result<(), Error> {
+    if !name.starts_with('@') || name.len() < 2 || name.contains(char::is_whitespace) {
+        return Err(Error::msg(format!("Invalid schedule alias name '{}', it must start with '@' and contain no whitespace", name)));
+    }
+    if is_builtin_schedule(name) {
+        return Err(Error::msg(format!("The schedule alias {} can not shadow a built-in schedule", name)));
+    }
+    if pattern.trim().starts_with('@') && !is_builtin_schedule(pattern.trim()) {
+        return Err(Error::msg(format!("The schedule alias {} can not refer to another alias ({})", name, pattern)));
+    }
+    schedule_to_cron(pattern).map(|_| ())
+        .map_err(|e| Error::msg(format!("Invalid schedule for alias {}: {}", name, e)))
+}
+
+/// Replace a schedule alias with the schedule it stands for
+pub fn resolve_schedule_alias(sched: &str, aliases: &HashMap<String, String>) -> Result<String, Error> {
+    let sched = sched.trim();
+    if !sched.starts_with('@') || is_builtin_schedule(sched) {
+        return Ok(sched.to_string());
+    }
+    aliases.get(sched).cloned().ok_or_else(|| {
+        let mut known: Vec<&str> = BUILTIN_SCHEDULES.to_vec();
+        let mut custom: Vec<&str> = aliases.keys().map(|k| k.as_str()).collect();
+        custom.sort();
+        known.extend(custom);
+        Error::msg(format!("Unknown schedule alias '{}', known aliases are: {}", sched, known.join(", ")))
+    })
+}
+
+/// Parse a user-provided string to generate the corresponding job schedule.
+///
+/// `@every` intervals that fit in a single cron field are converted to a cron
+/// pattern, other intervals trigger on multiples of the interval since the Unix epoch.
+/// Whole days up to 31 days are a day-of-month step, which restarts on the 1st of every
+/// month: `@every 7d` runs on the 1st, 8th, 15th, 22nd and 29th, so the last interval of
+/// a month is shorter.
+pub(crate) fn schedule_to_cron(sched: &str) -> Result<Schedule, Error> {
+    let source = sched.trim().to_string();
+    let mut sched = source.clone();
+    for keyword in ["@every", "@after"] {
+        if sched.strip_prefix(keyword).is_some_and(|i| !i.is_empty() && !i.starts_with(char::is_whitespace)) {
+            return Err(Error::msg(format!("Invalid schedule '{}': expected '{} <interval>', e.g. '{} 1h30m'", source, keyword, keyword)));
+        }
+    }
+    let re = Regex::new("^@every(?:\\s+(?<interval>.*))?$").unwrap();
+    if let Some(c) = re.captures(sched.as_str()) {
+        let interval = parse_duration(c.name("interval").map_or("", |i| i.as_str()))
+            .map_err(|e| Error::msg(format!("Invalid schedule '{}': {}", source, e)))?
+            .as_secs();
+        sched = match interval {
+            0 => return Err(Error::msg(format!("Invalid schedule '{}': the interval must not be empty", source))),
+            i if i % 86400 == 0 && i / 86400 <= 31 => format!("0 0 0 */{} * *", i / 86400),
+            i if i % 3600 == 0 && i / 3600 < 24 => format!("0 0 */{} * * *", i / 3600),
+            i if i % 60 == 0 && i / 60 < 60 => format!("0 */{} * * * *", i / 60),
+            i if i < 60 => format!("*/{} * * * * *", i),
+            i => return Ok(Schedule { source, seconds: None, strict: false, jitter: false, timezone: None, trigger: Trigger::Every { interval: Duration::from_secs(i), offset: Duration::ZERO } }),
+        };
+    }
+    let re = Regex::new("^@after(?:\\s+(?<interval>.*))?$").unwrap();
+    if let Some(c) = re.captures(sched.as_str()) {
+        let interval = parse_duration(c.name("interval").map_or("", |i| i.as_str()))
+            .map_err(|e| Error::msg(format!("Invalid schedule '{}': {}", source, e)))?;
+        if interval.is_zero() {
+            return Err(Error::msg(format!("Invalid schedule '{}': the interval must not be empty", source)));
+        }
+        return Ok(Schedule { source, seconds: None, strict: false, jitter: false, timezone: None, trigger: Trigger::After(interval) });
+    }
+    let cron = Cron::new(&sched).with_seconds_optional().parse().map_err(Error::new)?;
+    Ok(Schedule { source, seconds: None, strict: false, jitter: false, timezone: None, trigger: Trigger::Cron(Box::new(cron)) })
+}
+
+/// Hash values into a number that does not change between runs and versions (FNV-1a)
+fn stable_hash(values: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in values.iter().flat_map(|v| v.bytes().chain([0])) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Extract a job's schedule from its parameter map, along with the `seconds` hint
+/// and the `strict-schedule` flag that control how ambiguous cron expressions are handled
+pub fn take_schedule(value: &mut HashMap<String, Vec<String>>, job: &str) -> Result<Schedule, Error> {
+    if let Some(schedules) = value.get("schedule").filter(|s| s.len() > 1) {
+        if schedules.iter().any(|s| s.trim().starts_with("@after")) {
+            return Err(Error::msg(format!("The @after schedule can not be combined with other schedules ({:?})", schedules)));
+        }
+    }
+    let mut schedule = schedule_to_cron(require_one!(value, "schedule")?.as_str())?;
+    schedule.seconds = take_one!(value, "seconds")?.map(|t| t.parse().map_err(Error::new)).transpose()?;
+    schedule.strict = take_one!(value, "strict-schedule")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?;
+    schedule.timezone = take_one!(value, "timezone")?.map(|t| t.parse()).transpose()?;
+    schedule.jitter = take_one!(value, "jitter")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?;
+    if schedule.jitter {
+        match schedule.source.strip_prefix("@every").and_then(|i| parse_duration(i).ok()) {
+            Some(interval) => {
+                let offset = Duration::from_secs(stable_hash(&[job, &schedule.source]) % interval.as_secs());
+                schedule.trigger = Trigger::Every { interval, offset };
+            },
+            None => warn!("The jitter flag of job '{}' is ignored as it only applies to @every schedules", job),
+        }
+    }
+    let field_count = schedule.source.split_whitespace().count();
+    if let Some(seconds) = schedule.seconds.filter(|_| !schedule.source.starts_with('@')) {
+        if (field_count == 6) != seconds {
+            let message = format!(
+                "The schedule '{}' has {} fields but {}, {}",
+                schedule.source, field_count,
+                if seconds { "a seconds field was expected" } else { "no seconds field was expected" },
+                if field_count == 6 { "its first field is read as seconds" } else { "it is read as second 0 of the matching minutes" },
+            );
+            if schedule.strict {
+                return Err(Error::msg(message));
             }
+            warn!("{}", message);
+        }
+    }
+    Ok(schedule)
+}
+
+/// Split an environment entry into its variable name and value.
+///
+/// Entries are expected to be `NAME=VALUE` pairs where `NAME` is a valid environment
+/// variable identifier. A bare `NAME` is accepted and returns no value.
+pub fn parse_environment_entry(entry: &str) -> Result<(&str, Option<&str>), Error> {
+    let (name, value) = match entry.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (entry, None),
+    };
+    if !Regex::new("^[A-Za-z_][A-Za-z0-9_]*$").unwrap().is_match(name) {
+        return Err(Error::msg(format!("'{}' is not a valid variable name, expected NAME=VALUE", name)));
+    }
+    Ok((name, value))
+}
+
+/// Normalize an environment entry to the `NAME=VALUE` form.
+///
+/// A bare `NAME` takes its value from `lookup` when `pass_environment` is set, and is rejected otherwise.
+pub fn resolve_environment_entry(entry: &str, pass_environment: bool, lookup: impl Fn(&str) -> Option<String>) -> Result<String, Error> {
+    match parse_environment_entry(entry)? {
+        (_, Some(_)) => Ok(entry.to_string()),
+        (name, None) if pass_environment => lookup(name)
+            .map(|value| format!("{}={}", name, value))
+            .ok_or_else(|| Error::msg(format!("The variable {} is not set in cfc's environment", name))),
+        (name, None) => Err(Error::msg(format!("The variable {} has no value, set pass-environment to forward it from cfc's environment", name))),
+    }
+}
+
+/// Extract and validate a job's environment entries from its parameter map
+pub(crate) fn take_environment(value: &mut HashMap<String, Vec<String>>, job: &str) -> Result<Vec<String>, Error> {
+    let pass_environment = take_one!(value, "pass-environment")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?;
+    value.remove("environment").unwrap_or_default().iter()
+        .map(|entry| resolve_environment_entry(entry, pass_environment, |name| std::env::var(name).ok())
+            .and_then(|entry| check_secret_reference(&entry).map(|_| entry))
+            .map_err(|e| Error::msg(format!("Invalid environment entry '{}' in job '{}': {}", entry, job, e))))
+        .collect()
+}
+
+/// Parse the content of an environment file in the dotenv format into `NAME=VALUE` entries.
+///
+/// Each line sets a variable with `NAME=VALUE`, optionally preceded by `export`. Blank lines and
+/// lines starting with `#` are ignored. A value may be single-quoted (used verbatim) or
+/// double-quoted (where `\n`, `\"` and `\\` are unescaped). An unquoted value ends at a ` #`
+/// comment and is trimmed. Values can not span several lines.
+pub fn parse_environment_file(content: &str) -> Result<Vec<String>, Error> {
+    let mut entries = vec![];
+    for (number, line) in content.lines().enumerate() {
+        let invalid = |reason: &str| Error::msg(format!("line {}: {}", number + 1, reason));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(invalid("expected NAME=VALUE"));
+        };
+        let (name, value) = (name.trim_end(), value.trim_start());
+        parse_environment_entry(name).map_err(|e| invalid(&e.to_string()))?;
+        let value = match value.chars().next() {
+            Some('\'') => match value[1..].split_once('\'') {
+                Some((quoted, rest)) if rest.trim().is_empty() || rest.trim_start().starts_with('#') => quoted.to_string(),
+                Some(_) => return Err(invalid("unexpected characters after the closing quote")),
+                None => return Err(invalid("the single quote is not closed")),
+            },
+            Some('"') => {
+                let (mut quoted, mut chars, mut closed) = (String::new(), value[1..].chars(), false);
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => {
+                            closed = true;
+                            break;
+                        },
+                        '\\' => match chars.next() {
+                            Some('n') => quoted.push('\n'),
+                            Some(c @ ('"' | '\\')) => quoted.push(c),
+                            Some(c) => quoted.extend(['\\', c]),
+                            None => quoted.push('\\'),
+                        },
+                        c => quoted.push(c),
+                    }
+                }
+                let rest = chars.as_str().trim();
+                if !closed {
+                    return Err(invalid("the double quote is not closed"));
+                } else if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err(invalid("unexpected characters after the closing quote"));
+                }
+                quoted
+            },
+            _ => value.split_once(" #").map_or(value, |(value, _)| value).trim().to_string(),
+        };
+        let entry = format!("{}={}", name, value);
+        check_secret_reference(&entry).map_err(|e| invalid(&e.to_string()))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Read and parse a job's environment file, see [`parse_environment_file`]
+pub(crate) fn read_environment_file(path: &str) -> Result<Vec<String>, Error> {
+    let content = std::fs::read_to_string(path).map_err(|e| Error::msg(format!("Failed to read the environment file {}: {}", path, e)))?;
+    parse_environment_file(&content).map_err(|e| Error::msg(format!("Invalid environment file {}, {}", path, e)))
+}
+
+/// Merge the entries of an environment file with a job's inline entries, which take precedence
+pub(crate) fn merge_environment(file: Vec<String>, inline: &[String]) -> Vec<String> {
+    let name = |entry: &str| entry.split_once('=').map_or(entry, |(name, _)| name).to_string();
+    let overridden: HashSet<String> = inline.iter().map(|e| name(e)).collect();
+    file.into_iter()
+        .filter(|entry| !overridden.contains(&name(entry)))
+        .chain(inline.iter().cloned())
+        .collect()
+}
+
+/// The prefix of the environment values that are resolved from a secret backend when the job runs
+pub const SECRET_PREFIX: &str = "secret://";
+
+/// The backend of the secrets read from files, e.g. `secret://file/run/secrets/db_password`
+pub const FILE_SECRET_BACKEND: &str = "file";
+
+/// The backend of the secrets read from cfc's environment, e.g. `secret://env/VAULT_TOKEN`
+pub const ENV_SECRET_BACKEND: &str = "env";
+
+/// Resolves the secret references of a backend to their value.
+///
+/// Backends are registered with [`register_secret_backend`]. Their errors must never include
+/// the value of a secret.
+pub trait SecretBackend: Send + Sync {
+    /// Get the current value of a secret from its reference, the part of the value after `secret://<backend>/`
+    fn resolve(&self, reference: &str) -> Result<String, Error>;
+}
+
+/// Reads secrets from files, without their trailing line break
+struct FileSecrets;
+
+impl SecretBackend for FileSecrets {
+    fn resolve(&self, reference: &str) -> Result<String, Error> {
+        let path = format!("/{}", reference);
+        let value = std::fs::read_to_string(&path).map_err(|e| Error::msg(format!("Failed to read {}: {}", path, e)))?;
+        Ok(value.strip_suffix('\n').map(|v| v.strip_suffix('\r').unwrap_or(v)).unwrap_or(&value).to_string())
+    }
+}
+
+/// Reads secrets from cfc's environment
+struct EnvSecrets;
+
+impl SecretBackend for EnvSecrets {
+    fn resolve(&self, reference: &str) -> Result<String, Error> {
+        std::env::var(reference).map_err(|e| Error::msg(format!("The variable {} can not be read from cfc's environment: {}", reference, e)))
+    }
+}
+
+static SECRET_BACKENDS: LazyLock<RwLock<BTreeMap<String, Arc<dyn SecretBackend>>>> = LazyLock::new(|| {
+    let builtins: [(&str, Arc<dyn SecretBackend>); 2] = [(FILE_SECRET_BACKEND, Arc::new(FileSecrets)), (ENV_SECRET_BACKEND, Arc::new(EnvSecrets))];
+    RwLock::new(builtins.into_iter().map(|(name, backend)| (name.to_string(), backend)).collect())
+});
+
+/// Register a secret backend, used for the `secret://<name>/...` environment values.
+/// The backend previously registered with this name is replaced.
+pub fn register_secret_backend(name: &str, backend: impl SecretBackend + 'static) {
+    SECRET_BACKENDS.write().unwrap_or_else(|e| e.into_inner()).insert(name.to_string(), Arc::new(backend));
+}
+
+/// Split the value of an environment entry into its secret backend and reference, if it is a secret reference
+pub fn parse_secret_reference(entry: &str) -> Option<(&str, &str)> {
+    let (_, value) = entry.split_once('=')?;
+    let reference = value.strip_prefix(SECRET_PREFIX)?;
+    Some(reference.split_once('/').unwrap_or((reference, "")))
+}
+
+/// Check that the backend of an environment entry's secret reference is registered
+pub(crate) fn check_secret_reference(entry: &str) -> Result<(), Error> {
+    match parse_secret_reference(entry) {
+        Some((backend, _)) if !SECRET_BACKENDS.read().unwrap_or_else(|e| e.into_inner()).contains_key(backend) => {
+            Err(Error::msg(format!("Unknown secret backend '{}'", backend)))
         },
-        None => {},
+        Some((_, "")) => Err(Error::msg("The secret reference is empty")),
+        _ => Ok(()),
     }
-    Cron::new(&sched).with_seconds_optional().parse().map_err(|e| Error::new(e))
+}
+
+/// The values of the secrets resolved so far, hidden from the output of the executions
+static REDACTED_VALUES: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+
+/// The text that replaces the secrets in the output of the executions
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Replace the values of the resolved secrets in a text
+pub fn redact(text: &str) -> String {
+    let values = REDACTED_VALUES.read().unwrap_or_else(|e| e.into_inner());
+    // Longer values first, so that a secret containing another one is fully hidden
+    let mut values: Vec<&String> = values.iter().collect();
+    values.sort_by_key(|v| std::cmp::Reverse(v.len()));
+    values.into_iter().fold(text.to_string(), |text, value| text.replace(value.as_str(), REDACTED))
+}
+
+/// Resolve the secret references of a job's environment entries.
+///
+/// Secrets are resolved each time the job runs so that rotated values are used, and their
+/// values are added to the ones [redacted][redact] from the output of all executions.
+/// Errors only mention the secret's reference.
+pub fn resolve_secrets(environment: &[String]) -> Result<Vec<String>, Error> {
+    environment.iter().map(|entry| {
+        let Some((backend_name, reference)) = parse_secret_reference(entry) else {
+            return Ok(entry.clone());
+        };
+        let (name, _) = entry.split_once('=').unwrap_or_default();
+        let backend = SECRET_BACKENDS.read().unwrap_or_else(|e| e.into_inner()).get(backend_name).cloned()
+            .ok_or_else(|| Error::msg(format!("Unknown secret backend '{}' for variable {}", backend_name, name)))?;
+        let value = backend.resolve(reference)
+            .map_err(|e| Error::msg(format!("Failed to resolve the secret {}{}/{} of variable {}: {}", SECRET_PREFIX, backend_name, reference, name, e)))?;
+        if !value.is_empty() {
+            REDACTED_VALUES.write().unwrap_or_else(|e| e.into_inner()).insert(value.clone());
+        }
+        Ok(format!("{}={}", name, value))
+    }).collect()
+}
+
+/// Extract a job's commands from its parameter map.
+///
+/// The `command` key may be set several times, its commands are then run one after the other.
+pub(crate) fn take_commands(value: &mut HashMap<String, Vec<String>>, required: bool) -> Result<Vec<String>, Error> {
+    let commands = value.remove("command").unwrap_or_default();
+    if required && commands.is_empty() {
+        return Err(Error::msg("The job key command is required but not set"));
+    }
+    for command in &commands {
+        command_argv(command).map_err(|e| Error::msg(format!("Invalid command {}: {}", command, e)))?;
+    }
+    Ok(commands)
+}
+
+/// Split a command in its arguments.
+///
+/// A JSON array of strings (`["echo", "hello world"]`) is used verbatim, any other command
+/// is split like a shell would.
+pub(crate) fn command_argv(command: &str) -> Result<Vec<String>, Error> {
+    let argv = match serde_json::from_str(command.trim()) {
+        Ok(serde_json::Value::Array(items)) => items.iter()
+            .map(|i| i.as_str().map(|s| s.to_string()))
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| Error::msg("the arguments of a command array must be strings"))?,
+        _ => shell_words::split(command).map_err(|e| Error::msg(format!("it can not be split in arguments: {}", e)))?,
+    };
+    if argv.is_empty() {
+        return Err(Error::msg("the command is empty"));
+    }
+    Ok(argv)
+}
+
+/// The shell that runs the commands of the jobs that set `shell = true`
+pub const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// The arguments that a shell would read as operators
+const SHELL_OPERATORS: [&str; 11] = ["|", "||", "&", "&&", ";", ">", ">>", "<", "<<", "2>", "2>&1"];
+
+/// Parse the `shell` key of a job, which is either a boolean or the absolute path of a shell
+pub(crate) fn parse_shell(value: &str) -> Result<Option<String>, Error> {
+    match value.trim() {
+        "true" => Ok(Some(DEFAULT_SHELL.to_string())),
+        "false" => Ok(None),
+        path if path.starts_with('/') => Ok(Some(path.to_string())),
+        other => Err(Error::msg(format!("Invalid shell '{}', expected true, false or the absolute path of a shell", other))),
+    }
+}
+
+/// Get the arguments of a command that a shell would read as operators (pipes, redirections,
+/// command lists), which are passed verbatim to the command when it is not run in a shell
+pub(crate) fn shell_operators(command: &str) -> Vec<String> {
+    if matches!(serde_json::from_str(command.trim()), Ok(serde_json::Value::Array(_))) {
+        return vec![];
+    }
+    // The arguments that are quoted or escaped, even partially, are not operators
+    let (mut args, mut arg, mut quoted, mut quote) = (vec![], String::new(), false, None);
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') => { chars.next(); },
+            (Some(_), _) => {},
+            (None, '\'' | '"') => (quote, quoted) = (Some(c), true),
+            (None, '\\') => {
+                chars.next();
+                quoted = true;
+            },
+            (None, c) if c.is_whitespace() => {
+                if !quoted && !arg.is_empty() {
+                    args.push(std::mem::take(&mut arg));
+                }
+                (arg, quoted) = (String::new(), false);
+            },
+            (None, c) => arg.push(c),
+        }
+    }
+    if !quoted && !arg.is_empty() {
+        args.push(arg);
+    }
+    args.into_iter()
+        .filter(|arg| SHELL_OPERATORS.contains(&arg.as_str()) || arg.starts_with(['>', '<', '|']) || arg.starts_with("2>") || arg.starts_with("&&") || (arg.len() > 1 && arg.ends_with(';')))
+        .collect()
+}
+
+/// Describe a job's commands in the order they are run
+pub(crate) fn describe_commands(commands: &[String]) -> String {
+    commands.iter().map(|c| format!("'{}'", c)).collect::<Vec<_>>().join(", then ")
+}
+
+/// Run a job's commands one after the other and merge their reports.
+///
+/// The sequence stops at the first command that returns a non-zero value unless
+/// `continue_on_error` is set, an error stops it in any case.
+pub(crate) async fn run_commands<'a, F, Fut>(commands: &'a [String], continue_on_error: bool, mut run: F) -> Result<ExecutionReport, Error>
+where
+    F: FnMut(&'a str) -> Fut,
+    Fut: Future<Output = Result<ExecutionReport, Error>>,
+{
+    let mut reports = Vec::with_capacity(commands.len());
+    for command in commands {
+        let report = run(command).await?;
+        let failed = report.retval != 0;
+        reports.push((command.clone(), report));
+        if failed && !continue_on_error {
+            break;
+        }
+    }
+    Ok(ExecutionReport::merge(reports, commands.len()))
+}
+
+/// Insert a single-valued job key in a parameter map if it is set
+pub(crate) fn put_one(map: &mut BTreeMap<String, Vec<String>>, key: &str, value: Option<impl ToString>) {
+    if let Some(value) = value {
+        map.insert(key.to_string(), vec![value.to_string()]);
+    }
+}
+
+/// Insert a multi-valued job key in a parameter map if it has values
+pub(crate) fn put_all(map: &mut BTreeMap<String, Vec<String>>, key: &str, values: &[String]) {
+    if !values.is_empty() {
+        map.insert(key.to_string(), values.to_vec());
+    }
+}
+
+/// What to do when a job is triggered while one of its executions is still running
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Start a new execution alongside the running ones
+    #[default]
+    Allow,
+    /// Drop the new execution
+    Skip,
+    /// Start the new execution once the running ones ended, at most one execution is queued
+    Queue,
+    /// Abort the running executions before starting the new one
+    CancelRunning,
+}
+
+impl FromStr for OverlapPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "allow" => Ok(OverlapPolicy::Allow),
+            "skip" => Ok(OverlapPolicy::Skip),
+            "queue" => Ok(OverlapPolicy::Queue),
+            "cancel-running" => Ok(OverlapPolicy::CancelRunning),
+            s => Err(Error::msg(format!("Unsupported overlap policy '{}', expected one of allow, skip, queue or cancel-running", s))),
+        }
+    }
+}
+
+impl Display for OverlapPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverlapPolicy::Allow => write!(f, "allow"),
+            OverlapPolicy::Skip => write!(f, "skip"),
+            OverlapPolicy::Queue => write!(f, "queue"),
+            OverlapPolicy::CancelRunning => write!(f, "cancel-running"),
+        }
+    }
+}
+
+/// What to do when an execution of a job fails, either with a non-zero exit code or a timeout
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnFailure {
+    /// Keep scheduling the job
+    #[default]
+    Continue,
+    /// Stop scheduling the job
+    StopJob,
+    /// Stop scheduling all jobs and exit with an error
+    StopDaemon,
+}
+
+impl FromStr for OnFailure {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "continue" => Ok(OnFailure::Continue),
+            "stop-job" => Ok(OnFailure::StopJob),
+            "stop-daemon" => Ok(OnFailure::StopDaemon),
+            s => Err(Error::msg(format!("Unsupported on-failure policy '{}', expected one of continue, stop-job or stop-daemon", s))),
+        }
+    }
+}
+
+impl Display for OnFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OnFailure::Continue => write!(f, "continue"),
+            OnFailure::StopJob => write!(f, "stop-job"),
+            OnFailure::StopDaemon => write!(f, "stop-daemon"),
+        }
+    }
+}
+
+/// The prefix of the job keys that hold annotations, e.g. `annotation.team`
+pub const ANNOTATION_PREFIX: &str = "annotation.";
+
+/// Remove the annotations from a job's parameter map.
+///
+/// Annotations are free-form metadata that is passed through to reports, metrics and logs.
+/// They are removed before the job's kind reads its keys, so that they can never change how
+/// the job runs.
+pub(crate) fn take_annotations(value: &mut HashMap<String, Vec<String>>) -> Result<BTreeMap<String, String>, Error> {
+    let keys: Vec<String> = value.keys().filter(|k| k.starts_with(ANNOTATION_PREFIX)).cloned().collect();
+    let mut annotations = BTreeMap::new();
+    for key in keys {
+        let name = &key[ANNOTATION_PREFIX.len()..];
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(Error::msg(format!("Invalid annotation name '{}', expected letters, digits, '-' and '_'", name)));
+        }
+        if let Some(annotation) = take_one!(value, &key)? {
+            annotations.insert(name.to_string(), annotation);
+        }
+    }
+    Ok(annotations)
+}
+
+/// The number of bytes of each output stream kept in a report when the job does not set `max-output-size`
+pub const DEFAULT_MAX_OUTPUT_SIZE: usize = 1024 * 1024;
+
+/// Scheduling options that are supported by all job kinds
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JobOptions {
+    /// The maximum number of times the job may be triggered before its scheduling stops
+    pub max_runs: Option<u64>,
+    /// The job is not triggered before this date
+    pub not_before: Option<DateTime<FixedOffset>>,
+    /// The job is not triggered after this date
+    pub not_after: Option<DateTime<FixedOffset>>,
+    /// Whether the job is triggered once as soon as it is scheduled
+    pub run_on_start: bool,
+    /// The maximum delay between a scheduled occurence and the actual launch of the job, after which the run is skipped
+    pub deadline: Option<Duration>,
+    /// Whether the job's stderr is forwarded along its stdout instead of separately
+    pub merge_streams: bool,
+    /// What to do when the job is triggered while it is still running
+    pub overlap: OverlapPolicy,
+    /// The maximum duration of an execution, after which it is terminated
+    pub timeout: Option<Duration>,
+    /// What to do when an execution fails
+    pub on_failure: OnFailure,
+    /// The number of failed executions in a row that are tolerated, the failures beyond it are
+    /// logged as errors and trigger the `on_failure` policy, which otherwise applies to every failure
+    pub max_consecutive_failures: Option<u64>,
+    /// The mutual-exclusion group of the job, the executions of a group's jobs never overlap
+    pub group: Option<String>,
+    /// How long an execution waits for its group to be free before it is skipped
+    pub group_wait_timeout: Option<Duration>,
+    /// Whether the next commands of the job still run after one of them failed
+    pub continue_on_error: bool,
+    /// The URL the report of each execution is posted to
+    pub webhook_url: Option<WebhookUrl>,
+    /// Whether the job is declared with `enabled = false`, it is then validated but never scheduled
+    pub disabled: bool,
+    /// Whether the job is declared with `slack-on-failure = false`, its failures are then not notified to Slack
+    pub slack_muted: bool,
+    /// The number of executions kept in the job's history, see [`ExecutionHistory`][super::ExecutionHistory]
+    pub history_size: Option<usize>,
+    /// Free-form metadata passed through to reports, metrics and logs, see [`ANNOTATION_PREFIX`]
+    pub annotations: BTreeMap<String, String>,
+    /// The order in which the waiting executions get a permit when the executions running at the same time are limited
+    pub priority: i32,
+    /// The command run on the host after each successful execution
+    pub on_success: Option<String>,
+    /// The command run on the host after each execution that failed, timed out or could not be run
+    pub on_error: Option<String>,
+    /// Whether the job's stdout is logged as it is produced instead of being kept in its reports, only supported by exec and local jobs
+    pub capture_stdout: bool,
+    /// Whether the job's stderr is logged as it is produced instead of being kept in its reports
+    pub capture_stderr: bool,
+    /// Whether the job's output is logged as it is produced, while still being kept in its reports
+    pub stream_logs: bool,
+    /// The number of bytes of each output stream kept in the job's reports, see [`DEFAULT_MAX_OUTPUT_SIZE`]
+    pub max_output_size: Option<usize>,
+    /// Whether the job was discovered from the labels of a container, set by the loader
+    pub from_labels: bool,
+}
+
+impl JobOptions {
+    /// Extract the shared job options from a job's parameter map
+    pub fn take_from(value: &mut HashMap<String, Vec<String>>) -> Result<Self, Error> {
+        // Ofelia's no-overlap flag is an alias of the skip policy
+        let no_overlap = take_one!(value, "no-overlap")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?;
+        let overlap = match take_one!(value, "overlap-policy")?.map(|p| p.parse()).transpose()? {
+            Some(policy) if no_overlap && policy != OverlapPolicy::Skip => {
+                return Err(Error::msg(format!("The job's overlap-policy ({}) conflicts with no-overlap", policy)));
+            },
+            Some(policy) => policy,
+            None if no_overlap => OverlapPolicy::Skip,
+            None => OverlapPolicy::Allow,
+        };
+        let options = JobOptions {
+            max_runs: take_one!(value, "max-runs")?.map(|t| t.parse().map_err(Error::new)).transpose()?,
+            not_before: take_one!(value, "not-before")?.map(|t| parse_date("not-before", &t)).transpose()?,
+            not_after: take_one!(value, "not-after")?.map(|t| parse_date("not-after", &t)).transpose()?,
+            run_on_start: take_one!(value, "run-on-start")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?,
+            deadline: take_one!(value, "deadline")?.map(|t| parse_duration(&t)).transpose()?,
+            merge_streams: take_one!(value, "merge-streams")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?,
+            overlap,
+            timeout: take_one!(value, "timeout")?.map(|t| parse_duration(&t)).transpose()?,
+            on_failure: take_one!(value, "on-failure")?.map(|p| p.parse()).transpose()?.unwrap_or_default(),
+            max_consecutive_failures: take_one!(value, "max-consecutive-failures")?.map(|t| t.parse().map_err(Error::new)).transpose()?,
+            group: take_one!(value, "group")?.map(|g| g.trim().to_string()).filter(|g| !g.is_empty()),
+            group_wait_timeout: take_one!(value, "group-wait-timeout")?.map(|t| parse_duration(&t)).transpose()?,
+            continue_on_error: take_one!(value, "continue-on-error")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?,
+            webhook_url: take_one!(value, "webhook-url")?.map(|u| u.parse()).transpose()?,
+            disabled: !take_one!(value, "enabled")?.map_or(Ok(true), |t| t.parse().map_err(Error::new))?,
+            slack_muted: !take_one!(value, "slack-on-failure")?.map_or(Ok(true), |t| t.parse().map_err(Error::new))?,
+            history_size: take_one!(value, "history-size")?.map(|t| t.parse().map_err(Error::new)).transpose()?,
+            // Set by the job's constructor, see [`take_annotations`]
+            annotations: BTreeMap::new(),
+            priority: take_one!(value, "priority")?.map_or(Ok(0), |t| t.parse().map_err(|_| Error::msg(format!("The job key priority must be an integer, got '{}'", t))))?,
+            on_success: take_one!(value, "on-success")?,
+            on_error: take_one!(value, "on-error")?,
+            // Only supported by some job kinds, see [`JobOptions::take_output_options`]
+            capture_stdout: false,
+            capture_stderr: false,
+            stream_logs: false,
+            max_output_size: None,
+            // Set by the loader, see [`crate::loader::JobOrigin`]
+            from_labels: false,
+        };
+        if options.max_runs == Some(0) {
+            return Err(Error::msg("The job key max-runs must be greater than 0"));
+        }
+        if options.max_consecutive_failures == Some(0) {
+            return Err(Error::msg("The job key max-consecutive-failures must be greater than 0"));
+        }
+        if options.history_size.is_some_and(|s| s > MAX_HISTORY_SIZE) {
+            return Err(Error::msg(format!("The job key history-size must not be greater than {}", MAX_HISTORY_SIZE)));
+        }
+        if options.timeout.is_some_and(|t| t.is_zero()) {
+            return Err(Error::msg("The job key timeout must be greater than 0"));
+        }
+        if options.webhook_url.is_some() && !cfg!(feature = "webhook") {
+            return Err(Error::msg("The job key webhook-url requires cfc to be built with the webhook feature"));
+        }
+        if options.group_wait_timeout.is_some() && options.group.is_none() {
+            return Err(Error::msg("The job key group-wait-timeout requires a group"));
+        }
+        if let Some(command) = options.on_success.as_ref() {
+            parse_hook("on-success", command)?;
+        }
+        if let Some(command) = options.on_error.as_ref() {
+            parse_hook("on-error", command)?;
+        }
+        if let (Some(not_before), Some(not_after)) = (options.not_before, options.not_after) {
+            if not_after < not_before {
+                return Err(Error::msg(format!("The job's not-after date ({}) is earlier than its not-before date ({})", not_after, not_before)));
+            }
+        }
+        Ok(options)
+    }
+
+    /// Write the options back to a job's parameter map, omitting default values
+    pub fn put_into(&self, map: &mut BTreeMap<String, Vec<String>>) {
+        put_one(map, "max-runs", self.max_runs);
+        put_one(map, "not-before", self.not_before.map(|t| t.to_rfc3339()));
+        put_one(map, "not-after", self.not_after.map(|t| t.to_rfc3339()));
+        put_one(map, "run-on-start", self.run_on_start.then_some(true));
+        put_one(map, "deadline", self.deadline.map(|d| format!("{}s", d.as_secs())));
+        put_one(map, "merge-streams", self.merge_streams.then_some(true));
+        put_one(map, "overlap-policy", (self.overlap != OverlapPolicy::Allow).then_some(self.overlap));
+        put_one(map, "timeout", self.timeout.map(|d| format!("{}s", d.as_secs())));
+        put_one(map, "on-failure", (self.on_failure != OnFailure::Continue).then_some(self.on_failure));
+        put_one(map, "group", self.group.as_ref());
+        put_one(map, "group-wait-timeout", self.group_wait_timeout.map(|d| format!("{}s", d.as_secs())));
+        put_one(map, "continue-on-error", self.continue_on_error.then_some(true));
+        put_one(map, "webhook-url", self.webhook_url.as_ref());
+        put_one(map, "enabled", self.disabled.then_some(false));
+        put_one(map, "slack-on-failure", self.slack_muted.then_some(false));
+        put_one(map, "history-size", self.history_size);
+        put_one(map, "priority", (self.priority != 0).then_some(self.priority));
+        put_one(map, "on-success", self.on_success.as_ref());
+        put_one(map, "on-error", self.on_error.as_ref());
+        put_one(map, "capture-stdout", self.capture_stdout.then_some(true));
+        put_one(map, "capture-stderr", self.capture_stderr.then_some(true));
+        put_one(map, "stream-logs", self.stream_logs.then_some(true));
+        put_one(map, "max-output-size", self.max_output_size);
+        for (name, annotation) in &self.annotations {
+            put_one(map, &format!("{}{}", ANNOTATION_PREFIX, name), Some(annotation));
+        }
+    }
+}
+
+impl JobOptions {
+    /// Extract the `capture-stdout`, `capture-stderr`, `stream-logs` and `max-output-size` keys of
+    /// the job kinds that read the output of their commands
+    pub(crate) fn take_output_options(mut self, value: &mut HashMap<String, Vec<String>>) -> Result<Self, Error> {
+        self.capture_stdout = take_one!(value, "capture-stdout")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?;
+        self.capture_stderr = take_one!(value, "capture-stderr")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?;
+        self.stream_logs = take_one!(value, "stream-logs")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?;
+        self.max_output_size = take_one!(value, "max-output-size")?
+            .map(|t| t.trim().parse().map_err(|_| Error::msg(format!("The job key max-output-size must be a number of bytes, got '{}'", t))))
+            .transpose()?;
+        Ok(self)
+    }
+
+    /// The number of bytes of each output stream kept in the job's reports
+    pub fn max_output_size(&self) -> usize {
+        self.max_output_size.unwrap_or(DEFAULT_MAX_OUTPUT_SIZE)
+    }
+
+    /// The logger of one of the job's output streams if the job captures or streams it
+    pub(crate) fn output_lines(&self, job: &str, stream: StreamKind) -> Option<OutputLines> {
+        let (captured, logged_as) = match stream {
+            StreamKind::Stdout => (self.capture_stdout, StreamKind::Stdout),
+            StreamKind::Stderr if self.merge_streams => (self.capture_stderr, StreamKind::Stdout),
+            StreamKind::Stderr => (self.capture_stderr, StreamKind::Stderr),
+        };
+        match (captured, self.stream_logs) {
+            (true, _) => Some(OutputLines::new(job, logged_as)),
+            (false, true) => Some(OutputLines::new(job, logged_as).kept()),
+            (false, false) => None,
+        }
+    }
+
+    /// The number of executions kept in the job's history
+    pub fn history_size(&self) -> usize {
+        self.history_size.unwrap_or(DEFAULT_HISTORY_SIZE)
+    }
+
+    /// Whether the provided date is part of the job's activity window
+    pub fn is_active_at<Tz: TimeZone>(&self, date: &DateTime<Tz>) -> bool {
+        self.not_before.is_none_or(|t| *date >= t) && self.not_after.is_none_or(|t| *date <= t)
+    }
+
+    /// Whether a run launched at `launch` is too late for the occurence scheduled at `scheduled`
+    pub fn missed_deadline<Tz: TimeZone>(&self, scheduled: &DateTime<Tz>, launch: &DateTime<Tz>) -> bool {
+        self.deadline.is_some_and(|d| (launch.clone() - scheduled.clone()).to_std().is_ok_and(|delay| delay > d))
+    }
+}
+
+/// Parse an RFC3339 date set in a job key
+fn parse_date(key: &str, value: &str) -> Result<DateTime<FixedOffset>, Error> {
+    DateTime::parse_from_rfc3339(value.trim())
+        .map_err(|e| Error::msg(format!("The job key {} is not a valid RFC3339 date ({}): {}", key, value, e)))
 }
 
 /// Returned by the schedule watch when a job's execution should occur.
-#[derive(Clone, Debug, Default)]
-pub struct ExecutionSchedule {}
+#[derive(Clone, Debug)]
+pub struct ExecutionSchedule {
+    /// The time at which the execution was meant to start
+    pub scheduled_at: DateTime<Local>,
+}
+
+/// The output stream of a job's command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    Stdout,
+    Stderr,
+}
+
+impl Display for StreamKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamKind::Stdout => write!(f, "stdout"),
+            StreamKind::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// The result of one of the commands of a job that runs several
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CommandReport {
+    pub command: String,
+    pub retval: i64,
+}
 
 /// Returned by a job to report on its execution if no error occured
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct ExecutionReport {
+    /// The return value of the first failed command, or 0 if all commands succeeded
     pub retval: i64,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
+    /// Whether the execution was terminated because it exceeded the job's timeout
+    pub timed_out: bool,
+    /// The result of each command that was run, only set when the job has several commands
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<CommandReport>,
+    /// Whether a command produced no output within its job's `first-output-timeout`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stalled: bool,
+    /// The id of the scheduled run, also set on the `run` span of its logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<u64>,
+    /// The occurence of the job's schedule the run was triggered for
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_date")]
+    pub scheduled_at: Option<DateTime<Local>>,
+    /// When the run started, once it got its group lock and execution permit
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_date")]
+    pub started_at: Option<DateTime<Local>>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_date")]
+    pub finished_at: Option<DateTime<Local>>,
+    /// Whether some of the output was dropped as it exceeded the job's `max-output-size`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub output_truncated: bool,
+    /// The number of runs of the job that failed in a row, this one included, 0 if it succeeded
+    #[serde(skip_serializing_if = "is_zero")]
+    pub consecutive_failures: u64,
+}
+
+fn is_zero(count: &u64) -> bool {
+    *count == 0
+}
+
+/// Serialize a report's date as RFC3339
+fn serialize_date<S: serde::Serializer>(date: &Option<DateTime<Local>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match date {
+        Some(date) => serializer.serialize_str(&date.to_rfc3339()),
+        None => serializer.serialize_none(),
+    }
 }
 
 #[derive(Debug)]
 pub enum ExecInfo {
     Report(ExecutionReport),
     Schedule(ExecutionSchedule),
+    /// Returned by the schedule watch when the job should not be triggered anymore
+    ScheduleEnd,
+    /// Returned by an execution that gave up before running the job's action
+    Skipped,
 }
 
 impl ExecutionReport {
+    /// The time the run took, if it was scheduled
+    pub fn duration(&self) -> Option<Duration> {
+        (self.finished_at? - self.started_at?).to_std().ok()
+    }
+
+    /// The report of an execution that was terminated by the job's timeout
+    pub fn timeout() -> Self {
+        ExecutionReport { retval: -1, timed_out: true, ..Default::default() }
+    }
+
+    /// Hide the values of the resolved secrets from the output
+    pub fn redact(&mut self) {
+        for output in [&mut self.stdout, &mut self.stderr].into_iter().flatten() {
+            *output = redact(output);
+        }
+    }
+
+    /// Merge the reports of the commands that were run out of a sequence of `total` commands.
+    ///
+    /// The output of each command is preceded by a separator line in the merged streams.
+    /// The report of a job that has a single command is returned unchanged.
+    pub fn merge(mut reports: Vec<(String, ExecutionReport)>, total: usize) -> Self {
+        if total == 1 && reports.len() == 1 {
+            return reports.pop().unwrap().1;
+        }
+        let mut merged = ExecutionReport::default();
+        for (i, (command, report)) in reports.into_iter().enumerate() {
+            let separator = format!("--- command {}/{}: {} ---\n", i + 1, total, command);
+            for (merged_output, output) in [(&mut merged.stdout, report.stdout), (&mut merged.stderr, report.stderr)] {
+                let Some(output) = output else { continue };
+                let merged_output = merged_output.get_or_insert_with(String::new);
+                merged_output.push_str(&separator);
+                merged_output.push_str(&output);
+                if !output.ends_with('\n') {
+                    merged_output.push('\n');
+                }
+            }
+            if merged.retval == 0 {
+                merged.retval = report.retval;
+            }
+            merged.timed_out |= report.timed_out;
+            merged.output_truncated |= report.output_truncated;
+            merged.commands.push(CommandReport { command, retval: report.retval });
+        }
+        merged
+    }
+
     pub async fn exhaust_stream(&mut self, stream: Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>) -> Result<(), Error> {
+        self.exhaust_stream_lines(stream, None, None, DEFAULT_MAX_OUTPUT_SIZE).await
+    }
+
+    /// Consume a container output stream, logging the streams that have a logger as they are
+    /// produced instead of storing them in the report, unless their logger keeps them.
+    ///
+    /// Up to `max_size` bytes of each of the stored streams are kept.
+    pub(crate) async fn exhaust_stream_lines(
+        &mut self,
+        mut stream: Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>,
+        mut stdout_lines: Option<OutputLines>,
+        mut stderr_lines: Option<OutputLines>,
+        max_size: usize,
+    ) -> Result<(), Error> {
         if self.stdout.is_some() || self.stderr.is_some() {
             return Err(Error::msg("The report already contains a stream's data."))
         }
-        let l: Vec<_> = stream.try_collect().await.map_err(|e| Error::new(e))?;
-        let mut stdout = String::new();
-        let mut stderr = String::new();
-        for stream in l {
-            match stream {
-                bollard::container::LogOutput::StdErr { message } => stderr += &String::from_utf8(message.into()).map_err(|e| Error::new(e))?,
-                bollard::container::LogOutput::StdOut { message } => stdout += &String::from_utf8(message.into()).map_err(|e| Error::new(e))?,
-                bollard::container::LogOutput::StdIn { message: _ } => {},
-                bollard::container::LogOutput::Console { message } => stdout += &String::from_utf8(message.into()).map_err(|e| Error::new(e))?,
+        let mut stdout = OutputBuffer::new(max_size);
+        let mut stderr = OutputBuffer::new(max_size);
+        while let Some(output) = stream.try_next().await.map_err(Error::new)? {
+            let (message, lines, kept) = match output {
+                LogOutput::StdErr { message } => (message, &mut stderr_lines, &mut stderr),
+                LogOutput::StdOut { message } | LogOutput::Console { message } => (message, &mut stdout_lines, &mut stdout),
+                LogOutput::StdIn { message: _ } => continue,
+            };
+            if let Some(lines) = lines {
+                lines.push(&message);
+            }
+            if lines.as_ref().is_none_or(OutputLines::keeps_output) {
+                kept.push(&message);
             }
         }
-        if !stdout.is_empty() {
-            self.stdout = Some(stdout);
-        }
-        if !stderr.is_empty() {
-            self.stderr = Some(stderr);
+        for lines in [stdout_lines, stderr_lines].iter_mut().flatten() {
+            lines.flush();
         }
+        self.output_truncated = stdout.is_truncated() || stderr.is_truncated();
+        // Messages are only decoded once complete, a character may be split across them
+        self.stdout = stdout.finish().map_err(Error::new)?;
+        self.stderr = stderr.finish().map_err(Error::new)?;
         Ok(())
     }
+
+    /// Get the output's lines along with the stream they should be forwarded to.
+    ///
+    /// When `merge_streams` is set, stderr lines are forwarded to stdout after the stdout lines.
+    pub fn lines(&self, merge_streams: bool) -> Vec<(StreamKind, &str)> {
+        let stderr_kind = if merge_streams { StreamKind::Stdout } else { StreamKind::Stderr };
+        let stdout = self.stdout.iter().flat_map(|s| s.lines()).map(|l| (StreamKind::Stdout, l));
+        let stderr = self.stderr.iter().flat_map(|s| s.lines()).map(|l| (stderr_kind, l));
+        stdout.chain(stderr).collect()
+    }
+
+    /// Forward the output to the writers matching its streams
+    pub fn write_output(&self, merge_streams: bool, stdout: &mut impl Write, stderr: &mut impl Write) -> std::io::Result<()> {
+        for (kind, output) in [(StreamKind::Stdout, &self.stdout), (StreamKind::Stderr, &self.stderr)] {
+            let Some(output) = output else { continue };
+            match kind {
+                StreamKind::Stderr if !merge_streams => stderr.write_all(output.as_bytes())?,
+                _ => stdout.write_all(output.as_bytes())?,
+            }
+        }
+        stdout.flush()?;
+        stderr.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use chrono::{DateTime, Datelike, FixedOffset, TimeZone, Utc};
+
+    use super::{check_secret_reference, command_argv, merge_environment, parse_duration, parse_environment_file, parse_shell, shell_operators, register_secret_backend, resolve_environment_entry, resolve_schedule_alias, resolve_secrets, run_commands, schedule_to_cron, take_schedule, validate_schedule_alias, CommandReport, ExecutionReport, JobOptions, JobTimezone, OnFailure, OverlapPolicy, SecretBackend, StreamKind};
+
+    #[test]
+    fn parse_accepted_durations() {
+        for (value, seconds) in [
+            ("90s", 90),
+            ("1h30m", 5400),
+            ("1h 30m", 5400),
+            ("1h 30m 15s", 5415),
+            (" 2m ", 120),
+            ("PT90M", 5400),
+            ("PT1H30M", 5400),
+            ("PT15S", 15),
+            ("P1DT12H", 129600),
+            ("P2D", 172800),
+            ("7d", 604800),
+            ("2w 1d", 1296000),
+            ("01:30:00", 5400),
+            ("00:00:45", 45),
+            ("36:00:00", 129600),
+        ] {
+            assert_eq!(parse_duration(value).unwrap(), Duration::from_secs(seconds), "Failed to parse '{}'", value);
+        }
+    }
+
+    #[test]
+    fn parse_rejected_durations() {
+        for value in ["", "P", "PT", "P1DT", "-5m", "1h -30m", "PT1H 30m", "1h PT30M", "5", "5x", "1:30", "01:60:00", "1.5h"] {
+            let err = parse_duration(value);
+            assert!(err.is_err(), "Parsed invalid duration '{}'", value);
+            assert!(err.unwrap_err().to_string().contains("PT1H30M"));
+        }
+    }
+
+    #[test]
+    fn every_schedule_forms() {
+        for (value, pattern) in [
+            ("@every 10s", "*/10 * * * * *"),
+            ("@every 5m", "0 */5 * * * *"),
+            ("@every PT2H", "0 0 */2 * * *"),
+            ("@every 00:15:00", "0 */15 * * * *"),
+            ("@every 1d", "0 0 0 */1 * *"),
+            ("@every 24h", "0 0 0 */1 * *"),
+            ("@every 1w", "0 0 0 */7 * *"),
+            ("@every P3D", "0 0 0 */3 * *"),
+        ] {
+            let schedule = schedule_to_cron(value).unwrap();
+            assert_eq!(schedule.source, value);
+            assert_eq!(schedule.cron().unwrap().pattern.to_string(), pattern);
+        }
+        let schedule = schedule_to_cron("@every 1h 30m").unwrap();
+        assert!(schedule.cron().is_none());
+        assert_eq!(schedule.to_string(), "@every 1h 30m");
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 1, 0, 0).unwrap();
+        assert_eq!(schedule.find_next_occurrence(&start).unwrap(), Utc.with_ymd_and_hms(2024, 1, 1, 1, 30, 0).unwrap());
+        assert!(schedule_to_cron("@every").is_err());
+        assert!(schedule_to_cron("@every 0s").is_err());
+        assert!(schedule_to_cron("@every 1h PT30M").is_err());
+        // The day-of-month step restarts every month
+        let mut weekly = schedule_to_cron("@every 7d").unwrap();
+        weekly.timezone = Some(JobTimezone::Utc);
+        let days = |from: DateTime<Utc>| (0..6).scan(from, |t, _| { *t = weekly.find_next_occurrence(&*t).unwrap(); Some(t.day()) }).collect::<Vec<_>>();
+        assert_eq!(days(Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap()), [8, 15, 22, 29, 1, 8]);
+        // Longer intervals trigger on multiples of the interval
+        let schedule = schedule_to_cron("@every 5w").unwrap();
+        assert!(schedule.cron().is_none());
+        assert_eq!(schedule.find_next_occurrence(&start).unwrap().timestamp() % (35 * 86400), 0);
+        for unsupported in ["@every 1y", "@every 2mo", "@every5d", "@everyday", "@after10m"] {
+            let e = schedule_to_cron(unsupported).unwrap_err().to_string();
+            assert!(e.contains(unsupported), "{}: {}", unsupported, e);
+        }
+        assert!(schedule_to_cron("@every 1y").unwrap_err().to_string().contains("unsupported unit 'y'"));
+        // Intervals too large to be represented fail instead of overflowing
+        for huge in ["@every 9223372036854775807s", "@every 18446744073709551615s", "@after 18446744073709551615s"] {
+            assert!(schedule_to_cron(huge).unwrap().find_next_occurrence(&start).is_err(), "{}", huge);
+        }
+
+        let schedule = schedule_to_cron("@after 5m").unwrap();
+        assert_eq!(schedule.after_completion(), Some(Duration::from_secs(300)));
+        assert_eq!(schedule.to_string(), "@after 5m");
+        assert_eq!(schedule.find_next_occurrence(&start).unwrap(), Utc.with_ymd_and_hms(2024, 1, 1, 1, 5, 0).unwrap());
+        assert!(schedule_to_cron("@every 5m").unwrap().after_completion().is_none());
+        assert!(schedule_to_cron("@after").is_err());
+        assert!(schedule_to_cron("@after 0m").is_err());
+        let mixed = take_schedule(&mut HashMap::from([("schedule".to_string(), vec!["@after 5m".to_string(), "@hourly".to_string()])]), "job");
+        assert!(mixed.unwrap_err().to_string().contains("@after"));
+    }
+
+    #[test]
+    fn schedule_timezone() {
+        let schedule = |timezone: &str| take_schedule(&mut HashMap::from([
+            ("schedule".to_string(), vec!["0 0 9 * * *".to_string()]),
+            ("timezone".to_string(), vec![timezone.to_string()]),
+        ]), "job");
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        for (timezone, expected) in [("UTC", 9), ("+02:00", 7), ("-0530", 14), ("UTC+1", 8)] {
+            let next = schedule(timezone).unwrap().find_next_occurrence(&start).unwrap();
+            assert_eq!(next, Utc.with_ymd_and_hms(2024, 1, 1, expected, if timezone == "-0530" { 30 } else { 0 }, 0).unwrap(), "in {}", timezone);
+        }
+        assert_eq!(schedule("+02:00").unwrap().timezone.unwrap().to_string().parse::<JobTimezone>().unwrap(), JobTimezone::Fixed(FixedOffset::east_opt(7200).unwrap()));
+        assert_eq!("Local".parse::<JobTimezone>().unwrap(), JobTimezone::Local);
+        assert_eq!(schedule("America/New_York").unwrap().timezone.unwrap().to_string(), "America/New_York");
+        assert!(schedule("+25:00").is_err());
+        assert!(schedule("noon").is_err());
+        assert!(schedule("Mars/Olympus_Mons").is_err());
+    }
+
+    #[test]
+    fn schedule_daylight_saving_time() {
+        let runs = |cron: &str, from: DateTime<Utc>, count: usize| {
+            let schedule = take_schedule(&mut HashMap::from([
+                ("schedule".to_string(), vec![cron.to_string()]),
+                ("timezone".to_string(), vec!["America/New_York".to_string()]),
+            ]), "job").unwrap();
+            let mut dates = vec![from];
+            for _ in 0..count {
+                dates.push(schedule.find_next_occurrence(dates.last().unwrap()).unwrap());
+            }
+            dates.split_off(1)
+        };
+        let utc = |month, day, hour, minute| Utc.with_ymd_and_hms(2024, month, day, hour, minute, 0).unwrap();
+        // On 2024-03-10, the clocks go from 02:00 EST (07:00 UTC) to 03:00 EDT
+        assert_eq!(runs("0 30 2 * * *", utc(3, 10, 5, 0), 2), [utc(3, 10, 7, 0), utc(3, 11, 6, 30)]);
+        assert_eq!(runs("0 0 9 * * *", utc(3, 9, 0, 0), 2), [utc(3, 9, 14, 0), utc(3, 10, 13, 0)]);
+        assert_eq!(runs("0 0 * * * *", utc(3, 10, 5, 0), 3), [utc(3, 10, 6, 0), utc(3, 10, 7, 0), utc(3, 10, 8, 0)]);
+        // On 2024-11-03, the clocks go from 02:00 EDT (06:00 UTC) back to 01:00 EST
+        assert_eq!(runs("0 30 1 * * *", utc(11, 3, 4, 0), 2), [utc(11, 3, 5, 30), utc(11, 4, 6, 30)]);
+        assert_eq!(runs("0 30 * * * *", utc(11, 3, 4, 0), 4), [utc(11, 3, 4, 30), utc(11, 3, 5, 30), utc(11, 3, 6, 30), utc(11, 3, 7, 30)]);
+    }
+
+    #[test]
+    fn every_jitter() {
+        let schedule = |sched: &str, job: &str| take_schedule(&mut HashMap::from([
+            ("schedule".to_string(), vec![sched.to_string()]),
+            ("jitter".to_string(), vec!["true".to_string()]),
+        ]), job).unwrap();
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let next = |sched: &str, job: &str| schedule(sched, job).find_next_occurrence(&start).unwrap();
+        // The offset is stable and stays within the interval
+        assert_eq!(next("@every 10m", "a"), next("@every 10m", "a"));
+        let jobs: Vec<_> = (0..10).map(|i| next("@every 10m", &format!("job{}", i))).collect();
+        assert!(jobs.iter().all(|n| *n > start && *n <= start + chrono::Duration::minutes(10)));
+        assert!(jobs.iter().any(|n| *n != jobs[0]), "all jobs share the same offset");
+        let first = next("@every 10m", "a");
+        let second = schedule("@every 10m", "a").find_next_occurrence(&first).unwrap();
+        assert_eq!(second - first, chrono::Duration::minutes(10));
+        // Jitter is off by default and ignored for other schedules
+        assert_eq!(schedule_to_cron("@every 10m").unwrap().find_next_occurrence(&start).unwrap(), start + chrono::Duration::minutes(10));
+        assert!(schedule("@hourly", "a").jitter);
+        assert_eq!(next("@hourly", "a"), start + chrono::Duration::hours(1));
+    }
+
+    #[test]
+    fn strict_seconds_hint() {
+        let schedule = |sched: &str, seconds: &str, strict: &str| take_schedule(&mut HashMap::from([
+            ("schedule".to_string(), vec![sched.to_string()]),
+            ("seconds".to_string(), vec![seconds.to_string()]),
+            ("strict-schedule".to_string(), vec![strict.to_string()]),
+        ]), "job");
+        assert!(schedule("*/5 * * * * *", "true", "true").is_ok());
+        assert!(schedule("*/5 * * * *", "false", "true").is_ok());
+        assert!(schedule("*/5 * * * *", "true", "true").is_err());
+        assert!(schedule("*/5 * * * * *", "false", "true").is_err());
+        assert!(schedule("*/5 * * * *", "true", "false").is_ok());
+        assert!(schedule("@hourly", "true", "true").is_ok());
+        let fields = schedule("*/5 * * * *", "false", "true").unwrap().fields().unwrap();
+        assert_eq!(fields, ["0", "*/5", "*", "*", "*", "*"]);
+        assert!(schedule_to_cron("@every 90m").unwrap().fields().is_none());
+    }
+
+    #[test]
+    fn schedule_aliases() {
+        let aliases = HashMap::from([("@nightly-weekdays".to_string(), "0 17 3 * * 1-5".to_string())]);
+        assert_eq!(resolve_schedule_alias("@nightly-weekdays", &aliases).unwrap(), "0 17 3 * * 1-5");
+        assert_eq!(resolve_schedule_alias("@every 5m", &aliases).unwrap(), "@every 5m");
+        assert_eq!(resolve_schedule_alias("0 * * * *", &aliases).unwrap(), "0 * * * *");
+        let e = resolve_schedule_alias("@nightly", &aliases).unwrap_err().to_string();
+        assert!(e.contains("@hourly") && e.contains("@nightly-weekdays"), "{}", e);
+
+        assert!(validate_schedule_alias("@nightly-weekdays", "0 17 3 * * 1-5").is_ok());
+        assert!(validate_schedule_alias("@often", "@every 5m").is_ok());
+        assert!(validate_schedule_alias("@hourly", "0 0 * * * *").is_err());
+        assert!(validate_schedule_alias("@every", "0 0 * * * *").is_err());
+        assert!(validate_schedule_alias("nightly", "0 0 * * * *").is_err());
+        assert!(validate_schedule_alias("@nightly", "@nightly-weekdays").is_err());
+        assert!(validate_schedule_alias("@nightly", "not a schedule").is_err());
+    }
+
+    #[test]
+    fn job_options_timeout() {
+        let options = JobOptions::take_from(&mut HashMap::from([
+            ("timeout".to_string(), vec!["1h30m".to_string()]),
+        ])).unwrap();
+        assert_eq!(options.timeout, Some(Duration::from_secs(5400)));
+        for timeout in ["0s", "soon"] {
+            assert!(JobOptions::take_from(&mut HashMap::from([("timeout".to_string(), vec![timeout.to_string()])])).is_err());
+        }
+    }
+
+    #[test]
+    fn job_options_group() {
+        let options = JobOptions::take_from(&mut HashMap::from([
+            ("group".to_string(), vec!["sqlite".to_string()]),
+            ("group-wait-timeout".to_string(), vec!["5m".to_string()]),
+        ])).unwrap();
+        assert_eq!(options.group.as_deref(), Some("sqlite"));
+        assert_eq!(options.group_wait_timeout, Some(Duration::from_secs(300)));
+        assert!(JobOptions::take_from(&mut HashMap::from([("group-wait-timeout".to_string(), vec!["5m".to_string()])])).is_err());
+    }
+
+    #[test]
+    fn job_options_deadline() {
+        let options = JobOptions::take_from(&mut HashMap::from([
+            ("deadline".to_string(), vec!["30s".to_string()]),
+        ])).unwrap();
+        let scheduled = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        assert!(!options.missed_deadline(&scheduled, &(scheduled + chrono::Duration::seconds(30))));
+        assert!(options.missed_deadline(&scheduled, &(scheduled + chrono::Duration::seconds(31))));
+        assert!(!JobOptions::default().missed_deadline(&scheduled, &(scheduled + chrono::Duration::days(1))));
+        assert!(JobOptions::take_from(&mut HashMap::from([("deadline".to_string(), vec!["soon".to_string()])])).is_err());
+    }
+
+    #[test]
+    fn environment_entries() {
+        let lookup = |name: &str| (name == "HOME").then(|| "/root".to_string());
+        for (entry, pass, expected) in [
+            ("FOO=bar", false, Some("FOO=bar")),
+            ("_FOO_1=a=b", false, Some("_FOO_1=a=b")),
+            ("EMPTY=", false, Some("EMPTY=")),
+            ("HOME", true, Some("HOME=/root")),
+            ("HOME", false, None),
+            ("UNSET", true, None),
+            ("=bar", false, None),
+            ("1FOO=bar", false, None),
+            ("FOO BAR=baz", false, None),
+            ("", true, None),
+        ] {
+            let resolved = resolve_environment_entry(entry, pass, lookup).ok();
+            assert_eq!(resolved.as_deref(), expected, "for entry '{}'", entry);
+        }
+    }
+
+    #[test]
+    fn resolve_secret_references() {
+        let path = std::env::temp_dir().join(format!("cfc-secret-{}", std::process::id()));
+        std::fs::write(&path, "s3cr3t-from-file\n").unwrap();
+        std::env::set_var("CFC_TEST_SECRET", "s3cr3t-from-env");
+        let environment = vec![
+            "PLAIN=secret".to_string(),
+            format!("FILE=secret://file{}", path.display()),
+            "ENV=secret://env/CFC_TEST_SECRET".to_string(),
+        ];
+        let resolved = resolve_secrets(&environment).unwrap();
+        assert_eq!(resolved, vec!["PLAIN=secret", "FILE=s3cr3t-from-file", "ENV=s3cr3t-from-env"]);
+        // The file is read again on each resolution
+        std::fs::write(&path, "rotated-s3cr3t").unwrap();
+        assert_eq!(resolve_secrets(&environment).unwrap()[1], "FILE=rotated-s3cr3t");
+        std::fs::remove_file(&path).unwrap();
+
+        // Errors mention the reference only
+        let e = resolve_secrets(&environment).unwrap_err().to_string();
+        assert!(e.contains(&format!("secret://file{}", path.display())) && !e.contains("s3cr3t"), "{}", e);
+        std::env::remove_var("CFC_TEST_SECRET");
+        let e = resolve_secrets(&environment[2..]).unwrap_err().to_string();
+        assert!(e.contains("secret://env/CFC_TEST_SECRET") && !e.contains("s3cr3t"), "{}", e);
+
+        // The resolved values are hidden from the output
+        let mut report = ExecutionReport {
+            stdout: Some("token=s3cr3t-from-env, plain secret\n".to_string()),
+            stderr: Some("rotated-s3cr3t\n".to_string()),
+            ..Default::default()
+        };
+        report.redact();
+        assert_eq!(report.stdout.as_deref(), Some("token=[REDACTED], plain secret\n"));
+        assert_eq!(report.stderr.as_deref(), Some("[REDACTED]\n"));
+
+        assert!(check_secret_reference("A=secret://vault/a").is_err());
+        assert!(check_secret_reference("A=secret://env/").is_err());
+        assert!(check_secret_reference("A=secret://env/A").is_ok());
+    }
+
+    #[test]
+    fn custom_secret_backend() {
+        struct Upper;
+        impl SecretBackend for Upper {
+            fn resolve(&self, reference: &str) -> Result<String, anyhow::Error> {
+                Ok(reference.to_uppercase())
+            }
+        }
+        assert!(check_secret_reference("A=secret://upper/abc").is_err());
+        register_secret_backend("upper", Upper);
+        assert!(check_secret_reference("A=secret://upper/abc").is_ok());
+        assert_eq!(resolve_secrets(&["A=secret://upper/custom-value".to_string()]).unwrap(), vec!["A=CUSTOM-VALUE"]);
+        assert_eq!(super::redact("got CUSTOM-VALUE"), "got [REDACTED]");
+    }
+
+    #[test]
+    fn route_output_streams() {
+        let report = ExecutionReport { retval: 0, stdout: Some("out 1\nout 2\n".to_string()), stderr: Some("err 1\n".to_string()), ..Default::default() };
+        assert_eq!(report.lines(false), vec![
+            (StreamKind::Stdout, "out 1"), (StreamKind::Stdout, "out 2"), (StreamKind::Stderr, "err 1"),
+        ]);
+        assert!(report.lines(true).iter().all(|(kind, _)| *kind == StreamKind::Stdout));
+
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        report.write_output(false, &mut stdout, &mut stderr).unwrap();
+        assert_eq!((stdout.as_slice(), stderr.as_slice()), (&b"out 1\nout 2\n"[..], &b"err 1\n"[..]));
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        report.write_output(true, &mut stdout, &mut stderr).unwrap();
+        assert_eq!((stdout.as_slice(), stderr.as_slice()), (&b"out 1\nout 2\nerr 1\n"[..], &b""[..]));
+        assert!(JobOptions::take_from(&mut HashMap::from([("merge-streams".to_string(), vec!["true".to_string()])])).unwrap().merge_streams);
+    }
+
+    #[test]
+    fn job_options_overlap_policy() {
+        let overlap = |values: &[(&str, &str)]| JobOptions::take_from(&mut values.iter()
+            .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+            .collect()).map(|o| o.overlap);
+        assert_eq!(overlap(&[]).unwrap(), OverlapPolicy::Allow);
+        assert_eq!(overlap(&[("overlap-policy", "queue")]).unwrap(), OverlapPolicy::Queue);
+        assert_eq!(overlap(&[("overlap-policy", "cancel-running")]).unwrap(), OverlapPolicy::CancelRunning);
+        assert_eq!(overlap(&[("no-overlap", "true")]).unwrap(), OverlapPolicy::Skip);
+        assert_eq!(overlap(&[("no-overlap", "false")]).unwrap(), OverlapPolicy::Allow);
+        assert_eq!(overlap(&[("no-overlap", "true"), ("overlap-policy", "skip")]).unwrap(), OverlapPolicy::Skip);
+        assert!(overlap(&[("no-overlap", "true"), ("overlap-policy", "queue")]).is_err());
+        assert!(overlap(&[("overlap-policy", "later")]).is_err());
+    }
+
+    #[test]
+    fn job_options_on_failure() {
+        let on_failure = |value: &str| JobOptions::take_from(&mut HashMap::from([("on-failure".to_string(), vec![value.to_string()])])).map(|o| o.on_failure);
+        assert_eq!(JobOptions::default().on_failure, OnFailure::Continue);
+        assert_eq!(on_failure("stop-job").unwrap(), OnFailure::StopJob);
+        assert_eq!(on_failure("stop-daemon").unwrap(), OnFailure::StopDaemon);
+        assert!(on_failure("retry").is_err());
+    }
+
+    #[test]
+    fn job_options_date_window() {
+        let options = JobOptions::take_from(&mut HashMap::from([
+            ("not-before".to_string(), vec!["2024-01-01T00:00:00Z".to_string()]),
+            ("not-after".to_string(), vec!["2024-02-01T00:00:00+01:00".to_string()]),
+        ])).unwrap();
+        assert_eq!(options.not_before.unwrap(), Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+        assert_eq!(options.not_after.unwrap(), Utc.with_ymd_and_hms(2024, 1, 31, 23, 0, 0).unwrap());
+        assert!(options.is_active_at(&Utc.with_ymd_and_hms(2024, 1, 15, 0, 0, 0).unwrap()));
+        assert!(!options.is_active_at(&Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap()));
+        assert!(JobOptions::take_from(&mut HashMap::from([
+            ("not-before".to_string(), vec!["2024-02-01T00:00:00Z".to_string()]),
+            ("not-after".to_string(), vec!["2024-01-01T00:00:00Z".to_string()]),
+        ])).is_err());
+        assert!(JobOptions::take_from(&mut HashMap::from([
+            ("not-before".to_string(), vec!["2024-02-01".to_string()]),
+        ])).is_err());
+    }
+
+    #[test]
+    fn split_commands() {
+        assert_eq!(command_argv(r#" ["echo", "a b", "$HOME"] "#).unwrap(), ["echo", "a b", "$HOME"]);
+        assert_eq!(command_argv("echo 'a b' c").unwrap(), ["echo", "a b", "c"]);
+        // Brackets that are not a JSON array are left to the shell-like split
+        assert_eq!(command_argv("[ -f /tmp/a ]").unwrap(), ["[", "-f", "/tmp/a", "]"]);
+        for invalid in ["echo 'a", "", "  ", "[]", r#"["echo", 1]"#] {
+            assert!(command_argv(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_environment_files() {
+        let content = concat!(
+            "# Database\n\nexport DB_HOST = db.local\nDB_PASSWORD='p@ss # not a comment'\n",
+            "GREETING=\"hello \\\"world\\\"\\nbye\" # comment\nEMPTY=\nPATH_LIST=/a:/b # comment\nTOKEN=secret://env/TOKEN\n",
+        );
+        assert_eq!(parse_environment_file(content).unwrap(), [
+            "DB_HOST=db.local", "DB_PASSWORD=p@ss # not a comment", "GREETING=hello \"world\"\nbye", "EMPTY=", "PATH_LIST=/a:/b", "TOKEN=secret://env/TOKEN",
+        ]);
+        for (invalid, line) in [("A=1\nNOVALUE\n", 2), ("1A=1", 1), ("A='open", 1), ("\n\nA=\"open", 3), ("A=\"x\" y", 1), ("A=secret://unknown/x", 1)] {
+            let e = parse_environment_file(invalid).unwrap_err().to_string();
+            assert!(e.starts_with(&format!("line {}:", line)), "{:?}: {}", invalid, e);
+        }
+
+        let merged = merge_environment(vec!["A=file".to_string(), "B=file".to_string()], &["B=inline".to_string(), "C=inline".to_string()]);
+        assert_eq!(merged, ["A=file", "B=inline", "C=inline"]);
+    }
+
+    #[test]
+    fn detect_shell_operators() {
+        assert_eq!(shell_operators("pg_dump db | gzip >/backup.gz"), ["|", ">/backup.gz"]);
+        assert_eq!(shell_operators("cd /app && make; echo done 2>&1"), ["&&", "make;", "2>&1"]);
+        for plain in ["echo 'a | b'", "echo a \\| b \"&&\"", "grep -e '>' file", r#"["sh", "-c", "a | b"]"#, "echo a>b", "[ -f /tmp/a ]"] {
+            assert!(shell_operators(plain).is_empty(), "{:?}", plain);
+        }
+        assert_eq!(parse_shell("true").unwrap().as_deref(), Some("/bin/sh"));
+        assert_eq!(parse_shell(" /bin/bash ").unwrap().as_deref(), Some("/bin/bash"));
+        assert_eq!(parse_shell("false").unwrap(), None);
+        assert!(parse_shell("bash").is_err());
+    }
+
+    #[tokio::test]
+    async fn run_command_sequences() {
+        let commands: Vec<String> = ["migrate", "fail", "warm"].map(String::from).to_vec();
+        let run = |command: &str| {
+            let report = ExecutionReport {
+                retval: if command == "fail" { 2 } else { 0 },
+                stdout: Some(format!("{} done", command)),
+                ..Default::default()
+            };
+            async move { Ok(report) }
+        };
+        // A single command's report is left untouched
+        let single = run_commands(&commands[..1], false, run).await.unwrap();
+        assert_eq!(single.stdout.as_deref(), Some("migrate done"));
+        assert!(single.commands.is_empty());
+
+        let stopped = run_commands(&commands, false, run).await.unwrap();
+        assert_eq!(stopped.retval, 2);
+        assert_eq!(stopped.stdout.as_deref(), Some("--- command 1/3: migrate ---\nmigrate done\n--- command 2/3: fail ---\nfail done\n"));
+        assert_eq!(stopped.stderr, None);
+        assert_eq!(stopped.commands.len(), 2);
+
+        let continued = run_commands(&commands, true, run).await.unwrap();
+        assert_eq!(continued.retval, 2);
+        assert_eq!(continued.commands, vec![
+            CommandReport { command: "migrate".to_string(), retval: 0 },
+            CommandReport { command: "fail".to_string(), retval: 2 },
+            CommandReport { command: "warm".to_string(), retval: 0 },
+        ]);
+        assert!(continued.stdout.unwrap().ends_with("--- command 3/3: warm ---\nwarm done\n"));
+        assert!(JobOptions::take_from(&mut HashMap::from([("continue-on-error".to_string(), vec!["true".to_string()])])).unwrap().continue_on_error);
+    }
+
+    #[test]
+    fn job_options_enabled() {
+        let enabled = |value: &str| JobOptions::take_from(&mut HashMap::from([("enabled".to_string(), vec![value.to_string()])])).map(|o| !o.disabled);
+        assert!(!JobOptions::default().disabled);
+        assert!(enabled("true").unwrap());
+        assert!(!enabled("false").unwrap());
+        assert!(enabled("no").is_err());
+    }
+
+    #[test]
+    fn job_options_history_size() {
+        let history_size = |value: &str| JobOptions::take_from(&mut HashMap::from([("history-size".to_string(), vec![value.to_string()])])).map(|o| o.history_size());
+        assert_eq!(JobOptions::default().history_size(), 20);
+        assert_eq!(history_size("0").unwrap(), 0);
+        assert_eq!(history_size("100").unwrap(), 100);
+        assert!(history_size("1001").is_err());
+        assert!(history_size("-1").is_err());
+    }
+
+    #[test]
+    fn job_options_max_consecutive_failures() {
+        let max = |value: &str| JobOptions::take_from(&mut HashMap::from([("max-consecutive-failures".to_string(), vec![value.to_string()])])).map(|o| o.max_consecutive_failures);
+        assert_eq!(JobOptions::default().max_consecutive_failures, None);
+        assert_eq!(max("5").unwrap(), Some(5));
+        assert!(max("0").is_err());
+        assert!(max("-1").is_err());
+    }
 }
@@ -0,0 +1,162 @@
+//! Classification of the errors that occur while running jobs
+use std::{fmt::{Display, Formatter}, io::ErrorKind, sync::atomic::{AtomicU64, Ordering}};
+
+use anyhow::Error;
+
+/// The likely cause of a job execution's failure, used to spot problems shared by many jobs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// The container engine could not be reached
+    Connection,
+    /// The container, image or command used by the job does not exist
+    NotFound,
+    /// The container engine refused the request because of the target's state
+    Conflict,
+    /// The request was not authorized
+    Permission,
+    /// The request did not complete in time
+    Timeout,
+    /// The container engine, or a proxy in front of it, failed to handle the request
+    ServerError,
+    Other,
+}
+
+impl ErrorCategory {
+    pub const ALL: [ErrorCategory; 7] = [
+        ErrorCategory::Connection,
+        ErrorCategory::NotFound,
+        ErrorCategory::Conflict,
+        ErrorCategory::Permission,
+        ErrorCategory::Timeout,
+        ErrorCategory::ServerError,
+        ErrorCategory::Other,
+    ];
+
+    /// Classify an error by looking for a container engine or I/O error in its chain of causes
+    pub fn of(error: &Error) -> Self {
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<bollard::errors::Error>() {
+                return Self::of_docker(e);
+            }
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                return Self::of_io(e);
+            }
+        }
+        ErrorCategory::Other
+    }
+
+    fn of_docker(error: &bollard::errors::Error) -> Self {
+        use bollard::errors::Error as DockerError;
+        match error {
+            DockerError::DockerResponseServerError { status_code, .. } => Self::of_status(*status_code),
+            DockerError::RequestTimeoutError => ErrorCategory::Timeout,
+            DockerError::IOError { err } => Self::of_io(err),
+            DockerError::HyperLegacyError { .. }
+            | DockerError::HttpClientError { .. }
+            | DockerError::HyperResponseError { .. }
+            | DockerError::UnsupportedURISchemeError { .. } => ErrorCategory::Connection,
+            _ => ErrorCategory::Other,
+        }
+    }
+
+    fn of_status(status_code: u16) -> Self {
+        match status_code {
+            401 | 403 => ErrorCategory::Permission,
+            404 => ErrorCategory::NotFound,
+            409 => ErrorCategory::Conflict,
+            408 | 504 => ErrorCategory::Timeout,
+            // Rate limiting proxies are reported along server failures
+            429 | 500..=599 => ErrorCategory::ServerError,
+            _ => ErrorCategory::Other,
+        }
+    }
+
+    fn of_io(error: &std::io::Error) -> Self {
+        match error.kind() {
+            ErrorKind::NotFound => ErrorCategory::NotFound,
+            ErrorKind::PermissionDenied => ErrorCategory::Permission,
+            ErrorKind::TimedOut => ErrorCategory::Timeout,
+            ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => ErrorCategory::Connection,
+            _ => ErrorCategory::Other,
+        }
+    }
+
+    fn index(self) -> usize {
+        Self::ALL.iter().position(|c| *c == self).unwrap_or_default()
+    }
+}
+
+impl Display for ErrorCategory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorCategory::Connection => write!(f, "connection"),
+            ErrorCategory::NotFound => write!(f, "not-found"),
+            ErrorCategory::Conflict => write!(f, "conflict"),
+            ErrorCategory::Permission => write!(f, "permission"),
+            ErrorCategory::Timeout => write!(f, "timeout"),
+            ErrorCategory::ServerError => write!(f, "server-error"),
+            ErrorCategory::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// The number of failed executions per category since the process started
+static ERROR_COUNTS: [AtomicU64; 7] = [const { AtomicU64::new(0) }; 7];
+
+/// Count a failed execution, returning the number of failures of that category so far
+pub fn record_error(category: ErrorCategory) -> u64 {
+    ERROR_COUNTS[category.index()].fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Get the number of failed executions of every category
+pub fn error_counts() -> Vec<(ErrorCategory, u64)> {
+    ErrorCategory::ALL.iter().map(|c| (*c, ERROR_COUNTS[c.index()].load(Ordering::Relaxed))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+    use bollard::errors::Error as DockerError;
+
+    use super::{error_counts, record_error, ErrorCategory};
+
+    fn status(status_code: u16) -> Error {
+        Error::new(DockerError::DockerResponseServerError { status_code, message: "error".to_string() })
+    }
+
+    fn io(kind: std::io::ErrorKind) -> Error {
+        Error::new(DockerError::IOError { err: std::io::Error::from(kind) })
+    }
+
+    #[test]
+    fn classify_errors() {
+        for (error, category) in [
+            (io(std::io::ErrorKind::ConnectionRefused), ErrorCategory::Connection),
+            (Error::new(DockerError::UnsupportedURISchemeError { uri: "ftp://host".to_string() }), ErrorCategory::Connection),
+            (status(404), ErrorCategory::NotFound),
+            (Error::new(std::io::Error::from(std::io::ErrorKind::NotFound)), ErrorCategory::NotFound),
+            (status(409), ErrorCategory::Conflict),
+            (status(403), ErrorCategory::Permission),
+            (io(std::io::ErrorKind::PermissionDenied), ErrorCategory::Permission),
+            (Error::new(DockerError::RequestTimeoutError), ErrorCategory::Timeout),
+            (status(504), ErrorCategory::Timeout),
+            (status(500), ErrorCategory::ServerError),
+            (status(429), ErrorCategory::ServerError),
+            (status(400), ErrorCategory::Other),
+            (Error::msg("The command of local job 'a' is empty"), ErrorCategory::Other),
+            (status(404).context("Failed to start the container"), ErrorCategory::NotFound),
+        ] {
+            assert_eq!(ErrorCategory::of(&error), category, "for {:?}", error);
+        }
+    }
+
+    #[test]
+    fn count_errors() {
+        let before = record_error(ErrorCategory::Conflict);
+        assert_eq!(record_error(ErrorCategory::Conflict), before + 1);
+        let counts = error_counts();
+        assert_eq!(counts.len(), ErrorCategory::ALL.len());
+        assert!(counts.iter().any(|(c, n)| *c == ErrorCategory::Conflict && *n > before));
+    }
+}
@@ -1,28 +1,81 @@
-use std::{collections::HashMap, fmt::{Debug, Display, Formatter}};
+use std::{collections::{BTreeMap, HashMap}, fmt::{Debug, Display, Formatter}, pin::Pin, str::FromStr, time::Duration};
 
 use anyhow::Error;
-use bollard::{exec::{CreateExecOptions, StartExecOptions, StartExecResults}, secret::ExecInspectResponse, Docker};
-use croner::Cron;
+use bollard::{container::LogOutput, exec::{CreateExecOptions, StartExecOptions, StartExecResults}, secret::ExecInspectResponse, Docker};
+use futures_util::{future::BoxFuture, stream, Stream, StreamExt};
 use tracing::{debug, warn};
 
-use crate::{job::common::{ExecInfo, ExecutionReport}, require_one, take_one};
+use crate::{job::{common::{ExecInfo, ExecutionReport, JobOptions, StreamKind}, Job}, metrics, require_one, take_one};
 
-use super::common::schedule_to_cron;
+use super::{common::{command_argv, describe_commands, merge_environment, parse_duration, parse_shell, put_all, put_one, read_environment_file, resolve_secrets, run_commands, schedule_to_cron, shell_operators, take_commands, take_environment, take_schedule, Schedule}, schema::{warn_unused_keys, KeySpec, KeyType, OUTPUT_KEYS, CONTAINER_ENVIRONMENT_KEYS}};
 
 impl ExecutionReport {
     pub fn ingest_exec_inspect(&mut self, result: &ExecInspectResponse) -> Result<(), Error> {
-        if result.running.unwrap() {
+        if result.running.unwrap_or(false) {
             return Err(Error::msg("Called Exec Inspect ingest before the command's termination"));
         }
-        self.retval = result.exit_code.unwrap();
+        self.retval = result.exit_code.ok_or_else(|| Error::msg("The container engine did not report the command's exit code"))?;
         Ok(())
     }
 }
 
+/// The output of a command started in a container
+type OutputStream = Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>;
+
+/// What to do when an exec produces no output within its job's `first-output-timeout`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StallPolicy {
+    /// Log a warning and keep waiting for the command
+    #[default]
+    Warn,
+    /// Stop waiting for the command, the execution fails
+    Kill,
+}
+
+impl FromStr for StallPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "warn" => Ok(StallPolicy::Warn),
+            "kill" => Ok(StallPolicy::Kill),
+            s => Err(Error::msg(format!("Unsupported stall policy '{}', expected warn or kill", s))),
+        }
+    }
+}
+
+impl Display for StallPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StallPolicy::Warn => write!(f, "warn"),
+            StallPolicy::Kill => write!(f, "kill"),
+        }
+    }
+}
+
+/// Tracks a command started in a container while its output is awaited.
+///
+/// The container engine offers no way to stop an exec, so an execution that is dropped
+/// before the command ended (e.g. on timeout) can only report the exec it leaves behind.
+struct PendingExec<'a> {
+    job: &'a str,
+    container: &'a str,
+    id: &'a str,
+    ended: bool,
+}
+
+impl Drop for PendingExec<'_> {
+    fn drop(&mut self) {
+        if !self.ended {
+            warn!("Abandoning exec {} of job '{}', its command may still be running in container {}", self.id, self.job, self.container);
+        }
+    }
+}
+
 /// Execute an arbitrary command on a container.
 /// This is normally instanciated as the value of the enum obtained by calling
 /// [JobInfo::try_from][`crate::job::JobInfo::try_from`] with a `kind` key set
-/// to [`ExecJobInfo::LABEL`].
+/// to [`ExecJobInfo::LABEL`], or with [`ExecJobInfo::builder`].
 /// 
 /// The container must be started when the command is executed
 /// or it will fail.
@@ -38,88 +91,204 @@ impl ExecutionReport {
 ///     let mut job = ExecJobInfo::default();
 ///     // The job's name, command, and container should be 
 ///     job.name = "Demo job".into();
-///     job.command = "echo 3".into();
+///     job.command = vec!["echo 3".into()];
 ///     job.container = "democontainer".into();
 /// 
 ///     job.exec(&handle).await.ok();
 /// }
 /// ```
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct ExecJobInfo {
     /// The display name of the job
     pub name: String,
     /// The cron schedule for the job's execution
-    pub schedule: Cron,
-    /// The command that will be executed
-    pub command: String,
-    /// The target container's ID
+    pub schedule: Schedule,
+    /// The commands that will be executed, one after the other
+    pub command: Vec<String>,
+    /// The shell that runs the commands, which are split in arguments and run directly otherwise
+    pub shell: Option<String>,
+    /// The target container's name or ID, a name is resolved to the current container at each execution
     pub container: String,
     /// The user used to execute the command
     pub user: Option<String>,
+    /// The directory the command is executed in, the container's working directory by default
+    pub working_dir: Option<String>,
     /// Whether a tty should be provisionned for the command's execution
     pub tty: bool,
     /// The additional environment variables to set when executing the command
     pub environment: Vec<String>,
+    /// The dotenv file read at each execution for more environment variables, overridden by `environment`
+    pub environment_file: Option<String>,
+    /// How long the command may run without producing output before it is considered stalled
+    pub first_output_timeout: Option<Duration>,
+    /// What to do when the command is stalled
+    pub on_stall: StallPolicy,
+    /// The scheduling options shared by all job kinds
+    pub options: JobOptions,
 }
 
 impl TryFrom<HashMap<String, Vec<String>>> for ExecJobInfo {
     type Error = Error;
 
     fn try_from(mut value: HashMap<String, Vec<String>>) -> Result<Self, Self::Error> {
+        let job = Self::take_from(&mut value)?;
+        warn_unused_keys(Self::LABEL, &job.name, &value);
+        Ok(job)
+    }
+}
+
+impl ExecJobInfo {
+    pub const LABEL: &str = "job-exec";
+    /// The keys accepted by exec jobs, besides the ones shared by all kinds
+    pub const KEYS: &'static [KeySpec] = &[
+        KeySpec::new("command", KeyType::List, "touch /tmp/cfc").required(),
+        KeySpec::new("shell", KeyType::String, "/bin/bash").default("false"),
+        KeySpec::new("container", KeyType::String, "my-container").required(),
+        KeySpec::new("user", KeyType::String, "nobody"),
+        KeySpec::new("working-dir", KeyType::String, "/app"),
+        KeySpec::new("tty", KeyType::Bool, "true").default("false"),
+        CONTAINER_ENVIRONMENT_KEYS[0],
+        CONTAINER_ENVIRONMENT_KEYS[1],
+        // Reading a file of cfc's host could expose its content to whoever can set labels
+        KeySpec::new("environment-file", KeyType::String, "/etc/cfc/backup.env").unsafe_from_labels(),
+        OUTPUT_KEYS[0],
+        OUTPUT_KEYS[1],
+        OUTPUT_KEYS[2],
+        OUTPUT_KEYS[3],
+        KeySpec::new("first-output-timeout", KeyType::Duration, "5m"),
+        KeySpec::new("on-stall", KeyType::String, "kill").default("warn"),
+    ];
+
+    /// Build a job from the keys of its parameter map, leaving the keys it does not use
+    pub(crate) fn take_from(value: &mut HashMap<String, Vec<String>>) -> Result<Self, Error> {
+        let name = require_one!(value, "name").unwrap_or_else(|_| "".to_string());
+        let environment = take_environment(value, &name)?;
         let job = ExecJobInfo {
-            name: require_one!(value, "name").unwrap_or_else(|_| "".to_string()),
-            schedule: schedule_to_cron(&require_one!(value, "schedule")?.as_str())?,
-            command: require_one!(value, "command")?,
+            schedule: take_schedule(value, &name)?,
+            name,
+            command: take_commands(value, true)?,
+            shell: take_one!(value, "shell")?.map(|s| parse_shell(&s)).transpose()?.flatten(),
             container: require_one!(value, "container")?,
             user: take_one!(value, "user")?,
-            tty: take_one!(value, "tty")?.map_or(Ok(false), |t| t.parse().map_err(|e| Error::new(e)))?,
-            environment: value.remove("environment").unwrap_or(Default::default()),
+            working_dir: take_one!(value, "working-dir")?,
+            tty: take_one!(value, "tty")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?,
+            environment,
+            environment_file: take_one!(value, "environment-file")?,
+            first_output_timeout: take_one!(value, "first-output-timeout")?.map(|t| parse_duration(&t)).transpose()?,
+            on_stall: take_one!(value, "on-stall")?.map(|p| p.parse()).transpose()?.unwrap_or_default(),
+            options: JobOptions::take_from(value)?.take_output_options(value)?,
         };
-        if !value.is_empty() {
-            warn!("The job key map has excess attributes that will not be used: {:?}", value.keys());
+        if job.on_stall != StallPolicy::Warn && job.first_output_timeout.is_none() {
+            return Err(Error::msg("The job key on-stall requires a first-output-timeout"));
+        }
+        for command in &job.command {
+            match &job.shell {
+                Some(_) if serde_json::from_str::<Vec<String>>(command.trim()).is_ok() => {
+                    return Err(Error::msg(format!("The command {} is an array of arguments, which can not be run in a shell", command)));
+                },
+                Some(_) => {},
+                None => {
+                    let operators = shell_operators(command);
+                    if !operators.is_empty() {
+                        warn!(
+                            "The command '{}' of job '{}' contains shell operators ({}) that are passed to it as arguments, set shell = true to run it in a shell",
+                            command, job.name, operators.join(" "),
+                        );
+                    }
+                },
+            }
         }
         Ok(job)
     }
-}
 
-impl ExecJobInfo {
-    pub const LABEL: &'static str = "job-exec";
+    pub async fn exec(mut self, handle: &Docker) -> Result<ExecInfo, Error> {
+        debug!("Executing job '{}': {}", self.name, self.describe_action());
+        if let Some(path) = self.environment_file.as_ref() {
+            match read_environment_file(path) {
+                Ok(entries) => self.environment = merge_environment(entries, &self.environment),
+                Err(e) => {
+                    warn!("{}, the execution of job '{}' fails", e, self.name);
+                    return Ok(ExecInfo::Report(ExecutionReport { retval: -1, stderr: Some(format!("{}\n", e)), ..Default::default() }));
+                },
+            }
+        }
+        self.environment = resolve_secrets(&self.environment)?;
+        let container = match self.resolve_container(handle).await {
+            Ok(container) => container,
+            Err(reason) => {
+                warn!("{}, the execution of job '{}' fails", reason, self.name);
+                return Ok(ExecInfo::Report(ExecutionReport { retval: -1, stderr: Some(format!("{}\n", reason)), ..Default::default() }));
+            },
+        };
+        run_commands(&self.command, self.options.continue_on_error, |command| self.exec_command(handle, &container, command)).await
+            .map(ExecInfo::Report)
+    }
 
-    pub async fn exec(self, handle: &Docker) -> Result<ExecInfo, Error> {
-        debug!("Executing job '{}' on container {} ({})", self.name, self.container, self.command);
+    /// Find the current id of the job's container, which may have been recreated under the same
+    /// name since the job was loaded. The container is used as configured if it can not be
+    /// inspected, and the reason why the commands can not be executed is returned when it does
+    /// not exist or is not running.
+    async fn resolve_container(&self, handle: &Docker) -> Result<String, String> {
+        match handle.inspect_container(&self.container, None).await {
+            Ok(container) if container.state.as_ref().and_then(|s| s.running) == Some(false) => {
+                Err(format!("The container {} is not running", self.container))
+            },
+            Ok(container) => Ok(container.id.unwrap_or_else(|| self.container.clone())),
+            Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => {
+                Err(format!("The container {} does not exist", self.container))
+            },
+            Err(e) => {
+                debug!("Failed to inspect the container {} of job '{}', using it as is: {}", self.container, self.name, e);
+                Ok(self.container.clone())
+            },
+        }
+    }
+
+    /// Execute one of the job's commands in a container
+    async fn exec_command(&self, handle: &Docker, container: &str, command: &str) -> Result<ExecutionReport, Error> {
+        let argv = match &self.shell {
+            Some(shell) => vec![shell.clone(), "-c".to_string(), command.to_string()],
+            None => command_argv(command)
+                .map_err(|e| Error::msg(format!("Failed to parse the command of exec job '{}': {}", self.name, e)))?,
+        };
         let opts = CreateExecOptions {
             tty: Some(self.tty),
             attach_stdout: Some(true),
             attach_stderr: Some(true),
-            env: Some(self.environment),
-            cmd: Some(shell_words::split(self.command.as_ref()).unwrap()),
-            user: self.user,
+            env: Some(self.environment.clone()),
+            cmd: Some(argv),
+            user: self.user.clone(),
+            working_dir: self.working_dir.clone(),
             ..Default::default()
         };
-        let create_result;
-        match handle.create_exec(&self.container, opts).await {
-            Ok(c) => create_result = c,
+        let create_result = match handle.create_exec(container, opts).await {
+            Ok(c) => c,
             Err(e) => return Err(e.into())
-        }
+        };
         let opts = StartExecOptions {
             detach: false,
             tty: self.tty,
             output_capacity: None,
         };
+        let mut pending = PendingExec { job: &self.name, container: &self.container, id: &create_result.id, ended: false };
         let ostream;
         match handle.start_exec(&create_result.id, Some(opts)).await {
             Ok(r) => match r {
                 StartExecResults::Attached { output, input: _ } => {
                     ostream = output;
                 },
-                StartExecResults::Detached => panic!("Spawned a detached exec process, this should never happen."),
+                StartExecResults::Detached => return Err(Error::msg("Spawned a detached exec process, this should never happen.")),
             },
             Err(e) => { return Err(e.into()); },
         };
         let mut report = ExecutionReport::default();
-        if let Err(e) = report.exhaust_stream(ostream).await {
-            return Err(e.into());
-        }
+        let Some(ostream) = self.watch_first_output(ostream, &mut report).await else {
+            // The exec is left behind, which is reported when `pending` is dropped
+            return Ok(report);
+        };
+        let (stdout_lines, stderr_lines) = (self.options.output_lines(&self.name, StreamKind::Stdout), self.options.output_lines(&self.name, StreamKind::Stderr));
+        report.exhaust_stream_lines(ostream, stdout_lines, stderr_lines, self.options.max_output_size()).await?;
+        pending.ended = true;
         match handle.inspect_exec(&create_result.id).await {
             Ok(i) => {
                 report.ingest_exec_inspect(&i)?;
@@ -127,13 +296,87 @@ impl ExecJobInfo {
             },
             Err(e) => return Err(e.into()),
         }
-        Ok(ExecInfo::Report(report))
+        Ok(report)
+    }
+
+    /// Wait for the first output of a command if the job limits it, marking the report as
+    /// stalled when the command produced nothing in time.
+    ///
+    /// The returned stream yields the whole output, or is `None` when the job gave up on the command.
+    async fn watch_first_output(&self, mut output: OutputStream, report: &mut ExecutionReport) -> Option<OutputStream> {
+        let window = self.first_output_timeout?;
+        match tokio::time::timeout(window, output.next()).await {
+            Ok(Some(first)) => Some(stream::iter([first]).chain(output).boxed()),
+            // The command ended without any output
+            Ok(None) => Some(stream::empty().boxed()),
+            Err(_) => {
+                metrics::record_stall(&self.name);
+                report.stalled = true;
+                match self.on_stall {
+                    StallPolicy::Warn => {
+                        warn!("The command of job '{}' produced no output in container {} after {}s, it may be stalled", self.name, self.container, window.as_secs());
+                        Some(output)
+                    },
+                    StallPolicy::Kill => {
+                        warn!("The command of job '{}' produced no output in container {} after {}s, giving up on it", self.name, self.container, window.as_secs());
+                        report.retval = -1;
+                        report.stderr = Some(format!("The command produced no output after {}s and was abandoned\n", window.as_secs()));
+                        None
+                    },
+                }
+            },
+        }
+    }
+}
+
+impl Job for ExecJobInfo {
+    fn name(&self) -> &String {
+        &self.name
+    }
+    fn kind(&self) -> &str {
+        Self::LABEL
+    }
+    fn options(&self) -> &JobOptions {
+        &self.options
     }
-    pub fn get_schedule(&self) -> Cron {
-        self.schedule.clone()
+    fn options_mut(&mut self) -> &mut JobOptions {
+        &mut self.options
     }
-    pub fn may_run_parallel(&self) -> bool {
-        true
+    fn commands(&self) -> &[String] {
+        &self.command
+    }
+    fn exec(&self, handle: &Docker) -> BoxFuture<'static, Result<ExecInfo, Error>> {
+        let (job, handle) = (self.clone(), handle.clone());
+        Box::pin(async move { ExecJobInfo::exec(job, &handle).await })
+    }
+    fn clone_job(&self) -> Box<dyn Job> {
+        Box::new(self.clone())
+    }
+    fn to_parameters(&self) -> BTreeMap<String, Vec<String>> {
+        let mut map = BTreeMap::new();
+        put_one(&mut map, "name", Some(&self.name));
+        self.schedule.put_into(&mut map);
+        put_all(&mut map, "command", &self.command);
+        put_one(&mut map, "shell", self.shell.as_ref());
+        put_one(&mut map, "container", Some(&self.container));
+        put_one(&mut map, "user", self.user.as_ref());
+        put_one(&mut map, "working-dir", self.working_dir.as_ref());
+        put_one(&mut map, "tty", Some(self.tty));
+        put_all(&mut map, "environment", &self.environment);
+        put_one(&mut map, "environment-file", self.environment_file.as_ref());
+        put_one(&mut map, "first-output-timeout", self.first_output_timeout.map(|d| format!("{}s", d.as_secs())));
+        put_one(&mut map, "on-stall", (self.on_stall != StallPolicy::Warn).then_some(self.on_stall));
+        self.options.put_into(&mut map);
+        map
+    }
+    fn describe_action(&self) -> String {
+        match &self.shell {
+            Some(shell) => format!("exec {} with {} in {}", describe_commands(&self.command), shell, self.container),
+            None => format!("exec {} in {}", describe_commands(&self.command), self.container),
+        }
+    }
+    fn schedule(&self) -> &Schedule {
+        &self.schedule
     }
 }
 
@@ -141,12 +384,18 @@ impl Default for ExecJobInfo {
     fn default() -> Self {
         Self {
             name: Default::default(),
-            schedule: Cron::new("@hourly").parse().unwrap(),
+            schedule: schedule_to_cron("@hourly").unwrap(),
             command: Default::default(),
+            shell: None,
             container: Default::default(),
             user: None,
+            working_dir: None,
             tty: false,
             environment: Default::default(),
+            environment_file: None,
+            first_output_timeout: None,
+            on_stall: StallPolicy::Warn,
+            options: Default::default(),
         }
     }
 }
@@ -159,7 +408,11 @@ impl Display for ExecJobInfo {
             Self::LABEL,
             self.name,
             self.container,
-        )
+        )?;
+        match &self.working_dir {
+            Some(dir) => write!(fmt, ":{}", dir),
+            None => Ok(()),
+        }
     }
 }
 
@@ -167,21 +420,39 @@ impl Debug for ExecJobInfo {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ExecJobInfo")
             .field("name", &self.name)
-            .field("schedule", &self.schedule.pattern.to_string())
+            .field("schedule", &self.schedule)
             .field("command", &self.command)
+            .field("shell", &self.shell)
             .field("container", &self.container)
             .field("user", &self.user)
+            .field("working_dir", &self.working_dir)
             .field("tty", &self.tty)
             .field("environment", &self.environment)
+            .field("environment_file", &self.environment_file)
+            .field("first_output_timeout", &self.first_output_timeout)
+            .field("on_stall", &self.on_stall)
+            .field("options", &self.options)
             .finish()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::{collections::HashMap, time::Duration};
 
-    use super::ExecJobInfo;
+    use bollard::container::LogOutput;
+    use futures_util::{stream, StreamExt};
+
+    use super::{ExecJobInfo, OutputStream, StallPolicy};
+    use crate::job::{ExecutionReport, Job};
+
+    /// An exec output that produces its only message after a delay
+    fn delayed_output(delay: Duration) -> OutputStream {
+        stream::once(async move {
+            tokio::time::sleep(delay).await;
+            Ok(LogOutput::StdOut { message: "late\n".into() })
+        }).boxed()
+    }
 
     #[test]
     fn create_exec_job_instance() {
@@ -205,6 +476,110 @@ mod tests {
         assert!(job.is_err());
     }
 
+    #[test]
+    fn create_exec_job_instance_zero_max_runs() {
+        let job = ExecJobInfo::try_from(HashMap::from([
+            ("name".into(), vec!["test_job".into()]),
+            ("container".into(), vec!["test_container".into()]),
+            ("schedule".into(), vec!["@hourly".into()]),
+            ("command".into(), vec!["echo".into()]),
+            ("max-runs".into(), vec!["0".into()]),
+        ]));
+        assert!(job.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_first_output() {
+        let job = ExecJobInfo { name: "wedged".into(), first_output_timeout: Some(Duration::from_secs(60)), ..Default::default() };
+
+        // Warned stalls keep waiting for the whole output
+        let mut report = ExecutionReport::default();
+        let output = job.watch_first_output(delayed_output(Duration::from_secs(90)), &mut report).await.unwrap();
+        assert!(report.stalled);
+        report.exhaust_stream(output).await.unwrap();
+        assert_eq!((report.retval, report.stdout.as_deref()), (0, Some("late\n")));
+
+        // The output is unchanged when it arrives in time
+        let mut report = ExecutionReport::default();
+        let output = job.watch_first_output(delayed_output(Duration::from_secs(30)), &mut report).await.unwrap();
+        report.exhaust_stream(output).await.unwrap();
+        assert!(!report.stalled);
+        assert_eq!(report.stdout.as_deref(), Some("late\n"));
+
+        // Killed stalls give up on the command
+        let job = ExecJobInfo { on_stall: StallPolicy::Kill, ..job };
+        let mut report = ExecutionReport::default();
+        let start = tokio::time::Instant::now();
+        assert!(job.watch_first_output(delayed_output(Duration::from_secs(3600)), &mut report).await.is_none());
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+        assert!(report.stalled);
+        assert_eq!(report.retval, -1);
+    }
+
+    #[test]
+    fn create_exec_job_instance_on_stall() {
+        let mut parameters = HashMap::from([
+            ("name".into(), vec!["test_job".into()]),
+            ("container".into(), vec!["test_container".into()]),
+            ("schedule".into(), vec!["@hourly".into()]),
+            ("command".into(), vec!["echo".into()]),
+            ("on-stall".into(), vec!["kill".into()]),
+        ]);
+        assert!(ExecJobInfo::try_from(parameters.clone()).is_err());
+        parameters.insert("first-output-timeout".into(), vec!["5m".into()]);
+        let job = ExecJobInfo::try_from(parameters).unwrap();
+        assert_eq!((job.first_output_timeout, job.on_stall), (Some(Duration::from_secs(300)), StallPolicy::Kill));
+    }
+
+    #[test]
+    fn create_exec_job_instance_working_dir() {
+        let mut parameters = HashMap::from([
+            ("name".into(), vec!["test_job".into()]),
+            ("container".into(), vec!["test_container".into()]),
+            ("schedule".into(), vec!["@hourly".into()]),
+            ("command".into(), vec!["ls".into()]),
+        ]);
+        assert_eq!(ExecJobInfo::try_from(parameters.clone()).unwrap().to_string(), "job-exec.test_job.test_container");
+        parameters.insert("working-dir".into(), vec!["/app".into()]);
+        let job = ExecJobInfo::try_from(parameters).unwrap();
+        assert_eq!(job.working_dir.as_deref(), Some("/app"));
+        assert_eq!(job.to_string(), "job-exec.test_job.test_container:/app");
+        assert!(format!("{:?}", job).contains("working_dir: Some(\"/app\")"));
+        assert_eq!(job.to_parameters()["working-dir"], ["/app"]);
+    }
+
+    #[test]
+    fn create_exec_job_instance_shell() {
+        let parameters = |shell: &str, command: &str| HashMap::from([
+            ("name".into(), vec!["test_job".into()]),
+            ("container".into(), vec!["test_container".into()]),
+            ("schedule".into(), vec!["@hourly".into()]),
+            ("command".into(), vec![command.into()]),
+            ("shell".into(), vec![shell.into()]),
+        ]);
+        let job = ExecJobInfo::try_from(parameters("true", "pg_dump db | gzip > /backup.gz")).unwrap();
+        assert_eq!(job.shell.as_deref(), Some("/bin/sh"));
+        assert_eq!(job.describe_action(), "exec 'pg_dump db | gzip > /backup.gz' with /bin/sh in test_container");
+        assert_eq!(ExecJobInfo::try_from(parameters("/bin/bash", "echo")).unwrap().to_parameters()["shell"], ["/bin/bash"]);
+        assert_eq!(ExecJobInfo::try_from(parameters("false", "echo")).unwrap().shell, None);
+        assert!(ExecJobInfo::try_from(parameters("bash", "echo")).is_err());
+        assert!(ExecJobInfo::try_from(parameters("true", r#"["echo", "a"]"#)).is_err());
+    }
+
+    #[test]
+    fn create_exec_job_instance_environment_file() {
+        let job = ExecJobInfo::try_from(HashMap::from([
+            ("name".into(), vec!["test_job".into()]),
+            ("container".into(), vec!["test_container".into()]),
+            ("schedule".into(), vec!["@hourly".into()]),
+            ("command".into(), vec!["env".into()]),
+            ("environment-file".into(), vec!["/etc/cfc/backup.env".into()]),
+        ])).unwrap();
+        assert_eq!(job.environment_file.as_deref(), Some("/etc/cfc/backup.env"));
+        assert_eq!(job.to_parameters()["environment-file"], ["/etc/cfc/backup.env"]);
+        assert!(crate::job::find_key(ExecJobInfo::LABEL, "environment-file").is_some_and(|k| k.unsafe_from_labels));
+    }
+
     #[test]
     fn create_exec_job_instance_no_container() {
         let job = ExecJobInfo::try_from(HashMap::from([
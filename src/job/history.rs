@@ -0,0 +1,137 @@
+//! Bounded history of the executions of a job
+//!
+//! The history is kept in memory only: it holds the last reports of each job so that
+//! the previous runs can be inspected after a failure without scraping the logs.
+use std::{collections::VecDeque, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::Duration};
+
+use chrono::{DateTime, Local};
+
+use crate::report::{tail, OUTPUT_TAIL_LENGTH};
+
+use super::ExecutionReport;
+
+/// The number of executions kept for the jobs that do not set `history-size`
+pub const DEFAULT_HISTORY_SIZE: usize = 20;
+
+/// The maximum value of `history-size`
+pub const MAX_HISTORY_SIZE: usize = 1000;
+
+/// A completed execution of a job
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    /// The time at which the execution started
+    pub started_at: DateTime<Local>,
+    pub duration: Duration,
+    /// Whether the execution ended with a zero exit code and did not time out
+    pub success: bool,
+    /// The execution's report, with the output streams reduced to their tail
+    pub report: ExecutionReport,
+}
+
+/// A ring buffer of the last executions of a job, shared between the job's scheduler
+/// that records them and the readers that render them
+#[derive(Clone, Debug)]
+pub struct ExecutionHistory {
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<HistoryEntry>>>,
+    /// The number of executions that failed since the last successful one, even if they are not kept
+    consecutive_failures: Arc<AtomicU64>,
+}
+
+impl ExecutionHistory {
+    /// Create a history that keeps the last `capacity` executions, nothing is kept if it is 0
+    pub fn new(capacity: usize) -> Self {
+        ExecutionHistory { capacity, entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))), consecutive_failures: Arc::default() }
+    }
+
+    /// The maximum number of executions kept
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of executions that failed in a row since the last successful one
+    pub fn consecutive_failures(&self) -> u64 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
+
+    /// Record a completed execution, dropping the oldest one if the history is full.
+    ///
+    /// Returns the number of executions that failed in a row, this one included, which is also
+    /// set on the recorded report.
+    pub fn record(&self, started_at: DateTime<Local>, duration: Duration, mut report: ExecutionReport) -> u64 {
+        let success = report.retval == 0 && !report.timed_out;
+        report.consecutive_failures = match success {
+            true => {
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                0
+            },
+            false => self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1,
+        };
+        let consecutive_failures = report.consecutive_failures;
+        if self.capacity == 0 {
+            return consecutive_failures;
+        }
+        // Keep the memory used by each entry bounded, whatever the size of the job's output
+        tail(&mut report.stdout, OUTPUT_TAIL_LENGTH);
+        tail(&mut report.stderr, OUTPUT_TAIL_LENGTH);
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(HistoryEntry { started_at, duration, success, report });
+        consecutive_failures
+    }
+
+    /// Get the recorded executions, from the oldest to the most recent
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+    }
+}
+
+impl Default for ExecutionHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Local;
+
+    use crate::{job::ExecutionReport, report::OUTPUT_TAIL_LENGTH};
+
+    use super::ExecutionHistory;
+
+    #[test]
+    fn history_is_bounded() {
+        let history = ExecutionHistory::new(3);
+        for retval in 0..10 {
+            let report = ExecutionReport { retval, stdout: Some("x".repeat(1 << 20)), ..Default::default() };
+            history.record(Local::now(), Duration::from_secs(1), report);
+        }
+        let entries = history.entries();
+        assert_eq!(entries.iter().map(|e| e.report.retval).collect::<Vec<_>>(), vec![7, 8, 9]);
+        assert!(entries.iter().all(|e| !e.success && e.report.stdout.as_ref().unwrap().len() == OUTPUT_TAIL_LENGTH));
+        history.record(Local::now(), Duration::ZERO, ExecutionReport::default());
+        assert!(history.entries().last().unwrap().success);
+
+        let disabled = ExecutionHistory::new(0);
+        disabled.record(Local::now(), Duration::ZERO, ExecutionReport::default());
+        assert!(disabled.entries().is_empty());
+    }
+
+    #[test]
+    fn count_consecutive_failures() {
+        // The count goes beyond the capacity and is reset by a success
+        let history = ExecutionHistory::new(2);
+        let failed = || ExecutionReport { retval: 1, ..Default::default() };
+        assert_eq!((0..3).map(|_| history.record(Local::now(), Duration::ZERO, failed())).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(history.entries().iter().map(|e| e.report.consecutive_failures).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(history.consecutive_failures(), 3);
+        assert_eq!(history.record(Local::now(), Duration::ZERO, ExecutionReport::timeout()), 4);
+        assert_eq!(history.record(Local::now(), Duration::ZERO, ExecutionReport::default()), 0);
+        assert_eq!(history.record(Local::now(), Duration::ZERO, failed()), 1);
+    }
+}
@@ -0,0 +1,130 @@
+//! Commands run on the host once an execution of a job ended
+//!
+//! A job's `on-success` and `on-error` hooks are run like the command of a local job, in the
+//! background so that they never delay the job's next executions. Their failures are logged
+//! and never retried.
+use std::{path::PathBuf, sync::{atomic::{AtomicU64, Ordering}, LazyLock}, time::Duration};
+
+use anyhow::{Error, Result};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use super::{common::command_argv, ExecInfo, ExecutionReport};
+
+/// The variable set to the name of the job whose execution ended
+pub const JOB_NAME_VARIABLE: &str = "CFC_JOB_NAME";
+/// The variable set to the exit code of the execution, left unset when the job could not be run
+pub const EXIT_CODE_VARIABLE: &str = "CFC_EXIT_CODE";
+/// The variable set to the path of a file holding the execution's stdout
+pub const STDOUT_FILE_VARIABLE: &str = "CFC_STDOUT_FILE";
+
+/// How long the daemon waits for the running hooks to end when it exits
+pub const HOOK_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Distinguishes the output files of the hooks that run at the same time
+static OUTPUT_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// The number of hooks running
+static PENDING: LazyLock<watch::Sender<usize>> = LazyLock::new(|| watch::channel(0).0);
+
+/// Wait up to `timeout` for the running hooks to end, so that they are not interrupted
+/// when the daemon exits
+pub async fn flush_hooks(timeout: Duration) {
+    let mut pending = PENDING.subscribe();
+    if tokio::time::timeout(timeout, pending.wait_for(|n| *n == 0)).await.is_err() {
+        warn!("Exiting with {} hooks running", *pending.borrow());
+    }
+}
+
+/// Check that a hook command can be run
+pub(crate) fn parse_hook(key: &str, command: &str) -> Result<Vec<String>> {
+    command_argv(command).map_err(|e| Error::msg(format!("Failed to parse the job's {} command: {}", key, e)))
+}
+
+/// The output file of a hook, removed once the hook ended
+struct OutputFile(PathBuf);
+
+impl OutputFile {
+    fn create(content: &str) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("cfc-hook-{}-{}.stdout", std::process::id(), OUTPUT_FILES.fetch_add(1, Ordering::Relaxed)));
+        std::fs::write(&path, content).map_err(|e| Error::msg(format!("Failed to write the output file {}: {}", path.display(), e)))?;
+        Ok(OutputFile(path))
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Run a hook, describing the execution that ended in its environment
+pub(crate) async fn run_hook(job: &str, command: &str, report: Option<&ExecutionReport>) -> Result<ExecutionReport> {
+    let argv = parse_hook("hook", command)?;
+    let output_file = OutputFile::create(report.and_then(|r| r.stdout.as_deref()).unwrap_or_default())?;
+    let mut hook = tokio::process::Command::new(&argv[0]);
+    hook.args(&argv[1..])
+        .env(JOB_NAME_VARIABLE, job)
+        .env(STDOUT_FILE_VARIABLE, &output_file.0)
+        .stdin(std::process::Stdio::null());
+    if let Some(report) = report {
+        hook.env(EXIT_CODE_VARIABLE, report.retval.to_string());
+    }
+    let output = hook.output().await.map_err(|e| Error::msg(format!("Failed to start '{}': {}", command, e)))?;
+    Ok(ExecutionReport {
+        retval: output.status.code().unwrap_or(-1).into(),
+        stdout: Some(String::from_utf8_lossy(&output.stdout).into_owned()).filter(|s| !s.is_empty()),
+        stderr: Some(String::from_utf8_lossy(&output.stderr).into_owned()).filter(|s| !s.is_empty()),
+        ..Default::default()
+    })
+}
+
+/// Run the hook matching the result of an execution in the background, if the job has one.
+///
+/// The hook is awaited by [`flush_hooks`].
+pub(crate) fn spawn_hook(job: &str, on_success: Option<&String>, on_error: Option<&String>, result: &Result<ExecInfo, Error>) {
+    let (kind, command, report) = match result {
+        Ok(ExecInfo::Report(r)) if r.retval == 0 && !r.timed_out => ("on-success", on_success, Some(r)),
+        Ok(ExecInfo::Report(r)) => ("on-error", on_error, Some(r)),
+        Ok(_) => return,
+        Err(_) => ("on-error", on_error, None),
+    };
+    let Some(command) = command else { return };
+    let (job, command, report) = (job.to_string(), command.clone(), report.cloned());
+    debug!("Running the {} hook of job {}: {}", kind, job, command);
+    PENDING.send_modify(|n| *n += 1);
+    tokio::spawn(async move {
+        match run_hook(&job, &command, report.as_ref()).await {
+            Ok(r) if r.retval == 0 => info!("The {} hook of job {} ended successfully", kind, job),
+            Ok(r) => match r.stderr {
+                Some(stderr) => warn!("The {} hook of job {} failed with exit code {}: {}", kind, job, r.retval, stderr.trim_end()),
+                None => warn!("The {} hook of job {} failed with exit code {}", kind, job, r.retval),
+            },
+            Err(e) => warn!("The {} hook of job {} could not be run: {}", kind, job, e),
+        }
+        PENDING.send_modify(|n| *n -= 1);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_hook, run_hook};
+    use crate::job::ExecutionReport;
+
+    #[tokio::test]
+    async fn hook_environment() {
+        let report = ExecutionReport { retval: 3, stdout: Some("done\n".to_string()), ..Default::default() };
+        let hook = run_hook("backup", "sh -c 'echo \"$CFC_JOB_NAME $CFC_EXIT_CODE $(cat $CFC_STDOUT_FILE)\"; echo $CFC_STDOUT_FILE >&2'", Some(&report)).await.unwrap();
+        assert_eq!(hook.retval, 0);
+        assert_eq!(hook.stdout.as_deref(), Some("backup 3 done\n"));
+        // The output file only lives as long as the hook
+        assert!(!std::path::Path::new(hook.stderr.unwrap().trim_end()).exists());
+
+        // Jobs that could not be run have no exit code
+        let hook = run_hook("backup", "sh -c 'echo ${CFC_EXIT_CODE-unset}; exit 2'", None).await.unwrap();
+        assert_eq!((hook.retval, hook.stdout.as_deref()), (2, Some("unset\n")));
+        assert!(run_hook("backup", "/nonexistent", None).await.is_err());
+        assert!(parse_hook("on-success", "echo 'unterminated").is_err());
+        assert!(parse_hook("on-success", " ").is_err());
+    }
+}
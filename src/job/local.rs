@@ -1,89 +1,195 @@
-use std::{collections::HashMap, fmt::{Debug, Display, Formatter}};
+use std::{collections::{BTreeMap, HashMap}, fmt::{Debug, Display, Formatter}, process::Stdio};
 
 use anyhow::Error;
 use bollard::Docker;
-use croner::Cron;
-use tracing::{debug, error, info, warn};
+use futures_util::future::BoxFuture;
+use tracing::{error, info, warn};
 
 use crate::{require_one, take_one};
 
-use super::common::{schedule_to_cron, ExecInfo, ExecutionReport};
+use super::{common::{check_secret_reference, command_argv, describe_commands, put_all, put_one, resolve_secrets, run_commands, take_commands, take_schedule, Schedule, ExecInfo, ExecutionReport, JobOptions, StreamKind}, output::read_output, sandbox::{unit_name, Sandbox, SandboxKind, UnitGuard}, schema::{warn_unused_keys, KeySpec, KeyType, OUTPUT_KEYS}, Job};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct LocalJobInfo {
     pub name: String,
-    pub schedule: Cron,
-    pub command: String,
+    pub schedule: Schedule,
+    /// The commands to run, one after the other
+    pub command: Vec<String>,
     pub dir: Option<String>,
     pub environment: Vec<String>,
+    pub sandbox: Sandbox,
+    pub options: JobOptions,
 }
 
 impl TryFrom<HashMap<String, Vec<String>>> for LocalJobInfo {
     type Error = Error;
 
     fn try_from(mut value: HashMap<String, Vec<String>>) -> Result<Self, Self::Error> {
-        let job = LocalJobInfo {
-            name: require_one!(value, "name").unwrap_or_else(|_| "".to_string()),
-            schedule: schedule_to_cron(&require_one!(value, "schedule")?.as_str())?,
-            command: require_one!(value, "command")?,
-            dir: take_one!(value, "dir")?,
-            environment: value.remove("environment").unwrap_or(Default::default()),
-        };
-        if !value.is_empty() {
-            warn!("The job key map has excess attributes that will not be used: {:?}", value.keys());
-        }
+        let job = Self::take_from(&mut value)?;
+        warn_unused_keys(Self::LABEL, &job.name, &value);
         Ok(job)
     }
 }
 
 impl LocalJobInfo {
-    pub const LABEL: &'static str = "job-local";
-    pub async fn exec(self, _: &Docker) -> Result<ExecInfo, Error> {
-        let mut command = tokio::process::Command::new(self.command);
-        for e in self.environment {
-            let mut env_info = e.split("=");
-            if let Some(key) = env_info.next() {
-                let value = env_info.collect::<Vec<_>>().join(".");
-                command.env(key, value);
-            } else {
-                return Err(Error::msg(format!("Failed to parse environment variable '{}'", e)));
+    pub const LABEL: &str = "job-local";
+    /// The keys accepted by local jobs, besides the ones shared by all kinds
+    pub const KEYS: &'static [KeySpec] = &[
+        KeySpec::new("command", KeyType::List, "touch /tmp/cfc").required(),
+        KeySpec::new("working-dir", KeyType::String, "/tmp"),
+        KeySpec::new("environment", KeyType::List, "FOO=bar"),
+        KeySpec::new("sandbox", KeyType::String, "systemd-run").default("none"),
+        KeySpec::new("sandbox-required", KeyType::Bool, "true").default("false"),
+        KeySpec::new("sandbox-memory-max", KeyType::String, "512M"),
+        KeySpec::new("sandbox-cpu-quota", KeyType::String, "50%"),
+        KeySpec::new("sandbox-private-tmp", KeyType::Bool, "true").default("false"),
+        OUTPUT_KEYS[0],
+        OUTPUT_KEYS[1],
+        OUTPUT_KEYS[2],
+        OUTPUT_KEYS[3],
+    ];
+
+    /// Build a job from the keys of its parameter map, leaving the keys it does not use
+    pub(crate) fn take_from(value: &mut HashMap<String, Vec<String>>) -> Result<Self, Error> {
+        let name = require_one!(value, "name").unwrap_or_else(|_| "".to_string());
+        let environment = value.remove("environment").unwrap_or_default();
+        for entry in &environment {
+            check_secret_reference(entry).map_err(|e| Error::msg(format!("Invalid environment entry '{}' in job '{}': {}", entry, name, e)))?;
+        }
+        Ok(LocalJobInfo {
+            schedule: take_schedule(value, &name)?,
+            name,
+            command: take_commands(value, true)?,
+            dir: take_one!(value, "working-dir")?,
+            environment,
+            sandbox: Sandbox::take_from(value)?,
+            options: JobOptions::take_from(value)?.take_output_options(value)?,
+        })
+    }
+    pub async fn exec(mut self, _: &Docker) -> Result<ExecInfo, Error> {
+        self.environment = resolve_secrets(&self.environment)?;
+        run_commands(&self.command, self.options.continue_on_error, |command| self.exec_command(command)).await
+            .map(ExecInfo::Report)
+    }
+
+    /// Run one of the job's commands on the host
+    async fn exec_command(&self, command: &str) -> Result<ExecutionReport, Error> {
+        let argv = command_argv(command)
+            .map_err(|e| Error::msg(format!("Failed to parse the command of local job '{}': {}", self.name, e)))?;
+        let mut environment = vec![];
+        for e in &self.environment {
+            let (key, value) = e.split_once('=').unwrap_or((e, ""));
+            environment.push((key.to_string(), value.to_string()));
+        }
+        let build_command = |argv: &[String]| {
+            let mut command = tokio::process::Command::new(&argv[0]);
+            command.args(&argv[1..]);
+            command.envs(environment.iter().map(|(k, v)| (k, v)));
+            command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+            // Cancelled executions must not leave the command running
+            command.kill_on_drop(true);
+            if let Some(dir) = self.dir.as_ref() {
+                command.current_dir(dir);
             }
+            command
+        };
+        // The unit is named so that it can be stopped, as dropping the command only kills systemd-run
+        let unit = (self.sandbox.kind == SandboxKind::SystemdRun).then(|| unit_name(&self.name));
+        let sandboxed = self.sandbox.command_line(&argv, &environment, self.dir.as_deref(), unit.as_deref());
+        let mut unit_guard = None;
+        let child = match sandboxed {
+            Some(sandboxed) if self.sandbox.is_available() => match build_command(&sandboxed).spawn() {
+                Ok(child) => {
+                    unit_guard = unit.map(UnitGuard::new);
+                    Ok(child)
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && !self.sandbox.required => {
+                    warn!("The {} sandbox is not available for local job '{}', running it without sandbox", self.sandbox.kind, self.name);
+                    build_command(&argv).spawn()
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(Error::msg(format!("The {} sandbox required by local job '{}' is not available: {}", self.sandbox.kind, self.name, e)));
+                },
+                r => r,
+            },
+            Some(_) if self.sandbox.required => {
+                return Err(Error::msg(format!("The {} sandbox required by local job '{}' is not available on this host", self.sandbox.kind, self.name)));
+            },
+            Some(_) => {
+                warn!("The {} sandbox is not available for local job '{}', running it without sandbox", self.sandbox.kind, self.name);
+                build_command(&argv).spawn()
+            },
+            None => build_command(&argv).spawn(),
+        };
+        let mut child = child.map_err(Error::new)?;
+        let (stdout, stderr) = (child.stdout.take().expect("piped stdout"), child.stderr.take().expect("piped stderr"));
+        // Both streams are drained while the command runs so that it never blocks on a full pipe
+        let (status, (stdout, stdout_truncated), (stderr, stderr_truncated)) = tokio::try_join!(
+            child.wait(),
+            read_output(stdout, self.options.output_lines(&self.name, StreamKind::Stdout), self.options.max_output_size()),
+            read_output(stderr, self.options.output_lines(&self.name, StreamKind::Stderr), self.options.max_output_size()),
+        ).map_err(Error::new)?;
+        if let Some(unit_guard) = unit_guard {
+            unit_guard.disarm();
         }
-        if let Some(dir) = self.dir {
-            command.current_dir(dir);
+        // The output that was not captured is forwarded by the caller, which knows how each stream should be routed
+        if status.code().map(|c| c != 0).unwrap_or(true) {
+            error!("Unexpected error code {} in local job '{}'", status.code().unwrap_or(10000), self.name);
+        } else {
+            info!("Local job '{}' ended successfully.", self.name);
         }
-        command.output().await
-            .and_then(|o| {
-                // TODO: move this to the caller and return an object enum to handle the distinction between timer and job
-                if o.status.code().and_then(|c| Some(c != 0)).unwrap_or(true) {
-                    error!(
-                        "Unexpected error code {} in local job '{}'. [{}] [{}]",
-                        o.status.code().unwrap_or(10000),
-                        self.name,
-                        String::from_utf8(o.stdout).unwrap_or_else(|_| "FAILED_TO_PARSE_OUTPUT".to_string()),
-                        String::from_utf8(o.stderr).unwrap_or_else(|_| "FAILED_TO_PARSE_OUTPUT".to_string()),
-                    );
-                } else {
-                    info!("Local job '{}' ended successfully.", self.name);
-                    debug!(
-                        "Local job '{}' ended successfully ({}). [{}] [{}]",
-                        self.name,
-                        o.status.code().unwrap_or(10000),
-                        String::from_utf8(o.stdout).unwrap_or_else(|_| "FAILED_TO_PARSE_OUTPUT".to_string()),
-                        String::from_utf8(o.stderr).unwrap_or_else(|_| "FAILED_TO_PARSE_OUTPUT".to_string()),
-                    );
-                }
-                let mut report = ExecutionReport::default();
-                report.retval = o.status.code().unwrap().into();
-                Ok(ExecInfo::Report(report))
-            })
-            .map_err(|e| Error::new(e))
-    }
-    pub fn get_schedule(&self) -> Cron {
-        self.schedule.clone()
-    }
-    pub fn may_run_parallel(&self) -> bool {
-        true
+        Ok(ExecutionReport {
+            retval: status.code().unwrap_or(-1).into(),
+            stdout,
+            stderr,
+            output_truncated: stdout_truncated || stderr_truncated,
+            ..Default::default()
+        })
+    }
+}
+
+impl Job for LocalJobInfo {
+    fn name(&self) -> &String {
+        &self.name
+    }
+    fn kind(&self) -> &str {
+        Self::LABEL
+    }
+    fn options(&self) -> &JobOptions {
+        &self.options
+    }
+    fn options_mut(&mut self) -> &mut JobOptions {
+        &mut self.options
+    }
+    fn commands(&self) -> &[String] {
+        &self.command
+    }
+    fn exec(&self, handle: &Docker) -> BoxFuture<'static, Result<ExecInfo, Error>> {
+        let (job, handle) = (self.clone(), handle.clone());
+        Box::pin(async move { LocalJobInfo::exec(job, &handle).await })
+    }
+    fn clone_job(&self) -> Box<dyn Job> {
+        Box::new(self.clone())
+    }
+    fn to_parameters(&self) -> BTreeMap<String, Vec<String>> {
+        let mut map = BTreeMap::new();
+        put_one(&mut map, "name", Some(&self.name));
+        self.schedule.put_into(&mut map);
+        put_all(&mut map, "command", &self.command);
+        put_one(&mut map, "working-dir", self.dir.as_ref());
+        put_all(&mut map, "environment", &self.environment);
+        self.sandbox.put_into(&mut map);
+        self.options.put_into(&mut map);
+        map
+    }
+    fn describe_action(&self) -> String {
+        match self.sandbox.kind {
+            SandboxKind::None => format!("run {} on the host", describe_commands(&self.command)),
+            kind => format!("run {} on the host in the {} sandbox", describe_commands(&self.command), kind),
+        }
+    }
+    fn schedule(&self) -> &Schedule {
+        &self.schedule
     }
 }
 
@@ -91,10 +197,9 @@ impl Display for LocalJobInfo {
     fn fmt(&self, fmt: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(
             fmt,
-            "{}.{}.{}",
+            "{}.{}.CFC_HOST",
             Self::LABEL,
             self.name,
-            "CFC_HOST",
         )
     }
 }
@@ -103,10 +208,12 @@ impl Debug for LocalJobInfo {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("LocalJobInfo")
             .field("name", &self.name)
-            .field("schedule", &self.schedule.pattern.to_string())
+            .field("schedule", &self.schedule)
             .field("command", &self.command)
             .field("dir", &self.dir)
             .field("environment", &self.environment)
+            .field("sandbox", &self.sandbox)
+            .field("options", &self.options)
             .finish()
     }
 }
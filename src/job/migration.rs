@@ -0,0 +1,107 @@
+//! Renames of the job keys across the versions of the configuration format
+//!
+//! A configuration file declares the version it is written for with the `version` key of its
+//! `global` section, files without one are read as version 1. The keys that were renamed since
+//! the file's version are migrated to their current name when the jobs are loaded, with a
+//! warning that lists the edits to make. Every rename is declared in [`KEY_RENAMES`].
+use std::collections::HashMap;
+
+use anyhow::{Error, Result};
+
+use super::{ExecJobInfo, LocalJobInfo};
+
+/// The version of the configuration format read by this cfc
+pub const CONFIG_VERSION: u64 = 2;
+
+/// The version of the configuration files that do not declare one
+pub const DEFAULT_CONFIG_VERSION: u64 = 1;
+
+/// A job key that was renamed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyRename {
+    /// The job kinds whose key was renamed, all kinds if empty
+    pub kinds: &'static [&'static str],
+    pub from: &'static str,
+    pub to: &'static str,
+    /// The first version of the configuration format that uses the new name
+    pub version: u64,
+}
+
+impl KeyRename {
+    fn applies_to(&self, kind: &str) -> bool {
+        self.kinds.is_empty() || self.kinds.contains(&kind)
+    }
+}
+
+/// The renamed job keys, oldest first
+pub const KEY_RENAMES: &[KeyRename] = &[
+    KeyRename { kinds: &[LocalJobInfo::LABEL, ExecJobInfo::LABEL], from: "dir", to: "working-dir", version: 2 },
+];
+
+/// Parse the version of the configuration format declared by a file
+pub fn parse_config_version(value: &str) -> Result<u64> {
+    let version = value.trim().parse::<u64>().ok().filter(|v| *v > 0)
+        .ok_or_else(|| Error::msg(format!("Invalid configuration version '{}', expected a number from 1 to {}", value, CONFIG_VERSION)))?;
+    if version > CONFIG_VERSION {
+        return Err(Error::msg(format!("this cfc (config version {}) cannot read version {}", CONFIG_VERSION, version)));
+    }
+    Ok(version)
+}
+
+/// Get the rename of a key that a job of `kind` may not use anymore, if it was renamed
+pub(crate) fn find_rename(kind: &str, key: &str) -> Option<&'static KeyRename> {
+    KEY_RENAMES.iter().find(|r| r.from == key && r.applies_to(kind))
+}
+
+/// Rename the keys of a job's parameter map written for an older `version` of the
+/// configuration format, returning the renames that were applied
+pub fn migrate_job(kind: &str, parameters: &mut HashMap<String, Vec<String>>, version: u64) -> Result<Vec<&'static KeyRename>> {
+    let mut applied = vec![];
+    for rename in KEY_RENAMES.iter().filter(|r| r.version > version && r.applies_to(kind)) {
+        let Some(values) = parameters.remove(rename.from) else { continue };
+        if parameters.contains_key(rename.to) {
+            return Err(Error::msg(format!("The keys '{}' and '{}' are the same key, only set '{}'", rename.from, rename.to, rename.to)));
+        }
+        parameters.insert(rename.to.to_string(), values);
+        applied.push(rename);
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{migrate_job, parse_config_version, CONFIG_VERSION};
+
+    #[test]
+    fn config_versions() {
+        assert_eq!(parse_config_version("1").unwrap(), 1);
+        assert_eq!(parse_config_version(" 2 ").unwrap(), 2);
+        for invalid in ["0", "-1", "two", ""] {
+            assert!(parse_config_version(invalid).is_err(), "{:?}", invalid);
+        }
+        let e = parse_config_version(&(CONFIG_VERSION + 1).to_string()).unwrap_err();
+        assert_eq!(e.to_string(), format!("this cfc (config version {}) cannot read version {}", CONFIG_VERSION, CONFIG_VERSION + 1));
+    }
+
+    #[test]
+    fn rename_old_keys() {
+        let mut parameters = HashMap::from([("dir".to_string(), vec!["/tmp".to_string()])]);
+        let applied = migrate_job("job-local", &mut parameters, 1).unwrap();
+        assert_eq!(applied.iter().map(|r| (r.from, r.to)).collect::<Vec<_>>(), [("dir", "working-dir")]);
+        assert_eq!(parameters, HashMap::from([("working-dir".to_string(), vec!["/tmp".to_string()])]));
+        // As written in ofelia's exec jobs
+        let mut parameters = HashMap::from([("dir".to_string(), vec!["/app".to_string()])]);
+        assert_eq!(migrate_job("job-exec", &mut parameters, 1).unwrap().len(), 1);
+        assert!(parameters.contains_key("working-dir"));
+        // Renames only apply to older versions and to the kinds whose key was renamed
+        for (kind, version) in [("job-local", 2), ("job-exec", 2), ("job-run", 1)] {
+            let mut parameters = HashMap::from([("dir".to_string(), vec!["/tmp".to_string()])]);
+            assert!(migrate_job(kind, &mut parameters, version).unwrap().is_empty());
+            assert!(parameters.contains_key("dir"));
+        }
+        let mut both = HashMap::from([("dir".to_string(), vec!["/a".to_string()]), ("working-dir".to_string(), vec!["/b".to_string()])]);
+        assert!(migrate_job("job-local", &mut both, 1).is_err());
+    }
+}
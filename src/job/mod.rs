@@ -1,35 +1,430 @@
 //! Job representation
 use anyhow::Error;
 use bollard::Docker;
-use croner::Cron;
-use tokio::{task::JoinSet, time};
-use tracing::{debug, error, info};
-use std::{collections::HashMap, fmt::Debug, time::Duration};
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::{sync::{watch, OwnedMutexGuard}, task::{AbortHandle, JoinSet}, time};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use std::{collections::{BTreeMap, HashMap}, fmt::{Debug, Display}, future::Future, panic::AssertUnwindSafe, sync::{atomic::{AtomicU64, Ordering}, Arc, LazyLock, Mutex, RwLock}, time::Duration};
 
+mod admission;
+mod builder;
 mod common;
+mod error;
 mod exec;
+mod history;
+mod hook;
 mod run;
 mod local;
+mod migration;
+mod output;
+mod sandbox;
+mod schema;
 mod servicerun;
 
-pub use common::ExecutionReport;
-pub use exec::ExecJobInfo;
-pub use run::RunJobInfo;
+pub use admission::{ExecutionPermit, ExecutionPermits, DEFAULT_PROMOTION_DELAY};
+pub use builder::ExecJobBuilder;
+pub use common::{parse_duration, parse_environment_entry, parse_environment_file, parse_secret_reference, redact, register_secret_backend, resolve_environment_entry, resolve_schedule_alias, resolve_secrets, take_schedule, validate_schedule_alias, ExecutionReport, JobOptions, JobTimezone, OnFailure, OverlapPolicy, Schedule, SecretBackend, StreamKind, ANNOTATION_PREFIX, BUILTIN_SCHEDULES, DEFAULT_MAX_OUTPUT_SIZE, DEFAULT_SHELL, ENV_SECRET_BACKEND, FILE_SECRET_BACKEND, REDACTED, SECRET_PREFIX};
+pub use error::{error_counts, record_error, ErrorCategory};
+pub use exec::{ExecJobInfo, StallPolicy};
+pub use hook::{flush_hooks, EXIT_CODE_VARIABLE, HOOK_FLUSH_TIMEOUT, JOB_NAME_VARIABLE, STDOUT_FILE_VARIABLE};
+pub use history::{ExecutionHistory, HistoryEntry, DEFAULT_HISTORY_SIZE, MAX_HISTORY_SIZE};
+pub use run::{NameConflictPolicy, PullPolicy, RunJobInfo};
+pub(crate) use run::prepare_image;
 pub use local::LocalJobInfo;
+pub use migration::{migrate_job, parse_config_version, KeyRename, CONFIG_VERSION, DEFAULT_CONFIG_VERSION, KEY_RENAMES};
+pub use sandbox::{Sandbox, SandboxKind};
+pub use schema::{find_key, kind_keys, schema, suggest_key, KeySpec, KeyType, OPTION_KEYS, SCHEDULE_KEYS, SCHEMA_VERSION};
 pub use servicerun::ServiceRunJobInfo;
 
-use crate::job::common::ExecutionSchedule;
+use crate::{clock::ClockJump, job::{common::{take_annotations, ExecutionSchedule}, output::log_output_line}, metrics, scheduler::SpawnBudget};
 
 pub use self::common::ExecInfo;
 
-/// Sleep until the next occurence of the provided cron
-async fn cron_sleep(cron: &Cron) -> Result<ExecInfo, Error> {
+/// The labels of the built-in job kinds
+pub const JOB_KINDS: [&str; 4] = [ExecJobInfo::LABEL, RunJobInfo::LABEL, LocalJobInfo::LABEL, ServiceRunJobInfo::LABEL];
+
+/// The behavior shared by all job kinds.
+///
+/// Job kinds that are not built into cfc implement this trait and are wrapped in
+/// [`JobInfo::Custom`] by the constructor they [register][register_job_kind].
+pub trait Job: Debug + Send + Sync {
+    /// Get the name of the job
+    fn name(&self) -> &String;
+    /// Get the label of the job's kind, as used in the `kind` parameter
+    fn kind(&self) -> &str;
+    /// Get the scheduling options shared by all job kinds
+    fn options(&self) -> &JobOptions;
+    /// Get a mutable reference to the scheduling options shared by all job kinds
+    fn options_mut(&mut self) -> &mut JobOptions;
+    /// Get the schedule on which the job is executed
+    fn schedule(&self) -> &Schedule;
+    /// Get a copy of the schedule on which the job is executed
+    fn get_schedule(&self) -> Schedule {
+        self.schedule().clone()
+    }
+    /// Whether a new execution may start while a previous one is still running
+    fn may_run_parallel(&self) -> bool {
+        self.options().overlap == OverlapPolicy::Allow
+    }
+    /// Get the commands executed one after the other when the job is triggered, if the job runs commands of its own
+    fn commands(&self) -> &[String] {
+        &[]
+    }
+    /// Get a human-readable description of what the job does when it is triggered
+    fn describe_action(&self) -> String;
+    /// Get the job's parameter map without its kind, as it would be provided to the job kind's constructor
+    fn to_parameters(&self) -> BTreeMap<String, Vec<String>>;
+    /// Execute the job's action once
+    fn exec(&self, handle: &Docker) -> BoxFuture<'static, Result<ExecInfo, Error>>;
+    /// Get a boxed copy of the job
+    fn clone_job(&self) -> Box<dyn Job>;
+}
+
+impl Clone for Box<dyn Job> {
+    fn clone(&self) -> Self {
+        self.clone_job()
+    }
+}
+
+impl PartialEq for dyn Job {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind() == other.kind() && self.to_parameters() == other.to_parameters()
+    }
+}
+
+/// Build a job of a given kind from its parameter map, the `kind` parameter excluded
+pub type JobConstructor = fn(HashMap<String, Vec<String>>) -> Result<JobInfo, Error>;
+
+/// A job kind that can be built from a parameter map
+#[derive(Clone, Copy)]
+pub struct JobKind {
+    /// The keys accepted by the kind, besides the [scheduling keys][SCHEDULE_KEYS] and [options][OPTION_KEYS] shared by all kinds
+    pub keys: &'static [KeySpec],
+    /// Whether jobs of this kind may only be declared in labels when unsafe jobs are allowed
+    pub unsafe_from_labels: bool,
+    pub constructor: JobConstructor,
+}
+
+static JOB_CONSTRUCTORS: LazyLock<RwLock<BTreeMap<String, JobKind>>> = LazyLock::new(|| {
+    let builtins = [
+        (ExecJobInfo::LABEL, JobKind { keys: ExecJobInfo::KEYS, unsafe_from_labels: false, constructor: |p| Ok(JobInfo::ExecJob(Box::new(ExecJobInfo::try_from(p)?))) }),
+        (RunJobInfo::LABEL, JobKind { keys: RunJobInfo::KEYS, unsafe_from_labels: false, constructor: |p| Ok(JobInfo::RunJob(Box::new(RunJobInfo::try_from(p)?))) }),
+        (LocalJobInfo::LABEL, JobKind { keys: LocalJobInfo::KEYS, unsafe_from_labels: true, constructor: |p| Ok(JobInfo::LocalJob(Box::new(LocalJobInfo::try_from(p)?))) }),
+        (ServiceRunJobInfo::LABEL, JobKind { keys: ServiceRunJobInfo::KEYS, unsafe_from_labels: false, constructor: |p| Ok(JobInfo::ServiceRunJob(Box::new(ServiceRunJobInfo::try_from(p)?))) }),
+    ];
+    RwLock::new(builtins.into_iter().map(|(label, kind)| (label.to_string(), kind)).collect())
+});
+
+/// Register a job kind, replacing and returning the kind previously registered with this label if any.
+///
+/// ## Examples
+///
+/// ```rust
+/// # use std::collections::HashMap;
+/// # use cfc::job::{register_job_kind, JobInfo, JobKind, LocalJobInfo};
+/// // Alias an existing kind, a new kind would wrap its own job type in JobInfo::Custom
+/// register_job_kind("job-shell", JobKind {
+///     keys: LocalJobInfo::KEYS,
+///     unsafe_from_labels: true,
+///     constructor: |p| Ok(JobInfo::LocalJob(Box::new(LocalJobInfo::try_from(p)?))),
+/// });
+/// let job = JobInfo::try_from(HashMap::from([
+///    ("kind".to_string(), vec!["job-shell".to_string()]),
+///    ("name".to_string(), vec!["example_job".to_string()]),
+///    ("command".to_string(), vec!["echo 3".to_string()]),
+///    ("schedule".to_string(), vec!["@hourly".to_string()]),
+/// ])).unwrap();
+/// assert_eq!(job.kind(), "job-local");
+/// ```
+pub fn register_job_kind(label: &str, kind: JobKind) -> Option<JobKind> {
+    JOB_CONSTRUCTORS.write().unwrap_or_else(|e| e.into_inner()).insert(label.to_string(), kind)
+}
+
+/// Get a registered job kind
+pub fn registered_kind(label: &str) -> Option<JobKind> {
+    JOB_CONSTRUCTORS.read().unwrap_or_else(|e| e.into_inner()).get(label).copied()
+}
+
+/// Check whether jobs of a kind can be built
+pub fn is_registered_kind(kind: &str) -> bool {
+    JOB_CONSTRUCTORS.read().unwrap_or_else(|e| e.into_inner()).contains_key(kind)
+}
+
+/// Get the labels of all the job kinds that can be built, sorted
+pub fn registered_kinds() -> Vec<String> {
+    JOB_CONSTRUCTORS.read().unwrap_or_else(|e| e.into_inner()).keys().cloned().collect()
+}
+
+/// Returned by [`cron_sleep`] when the schedule can not be triggered anymore
+#[derive(Debug)]
+struct NoOccurrenceError {
+    schedule: String,
+    reason: Error,
+}
+
+impl Display for NoOccurrenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The schedule '{}' has no future occurence ({})", self.schedule, self.reason)
+    }
+}
+
+impl std::error::Error for NoOccurrenceError {}
+
+/// Returned by [`JobInfo::start`] when an execution failed and the job's
+/// [`on-failure`][OnFailure::StopDaemon] policy requires stopping all jobs
+#[derive(Debug)]
+pub struct StopDaemonError {
+    /// The name of the failed job
+    pub job: String,
+}
+
+impl Display for StopDaemonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The job {} failed and its on-failure policy is stop-daemon", self.job)
+    }
+}
+
+impl std::error::Error for StopDaemonError {}
+
+/// The locks of the jobs' mutual-exclusion groups, by group name
+#[derive(Clone, Debug, Default)]
+pub struct GroupLocks(Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>);
+
+impl GroupLocks {
+    /// Get the lock of a group, creating it if needed
+    pub fn get(&self, group: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).entry(group.to_string()).or_default().clone()
+    }
+}
+
+/// Wait for the lock of a job's group, giving up after `wait_timeout`
+async fn lock_group(job: &str, group: &str, lock: Arc<tokio::sync::Mutex<()>>, wait_timeout: Option<Duration>) -> Option<OwnedMutexGuard<()>> {
+    if let Ok(guard) = lock.clone().try_lock_owned() {
+        return Some(guard);
+    }
+    info!("Job {} waits for the running job of group {} to end", job, group);
+    let wait_start = time::Instant::now();
+    let guard = match wait_timeout {
+        Some(wait_timeout) => time::timeout(wait_timeout, lock.lock_owned()).await.ok(),
+        None => Some(lock.lock_owned().await),
+    };
+    match guard.is_some() {
+        true => info!("Job {} waited {:?} for group {}", job, wait_start.elapsed(), group),
+        false => warn!("Skipping the run of job {} as group {} stayed busy for {:?}", job, group, wait_start.elapsed()),
+    }
+    guard
+}
+
+/// Whether a job's triggers start executions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JobState {
+    #[default]
+    Active,
+    /// The job's schedule keeps being followed but its triggers are skipped
+    Paused,
+}
+
+/// The signals through which the scheduler controls a job it started
+#[derive(Clone, Debug)]
+pub struct JobControl {
+    /// Stops triggering the job once it changes
+    pub stop: watch::Receiver<bool>,
+    /// Shared by the executions of all jobs to limit how many run at the same time
+    pub permits: Option<ExecutionPermits>,
+    /// Shared by all jobs so that the executions of a group's jobs never overlap
+    pub groups: GroupLocks,
+    /// Notifies the job of the wall clock jumps detected by the daemon
+    pub clock_jumps: watch::Receiver<Option<ClockJump>>,
+    /// Only log what the job would execute when it is triggered, without running anything
+    pub dry_run: bool,
+    /// Where the job's completed executions are recorded
+    pub history: ExecutionHistory,
+    /// Shared by the jobs that create containers to cap how many they create per hour
+    pub spawn_budget: Option<SpawnBudget>,
+    /// Whether the job's triggers are currently skipped
+    pub state: watch::Receiver<JobState>,
+}
+
+impl JobControl {
+    /// Control a job through its stop and clock jump signals only: its executions do not wait for
+    /// permits nor share group locks with other jobs, are not dry runs, are recorded in a history
+    /// of their own, do not use a spawn budget and are never paused
+    pub fn new(stop: watch::Receiver<bool>, clock_jumps: watch::Receiver<Option<ClockJump>>) -> Self {
+        JobControl {
+            stop,
+            permits: None,
+            groups: GroupLocks::default(),
+            clock_jumps,
+            dry_run: false,
+            history: ExecutionHistory::default(),
+            spawn_budget: None,
+            state: watch::channel(JobState::Active).1,
+        }
+    }
+
+    /// Limit the executions with permits shared with other jobs
+    pub fn with_permits(mut self, permits: Option<ExecutionPermits>) -> Self {
+        self.permits = permits;
+        self
+    }
+
+    /// Share the locks of the job's group with other jobs
+    pub fn with_groups(mut self, groups: GroupLocks) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Set whether triggers only log what they would execute
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Record the completed executions in a history
+    pub fn with_history(mut self, history: ExecutionHistory) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Cap the containers created by the job with a budget shared with other jobs
+    pub fn with_spawn_budget(mut self, spawn_budget: Option<SpawnBudget>) -> Self {
+        self.spawn_budget = spawn_budget;
+        self
+    }
+
+    /// Skip the job's triggers while the state is [`JobState::Paused`]
+    pub fn with_state(mut self, state: watch::Receiver<JobState>) -> Self {
+        self.state = state;
+        self
+    }
+}
+
+/// Find the next occurence of the provided schedule after `from` that is part of the job's
+/// activity window. Returns `None` when no occurence remains in the window and a
+/// [`NoOccurrenceError`] if the schedule never matches again.
+fn next_trigger(cron: &Schedule, options: &JobOptions, from: &chrono::DateTime<chrono::Local>) -> Result<Option<chrono::DateTime<chrono::Local>>, Error> {
+    let start_time = match options.not_before {
+        Some(t) if t > *from => t.with_timezone(&chrono::Local) - chrono::Duration::seconds(1),
+        _ => *from,
+    };
+    let next_occurence = cron.find_next_occurrence(&start_time)
+        .map_err(|reason| Error::new(NoOccurrenceError { schedule: cron.source.clone(), reason }))?;
+    Ok(options.not_after.is_none_or(|t| next_occurence <= t).then_some(next_occurence))
+}
+
+/// Sleep until the next occurence of the provided schedule that is part of the job's
+/// activity window. Returns [`ExecInfo::ScheduleEnd`] when no occurence remains in the window
+/// and a [`NoOccurrenceError`] if the schedule never matches again.
+async fn cron_sleep(cron: &Schedule, options: &JobOptions) -> Result<ExecInfo, Error> {
     let current_time = chrono::Local::now();
-    let next_occurence = cron.find_next_occurrence(&current_time, false).unwrap();
+    let Some(next_occurence) = next_trigger(cron, options, &current_time)? else {
+        return Ok(ExecInfo::ScheduleEnd);
+    };
     let sleep = (next_occurence - current_time).num_milliseconds();
-    assert!(sleep >= 0);
-    tokio::time::sleep(Duration::from_millis(sleep as u64)).await;
-    Ok(ExecInfo::Schedule(ExecutionSchedule{}))
+    if sleep < 0 {
+        warn!("The next occurence of schedule '{}' ({}) is {}ms in the past, triggering it now", cron.source, next_occurence, -sleep);
+    }
+    tokio::time::sleep(Duration::from_millis(sleep.max(0) as u64)).await;
+    Ok(ExecInfo::Schedule(ExecutionSchedule{ scheduled_at: next_occurence }))
+}
+
+/// The number of bytes of a failed run's output quoted when its job exceeds its `max-consecutive-failures`
+const FAILURE_EXCERPT_LENGTH: usize = 512;
+
+/// Describe a failed run with the end of its stderr, or of its stdout when it has none
+fn failure_excerpt(report: &ExecutionReport) -> String {
+    if report.timed_out {
+        return "the last run timed out".to_string();
+    }
+    let mut output = report.stderr.clone().or_else(|| report.stdout.clone());
+    crate::report::tail(&mut output, FAILURE_EXCERPT_LENGTH);
+    match output.as_deref().map(str::trim).filter(|o| !o.is_empty()) {
+        Some(output) => format!("the last run exited with code {}: {}", report.retval, output),
+        None => format!("the last run exited with code {}", report.retval),
+    }
+}
+
+/// Spawn the task that waits for the next occurence of the job's schedule
+fn arm_timer(set: &mut JoinSet<Result<ExecInfo, Error>>, cron: &Schedule, options: &JobOptions) -> AbortHandle {
+    let (cron, options) = (cron.clone(), options.clone());
+    set.spawn(async move {cron_sleep(&cron, &options).await})
+}
+
+/// Await a job's execution, terminating it if it outlasts the job's timeout.
+///
+/// The execution's future is dropped on expiry, which is when each job kind releases what it
+/// started: local commands are killed and pending container execs are logged as abandoned.
+/// The values of the resolved secrets are redacted from the execution's output.
+async fn exec_with_timeout(name: &str, timeout: Option<Duration>, exec: impl Future<Output = Result<ExecInfo, Error>>) -> Result<ExecInfo, Error> {
+    let result = match timeout {
+        None => exec.await,
+        Some(timeout) => match time::timeout(timeout, exec).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("Job {} timed out after {} seconds and was terminated", name, timeout.as_secs());
+                Ok(ExecInfo::Report(ExecutionReport::timeout()))
+            },
+        },
+    };
+    // The output is logged and reported by the callers, it must not leak the secrets passed to the job
+    result.map(|info| match info {
+        ExecInfo::Report(mut report) => {
+            report.redact();
+            ExecInfo::Report(report)
+        },
+        info => info,
+    })
+}
+
+/// Get the message of a caught panic
+pub(crate) fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    panic.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Run an execution, a panic is turned into a failed report so that it only fails this run
+async fn exec_catching_panics(name: &str, exec: impl Future<Output = Result<ExecInfo, Error>>) -> Result<ExecInfo, Error> {
+    AssertUnwindSafe(exec).catch_unwind().await.unwrap_or_else(|p| {
+        let message = panic_message(p.as_ref());
+        error!("An execution of job {} panicked: {}", name, message);
+        Ok(ExecInfo::Report(ExecutionReport { retval: -1, stderr: Some(format!("The execution panicked: {}", message)), ..Default::default() }))
+    })
+}
+
+/// The executions that are currently running, by id, with their job's name and start date
+static RUNNING_EXECUTIONS: Mutex<BTreeMap<u64, (String, chrono::DateTime<chrono::Local>)>> = Mutex::new(BTreeMap::new());
+
+static NEXT_EXECUTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Registers an execution in [`RUNNING_EXECUTIONS`] until it is dropped
+struct RunningExecution(u64);
+
+impl RunningExecution {
+    fn register(id: u64, name: &str) -> Self {
+        RUNNING_EXECUTIONS.lock().unwrap_or_else(|e| e.into_inner()).insert(id, (name.to_string(), chrono::Local::now()));
+        RunningExecution(id)
+    }
+}
+
+impl Drop for RunningExecution {
+    fn drop(&mut self) {
+        RUNNING_EXECUTIONS.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.0);
+    }
+}
+
+/// The span of a scheduled run, which holds the logs emitted while the run executes
+fn run_span(name: &str, kind: &str, run_id: u64, scheduled_at: Option<chrono::DateTime<chrono::Local>>) -> tracing::Span {
+    let span = info_span!("run", job.name = %name, job.kind = %kind, run.id = run_id, run.scheduled_at = tracing::field::Empty);
+    if let Some(scheduled_at) = scheduled_at {
+        span.record("run.scheduled_at", tracing::field::display(scheduled_at.to_rfc3339()));
+    }
+    span
+}
+
+/// Get the name and start date of the scheduled executions that are currently running, oldest first
+pub fn running_executions() -> Vec<(String, chrono::DateTime<chrono::Local>)> {
+    RUNNING_EXECUTIONS.lock().unwrap_or_else(|e| e.into_inner()).values().cloned().collect()
 }
 
 /// A job's information container that allows to start the corresponding cron.
@@ -53,21 +448,25 @@ async fn cron_sleep(cron: &Cron) -> Result<ExecInfo, Error> {
 ///     _ => panic!("The generated job does not have the expected type"),
 /// }
 /// ```
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum JobInfo {
     ExecJob(Box<ExecJobInfo>),
     RunJob(Box<RunJobInfo>),
     LocalJob(Box<LocalJobInfo>),
     ServiceRunJob(Box<ServiceRunJobInfo>),
+    /// A job of a kind that is not built into cfc
+    Custom(Box<dyn Job>),
 }
 
-/// Perform a match on all JobInfo enum members and apply the same processing to all of them
+/// Perform a match on all JobInfo enum members and apply the same processing to all of them.
+///
+/// As the matched value may be a [`Job`] trait object, the processing should only rely on the trait's methods.
 /// 
 /// ## Examples
 /// 
 /// ```rust
 /// # use std::collections::HashMap;
-/// # use cfc::job::{JobInfo, match_all_jobs};
+/// # use cfc::job::{Job, JobInfo, match_all_jobs};
 /// let job = JobInfo::try_from(HashMap::from([
 ///     // ...
 /// #    ("kind".to_string(), vec!["job-local".to_string()]),
@@ -75,7 +474,7 @@ pub enum JobInfo {
 /// #    ("command".to_string(), vec!["echo 3".to_string()]),
 /// #    ("schedule".to_string(), vec!["@hourly".to_string()]),
 /// ])).unwrap();
-/// let name = match_all_jobs!(&job, e, &e.name);
+/// let name = match_all_jobs!(&job, e, e.name());
 /// # assert_eq!(name, "example_job");
 /// ```
 #[macro_export]
@@ -86,6 +485,7 @@ macro_rules! match_all_jobs {
             JobInfo::RunJob($varname) => $processing,
             JobInfo::LocalJob($varname) => $processing,
             JobInfo::ServiceRunJob($varname) => $processing,
+            JobInfo::Custom($varname) => $processing,
         }
     };
 }
@@ -97,108 +497,1014 @@ impl TryFrom<HashMap<String, Vec<String>>> for JobInfo {
 
     fn try_from(mut parameters: HashMap<String, Vec<String>>) -> Result<Self, Self::Error> {
         let kind = parameters.remove("kind");
-        if kind == None {
-            return Err(Error::msg(format!["The job has no job kind"]));
+        if kind.is_none() {
+            return Err(Error::msg("The job has no job kind"));
         }
         if kind.as_ref().unwrap().len() != 1 {
             debug!["The job has several kinds set, using the last configured one"];
         }
         let kind = kind.unwrap().pop().unwrap();
-        let job_info: JobInfo;
-        match kind.as_str() {
-            ExecJobInfo::LABEL => {
-                let job = ExecJobInfo::try_from(parameters)?;
-                job_info = JobInfo::ExecJob(Box::new(job));
-            },
-            RunJobInfo::LABEL => {
-                let job = RunJobInfo::try_from(parameters)?;
-                job_info = JobInfo::RunJob(Box::new(job));
-            },
-            LocalJobInfo::LABEL => {
-                let job = LocalJobInfo::try_from(parameters)?;
-                job_info = JobInfo::LocalJob(Box::new(job));
-            },
-            ServiceRunJobInfo::LABEL => {
-                let job = ServiceRunJobInfo::try_from(parameters)?;
-                job_info = JobInfo::ServiceRunJob(Box::new(job));
-            }
-            _ => return Err(Error::msg(format!["Unsupported job type {}", kind])),
-        }
-        Ok(job_info)
+        // The kind's constructor never sees the annotations
+        let annotations = take_annotations(&mut parameters)?;
+        let mut job = match registered_kind(&kind) {
+            Some(job_kind) => (job_kind.constructor)(parameters)?,
+            None => return Err(Error::msg(format!["Unsupported job type {}", kind])),
+        };
+        job.options_mut().annotations = annotations;
+        Ok(job)
     }
 }
 
 impl JobInfo {
     /// Start scheduling the execution of the job.
-    /// This future should never return unless a fatal configuration error occured
+    ///
+    /// This future only returns once the job will not be triggered anymore: it resolves to
+    /// `Some(true)` when the job's schedule ended normally, and to `Some(false)` when the job's
+    /// schedule can not be computed anymore. An error is returned if the scheduling failed unexpectedly.
     pub async fn start(self, handle: Docker) -> Result<Option<bool>, Error> {
+        let (_stop_sender, stop) = watch::channel(false);
+        let (_clock_sender, clock_jumps) = watch::channel(None);
+        let history = ExecutionHistory::new(self.options().history_size());
+        self.start_until(handle, JobControl::new(stop, clock_jumps).with_history(history)).await
+    }
+
+    /// Start scheduling the execution of the job until `control.stop` changes.
+    ///
+    /// Once stopped, the job is not triggered anymore but the executions that are already
+    /// running are awaited, and the future resolves to `Some(true)` when they end.
+    ///
+    /// When `control.permits` is set, each execution waits for one of its permits before it starts.
+    /// When a clock jump is received, the job's timer is re-armed from the current time and, if an
+    /// occurence was skipped by the jump, a single run is triggered for it.
+    /// The reports of the completed executions are recorded in `control.history`.
+    /// The executions of the jobs that create containers are skipped while `control.spawn_budget`
+    /// is exhausted.
+    /// While `control.state` is [`JobState::Paused`], the schedule is followed but its triggers
+    /// are skipped, so that no missed run is caught up once the job is resumed.
+    pub async fn start_until(self, handle: Docker, control: JobControl) -> Result<Option<bool>, Error> {
+        let (mut stop, mut clock_jumps) = (control.stop.clone(), control.clock_jumps.clone());
         let mut set = JoinSet::new();
 
-        let cron;
-        let may_run_parallel;
-        match_all_jobs!(&self, e, {cron = e.get_schedule(); may_run_parallel = e.may_run_parallel();});
-        let initial_cron = cron.clone();
-        set.spawn(async move {cron_sleep(&initial_cron).await});
-        while let Some(res) = set.join_next().await {
+        let cron = self.as_job().get_schedule();
+        let options = self.options().clone();
+        if !control.dry_run {
+            metrics::record_annotations(self.name(), &options.annotations);
+        }
+        let mut running: Vec<AbortHandle> = vec![];
+        let mut queued = false;
+        // The occurence of the queued run
+        let mut queued_at = None;
+        let mut run_count: u64 = 0;
+        let mut schedule_ended = false;
+        let mut schedule_failed = false;
+        let mut stopping = false;
+        // When the schedule is measured from completion, the timer is only armed once the previous execution ended
+        let after_completion = cron.after_completion().is_some();
+        // Delays measured from completion do not depend on the wall clock
+        let mut watch_clock = !after_completion;
+        let mut last_trigger: Option<chrono::DateTime<chrono::Local>> = None;
+        let mut timer = if options.run_on_start && options.is_active_at(&chrono::Local::now()) {
+            // Trigger the first run right away, the cron sleep is started when it is handled
+            debug!("Triggering job {} on start", self.name());
+            set.spawn(async move {Ok(ExecInfo::Schedule(ExecutionSchedule{ scheduled_at: chrono::Local::now() }))})
+        } else {
+            arm_timer(&mut set, &cron, &options)
+        };
+        loop {
+            let res = tokio::select! {
+                res = set.join_next() => match res {
+                    Some(res) => res,
+                    None => break,
+                },
+                _ = stop.changed(), if !stopping => {
+                    debug!("Job {} is stopping, waiting for running executions to end", self.name());
+                    stopping = true;
+                    timer.abort();
+                    continue;
+                },
+                changed = clock_jumps.changed(), if watch_clock && !stopping && !schedule_ended => {
+                    let Some(jump) = changed.as_ref().ok().and(*clock_jumps.borrow_and_update()) else {
+                        // The daemon does not watch the clock anymore
+                        watch_clock = changed.is_ok();
+                        continue;
+                    };
+                    timer.abort();
+                    // An occurence skipped by the jump is run once, no matter how many were skipped
+                    let missed = jump.skipped()
+                        .and_then(|(before, after)| {
+                            let from = last_trigger.map_or(before, |t| t.max(before));
+                            next_trigger(&cron, &options, &from).ok().flatten().filter(|o| *o <= after)
+                        });
+                    timer = match missed {
+                        Some(scheduled_at) => {
+                            warn!("The run of job {} scheduled at {} was missed during the clock jump", self.name(), scheduled_at);
+                            set.spawn(async move { Ok(ExecInfo::Schedule(ExecutionSchedule { scheduled_at })) })
+                        },
+                        None => {
+                            debug!("Re-arming the timer of job {} after the clock jump", self.name());
+                            arm_timer(&mut set, &cron, &options)
+                        },
+                    };
+                    continue;
+                },
+            };
+            let mut execution_ended = false;
             match res {
-                Ok(Ok(ExecInfo::Schedule(_))) => {
+                Ok(Ok(ExecInfo::Schedule(_))) if stopping => {},
+                Ok(Ok(ExecInfo::Schedule(schedule))) => {
                     // Return from timer
-                    if may_run_parallel || set.is_empty() {
-                        let handle_copy = handle.clone();
-                        match_all_jobs!(&self, e, {
-                            let exec_job = e.as_ref().clone();
-                            set.spawn(async move {
-                                let start_time = time::Instant::now();
-                                let name = exec_job.name.clone();
-                                let e = exec_job.exec(&handle_copy).await;
-                                let duration = time::Instant::now() - start_time;
-                                info!("Job {} ended in {}.{:04} seconds", name, duration.as_secs(), duration.as_millis()%1000);
-                                e
-                            });
-                        });
+                    last_trigger = Some(schedule.scheduled_at);
+                    let mut triggered = false;
+                    let launch_time = chrono::Local::now();
+                    if options.missed_deadline(&schedule.scheduled_at, &launch_time) {
+                        warn!(
+                            "Skipping the run of job {} scheduled at {} as it is launched after its deadline (at {})",
+                            self.name(), schedule.scheduled_at, launch_time,
+                        );
+                    } else if *control.state.borrow() == JobState::Paused {
+                        info!("Job {} is paused, skipping the run scheduled at {}", self.name(), schedule.scheduled_at);
+                    } else {
+                        running.retain(|h| !h.is_finished());
+                        let policy = if running.is_empty() { OverlapPolicy::Allow } else { options.overlap };
+                        match policy {
+                            OverlapPolicy::Skip => warn!(
+                                "Skipping the run of job {} scheduled at {} as a previous run is still in progress",
+                                self.name(), schedule.scheduled_at,
+                            ),
+                            OverlapPolicy::Queue if queued => warn!(
+                                "Skipping the run of job {} scheduled at {} as a run is already queued",
+                                self.name(), schedule.scheduled_at,
+                            ),
+                            OverlapPolicy::Queue => {
+                                debug!("Queueing the run of job {} until its previous run ends", self.name());
+                                run_count += 1;
+                                triggered = true;
+                                queued = true;
+                                queued_at = Some(schedule.scheduled_at);
+                            },
+                            OverlapPolicy::Allow | OverlapPolicy::CancelRunning => {
+                                if policy == OverlapPolicy::CancelRunning {
+                                    warn!("Cancelling the {} running executions of job {} to start a new one", running.len(), self.name());
+                                    running.drain(..).for_each(|h| h.abort());
+                                }
+                                run_count += 1;
+                                triggered = true;
+                                running.push(self.spawn_execution(&mut set, &handle, &control, Some(schedule.scheduled_at)));
+                            },
+                        }
                     }
-                    let cron = cron.clone();
-                    set.spawn(async move {cron_sleep(&cron).await});
+                    if options.max_runs.is_some_and(|m| run_count >= m) {
+                        debug!("Job {} reached its maximum number of runs, waiting for running executions to end", self.name());
+                        schedule_ended = true;
+                    } else if !(after_completion && triggered) {
+                        timer = arm_timer(&mut set, &cron, &options);
+                    }
+                },
+                Ok(Ok(ExecInfo::Skipped)) => execution_ended = true,
+                Ok(Ok(ExecInfo::ScheduleEnd)) => {
+                    debug!("Job {} has no occurence left before its not-after date, waiting for running executions to end", self.name());
+                    schedule_ended = true;
                 },
                 Ok(Ok(ExecInfo::Report(r))) => {
-                    info!("Job ended successfully: {} - {:?}", self.name(), r);
+                    // The outcome is logged in the run's span so that it is correlated with its execution
+                    let span = r.run_id.map_or_else(tracing::Span::none, |id| run_span(self.name(), self.kind(), id, None));
+                    span.in_scope(|| if r.timed_out {
+                        let count = record_error(ErrorCategory::Timeout);
+                        error!(category = %ErrorCategory::Timeout, "Job {} timed out ({} {} errors so far)", self.name(), count, ErrorCategory::Timeout);
+                    } else {
+                        if r.output_truncated {
+                            warn!("The output of job {} exceeded its max-output-size of {} bytes, the rest was dropped", self.name(), options.max_output_size());
+                        }
+                        // Captured and streamed output was already logged as it was produced
+                        if !options.stream_logs {
+                            for (stream, line) in r.lines(options.merge_streams) {
+                                log_output_line(self.name(), stream, line);
+                            }
+                        }
+                        if r.retval == 0 {
+                            info!("Job ended successfully: {} - exit code {}", self.name(), r.retval);
+                        } else {
+                            warn!("Job failed: {} - exit code {}", self.name(), r.retval);
+                        }
+                    });
+                    execution_ended = true;
+                    // With max-consecutive-failures, the failures that are tolerated do not trigger the on-failure policy
+                    let exceeded = options.max_consecutive_failures.map(|max| r.consecutive_failures > max);
+                    if exceeded == Some(true) {
+                        span.in_scope(|| error!(
+                            "Job {} failed {} times in a row, more than its max-consecutive-failures of {}: {}",
+                            self.name(), r.consecutive_failures, options.max_consecutive_failures.unwrap_or_default(), failure_excerpt(&r),
+                        ));
+                    }
+                    if (r.timed_out || r.retval != 0) && exceeded != Some(false) {
+                        match options.on_failure {
+                            OnFailure::Continue => {},
+                            OnFailure::StopJob if stopping => {},
+                            OnFailure::StopJob => {
+                                warn!("Job {} failed, waiting for its running executions to end before it stops", self.name());
+                                stopping = true;
+                                timer.abort();
+                            },
+                            OnFailure::StopDaemon => return Err(Error::new(StopDaemonError { job: self.name().clone() })),
+                        }
+                    }
+                },
+                Ok(Err(e)) if e.is::<NoOccurrenceError>() => {
+                    error!("Job {} will not be scheduled anymore: {}", self.name(), e);
+                    schedule_failed = true;
                 },
                 Ok(Err(e)) => {
-                    error!("An error occured while running job {}: {}", self.name(), e);
-                    // break;
+                    let category = ErrorCategory::of(&e);
+                    let count = record_error(category);
+                    error!(category = %category, "An error occured while running job {}: {} ({} {} errors so far)", self.name(), e, count, category);
+                    execution_ended = true;
                 },
+                Err(e) if e.is_cancelled() => debug!("An execution of job {} was cancelled", self.name()),
                 Err(e) => {
                     error!("A join error occured while running job {}: {}", self.name(), e);
                     return Err(Error::new(e));
                 }
             }
+            if execution_ended && !stopping {
+                running.retain(|h| !h.is_finished());
+                if queued && running.is_empty() && *control.state.borrow() == JobState::Paused {
+                    info!("Job {} is paused, skipping its queued run", self.name());
+                    queued = false;
+                    if after_completion && !schedule_ended {
+                        timer = arm_timer(&mut set, &cron, &options);
+                    }
+                } else if queued && running.is_empty() {
+                    debug!("Triggering the queued run of job {}: {}", self.name(), self.describe_action());
+                    queued = false;
+                    running.push(self.spawn_execution(&mut set, &handle, &control, queued_at.take()));
+                } else if after_completion && !schedule_ended {
+                    timer = arm_timer(&mut set, &cron, &options);
+                }
+            }
+        }
+        if schedule_failed {
+            return Ok(Some(false));
+        }
+        if stopping {
+            info!("Job {} was stopped after {} runs", self.name(), run_count);
+            return Ok(Some(true));
+        }
+        if schedule_ended {
+            info!("Job {} ran {} times and will not be scheduled anymore", self.name(), run_count);
+            return Ok(Some(true));
         }
         Err(Error::msg(format!("The job {} unexpectedly exhausted all its runners", self.name())))
     }
 
+    /// Spawn an execution of the job's action in the provided set.
+    ///
+    /// In dry-run mode, the action is only logged and an empty successful report is produced in its
+    /// place, which is only recorded in the job's history.
+    /// The execution runs in a [`run` span][run_span] that identifies it in the logs.
+    fn spawn_execution(&self, set: &mut JoinSet<Result<ExecInfo, Error>>, handle: &Docker, control: &JobControl, scheduled_at: Option<chrono::DateTime<chrono::Local>>) -> AbortHandle {
+        let run_id = NEXT_EXECUTION_ID.fetch_add(1, Ordering::Relaxed);
+        let span = run_span(self.name(), self.kind(), run_id, scheduled_at);
+        span.in_scope(|| debug!("Triggering job {}: {}", self.name(), self.describe_action()));
+        let dry_run = control.dry_run;
+        let exec: BoxFuture<'static, Result<ExecInfo, Error>> = match dry_run {
+            true => {
+                let (name, kind, action) = (self.name().clone(), self.kind().to_string(), self.describe_action());
+                Box::pin(async move {
+                    info!("Dry run: would run job {} ({}) now, it would {}", name, kind, action);
+                    Ok(ExecInfo::Report(ExecutionReport::default()))
+                })
+            },
+            false => self.as_job().exec(handle),
+        };
+        let name = self.name().clone();
+        let kind = self.kind().to_string();
+        let timeout = self.options().timeout;
+        let group = self.options().group.clone().map(|g| (control.groups.get(&g), g));
+        let group_wait_timeout = self.options().group_wait_timeout;
+        let (permits, history) = (control.permits.clone(), control.history.clone());
+        let priority = self.options().priority;
+        let spawn_budget = control.spawn_budget.clone().filter(|_| !dry_run && self.creates_containers());
+        let hooks = (self.options().on_success.clone(), self.options().on_error.clone());
+        let from_labels = self.options().from_labels;
+        #[cfg(feature = "webhook")]
+        let (webhook_url, slack_muted, annotations) = (self.options().webhook_url.clone(), self.options().slack_muted, self.options().annotations.clone());
+        set.spawn(async move {
+            // The group lock and the permit are released when the task ends, even if it is aborted
+            let _group_guard = match group {
+                Some((lock, group)) => match lock_group(&name, &group, lock, group_wait_timeout).await {
+                    Some(guard) => Some(guard),
+                    None => return Ok(ExecInfo::Skipped),
+                },
+                None => None,
+            };
+            let _permit = match permits {
+                Some(permits) => Some(permits.acquire(&name, priority).await?),
+                None => None,
+            };
+            if spawn_budget.is_some_and(|budget| !budget.try_spawn(&name, time::Instant::now())) {
+                metrics::record_spawn_skipped(&name);
+                return Ok(ExecInfo::Skipped);
+            }
+            let (start_time, started_at) = (time::Instant::now(), chrono::Local::now());
+            let _running = RunningExecution::register(run_id, &name);
+            let mut e = exec_with_timeout(&name, timeout, exec_catching_panics(&name, exec)).await;
+            let duration = time::Instant::now() - start_time;
+            let finished_at = chrono::Local::now();
+            if let Ok(ExecInfo::Report(report)) = &mut e {
+                report.run_id = Some(run_id);
+                (report.scheduled_at, report.started_at, report.finished_at) = (scheduled_at, Some(started_at), Some(finished_at));
+            }
+            info!(
+                "Job {} ended in {}.{:04} seconds (scheduled at {}, started at {}, finished at {})",
+                name, duration.as_secs(), duration.as_millis()%1000,
+                scheduled_at.map_or("-".to_string(), |t| t.to_rfc3339()), started_at.to_rfc3339(), finished_at.to_rfc3339(),
+            );
+            if !dry_run {
+                metrics::record_execution(&name, &kind, metrics::RunResult::of(&e), duration);
+                #[cfg(feature = "history")]
+                crate::history_db::record_execution(&name, &kind, started_at, duration, &e);
+            }
+            // The runs of a dry run are kept so that the firing times can be checked
+            if let Ok(ExecInfo::Report(report)) = &mut e {
+                report.consecutive_failures = history.record(started_at, duration, report.clone());
+            }
+            if !dry_run {
+                hook::spawn_hook(&name, hooks.0.as_ref(), hooks.1.as_ref(), &e);
+                crate::state::publish(&name, &kind, from_labels, &e, duration);
+            }
+            #[cfg(feature = "webhook")]
+            if !dry_run {
+                let report = crate::report::JobRunReport::of_execution(&name, &kind, &annotations, &e, duration);
+                if report.result != metrics::RunResult::Success && !slack_muted {
+                    crate::slack::notify_failure(report.clone());
+                }
+                if let Some(url) = webhook_url {
+                    crate::webhook::notify(url, report);
+                }
+            }
+            e
+        }.instrument(span))
+    }
+
+    /// Execute the job's action once, outside of its schedule
+    pub async fn run_once(self, handle: &Docker) -> Result<ExecutionReport, Error> {
+        let name = self.name().clone();
+        debug!("Running job {} once: {}", name, self.describe_action());
+        let timeout = self.options().timeout;
+        match exec_with_timeout(&name, timeout, self.as_job().exec(handle)).await? {
+            ExecInfo::Report(report) => Ok(report),
+            other => Err(Error::msg(format!("The job {} returned an unexpected result: {:?}", name, other))),
+        }
+    }
+
+    /// Get a string that uniquely identifies the job's definition.
+    /// Jobs that share the same fingerprint behave identically.
+    ///
+    /// It is the canonical JSON form of the job's sorted [parameters][`JobInfo::to_parameters`],
+    /// so it does not depend on the order or format in which the job was declared.
+    pub fn fingerprint(&self) -> String {
+        // Serializing maps and strings can not fail
+        serde_json::to_string(&self.to_parameters()).unwrap_or_default()
+    }
+
+    /// Get the job's normalized parameter map, including its kind.
+    /// Building a job from this map yields an identical job.
+    pub fn to_parameters(&self) -> BTreeMap<String, Vec<String>> {
+        let mut map = self.as_job().to_parameters();
+        map.insert("kind".to_string(), vec![self.kind().to_string()]);
+        map
+    }
+
+    /// Get the job as a [`Job`] trait object
+    pub fn as_job(&self) -> &dyn Job {
+        match_all_jobs!(self, e, &**e as &dyn Job)
+    }
+
+    /// Get the scheduling options shared by all job kinds
+    pub fn options(&self) -> &JobOptions {
+        self.as_job().options()
+    }
+
+    /// Get a mutable reference to the scheduling options shared by all job kinds
+    pub fn options_mut(&mut self) -> &mut JobOptions {
+        match_all_jobs!(self, e, e.options_mut())
+    }
+
     /// Get the name of the job
     pub fn name(&self) -> &String {
-        match_all_jobs!(self, e, &e.name)
+        self.as_job().name()
+    }
+
+    /// Get the commands executed one after the other when the job is triggered
+    pub fn commands(&self) -> &[String] {
+        self.as_job().commands()
     }
 
-    /// Get the command executed when the job is triggered
-    pub fn command(&self) -> &String {
-        match_all_jobs!(self, e, &e.command)
+    /// Get the command executed when the job is triggered, if the job runs a single command of its own.
+    ///
+    /// Jobs that run several commands return `None`, use [`JobInfo::commands`] to get them.
+    pub fn command(&self) -> Option<&str> {
+        match self.commands() {
+            [command] => Some(command),
+            _ => None,
+        }
+    }
+
+    /// Get the first command executed when the job is triggered, or an empty string if it has none
+    #[deprecated(note = "use command(), or commands() for jobs that run several commands")]
+    pub fn command_string(&self) -> &String {
+        static NO_COMMAND: String = String::new();
+        self.commands().first().unwrap_or(&NO_COMMAND)
+    }
+
+    /// Whether the job creates a container on each execution, which is capped by the [`SpawnBudget`]
+    pub fn creates_containers(&self) -> bool {
+        matches!(self, JobInfo::RunJob(_) | JobInfo::ServiceRunJob(_))
+    }
+
+    /// Get a human-readable description of what the job does when it is triggered
+    pub fn describe_action(&self) -> String {
+        self.as_job().describe_action()
+    }
+
+    /// Get the next time at which the job will be triggered after the provided date, if any
+    pub fn next_occurrence(&self, from: &chrono::DateTime<chrono::Local>) -> Result<Option<chrono::DateTime<chrono::Local>>, Error> {
+        next_trigger(self.as_job().schedule(), self.options(), from)
+    }
+
+    /// Get the next `n` times at which the job will be triggered.
+    ///
+    /// Fewer dates are returned if the job's schedule or activity window ends earlier.
+    pub fn next_occurrences(&self, n: usize) -> Vec<chrono::DateTime<chrono::Local>> {
+        let (schedule, options) = (self.as_job().get_schedule(), self.options());
+        let mut occurrences = Vec::with_capacity(n);
+        let mut from = chrono::Local::now();
+        while occurrences.len() < n {
+            match next_trigger(&schedule, options, &from) {
+                Ok(Some(next)) => {
+                    occurrences.push(next);
+                    from = next;
+                },
+                _ => break,
+            }
+        }
+        occurrences
     }
 
     /// Get the schedule on which the job is executed
     #[deprecated]
-    pub fn schedule(&self) -> &Cron {
-        match_all_jobs!(self, e, &e.schedule)
+    pub fn schedule(&self) -> &Schedule {
+        self.as_job().schedule()
     }
 
     /// Get the job's type as a str
     pub fn kind(&self) -> &str {
-        match self {
-            JobInfo::ExecJob(_) => ExecJobInfo::LABEL,
-            JobInfo::RunJob(_) => RunJobInfo::LABEL,
-            JobInfo::LocalJob(_) => LocalJobInfo::LABEL,
-            JobInfo::ServiceRunJob(_) => ServiceRunJobInfo::LABEL,
+        self.as_job().kind()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use anyhow::Error;
+    use bollard::Docker;
+    use futures_util::future::BoxFuture;
+    use tokio::time::{timeout, Duration};
+
+    use super::{ExecutionHistory, ExecutionPermits, JobControl, JobInfo, JobState, DEFAULT_PROMOTION_DELAY};
+
+    fn job(values: &[(&str, &str)]) -> JobInfo {
+        JobInfo::try_from(values.iter()
+            .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+            .collect::<HashMap<String, Vec<String>>>()).unwrap()
+    }
+
+    #[test]
+    fn describe_job_actions() {
+        let schedule = ("schedule", "@hourly");
+        let exec = job(&[("kind", "job-exec"), schedule, ("command", "pg_dump db"), ("container", "db")]);
+        assert_eq!(exec.describe_action(), "exec 'pg_dump db' in db");
+        assert_eq!(exec.command(), Some("pg_dump db"));
+        let run = job(&[("kind", "job-run"), schedule, ("image", "alpine")]);
+        assert_eq!(run.describe_action(), "run the default command in a new alpine container");
+        assert_eq!(run.command(), None);
+        let run = job(&[("kind", "job-run"), schedule, ("command", "ls"), ("container", "web")]);
+        assert_eq!(run.describe_action(), "run 'ls' in container web");
+        let run = job(&[("kind", "job-run"), schedule, ("image", "alpine"), ("network", "none")]);
+        assert_eq!(run.describe_action(), "run the default command in a new alpine container on network none");
+        let service = job(&[("kind", "job-service-run"), schedule, ("command", "ls"), ("image", "alpine")]);
+        assert_eq!(service.describe_action(), "run 'ls' in a new alpine service");
+        let local = job(&[("kind", "job-local"), schedule, ("command", "ls")]);
+        assert_eq!(local.describe_action(), "run 'ls' on the host");
+        let local = job(&[("kind", "job-local"), schedule, ("command", "ls"), ("sandbox", "unshare")]);
+        assert_eq!(local.describe_action(), "run 'ls' on the host in the unshare sandbox");
+    }
+
+    #[tokio::test]
+    async fn run_several_commands() {
+        let local = |continue_on_error: &str| JobInfo::try_from(HashMap::from([
+            ("kind".to_string(), vec!["job-local".to_string()]),
+            ("schedule".to_string(), vec!["@hourly".to_string()]),
+            ("command".to_string(), vec!["echo migrated".to_string(), "false".to_string(), "echo warmed".to_string()]),
+            ("continue-on-error".to_string(), vec![continue_on_error.to_string()]),
+        ])).unwrap();
+        let handle = Docker::connect_with_http_defaults().unwrap();
+        let job = local("false");
+        assert_eq!(job.describe_action(), "run 'echo migrated', then 'false', then 'echo warmed' on the host");
+        assert_eq!(job.command(), None);
+        assert_eq!(job.commands(), ["echo migrated", "false", "echo warmed"]);
+        #[allow(deprecated)]
+        let first = job.command_string();
+        assert_eq!(first, "echo migrated");
+        let report = job.run_once(&handle).await.unwrap();
+        assert_eq!(report.retval, 1);
+        assert_eq!(report.stdout.as_deref(), Some("--- command 1/3: echo migrated ---\nmigrated\n"));
+        assert_eq!(report.commands.iter().map(|c| c.retval).collect::<Vec<_>>(), vec![0, 1]);
+        let report = local("true").run_once(&handle).await.unwrap();
+        assert_eq!(report.retval, 1);
+        assert_eq!(report.commands.len(), 3);
+        assert!(report.stdout.unwrap().ends_with("--- command 3/3: echo warmed ---\nwarmed\n"));
+    }
+
+    #[tokio::test]
+    async fn run_once_reports_output() {
+        let job = job(&[("kind", "job-local"), ("name", "once"), ("schedule", "@yearly"), ("command", "sh -c 'echo out; exit 3'")]);
+        let report = job.run_once(&Docker::connect_with_http_defaults().unwrap()).await.unwrap();
+        assert_eq!(report.retval, 3);
+        assert_eq!(report.stdout.as_deref(), Some("out\n"));
+        assert_eq!(report.stderr, None);
+    }
+
+    #[tokio::test]
+    async fn run_once_separates_streams() {
+        let streams = job(&[("kind", "job-local"), ("name", "streams"), ("schedule", "@yearly"), ("command", "sh -c 'echo OUT_MARKER; echo ERR_MARKER >&2'")]);
+        let report = streams.run_once(&Docker::connect_with_http_defaults().unwrap()).await.unwrap();
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        report.write_output(false, &mut stdout, &mut stderr).unwrap();
+        assert_eq!(String::from_utf8(stdout).unwrap(), "OUT_MARKER\n");
+        assert_eq!(String::from_utf8(stderr).unwrap(), "ERR_MARKER\n");
+    }
+
+    #[tokio::test]
+    async fn captured_streams_are_not_reported() {
+        let command = "sh -c 'head -c 200000 /dev/zero | tr \"\\\\0\" x; echo; echo ERR_MARKER >&2'";
+        let captured = job(&[("kind", "job-local"), ("name", "captured"), ("schedule", "@yearly"), ("command", command), ("capture-stdout", "true")]);
+        assert!(captured.to_parameters().contains_key("capture-stdout"));
+        let report = captured.run_once(&Docker::connect_with_http_defaults().unwrap()).await.unwrap();
+        assert_eq!(report.retval, 0);
+        assert_eq!(report.stdout, None);
+        assert_eq!(report.stderr.as_deref(), Some("ERR_MARKER\n"));
+        // Run jobs do not capture their output
+        let run = job(&[("kind", "job-run"), ("name", "run"), ("schedule", "@yearly"), ("image", "alpine"), ("capture-stdout", "true")]);
+        assert!(!run.to_parameters().contains_key("capture-stdout"));
+        // Streamed output is also kept in the report, unless its stream is captured
+        let streamed = job(&[("kind", "job-local"), ("name", "streamed"), ("schedule", "@yearly"), ("command", command),
+            ("stream-logs", "true"), ("capture-stderr", "true")]);
+        assert!(streamed.to_parameters().contains_key("stream-logs"));
+        let report = streamed.run_once(&Docker::connect_with_http_defaults().unwrap()).await.unwrap();
+        assert_eq!(report.stdout.map(|s| s.len()), Some(200_001));
+        assert_eq!(report.stderr, None);
+    }
+
+    #[tokio::test]
+    async fn truncated_output_is_reported() {
+        let handle = Docker::connect_with_http_defaults().unwrap();
+        let chatty = job(&[("kind", "job-local"), ("name", "chatty"), ("schedule", "@yearly"), ("command", "sh -c 'echo 0123456789; echo err >&2'"), ("max-output-size", "4")]);
+        let report = chatty.run_once(&handle).await.unwrap();
+        assert!(report.output_truncated);
+        assert_eq!(report.stdout.as_deref(), Some("0123\n… (truncated 7 bytes)\n"));
+        // The limit applies to each stream separately
+        assert_eq!(report.stderr.as_deref(), Some("err\n"));
+        let quiet = job(&[("kind", "job-local"), ("name", "quiet"), ("schedule", "@yearly"), ("command", "echo ok")]);
+        assert!(!quiet.run_once(&handle).await.unwrap().output_truncated);
+    }
+
+    /// A job kind that is not built into cfc, which reports a message as its output
+    #[derive(Clone, Debug, PartialEq)]
+    struct EchoJob {
+        name: String,
+        schedule: super::Schedule,
+        message: String,
+        options: super::JobOptions,
+    }
+
+    impl EchoJob {
+        const KEYS: &'static [super::KeySpec] = &[super::KeySpec::new("message", super::KeyType::String, "hello").required()];
+    }
+
+    impl super::Job for EchoJob {
+        fn name(&self) -> &String {
+            &self.name
+        }
+        fn kind(&self) -> &str {
+            "job-echo"
+        }
+        fn options(&self) -> &super::JobOptions {
+            &self.options
+        }
+        fn options_mut(&mut self) -> &mut super::JobOptions {
+            &mut self.options
+        }
+        fn schedule(&self) -> &super::Schedule {
+            &self.schedule
+        }
+        fn describe_action(&self) -> String {
+            format!("echo '{}'", self.message)
+        }
+        fn to_parameters(&self) -> BTreeMap<String, Vec<String>> {
+            let mut map = BTreeMap::from([
+                ("name".to_string(), vec![self.name.clone()]),
+                ("message".to_string(), vec![self.message.clone()]),
+            ]);
+            self.schedule.put_into(&mut map);
+            self.options.put_into(&mut map);
+            map
+        }
+        fn exec(&self, _: &Docker) -> BoxFuture<'static, Result<super::ExecInfo, Error>> {
+            let stdout = Some(self.message.clone());
+            Box::pin(async move {
+                if stdout.as_deref() == Some("panic") {
+                    panic!("echo panicked");
+                }
+                Ok(super::ExecInfo::Report(super::ExecutionReport { stdout, ..Default::default() }))
+            })
+        }
+        fn clone_job(&self) -> Box<dyn super::Job> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn register_custom_job_kind() {
+        assert!(!super::is_registered_kind("job-echo"));
+        super::register_job_kind("job-echo", super::JobKind {
+            keys: EchoJob::KEYS,
+            unsafe_from_labels: false,
+            constructor: |mut p| {
+                let name = p.remove("name").and_then(|mut n| n.pop()).unwrap_or_default();
+                Ok(JobInfo::Custom(Box::new(EchoJob {
+                    schedule: super::take_schedule(&mut p, &name)?,
+                    message: p.remove("message").and_then(|mut m| m.pop()).ok_or_else(|| Error::msg("No message"))?,
+                    options: super::JobOptions::take_from(&mut p)?,
+                    name,
+                })))
+            },
+        });
+        assert!(super::registered_kinds().contains(&"job-echo".to_string()));
+        assert_eq!(super::suggest_key("job-echo", "mesage"), Some("message"));
+
+        let echo = job(&[("kind", "job-echo"), ("name", "echo"), ("schedule", "@yearly"), ("message", "hello"), ("timeout", "5s")]);
+        assert_eq!(echo.kind(), "job-echo");
+        assert_eq!(echo.describe_action(), "echo 'hello'");
+        assert_eq!(echo.options().timeout, Some(Duration::from_secs(5)));
+        assert!(echo.as_job().may_run_parallel());
+        // The job can be rebuilt from its parameters
+        let parameters = echo.to_parameters().into_iter().collect::<HashMap<_, _>>();
+        assert_eq!(JobInfo::try_from(parameters).unwrap(), echo.clone());
+        assert_ne!(echo, job(&[("kind", "job-echo"), ("name", "echo"), ("schedule", "@yearly"), ("message", "bye")]));
+
+        let report = echo.run_once(&Docker::connect_with_http_defaults().unwrap()).await.unwrap();
+        assert_eq!(report.stdout.as_deref(), Some("hello"));
+    }
+
+    #[tokio::test]
+    async fn track_running_executions() {
+        let job = job(&[("kind", "job-local"), ("name", "tracked"), ("schedule", "@yearly"), ("command", "sleep 0.5"),
+            ("run-on-start", "true"), ("max-runs", "1")]);
+        let tracked = || super::running_executions().iter().filter(|(name, _)| name == "tracked").count();
+        let task = tokio::spawn(job.start(Docker::connect_with_http_defaults().unwrap()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(tracked(), 1);
+        timeout(Duration::from_secs(5), task).await.unwrap().unwrap().unwrap();
+        assert_eq!(tracked(), 0);
+    }
+
+    #[tokio::test]
+    async fn timeout_kills_local_command() {
+        let trace = std::env::temp_dir().join(format!("cfc-timeout-{}", std::process::id()));
+        let _ = std::fs::remove_file(&trace);
+        let command = format!("sh -c 'sleep 2; touch {}'", trace.display());
+        let slow = job(&[("kind", "job-local"), ("name", "slow"), ("schedule", "@yearly"), ("command", &command), ("timeout", "1s")]);
+        let started = std::time::Instant::now();
+        let report = slow.run_once(&Docker::connect_with_http_defaults().unwrap()).await.unwrap();
+        assert!(report.timed_out);
+        assert!(started.elapsed() < Duration::from_millis(1900));
+        // The command does not outlive its execution
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!trace.exists());
+
+        let fast = job(&[("kind", "job-local"), ("name", "fast"), ("schedule", "@yearly"), ("command", "true"), ("timeout", "1s")]);
+        assert!(!fast.run_once(&Docker::connect_with_http_defaults().unwrap()).await.unwrap().timed_out);
+    }
+
+    #[test]
+    fn preview_next_occurrences() {
+        let hourly = job(&[("kind", "job-local"), ("name", "hourly"), ("schedule", "@hourly"), ("command", "true")]);
+        let next = hourly.next_occurrences(3);
+        assert_eq!(next.len(), 3);
+        assert!(next.windows(2).all(|w| w[1] - w[0] == chrono::Duration::hours(1)));
+
+        let never = job(&[("kind", "job-local"), ("name", "never"), ("schedule", "0 0 0 30 2 *"), ("command", "true")]);
+        assert!(never.next_occurrences(3).is_empty());
+        let ended = job(&[("kind", "job-local"), ("name", "ended"), ("schedule", "@hourly"), ("command", "true"),
+            ("not-after", "2000-01-01T00:00:00Z")]);
+        assert!(ended.next_occurrences(3).is_empty());
+    }
+
+    #[test]
+    fn fingerprint_follows_parameters() {
+        let hourly = job(&[("kind", "job-local"), ("name", "hourly"), ("schedule", "@hourly"), ("command", "true")]);
+        let reordered = job(&[("command", "true"), ("schedule", "@hourly"), ("name", "hourly"), ("kind", "job-local")]);
+        assert_eq!(hourly.fingerprint(), reordered.fingerprint());
+        let daily = job(&[("kind", "job-local"), ("name", "hourly"), ("schedule", "@daily"), ("command", "true")]);
+        assert_ne!(hourly.fingerprint(), daily.fingerprint());
+        assert_eq!(hourly.fingerprint(), serde_json::to_string(&hourly.to_parameters()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn after_completion_never_overlaps() {
+        let trace = std::env::temp_dir().join(format!("cfc-after-{}", std::process::id()));
+        let _ = std::fs::remove_file(&trace);
+        let command = format!("sh -c 'echo start >> {0}; sleep 0.5; echo end >> {0}'", trace.display());
+        let job = job(&[("kind", "job-local"), ("name", "after"), ("schedule", "@after 1s"), ("command", &command),
+            ("run-on-start", "true"), ("max-runs", "2")]);
+        let started = std::time::Instant::now();
+        let result = timeout(Duration::from_secs(10), job.start(Docker::connect_with_http_defaults().unwrap())).await.unwrap();
+        assert_eq!(result.unwrap(), Some(true));
+        // The second run waits for the end of the first one, even though jobs may run in parallel
+        assert!(started.elapsed() >= Duration::from_millis(2000));
+        assert_eq!(std::fs::read_to_string(&trace).unwrap(), "start\nend\nstart\nend\n");
+        std::fs::remove_file(&trace).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn after_completion_arms_timer_on_completion() {
+        // Fast executions: each run is triggered the interval after the end of the previous one
+        let fast = job(&[("kind", "job-local"), ("name", "fast"), ("schedule", "@after 10m"), ("command", "true"),
+            ("run-on-start", "true"), ("max-runs", "3")]);
+        let started = tokio::time::Instant::now();
+        assert_eq!(fast.start(Docker::connect_with_http_defaults().unwrap()).await.unwrap(), Some(true));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(1200) && elapsed < Duration::from_secs(1260), "{:?}", elapsed);
+
+        // Without run-on-start, the first run waits for the interval after startup
+        let slow = job(&[("kind", "job-local"), ("name", "slow"), ("schedule", "@after 10m"), ("command", "sleep 0.2"),
+            ("max-runs", "2")]);
+        let started = tokio::time::Instant::now();
+        assert_eq!(slow.start(Docker::connect_with_http_defaults().unwrap()).await.unwrap(), Some(true));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(1200) && elapsed < Duration::from_secs(1260), "{:?}", elapsed);
+    }
+
+    /// Run a job that fires every second and takes longer than that, returning the trace of its executions
+    async fn overlapping_runs(policy: &str) -> String {
+        let trace = std::env::temp_dir().join(format!("cfc-overlap-{}-{}", policy, std::process::id()));
+        let _ = std::fs::remove_file(&trace);
+        let command = format!("sh -c 'echo start >> {0}; sleep 1.5; echo end >> {0}'", trace.display());
+        let job = job(&[("kind", "job-local"), ("name", policy), ("schedule", "* * * * * *"), ("command", &command),
+            ("run-on-start", "true"), ("max-runs", "2"), ("overlap-policy", policy)]);
+        let result = timeout(Duration::from_secs(10), job.start(Docker::connect_with_http_defaults().unwrap())).await.unwrap();
+        assert_eq!(result.unwrap(), Some(true));
+        let content = std::fs::read_to_string(&trace).unwrap();
+        std::fs::remove_file(&trace).unwrap();
+        content
+    }
+
+    #[tokio::test]
+    async fn overlap_policies() {
+        let (allow, skip, queue, cancel) = tokio::join!(
+            overlapping_runs("allow"),
+            overlapping_runs("skip"),
+            overlapping_runs("queue"),
+            overlapping_runs("cancel-running"),
+        );
+        assert_eq!(allow, "start\nstart\nend\nend\n");
+        assert_eq!(skip, "start\nend\nstart\nend\n");
+        assert_eq!(queue, "start\nend\nstart\nend\n");
+        // The first run is killed before it ends
+        assert_eq!(cancel, "start\nstart\nend\n");
+    }
+
+    #[tokio::test]
+    async fn run_on_start_triggers_immediately() {
+        let job = JobInfo::try_from(HashMap::from([
+            ("kind".to_string(), vec!["job-local".to_string()]),
+            ("name".to_string(), vec!["on_start".to_string()]),
+            ("command".to_string(), vec!["true".to_string()]),
+            ("schedule".to_string(), vec!["@yearly".to_string()]),
+            ("run-on-start".to_string(), vec!["true".to_string()]),
+            ("max-runs".to_string(), vec!["1".to_string()]),
+        ])).unwrap();
+        let handle = Docker::connect_with_http_defaults().unwrap();
+        let result = timeout(Duration::from_secs(5), job.start(handle)).await.unwrap();
+        assert_eq!(result.unwrap(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn schedule_without_occurrence_ends_job() {
+        let job = JobInfo::try_from(HashMap::from([
+            ("kind".to_string(), vec!["job-local".to_string()]),
+            ("name".to_string(), vec!["never".to_string()]),
+            ("command".to_string(), vec!["true".to_string()]),
+            ("schedule".to_string(), vec!["0 0 0 30 2 *".to_string()]),
+        ])).unwrap();
+        let handle = Docker::connect_with_http_defaults().unwrap();
+        let result = timeout(Duration::from_secs(5), job.start(handle)).await.unwrap();
+        assert_eq!(result.unwrap(), Some(false));
+    }
+
+    #[tokio::test]
+    async fn on_failure_policies() {
+        let failing = |policy: &str| job(&[("kind", "job-local"), ("name", "failing"), ("schedule", "* * * * * *"),
+            ("command", "false"), ("run-on-start", "true"), ("on-failure", policy)]);
+        let handle = Docker::connect_with_http_defaults().unwrap();
+        // The job is stopped after its first failure instead of running every second
+        let result = timeout(Duration::from_secs(5), failing("stop-job").start(handle.clone())).await.unwrap();
+        assert_eq!(result.unwrap(), Some(true));
+        let error = timeout(Duration::from_secs(5), failing("stop-daemon").start(handle.clone())).await.unwrap().unwrap_err();
+        assert_eq!(error.downcast_ref::<super::StopDaemonError>().unwrap().job, "failing");
+        // Successful runs do not trigger the policy
+        let passing = job(&[("kind", "job-local"), ("name", "passing"), ("schedule", "* * * * * *"), ("command", "true"),
+            ("run-on-start", "true"), ("max-runs", "2"), ("on-failure", "stop-daemon")]);
+        let result = timeout(Duration::from_secs(5), passing.start(handle)).await.unwrap();
+        assert_eq!(result.unwrap(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn max_consecutive_failures() {
+        use tokio::sync::watch;
+
+        let (_stop_sender, stop) = watch::channel(false);
+        let (_clock_sender, clock_jumps) = watch::channel(None);
+        let history = ExecutionHistory::default();
+        let control = JobControl::new(stop, clock_jumps).with_history(history.clone());
+        let job = job(&[("kind", "job-local"), ("name", "flaky"), ("schedule", "* * * * * *"), ("command", "sh -c 'echo disk full >&2; false'"),
+            ("run-on-start", "true"), ("on-failure", "stop-job"), ("max-consecutive-failures", "2")]);
+        let result = timeout(Duration::from_secs(10), job.start_until(Docker::connect_with_http_defaults().unwrap(), control)).await.unwrap();
+        assert_eq!(result.unwrap(), Some(true));
+        // The job is only stopped once its third failure in a row exceeds the limit
+        let entries = history.entries();
+        assert_eq!(entries.iter().map(|e| e.report.consecutive_failures).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(super::failure_excerpt(&entries[2].report), "the last run exited with code 1: disk full");
+    }
+
+    #[tokio::test]
+    async fn execution_permits_limit_parallel_runs() {
+        use tokio::sync::watch;
+
+        let trace = std::env::temp_dir().join(format!("cfc-permits-{}", std::process::id()));
+        let _ = std::fs::remove_file(&trace);
+        let command = format!("sh -c 'echo start >> {0}; sleep 0.5; echo end >> {0}'", trace.display());
+        let permits = ExecutionPermits::new(1, DEFAULT_PROMOTION_DELAY);
+        let (_stop_sender, stop) = watch::channel(false);
+        let (_clock_sender, clock_jumps) = watch::channel(None);
+        let control = JobControl::new(stop, clock_jumps).with_permits(Some(permits.clone()));
+        let handle = Docker::connect_with_http_defaults().unwrap();
+        let runs = ["first", "second"].map(|name| job(&[("kind", "job-local"), ("name", name), ("schedule", "@yearly"),
+            ("command", &command), ("run-on-start", "true"), ("max-runs", "1")])
+            .start_until(handle.clone(), control.clone()));
+        let [first, second] = timeout(Duration::from_secs(5), futures_util::future::join_all(runs)).await.unwrap().try_into().unwrap();
+        assert_eq!((first.unwrap(), second.unwrap()), (Some(true), Some(true)));
+        // The executions of both jobs ran one after the other and released their permit
+        assert_eq!(std::fs::read_to_string(&trace).unwrap(), "start\nend\nstart\nend\n");
+        assert_eq!(permits.available_permits(), 1);
+        std::fs::remove_file(&trace).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clock_jump_triggers_missed_run() {
+        use chrono::{Datelike, TimeZone};
+        use tokio::sync::watch;
+
+        use crate::clock::ClockJump;
+
+        // The yearly occurence of New Year's Day was skipped while the host was suspended
+        let after = chrono::Local::now();
+        let before = chrono::Local.with_ymd_and_hms(after.year(), 1, 1, 0, 0, 0).unwrap() - chrono::Duration::hours(1);
+        let (_stop_sender, stop) = watch::channel(false);
+        let (clock_sender, clock_jumps) = watch::channel(None);
+        clock_sender.send_replace(Some(ClockJump { before, after, gap: after - before }));
+        let job = job(&[("kind", "job-local"), ("name", "new_year"), ("schedule", "0 0 0 1 1 *"), ("command", "true"), ("max-runs", "1")]);
+        let started = tokio::time::Instant::now();
+        let result = job.start_until(Docker::connect_with_http_defaults().unwrap(), JobControl::new(stop, clock_jumps)).await;
+        assert_eq!(result.unwrap(), Some(true));
+        // The missed run was triggered right away instead of waiting for next year's
+        assert!(started.elapsed() < Duration::from_secs(60), "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn dry_run_does_not_execute() {
+        use tokio::sync::watch;
+
+        let trace = std::env::temp_dir().join(format!("cfc-dry-run-{}", std::process::id()));
+        let _ = std::fs::remove_file(&trace);
+        let (_stop_sender, stop) = watch::channel(false);
+        let (_clock_sender, clock_jumps) = watch::channel(None);
+        let history = ExecutionHistory::default();
+        let control = JobControl::new(stop, clock_jumps).with_dry_run(true).with_history(history.clone());
+        let job = job(&[("kind", "job-local"), ("name", "dry"), ("schedule", "* * * * * *"), ("command", &format!("touch {}", trace.display())),
+            ("run-on-start", "true"), ("max-runs", "2"), ("on-failure", "stop-daemon")]);
+        let result = timeout(Duration::from_secs(5), job.start_until(Docker::connect_with_http_defaults().unwrap(), control)).await.unwrap();
+        // Both runs were reported as successful without running the command
+        assert_eq!(result.unwrap(), Some(true));
+        assert!(!trace.exists());
+        // The no-op reports are recorded in the history
+        let entries = history.entries();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|e| e.success && e.report.stdout.is_none()));
+    }
+
+    #[tokio::test]
+    async fn panics_fail_single_runs() {
+        use tokio::sync::watch;
+
+        let (_stop_sender, stop) = watch::channel(false);
+        let (_clock_sender, clock_jumps) = watch::channel(None);
+        let history = ExecutionHistory::default();
+        let control = JobControl::new(stop, clock_jumps).with_history(history.clone());
+        let mut parameters = HashMap::from([
+            ("schedule".to_string(), vec!["* * * * * *".to_string()]),
+            ("run-on-start".to_string(), vec!["true".to_string()]),
+            ("max-runs".to_string(), vec!["2".to_string()]),
+        ]);
+        let job = JobInfo::Custom(Box::new(EchoJob {
+            name: "panicking".to_string(),
+            schedule: super::take_schedule(&mut parameters, "panicking").unwrap(),
+            message: "panic".to_string(),
+            options: super::JobOptions::take_from(&mut parameters).unwrap(),
+        }));
+        let result = timeout(Duration::from_secs(5), job.start_until(Docker::connect_with_http_defaults().unwrap(), control)).await.unwrap();
+        // Each run failed with the panic's message, and the job kept being scheduled
+        assert_eq!(result.unwrap(), Some(true));
+        let entries = history.entries();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|e| !e.success && e.report.stderr.as_deref() == Some("The execution panicked: echo panicked")));
+    }
+
+    #[tokio::test]
+    async fn paused_jobs_skip_their_runs() {
+        use tokio::sync::watch;
+
+        let (_stop_sender, stop) = watch::channel(false);
+        let (_clock_sender, clock_jumps) = watch::channel(None);
+        let (state_sender, state) = watch::channel(JobState::Paused);
+        let history = ExecutionHistory::default();
+        let control = JobControl::new(stop, clock_jumps).with_history(history.clone()).with_state(state);
+        let job = job(&[("kind", "job-local"), ("name", "paused"), ("schedule", "* * * * * *"), ("command", "true"),
+            ("run-on-start", "true"), ("max-runs", "2")]);
+        let started = std::time::Instant::now();
+        let run = tokio::spawn(job.start_until(Docker::connect_with_http_defaults().unwrap(), control));
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(history.entries().is_empty());
+        state_sender.send_replace(JobState::Active);
+        let result = timeout(Duration::from_secs(5), run).await.unwrap().unwrap();
+        assert_eq!(result.unwrap(), Some(true));
+        // The runs skipped while paused are not caught up, the next ones follow the schedule
+        let entries = history.entries();
+        assert_eq!(entries.len(), 2);
+        assert!(started.elapsed() >= Duration::from_secs(3), "{:?}", started.elapsed());
+        // Each run is identified in its report
+        let run_ids: Vec<_> = entries.iter().map(|e| e.report.run_id.unwrap()).collect();
+        assert_ne!(run_ids[0], run_ids[1]);
+        for report in entries.iter().map(|e| &e.report) {
+            let (scheduled_at, started_at) = (report.scheduled_at.unwrap(), report.started_at.unwrap());
+            // The timer sleeps a whole number of milliseconds, so a run may start just before its occurence
+            assert!(scheduled_at - chrono::Duration::milliseconds(1) <= started_at && started_at <= report.finished_at.unwrap());
+            assert!(report.duration().is_some());
+        }
+    }
+
+    /// Start two jobs of the same group at once, returning the trace of their executions
+    async fn grouped_runs(name: &str, wait_timeout: Option<&str>) -> String {
+        use tokio::sync::watch;
+
+        let trace = std::env::temp_dir().join(format!("cfc-group-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&trace);
+        let command = format!("sh -c 'echo start >> {0}; sleep 1.5; echo end >> {0}'", trace.display());
+        let (_stop_sender, stop) = watch::channel(false);
+        let (_clock_sender, clock_jumps) = watch::channel(None);
+        let control = JobControl::new(stop, clock_jumps);
+        let handle = Docker::connect_with_http_defaults().unwrap();
+        let runs = ["first", "second"].map(|job_name| {
+            let mut values = vec![("kind", "job-local"), ("name", job_name), ("schedule", "@yearly"), ("command", &command),
+                ("run-on-start", "true"), ("max-runs", "1"), ("group", name)];
+            values.extend(wait_timeout.map(|t| ("group-wait-timeout", t)));
+            job(&values).start_until(handle.clone(), control.clone())
+        });
+        for result in timeout(Duration::from_secs(5), futures_util::future::join_all(runs)).await.unwrap() {
+            assert_eq!(result.unwrap(), Some(true));
         }
+        let content = std::fs::read_to_string(&trace).unwrap();
+        std::fs::remove_file(&trace).unwrap();
+        content
+    }
+
+    #[tokio::test]
+    async fn group_executions_never_overlap() {
+        let (waiting, skipping) = tokio::join!(grouped_runs("waiting", None), grouped_runs("skipping", Some("1s")));
+        assert_eq!(waiting, "start\nend\nstart\nend\n");
+        // The second job gave up waiting for the first one to end
+        assert_eq!(skipping, "start\nend\n");
     }
 }
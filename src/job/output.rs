@@ -0,0 +1,225 @@
+//! Output of the executions logged as it is produced
+//!
+//! The streams that a job captures with its `capture-stdout` and `capture-stderr` keys are
+//! logged line by line, tagged with the job's name, instead of being kept in the execution's
+//! report. With `stream-logs`, the streams are logged the same way and also kept in the report.
+//! Secrets are redacted from each line before it is logged. The streams that are kept are
+//! bounded by the job's `max-output-size`, the output beyond it is dropped.
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::{info, warn};
+
+use super::{common::redact, StreamKind};
+
+/// The length after which a line that is still not terminated is logged anyway
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Log a line of a job's output, stderr lines are logged as warnings
+pub(crate) fn log_output_line(job: &str, stream: StreamKind, line: &str) {
+    match stream {
+        StreamKind::Stdout => info!(stream = %stream, "[{}] {}", job, line),
+        StreamKind::Stderr => warn!(stream = %stream, "[{}] {}", job, line),
+    }
+}
+
+/// Logs the lines of one of a job's output streams as they are produced
+#[derive(Debug)]
+pub(crate) struct OutputLines {
+    job: String,
+    /// The stream the lines are logged as, stderr lines are logged as stdout when the job merges its streams
+    stream: StreamKind,
+    /// The end of the output that is not a complete line yet
+    partial: Vec<u8>,
+    /// Whether the output is also kept in the execution's report
+    keep: bool,
+}
+
+impl OutputLines {
+    pub fn new(job: &str, stream: StreamKind) -> Self {
+        OutputLines { job: job.to_string(), stream, partial: vec![], keep: false }
+    }
+
+    /// Also keep the logged output in the execution's report
+    pub fn kept(mut self) -> Self {
+        self.keep = true;
+        self
+    }
+
+    pub fn keeps_output(&self) -> bool {
+        self.keep
+    }
+
+    /// Log the complete lines of a chunk of output, keeping its last line until it is terminated
+    pub fn push(&mut self, chunk: &[u8]) {
+        for line in self.take_lines(chunk) {
+            log_output_line(&self.job, self.stream, &redact(&line));
+        }
+    }
+
+    /// Log the last line of the output if it was not terminated
+    pub fn flush(&mut self) {
+        if !self.partial.is_empty() {
+            let line = String::from_utf8_lossy(&std::mem::take(&mut self.partial)).into_owned();
+            log_output_line(&self.job, self.stream, &redact(&line));
+        }
+    }
+
+    fn take_lines(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.partial.extend_from_slice(chunk);
+        let mut lines = vec![];
+        while let Some(end) = self.partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            lines.push(line.trim_end_matches(['\n', '\r']).to_string());
+        }
+        while self.partial.len() >= MAX_LINE_LENGTH {
+            // Never split a character that is only partially received
+            let end = match std::str::from_utf8(&self.partial[..MAX_LINE_LENGTH]) {
+                Err(e) if e.error_len().is_none() && e.valid_up_to() > 0 => e.valid_up_to(),
+                _ => MAX_LINE_LENGTH,
+            };
+            let line: Vec<u8> = self.partial.drain(..end).collect();
+            lines.push(String::from_utf8_lossy(&line).into_owned());
+        }
+        lines
+    }
+}
+
+/// Keeps the beginning of one of a job's output streams, up to a number of bytes
+#[derive(Debug)]
+pub(crate) struct OutputBuffer {
+    output: Vec<u8>,
+    max_size: usize,
+    /// The number of bytes that were dropped
+    truncated: usize,
+}
+
+impl OutputBuffer {
+    pub fn new(max_size: usize) -> Self {
+        OutputBuffer { output: vec![], max_size, truncated: 0 }
+    }
+
+    /// Whether some output was dropped
+    pub fn is_truncated(&self) -> bool {
+        self.truncated > 0
+    }
+
+    /// Keep a chunk of output, or the part of it that fits in the buffer
+    pub fn push(&mut self, chunk: &[u8]) {
+        let kept = chunk.len().min(self.max_size - self.output.len());
+        self.output.extend_from_slice(&chunk[..kept]);
+        self.truncated += chunk.len() - kept;
+    }
+
+    /// Decode the output kept, which is followed by a `… (truncated N bytes)` line if some was dropped
+    pub fn finish(mut self) -> Result<Option<String>, std::string::FromUtf8Error> {
+        if self.truncated > 0 {
+            // A character cut by the limit is dropped along with the rest of the output
+            if let Err(e) = std::str::from_utf8(&self.output) {
+                if e.error_len().is_none() {
+                    self.truncated += self.output.len() - e.valid_up_to();
+                    self.output.truncate(e.valid_up_to());
+                }
+            }
+        }
+        let mut output = String::from_utf8(self.output)?;
+        if self.truncated > 0 {
+            if !output.is_empty() && !output.ends_with('\n') {
+                output.push('\n');
+            }
+            output.push_str(&format!("… (truncated {} bytes)\n", self.truncated));
+        }
+        Ok(Some(output).filter(|s| !s.is_empty()))
+    }
+}
+
+/// Read one of a command's output streams to its end.
+///
+/// The output is logged as it is produced when `lines` is set, and `None` is returned unless
+/// `lines` [keeps the output][OutputLines::kept]. Otherwise up to `max_size` bytes of it are returned,
+/// along with whether the rest was dropped.
+pub(crate) async fn read_output(mut reader: impl AsyncRead + Unpin, mut lines: Option<OutputLines>, max_size: usize) -> std::io::Result<(Option<String>, bool)> {
+    let mut kept = OutputBuffer::new(max_size);
+    let mut buffer = vec![0; 8192];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        if let Some(lines) = lines.as_mut() {
+            lines.push(&buffer[..read]);
+        }
+        if lines.as_ref().is_none_or(OutputLines::keeps_output) {
+            kept.push(&buffer[..read]);
+        }
+    }
+    if let Some(lines) = lines.as_mut() {
+        lines.flush();
+    }
+    match lines.is_none_or(|lines| lines.keeps_output()) {
+        true => {
+            let truncated = kept.is_truncated();
+            Ok((kept.finish().unwrap_or_else(|_| Some("FAILED_TO_PARSE_OUTPUT".to_string())), truncated))
+        },
+        false => Ok((None, false)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_output, OutputBuffer, OutputLines, MAX_LINE_LENGTH};
+    use crate::job::StreamKind;
+
+    #[test]
+    fn split_output_lines() {
+        let mut lines = OutputLines::new("backup", StreamKind::Stdout);
+        assert_eq!(lines.take_lines(b"first\r\nsec"), ["first"]);
+        assert_eq!(lines.take_lines(b"ond\n\nthi"), ["second", ""]);
+        assert_eq!(lines.partial, b"thi");
+
+        // Overlong lines are logged without splitting a character
+        let mut lines = OutputLines::new("backup", StreamKind::Stdout);
+        let mut chunk = vec![b'a'; MAX_LINE_LENGTH - 1];
+        chunk.extend_from_slice("é".as_bytes());
+        let taken = lines.take_lines(&chunk[..MAX_LINE_LENGTH]);
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].len(), MAX_LINE_LENGTH - 1);
+        assert_eq!(lines.take_lines(&chunk[MAX_LINE_LENGTH..]), Vec::<String>::new());
+        assert_eq!(lines.take_lines(b"\n"), ["é"]);
+    }
+
+    #[tokio::test]
+    async fn captured_output_is_not_kept() {
+        assert_eq!(read_output(&b"kept\n"[..], None, 1024).await.unwrap(), (Some("kept\n".to_string()), false));
+        assert_eq!(read_output(&b""[..], None, 1024).await.unwrap(), (None, false));
+        assert_eq!(read_output(&b"logged\nunterminated"[..], Some(OutputLines::new("backup", StreamKind::Stderr)), 1024).await.unwrap(), (None, false));
+    }
+
+    #[tokio::test]
+    async fn streamed_output_is_kept() {
+        // A character split across two reads is neither corrupted in the logs nor in the report
+        let output = "début\nfin".as_bytes();
+        let (first, second) = output.split_at(2);
+        let reader = tokio::io::AsyncReadExt::chain(first, second);
+        let (kept, _) = read_output(reader, Some(OutputLines::new("backup", StreamKind::Stdout).kept()), 1024).await.unwrap();
+        assert_eq!(kept.as_deref(), Some("début\nfin"));
+        let mut lines = OutputLines::new("backup", StreamKind::Stdout).kept();
+        assert_eq!(lines.take_lines(first), Vec::<String>::new());
+        assert_eq!(lines.take_lines(second), ["début"]);
+        assert_eq!(read_output(&b"x"[..], Some(OutputLines::new("backup", StreamKind::Stdout).kept()), 0).await.unwrap(), (Some("… (truncated 1 bytes)\n".to_string()), true));
+    }
+
+    #[tokio::test]
+    async fn truncate_long_output() {
+        let output = "x".repeat(20_000);
+        assert_eq!(read_output(output.as_bytes(), None, 10_000).await.unwrap(), (Some(format!("{}\n… (truncated 10000 bytes)\n", "x".repeat(10_000))), true));
+        assert_eq!(read_output(output.as_bytes(), None, 20_000).await.unwrap(), (Some(output), false));
+
+        // Characters are never cut in half
+        let mut buffer = OutputBuffer::new(2);
+        buffer.push("aé\nb".as_bytes());
+        assert_eq!(buffer.finish().unwrap().as_deref(), Some("a\n… (truncated 4 bytes)\n"));
+        let mut buffer = OutputBuffer::new(0);
+        buffer.push(b"dropped");
+        assert_eq!(buffer.finish().unwrap().as_deref(), Some("… (truncated 7 bytes)\n"));
+    }
+}
@@ -1,65 +1,412 @@
-use std::{collections::HashMap, fmt::{Debug, Display, Formatter}};
+use std::{collections::{BTreeMap, HashMap}, fmt::{Debug, Display, Formatter}, str::FromStr};
 
 use anyhow::Error;
-use bollard::Docker;
-use croner::Cron;
-use tracing::warn;
+use bollard::{container::{Config, CreateContainerOptions, LogsOptions, RemoveContainerOptions, WaitContainerOptions}, image::CreateImageOptions, models::HostConfig, network::ConnectNetworkOptions, Docker};
+use futures_util::{future::BoxFuture, StreamExt, TryStreamExt};
+use tracing::{debug, warn};
 
 use crate::{job::common::UNKNOWN_CONTAINER_LABEL, require_one, take_one};
 
-use super::common::{schedule_to_cron, ExecInfo};
+use super::{common::{command_argv, describe_commands, put_all, put_one, resolve_secrets, run_commands, take_commands, take_environment, take_schedule, Schedule, ExecInfo, ExecutionReport, JobOptions, StreamKind}, schema::{warn_unused_keys, KeySpec, KeyType, CONTAINER_ENVIRONMENT_KEYS}, Job};
 
-#[derive(Clone)]
+/// When the image of a run job is pulled before its container is created
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PullPolicy {
+    /// Pull the image before every execution
+    Always,
+    /// Pull the image when it is not present locally
+    #[default]
+    Missing,
+    /// Never pull the image, the execution fails when it is not present locally
+    Never,
+}
+
+impl FromStr for PullPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "always" => Ok(PullPolicy::Always),
+            "missing" => Ok(PullPolicy::Missing),
+            "never" => Ok(PullPolicy::Never),
+            s => Err(Error::msg(format!("Unsupported pull policy '{}', expected one of always, missing or never", s))),
+        }
+    }
+}
+
+impl Display for PullPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PullPolicy::Always => write!(f, "always"),
+            PullPolicy::Missing => write!(f, "missing"),
+            PullPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// What to do when the container a run job creates has the name of an existing container
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NameConflictPolicy {
+    /// Fail the execution and leave the existing container alone
+    #[default]
+    Fail,
+    /// Remove the existing container, e.g. the leftover of a crashed run, and create the new one
+    Replace,
+}
+
+impl FromStr for NameConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "fail" => Ok(NameConflictPolicy::Fail),
+            "replace" => Ok(NameConflictPolicy::Replace),
+            s => Err(Error::msg(format!("Unsupported name conflict policy '{}', expected fail or replace", s))),
+        }
+    }
+}
+
+impl Display for NameConflictPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameConflictPolicy::Fail => write!(f, "fail"),
+            NameConflictPolicy::Replace => write!(f, "replace"),
+        }
+    }
+}
+
+/// Split an image reference in the repository and tag that are pulled, the tag defaults to
+/// `latest` and is empty when the image is referenced by digest
+fn image_tag(image: &str) -> (&str, &str) {
+    if image.contains('@') {
+        return (image, "");
+    }
+    match image.rsplit_once(':') {
+        // The colon of a registry's port is followed by the image's path
+        Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+        _ => (image, "latest"),
+    }
+}
+
+/// The container of an execution, stopped in the background if the execution is dropped before
+/// the container stopped (e.g. on timeout), and removed if the job deletes its containers
+struct PendingContainer<'a> {
+    handle: &'a Docker,
+    job: &'a str,
+    id: &'a str,
+    remove: bool,
+    ended: bool,
+}
+
+impl Drop for PendingContainer<'_> {
+    fn drop(&mut self) {
+        if self.ended {
+            return;
+        }
+        warn!("Stopping the container {} of job '{}', whose execution was interrupted", self.id, self.job);
+        let (handle, job, id, remove) = (self.handle.clone(), self.job.to_string(), self.id.to_string(), self.remove);
+        tokio::spawn(async move {
+            let result = match remove {
+                true => handle.remove_container(&id, Some(RemoveContainerOptions { force: true, ..Default::default() })).await,
+                false => handle.stop_container(&id, None).await,
+            };
+            if let Err(e) = result {
+                warn!("Failed to stop the container {} of job '{}': {}", id, job, e);
+            }
+        });
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct RunJobInfo {
     pub name: String,
-    pub schedule: Cron,
-    pub command: String,
+    pub schedule: Schedule,
+    /// The commands to run one after the other, the image's default command is used if there are none
+    pub command: Vec<String>,
     pub image: Option<String>,
+    /// When the image is pulled before the container is created
+    pub pull: PullPolicy,
     pub user: Option<String>,
     pub network: Option<Vec<String>>,
     pub hostname: Option<String>,
     pub delete: bool,
+    /// The name of the container created from the image, or the existing container to run without an image
     pub container: Option<String>,
+    /// What to do when a container already has the name of the created container
+    pub on_name_conflict: NameConflictPolicy,
     pub tty: bool,
     pub volume: Vec<String>,
     pub environment: Vec<String>,
+    pub options: JobOptions,
 }
 
 impl TryFrom<HashMap<String, Vec<String>>> for RunJobInfo {
     type Error = Error;
 
     fn try_from(mut value: HashMap<String, Vec<String>>) -> Result<Self, Self::Error> {
+        let job = Self::take_from(&mut value)?;
+        warn_unused_keys(Self::LABEL, &job.name, &value);
+        Ok(job)
+    }
+}
+
+impl RunJobInfo {
+    pub const LABEL: &str = "job-run";
+    /// The keys accepted by run jobs, besides the ones shared by all kinds
+    pub const KEYS: &'static [KeySpec] = &[
+        KeySpec::new("command", KeyType::List, "touch /tmp/cfc"),
+        KeySpec::new("image", KeyType::String, "alpine:latest"),
+        KeySpec::new("pull", KeyType::String, "always").default("missing"),
+        KeySpec::new("user", KeyType::String, "nobody"),
+        KeySpec::new("network", KeyType::List, "my-network"),
+        KeySpec::new("hostname", KeyType::String, "cron"),
+        KeySpec::new("delete", KeyType::Bool, "false").default("true"),
+        KeySpec::new("container", KeyType::String, "my-container"),
+        KeySpec::new("on-name-conflict", KeyType::String, "replace").default("fail"),
+        KeySpec::new("tty", KeyType::Bool, "true").default("false"),
+        KeySpec::new("volume", KeyType::List, "/tmp:/tmp:ro"),
+        CONTAINER_ENVIRONMENT_KEYS[0],
+        CONTAINER_ENVIRONMENT_KEYS[1],
+    ];
+
+    /// Build a job from the keys of its parameter map, leaving the keys it does not use
+    pub(crate) fn take_from(value: &mut HashMap<String, Vec<String>>) -> Result<Self, Error> {
+        let name = require_one!(value, "name").unwrap_or_else(|_| "".to_string());
+        let environment = take_environment(value, &name)?;
         let job = RunJobInfo {
-            name: require_one!(value, "name").unwrap_or_else(|_| "".to_string()),
-            schedule: schedule_to_cron(&require_one!(value, "schedule")?.as_str())?,
-            command: require_one!(value, "command")?,
+            schedule: take_schedule(value, &name)?,
+            name,
+            command: take_commands(value, false)?,
             image: take_one!(value, "image")?,
+            pull: take_one!(value, "pull")?.map(|p| p.parse()).transpose()?.unwrap_or_default(),
             user: take_one!(value, "user")?,
             network: value.remove("network"),
             hostname: take_one!(value, "hostname")?,
-            delete: take_one!(value, "delete")?.map_or(Ok(true), |t| t.parse().map_err(|e| Error::new(e)))?,
+            delete: take_one!(value, "delete")?.map_or(Ok(true), |t| t.parse().map_err(Error::new))?,
             container: take_one!(value, "container")?,
-            tty: take_one!(value, "tty")?.map_or(Ok(false), |t| t.parse().map_err(|e| Error::new(e)))?,
-            volume: value.remove("volume").unwrap_or_else(|| Default::default()),
-            environment: value.remove("environment").unwrap_or(Default::default()),
+            on_name_conflict: take_one!(value, "on-name-conflict")?.map(|p| p.parse()).transpose()?.unwrap_or_default(),
+            tty: take_one!(value, "tty")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?,
+            volume: value.remove("volume").unwrap_or_default(),
+            environment,
+            options: JobOptions::take_from(value)?,
         };
-        if !value.is_empty() {
-            warn!("The job key map has excess attributes that will not be used: {:?}", value.keys());
+        if job.pull != PullPolicy::Missing && job.image.is_none() {
+            return Err(Error::msg("The job key pull requires an image"));
+        }
+        if job.on_name_conflict != NameConflictPolicy::Fail && (job.image.is_none() || job.container.is_none()) {
+            return Err(Error::msg("The job key on-name-conflict requires an image and a container"));
         }
         Ok(job)
     }
+    pub async fn exec(mut self, handle: &Docker) -> Result<ExecInfo, Error> {
+        debug!("Executing job '{}': {}", self.name, self.describe_action());
+        self.environment = resolve_secrets(&self.environment)?;
+        let Some(image) = self.image.clone() else {
+            return self.start_existing(handle).await.map(ExecInfo::Report);
+        };
+        self.prepare_image(handle, &image).await?;
+        if self.command.is_empty() {
+            return self.run_container(handle, &image, None).await.map(ExecInfo::Report);
+        }
+        run_commands(&self.command, self.options.continue_on_error, |command| self.run_container(handle, &image, Some(command))).await
+            .map(ExecInfo::Report)
+    }
+
+    /// Run a command, or the image's default command, in a new container
+    async fn run_container(&self, handle: &Docker, image: &str, command: Option<&str>) -> Result<ExecutionReport, Error> {
+        let cmd = command.map(command_argv).transpose()
+            .map_err(|e| Error::msg(format!("Failed to parse the command of run job '{}': {}", self.name, e)))?;
+        if let Some(name) = self.container.as_ref() {
+            self.clear_name(handle, name).await?;
+        }
+        let networks = self.network.as_deref().unwrap_or_default();
+        let config = Config {
+            image: Some(image.to_string()),
+            cmd,
+            user: self.user.clone(),
+            hostname: self.hostname.clone(),
+            tty: Some(self.tty),
+            env: Some(self.environment.clone()),
+            host_config: Some(HostConfig {
+                binds: Some(self.volume.clone()),
+                network_mode: networks.first().cloned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let options = self.container.as_ref().map(|name| CreateContainerOptions { name: name.as_str(), platform: None });
+        let id = handle.create_container(options, config).await
+            .map_err(|e| Error::msg(format!("Failed to create the container of job '{}': {}", self.name, e)))?.id;
+        let mut pending = PendingContainer { handle, job: &self.name, id: &id, remove: self.delete, ended: false };
+        for network in networks.iter().skip(1) {
+            let options = ConnectNetworkOptions { container: id.as_str(), ..Default::default() };
+            handle.connect_network(network, options).await
+                .map_err(|e| Error::msg(format!("Failed to connect the container of job '{}' to network {}: {}", self.name, network, e)))?;
+        }
+        let report = self.wait_container(handle, &id, None).await?;
+        pending.ended = true;
+        if self.delete {
+            if let Err(e) = handle.remove_container(&id, None).await {
+                warn!("Failed to remove the container {} of job '{}': {}", id, self.name, e);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Start the job's existing container, which runs its own command
+    async fn start_existing(&self, handle: &Docker) -> Result<ExecutionReport, Error> {
+        let Some(container) = self.container.as_ref() else {
+            return Err(Error::msg(format!("Job '{}' has neither an image nor a container to run", self.name)));
+        };
+        if !self.command.is_empty() {
+            warn!("The command of job '{}' is ignored, the existing container {} runs its own", self.name, container);
+        }
+        // Only the output of this run is read, not the one of the container's previous runs
+        let since = chrono::Utc::now().timestamp();
+        let mut pending = PendingContainer { handle, job: &self.name, id: container, remove: false, ended: false };
+        let report = self.wait_container(handle, container, Some(since)).await?;
+        pending.ended = true;
+        Ok(report)
+    }
+
+    /// Start a container and wait for it to stop, reading its output as it is produced
+    async fn wait_container(&self, handle: &Docker, container: &str, since: Option<i64>) -> Result<ExecutionReport, Error> {
+        handle.start_container::<String>(container, None).await
+            .map_err(|e| Error::msg(format!("Failed to start the container {} of job '{}': {}", container, self.name, e)))?;
+        let options = LogsOptions { follow: true, stdout: true, stderr: true, since: since.unwrap_or_default(), tail: "all".to_string(), ..Default::default() };
+        let mut report = ExecutionReport::default();
+        let (stdout_lines, stderr_lines) = (self.options.output_lines(&self.name, StreamKind::Stdout), self.options.output_lines(&self.name, StreamKind::Stderr));
+        report.exhaust_stream_lines(handle.logs(container, Some(options)).boxed(), stdout_lines, stderr_lines, self.options.max_output_size()).await?;
+        report.retval = match handle.wait_container(container, None::<WaitContainerOptions<String>>).try_next().await {
+            Ok(Some(response)) => response.status_code,
+            // A non-zero exit code is returned as an error
+            Err(bollard::errors::Error::DockerContainerWaitError { code, .. }) => code,
+            Ok(None) => return Err(Error::msg(format!("The container {} of job '{}' was not waited for", container, self.name))),
+            Err(e) => return Err(Error::msg(format!("Failed to wait for the container {} of job '{}': {}", container, self.name, e))),
+        };
+        debug!("The container {} of job '{}' stopped with exit code {}", container, self.name, report.retval);
+        Ok(report)
+    }
+
+    /// Make sure that no container has the name of the container to create, removing a stale
+    /// one if the job's name conflict policy allows it
+    async fn clear_name(&self, handle: &Docker, name: &str) -> Result<(), Error> {
+        let existing = match handle.inspect_container(name, None).await {
+            Ok(existing) => existing,
+            Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => return Ok(()),
+            Err(e) => return Err(Error::msg(format!("Failed to inspect the container {} of job '{}': {}", name, self.name, e))),
+        };
+        let state = existing.state.as_ref().and_then(|s| s.status).map_or("unknown".to_string(), |s| s.to_string());
+        if self.on_name_conflict == NameConflictPolicy::Fail {
+            return Err(Error::msg(format!(
+                "A container named {} already exists (state {}), remove it or set on-name-conflict = replace on job '{}'",
+                name, state, self.name,
+            )));
+        }
+        warn!(
+            "Removing the stale container {} ({}) of job '{}', which was {} and created on {}",
+            name, existing.id.as_deref().unwrap_or("unknown id"), self.name, state, existing.created.as_deref().unwrap_or("an unknown date"),
+        );
+        let options = RemoveContainerOptions { force: true, ..Default::default() };
+        handle.remove_container(name, Some(options)).await
+            .map_err(|e| Error::msg(format!("Failed to remove the stale container {} of job '{}': {}", name, self.name, e)))
+    }
+
+    /// Make sure that the job's image is present locally, pulling it as the job's pull policy requires
+    async fn prepare_image(&self, handle: &Docker, image: &str) -> Result<(), Error> {
+        prepare_image(handle, image, self.pull, &format!("job '{}'", self.name)).await
+    }
 }
 
-impl RunJobInfo {
-    pub const LABEL: &'static str = "job-run";
-    pub async fn exec(self, _handle: &Docker) -> Result<ExecInfo, Error> {
-        Err(Error::msg("message")) // TODO
+/// Make sure that an image is present locally, pulling it as a pull policy requires.
+/// `owner` names what the image is used by in logs and errors, e.g. `job 'backup'`.
+pub(crate) async fn prepare_image(handle: &Docker, image: &str, pull: PullPolicy, owner: &str) -> Result<(), Error> {
+    let (repository, tag) = image_tag(image);
+    if pull != PullPolicy::Always {
+        match handle.inspect_image(image).await {
+            Ok(_) => return Ok(()),
+            Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) if pull == PullPolicy::Never => {
+                return Err(Error::msg(format!("The image {} of {} is not present locally and its pull policy is never", image, owner)));
+            },
+            Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => {},
+            Err(e) => return Err(Error::msg(format!("Failed to inspect the image {} of {}: {}", image, owner, e))),
+        }
+    }
+    debug!("Pulling the image {} (tag '{}') of {}", repository, tag, owner);
+    let options = CreateImageOptions { from_image: repository, tag, ..Default::default() };
+    let mut progress = handle.create_image(Some(options), None, None);
+    while let Some(info) = progress.next().await {
+        let failed = |e: &dyn Display| Error::msg(format!("Failed to pull the image {} (tag '{}') of {}: {}", repository, tag, owner, e));
+        let info = info.map_err(|e| failed(&e))?;
+        if let Some(error) = info.error {
+            return Err(failed(&error));
+        }
+        debug!("Pulling the image {} of {}: {} {}", image, owner, info.status.unwrap_or_default(), info.progress.unwrap_or_default());
+    }
+    Ok(())
+}
+
+impl Job for RunJobInfo {
+    fn name(&self) -> &String {
+        &self.name
+    }
+    fn kind(&self) -> &str {
+        Self::LABEL
+    }
+    fn options(&self) -> &JobOptions {
+        &self.options
+    }
+    fn options_mut(&mut self) -> &mut JobOptions {
+        &mut self.options
+    }
+    fn commands(&self) -> &[String] {
+        &self.command
+    }
+    fn exec(&self, handle: &Docker) -> BoxFuture<'static, Result<ExecInfo, Error>> {
+        let (job, handle) = (self.clone(), handle.clone());
+        Box::pin(async move { RunJobInfo::exec(job, &handle).await })
     }
-    pub fn get_schedule(&self) -> Cron {
-        self.schedule.clone()
+    fn clone_job(&self) -> Box<dyn Job> {
+        Box::new(self.clone())
+    }
+    fn to_parameters(&self) -> BTreeMap<String, Vec<String>> {
+        let mut map = BTreeMap::new();
+        put_one(&mut map, "name", Some(&self.name));
+        self.schedule.put_into(&mut map);
+        put_all(&mut map, "command", &self.command);
+        put_one(&mut map, "image", self.image.as_ref());
+        put_one(&mut map, "pull", (self.pull != PullPolicy::Missing).then_some(self.pull));
+        put_one(&mut map, "user", self.user.as_ref());
+        if let Some(network) = self.network.as_ref() {
+            map.insert("network".to_string(), network.clone());
+        }
+        put_one(&mut map, "hostname", self.hostname.as_ref());
+        put_one(&mut map, "delete", Some(self.delete));
+        put_one(&mut map, "container", self.container.as_ref());
+        put_one(&mut map, "on-name-conflict", (self.on_name_conflict != NameConflictPolicy::Fail).then_some(self.on_name_conflict));
+        put_one(&mut map, "tty", Some(self.tty));
+        put_all(&mut map, "volume", &self.volume);
+        put_all(&mut map, "environment", &self.environment);
+        self.options.put_into(&mut map);
+        map
+    }
+    fn describe_action(&self) -> String {
+        let command = match self.command.is_empty() {
+            true => "the default command".to_string(),
+            false => describe_commands(&self.command),
+        };
+        let action = match (self.image.as_ref(), self.container.as_ref()) {
+            (Some(image), _) => format!("run {} in a new {} container", command, image),
+            (None, Some(container)) => format!("run {} in container {}", command, container),
+            (None, None) => format!("run {}", command),
+        };
+        match self.network.as_ref() {
+            Some(network) => format!("{} on network {}", action, network.join(", ")),
+            None => action,
+        }
     }
-    pub fn may_run_parallel(&self) -> bool {
-        true
+    fn schedule(&self) -> &Schedule {
+        &self.schedule
     }
 }
 
@@ -79,17 +426,69 @@ impl Debug for RunJobInfo {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("RunJobInfo")
             .field("name", &self.name)
-            .field("schedule", &self.schedule.pattern.to_string())
+            .field("schedule", &self.schedule)
             .field("command", &self.command)
             .field("image", &self.image)
+            .field("pull", &self.pull)
             .field("user", &self.user)
             .field("network", &self.network)
             .field("hostname", &self.hostname)
             .field("delete", &self.delete)
             .field("container", &self.container)
+            .field("on_name_conflict", &self.on_name_conflict)
             .field("tty", &self.tty)
             .field("volume", &self.volume)
             .field("environment", &self.environment)
+            .field("options", &self.options)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{image_tag, NameConflictPolicy, PullPolicy, RunJobInfo};
+    use crate::job::Job;
+
+    /// The parameter map of a run job with the given keys
+    fn parameters(keys: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        [("name", "test_job"), ("schedule", "@hourly")].iter().chain(keys)
+            .map(|(k, v)| (k.to_string(), vec![v.to_string()])).collect()
+    }
+
+    #[test]
+    fn create_run_job_instance_pull() {
+        let image = ("image", "alpine");
+        let job = RunJobInfo::try_from(parameters(&[image])).unwrap();
+        assert_eq!(job.pull, PullPolicy::Missing);
+        assert!(!job.to_parameters().contains_key("pull"));
+        let job = RunJobInfo::try_from(parameters(&[image, ("pull", "always")])).unwrap();
+        assert_eq!(job.pull, PullPolicy::Always);
+        assert_eq!(job.to_parameters()["pull"], ["always"]);
+        assert!(RunJobInfo::try_from(parameters(&[image, ("pull", "sometimes")])).is_err());
+        assert!(RunJobInfo::try_from(parameters(&[("pull", "never"), ("container", "db")])).is_err());
+    }
+
+    #[test]
+    fn create_run_job_instance_on_name_conflict() {
+        let named = [("image", "alpine"), ("container", "backup")];
+        assert_eq!(RunJobInfo::try_from(parameters(&named)).unwrap().on_name_conflict, NameConflictPolicy::Fail);
+        let job = RunJobInfo::try_from(parameters(&[named[0], named[1], ("on-name-conflict", "replace")])).unwrap();
+        assert_eq!(job.on_name_conflict, NameConflictPolicy::Replace);
+        assert_eq!(job.to_parameters()["on-name-conflict"], ["replace"]);
+        assert!(RunJobInfo::try_from(parameters(&[named[0], named[1], ("on-name-conflict", "rename")])).is_err());
+        // Only the containers created from an image are named
+        assert!(RunJobInfo::try_from(parameters(&[named[0], ("on-name-conflict", "replace")])).is_err());
+        assert!(RunJobInfo::try_from(parameters(&[named[1], ("on-name-conflict", "replace")])).is_err());
+    }
+
+    #[test]
+    fn split_image_tags() {
+        assert_eq!(image_tag("alpine"), ("alpine", "latest"));
+        assert_eq!(image_tag("alpine:3.20"), ("alpine", "3.20"));
+        assert_eq!(image_tag("localhost:5000/team/app"), ("localhost:5000/team/app", "latest"));
+        assert_eq!(image_tag("localhost:5000/team/app:v2"), ("localhost:5000/team/app", "v2"));
+        assert_eq!(image_tag("alpine@sha256:abcd"), ("alpine@sha256:abcd", ""));
+    }
+}
@@ -0,0 +1,283 @@
+//! Sandboxing of the commands run by local jobs
+use std::{collections::{BTreeMap, HashMap}, fmt::{Display, Formatter}, os::unix::fs::MetadataExt, process::{Command, Stdio}, str::FromStr, sync::atomic::{AtomicU64, Ordering}};
+
+use anyhow::Error;
+use regex::Regex;
+use tracing::{debug, warn};
+
+use crate::take_one;
+
+use super::common::put_one;
+
+/// The tool used to isolate a local job's command from the host
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SandboxKind {
+    /// Run the command directly
+    #[default]
+    None,
+    /// Run the command in a transient systemd unit
+    SystemdRun,
+    /// Run the command in new mount and pid namespaces
+    Unshare,
+}
+
+impl FromStr for SandboxKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(SandboxKind::None),
+            "systemd-run" => Ok(SandboxKind::SystemdRun),
+            "unshare" => Ok(SandboxKind::Unshare),
+            s => Err(Error::msg(format!("Unsupported sandbox '{}', expected one of systemd-run, unshare or none", s))),
+        }
+    }
+}
+
+impl Display for SandboxKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SandboxKind::None => write!(f, "none"),
+            SandboxKind::SystemdRun => write!(f, "systemd-run"),
+            SandboxKind::Unshare => write!(f, "unshare"),
+        }
+    }
+}
+
+/// The sandboxing configuration of a local job
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sandbox {
+    /// The tool used to wrap the command
+    pub kind: SandboxKind,
+    /// Whether the job should fail if the sandboxing tool is not available instead of running unsandboxed
+    pub required: bool,
+    /// The systemd `MemoryMax` property (e.g. `512M`)
+    pub memory_max: Option<String>,
+    /// The systemd `CPUQuota` property (e.g. `50%`)
+    pub cpu_quota: Option<String>,
+    /// The systemd `PrivateTmp` property
+    pub private_tmp: bool,
+}
+
+impl Sandbox {
+    /// Extract the sandbox configuration from a job's parameter map
+    pub(crate) fn take_from(value: &mut HashMap<String, Vec<String>>) -> Result<Self, Error> {
+        let sandbox = Sandbox {
+            kind: take_one!(value, "sandbox")?.map_or(Ok(SandboxKind::None), |s| s.parse())?,
+            required: take_one!(value, "sandbox-required")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?,
+            memory_max: take_one!(value, "sandbox-memory-max")?,
+            cpu_quota: take_one!(value, "sandbox-cpu-quota")?,
+            private_tmp: take_one!(value, "sandbox-private-tmp")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?,
+        };
+        if let Some(memory_max) = sandbox.memory_max.as_ref() {
+            if !Regex::new("^([0-9]+[KMGT]?|[0-9]+%|infinity)$").unwrap().is_match(memory_max) {
+                return Err(Error::msg(format!("Invalid sandbox-memory-max '{}', expected a size such as 512M", memory_max)));
+            }
+        }
+        if let Some(cpu_quota) = sandbox.cpu_quota.as_ref() {
+            if !Regex::new("^[0-9]+%$").unwrap().is_match(cpu_quota) {
+                return Err(Error::msg(format!("Invalid sandbox-cpu-quota '{}', expected a percentage such as 50%", cpu_quota)));
+            }
+        }
+        let has_properties = sandbox.memory_max.is_some() || sandbox.cpu_quota.is_some() || sandbox.private_tmp;
+        if has_properties && sandbox.kind != SandboxKind::SystemdRun {
+            return Err(Error::msg(format!("The sandbox properties are only supported with the systemd-run sandbox (found {})", sandbox.kind)));
+        }
+        Ok(sandbox)
+    }
+
+    /// Write the sandbox configuration back to a job's parameter map, omitting default values
+    pub(crate) fn put_into(&self, map: &mut BTreeMap<String, Vec<String>>) {
+        put_one(map, "sandbox", (self.kind != SandboxKind::None).then_some(self.kind));
+        put_one(map, "sandbox-required", self.required.then_some(true));
+        put_one(map, "sandbox-memory-max", self.memory_max.as_ref());
+        put_one(map, "sandbox-cpu-quota", self.cpu_quota.as_ref());
+        put_one(map, "sandbox-private-tmp", self.private_tmp.then_some(true));
+    }
+
+    /// Whether the sandboxing tool can be used on this host
+    pub fn is_available(&self) -> bool {
+        match self.kind {
+            // Same check as systemd's sd_booted()
+            SandboxKind::SystemdRun => std::fs::metadata("/run/systemd/system").is_ok(),
+            SandboxKind::None | SandboxKind::Unshare => true,
+        }
+    }
+
+    /// Build the command-line that runs `argv` in the sandbox.
+    ///
+    /// Returns `None` when the command should not be wrapped. The working directory and the names
+    /// of the environment variables are only part of the command-line for sandboxes that do not
+    /// inherit them: their values are read by the sandboxing tool from its own environment, so
+    /// that resolved secrets never show up in the process list. `unit` names the transient unit
+    /// of the systemd-run sandbox.
+    pub fn command_line(&self, argv: &[String], environment: &[(String, String)], dir: Option<&str>, unit: Option<&str>) -> Option<Vec<String>> {
+        let mut command_line: Vec<String> = match self.kind {
+            SandboxKind::None => return None,
+            SandboxKind::SystemdRun => {
+                let mut args: Vec<String> = ["systemd-run", "--quiet", "--collect", "--wait", "--pipe"]
+                    .iter().map(|s| s.to_string()).collect();
+                if let Some(unit) = unit {
+                    args.push(format!("--unit={}", unit));
+                }
+                if let Some(dir) = dir {
+                    args.push(format!("--working-directory={}", dir));
+                }
+                for (key, _) in environment {
+                    args.push(format!("--setenv={}", key));
+                }
+                if let Some(memory_max) = self.memory_max.as_ref() {
+                    args.extend(["-p".to_string(), format!("MemoryMax={}", memory_max)]);
+                }
+                if let Some(cpu_quota) = self.cpu_quota.as_ref() {
+                    args.extend(["-p".to_string(), format!("CPUQuota={}", cpu_quota)]);
+                }
+                if self.private_tmp {
+                    args.extend(["-p".to_string(), "PrivateTmp=yes".to_string()]);
+                }
+                args
+            },
+            SandboxKind::Unshare => {
+                let mut args: Vec<String> = ["unshare", "--mount", "--pid", "--fork", "--mount-proc"]
+                    .iter().map(|s| s.to_string()).collect();
+                // Unprivileged users may only create namespaces inside a new user namespace
+                if std::fs::metadata("/proc/self").map_or(true, |m| m.uid() != 0) {
+                    args.push("--map-root-user".to_string());
+                }
+                args
+            },
+        };
+        command_line.push("--".to_string());
+        command_line.extend(argv.iter().cloned());
+        Some(command_line)
+    }
+}
+
+/// Get a name for the transient unit of an execution of a job, unique on the host
+pub(crate) fn unit_name(job: &str) -> String {
+    static EXECUTIONS: AtomicU64 = AtomicU64::new(0);
+    let job: String = job.chars().take(128).map(|c| if c.is_ascii_alphanumeric() || "_.-".contains(c) { c } else { '_' }).collect();
+    format!("cfc-{}-{}-{}", job, std::process::id(), EXECUTIONS.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Stops a transient unit when dropped before being disarmed, e.g. when its execution times out.
+///
+/// Killing systemd-run only stops the client, the command keeps running in its unit.
+pub(crate) struct UnitGuard(Option<String>);
+
+impl UnitGuard {
+    pub(crate) fn new(unit: String) -> Self {
+        UnitGuard(Some(unit))
+    }
+
+    /// Leave the unit alone, once its command ended
+    pub(crate) fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for UnitGuard {
+    fn drop(&mut self) {
+        let Some(unit) = self.0.take() else { return };
+        // Dropping happens in async code, systemctl is waited for in its own thread
+        std::thread::spawn(move || {
+            let status = Command::new("systemctl").args(["stop", &unit])
+                .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+                .status();
+            match status {
+                Ok(status) if status.success() => debug!("Stopped the unit {} of an interrupted execution", unit),
+                Ok(status) => warn!("Failed to stop the unit {} of an interrupted execution: systemctl {}", unit, status),
+                Err(e) => warn!("Failed to stop the unit {} of an interrupted execution: {}", unit, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::job::resolve_secrets;
+
+    use super::{unit_name, Sandbox, SandboxKind};
+
+    fn to_map(values: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        values.iter().map(|(k, v)| (k.to_string(), vec![v.to_string()])).collect()
+    }
+
+    #[test]
+    fn systemd_run_command_line() {
+        let sandbox = Sandbox::take_from(&mut to_map(&[
+            ("sandbox", "systemd-run"),
+            ("sandbox-memory-max", "512M"),
+            ("sandbox-cpu-quota", "50%"),
+            ("sandbox-private-tmp", "true"),
+        ])).unwrap();
+        let command_line = sandbox.command_line(
+            &["echo".to_string(), "a b".to_string()],
+            &[("FOO".to_string(), "bar".to_string())],
+            Some("/tmp"),
+            Some("cfc-backup-1-0"),
+        ).unwrap();
+        assert_eq!(command_line, vec![
+            "systemd-run", "--quiet", "--collect", "--wait", "--pipe",
+            "--unit=cfc-backup-1-0", "--working-directory=/tmp", "--setenv=FOO",
+            "-p", "MemoryMax=512M", "-p", "CPUQuota=50%", "-p", "PrivateTmp=yes",
+            "--", "echo", "a b",
+        ]);
+    }
+
+    #[test]
+    fn name_transient_units() {
+        let (first, second) = (unit_name("db/backup"), unit_name("db/backup"));
+        assert!(first.starts_with(&format!("cfc-db_backup-{}-", std::process::id())), "{}", first);
+        assert_ne!(first, second);
+        let sandbox = Sandbox { kind: SandboxKind::SystemdRun, ..Default::default() };
+        let command_line = sandbox.command_line(&["true".to_string()], &[], None, Some(&first)).unwrap();
+        assert!(command_line.contains(&format!("--unit={}", first)), "{:?}", command_line);
+    }
+
+    #[test]
+    fn keep_environment_values_off_command_line() {
+        let secret = std::env::temp_dir().join(format!("cfc-sandbox-secret-{}", std::process::id()));
+        std::fs::write(&secret, "s3cr3t-value\n").unwrap();
+        let environment = resolve_secrets(&[format!("TOKEN=secret://file{}", secret.display())]);
+        std::fs::remove_file(&secret).unwrap();
+        let environment: Vec<(String, String)> = environment.unwrap().iter()
+            .map(|e| e.split_once('=').map(|(k, v)| (k.to_string(), v.to_string())).unwrap())
+            .collect();
+        assert_eq!(environment, vec![("TOKEN".to_string(), "s3cr3t-value".to_string())]);
+        let sandbox = Sandbox { kind: SandboxKind::SystemdRun, ..Default::default() };
+        let command_line = sandbox.command_line(&["true".to_string()], &environment, None, None).unwrap();
+        assert!(command_line.contains(&"--setenv=TOKEN".to_string()), "{:?}", command_line);
+        assert!(!command_line.iter().any(|arg| arg.contains("s3cr3t-value")), "{:?}", command_line);
+    }
+
+    #[test]
+    fn unshare_command_line() {
+        let sandbox = Sandbox { kind: SandboxKind::Unshare, ..Default::default() };
+        let command_line = sandbox.command_line(&["true".to_string()], &[], None, None).unwrap();
+        assert_eq!(&command_line[..5], &["unshare", "--mount", "--pid", "--fork", "--mount-proc"]);
+        assert_eq!(&command_line[command_line.len() - 2..], &["--", "true"]);
+        assert!(Sandbox::default().command_line(&["true".to_string()], &[], None, None).is_none());
+    }
+
+    #[test]
+    fn invalid_sandbox_configuration() {
+        assert!(Sandbox::take_from(&mut to_map(&[("sandbox", "chroot")])).is_err());
+        assert!(Sandbox::take_from(&mut to_map(&[("sandbox", "systemd-run"), ("sandbox-memory-max", "lots")])).is_err());
+        assert!(Sandbox::take_from(&mut to_map(&[("sandbox", "systemd-run"), ("sandbox-cpu-quota", "0.5")])).is_err());
+        assert!(Sandbox::take_from(&mut to_map(&[("sandbox", "unshare"), ("sandbox-private-tmp", "true")])).is_err());
+    }
+
+    #[tokio::test]
+    async fn systemd_run_integration() {
+        let sandbox = Sandbox { kind: SandboxKind::SystemdRun, ..Default::default() };
+        if !sandbox.is_available() {
+            return;
+        }
+        let command_line = sandbox.command_line(&["true".to_string()], &[], None, None).unwrap();
+        let status = tokio::process::Command::new(&command_line[0]).args(&command_line[1..]).status().await.unwrap();
+        assert!(status.success());
+    }
+}
@@ -0,0 +1,282 @@
+//! Declarative description of the keys accepted by each job kind
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+use tracing::warn;
+
+use super::{migration::find_rename, registered_kind, registered_kinds};
+
+/// The version of the schema's layout, increased when it changes in an incompatible way
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// The type of the values of a job key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    String,
+    Bool,
+    Integer,
+    /// A duration such as `1h30m`, see [`parse_duration`][super::parse_duration]
+    Duration,
+    /// An RFC3339 date
+    Date,
+    /// A cron expression or a schedule alias
+    Schedule,
+    /// A key that may be set several times
+    List,
+}
+
+impl KeyType {
+    /// Get the name of the type, as used in the schema
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyType::String => "string",
+            KeyType::Bool => "bool",
+            KeyType::Integer => "integer",
+            KeyType::Duration => "duration",
+            KeyType::Date => "date",
+            KeyType::Schedule => "schedule",
+            KeyType::List => "list",
+        }
+    }
+}
+
+/// The description of a job key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeySpec {
+    pub name: &'static str,
+    pub value_type: KeyType,
+    pub required: bool,
+    /// The value used when the key is not set, if it has one
+    pub default: Option<&'static str>,
+    /// Whether the key may only be set in labels when unsafe jobs are allowed
+    pub unsafe_from_labels: bool,
+    /// A valid value of the key
+    pub example: &'static str,
+}
+
+impl KeySpec {
+    /// Describe an optional key without default value
+    pub const fn new(name: &'static str, value_type: KeyType, example: &'static str) -> Self {
+        KeySpec { name, value_type, required: false, default: None, unsafe_from_labels: false, example }
+    }
+
+    /// Mark the key as required
+    pub const fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    /// Set the value used when the key is not set
+    pub const fn default(mut self, default: &'static str) -> Self {
+        self.default = Some(default);
+        self
+    }
+
+    /// Mark the key as only settable in labels when unsafe jobs are allowed
+    pub const fn unsafe_from_labels(mut self) -> Self {
+        self.unsafe_from_labels = true;
+        self
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "name": self.name,
+            "type": self.value_type.as_str(),
+            "required": self.required,
+            "default": self.default,
+            "unsafe-from-labels": self.unsafe_from_labels,
+            "example": self.example,
+        })
+    }
+}
+
+/// The keys shared by all job kinds that identify and schedule the job
+pub const SCHEDULE_KEYS: &[KeySpec] = &[
+    KeySpec::new("name", KeyType::String, "backup"),
+    KeySpec::new("schedule", KeyType::Schedule, "@hourly").required(),
+    KeySpec::new("seconds", KeyType::Bool, "true"),
+    KeySpec::new("strict-schedule", KeyType::Bool, "true").default("false"),
+    KeySpec::new("timezone", KeyType::String, "UTC"),
+    KeySpec::new("jitter", KeyType::Bool, "true").default("false"),
+];
+
+/// The scheduling options shared by all job kinds, see [`JobOptions`][super::JobOptions]
+pub const OPTION_KEYS: &[KeySpec] = &[
+    KeySpec::new("no-overlap", KeyType::Bool, "true").default("false"),
+    KeySpec::new("overlap-policy", KeyType::String, "skip").default("allow"),
+    KeySpec::new("max-runs", KeyType::Integer, "3"),
+    KeySpec::new("not-before", KeyType::Date, "2024-01-01T00:00:00Z"),
+    KeySpec::new("not-after", KeyType::Date, "2099-01-01T00:00:00Z"),
+    KeySpec::new("run-on-start", KeyType::Bool, "true").default("false"),
+    KeySpec::new("deadline", KeyType::Duration, "1h"),
+    KeySpec::new("merge-streams", KeyType::Bool, "true").default("false"),
+    KeySpec::new("timeout", KeyType::Duration, "10m"),
+    KeySpec::new("on-failure", KeyType::String, "stop-job").default("continue"),
+    KeySpec::new("max-consecutive-failures", KeyType::Integer, "5"),
+    KeySpec::new("group", KeyType::String, "database"),
+    KeySpec::new("group-wait-timeout", KeyType::Duration, "5m"),
+    KeySpec::new("continue-on-error", KeyType::Bool, "true").default("false"),
+    KeySpec::new("enabled", KeyType::Bool, "false").default("true"),
+    KeySpec::new("history-size", KeyType::Integer, "50").default("20"),
+    KeySpec::new("priority", KeyType::Integer, "10").default("0"),
+    // Hooks run on the host like local jobs
+    KeySpec::new("on-success", KeyType::String, "touch /tmp/backup-done").unsafe_from_labels(),
+    KeySpec::new("on-error", KeyType::String, "/usr/local/bin/alert").unsafe_from_labels(),
+    #[cfg(feature = "webhook")]
+    KeySpec::new("slack-on-failure", KeyType::Bool, "false").default("true"),
+    #[cfg(feature = "webhook")]
+    KeySpec::new("webhook-url", KeyType::String, "http://relay:8080/cfc").unsafe_from_labels(),
+];
+
+/// The keys of the job kinds that pass environment variables to a container
+pub(crate) const CONTAINER_ENVIRONMENT_KEYS: [KeySpec; 2] = [
+    KeySpec::new("environment", KeyType::List, "FOO=bar"),
+    // Forwarding cfc's own environment could expose its secrets to whoever can set labels
+    KeySpec::new("pass-environment", KeyType::Bool, "true").default("false").unsafe_from_labels(),
+];
+
+/// The keys of the job kinds that read the output of their commands, see [`JobOptions`][super::JobOptions]
+pub(crate) const OUTPUT_KEYS: [KeySpec; 4] = [
+    KeySpec::new("capture-stdout", KeyType::Bool, "true").default("false"),
+    KeySpec::new("capture-stderr", KeyType::Bool, "true").default("false"),
+    KeySpec::new("stream-logs", KeyType::Bool, "true").default("false"),
+    KeySpec::new("max-output-size", KeyType::Integer, "65536").default("1048576"),
+];
+
+/// Get all the keys accepted by a registered job kind
+pub fn kind_keys(kind: &str) -> Option<Vec<KeySpec>> {
+    let kind = registered_kind(kind)?;
+    Some(SCHEDULE_KEYS.iter().chain(kind.keys).chain(OPTION_KEYS).copied().collect())
+}
+
+/// Find the description of a key of a registered job kind
+pub fn find_key(kind: &str, key: &str) -> Option<KeySpec> {
+    kind_keys(kind)?.into_iter().find(|k| k.name == key)
+}
+
+/// Describe the keys accepted by every registered job kind.
+///
+/// ## Examples
+///
+/// ```rust
+/// let schema = cfc::job::schema();
+/// let local = schema["kinds"].as_array().unwrap().iter().find(|k| k["kind"] == "job-local").unwrap();
+/// assert_eq!(local["unsafe-from-labels"], true);
+/// ```
+pub fn schema() -> Value {
+    let kinds: Vec<Value> = registered_kinds().iter()
+        .filter_map(|name| Some((name, registered_kind(name)?, kind_keys(name)?)))
+        .map(|(name, kind, keys)| json!({
+            "kind": name,
+            "unsafe-from-labels": kind.unsafe_from_labels,
+            "keys": keys.into_iter().map(KeySpec::to_json).collect::<Vec<Value>>(),
+        }))
+        .collect();
+    json!({ "version": SCHEMA_VERSION, "kinds": kinds })
+}
+
+/// Get the number of single-character edits needed to turn a string into another
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb { previous } else { 1 + previous.min(row[j]).min(current) };
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+/// Get the known key of a job kind that is the closest to an unknown key, if one is close enough
+pub fn suggest_key(kind: &str, key: &str) -> Option<&'static str> {
+    let threshold = (key.chars().count() / 3).max(1);
+    kind_keys(kind)?.into_iter()
+        .map(|k| (edit_distance(key, k.name), k.name))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min()
+        .map(|(_, name)| name)
+}
+
+/// Warn about the keys left in a job's parameter map once its kind took the ones it uses
+pub(crate) fn warn_unused_keys(kind: &str, job: &str, value: &HashMap<String, Vec<String>>) {
+    let mut keys: Vec<&String> = value.keys().collect();
+    keys.sort();
+    for key in keys {
+        if let Some(rename) = find_rename(kind, key) {
+            warn!("The key '{}' of job '{}' was renamed to '{}' in configuration version {}", key, job, rename.to, rename.version);
+            continue;
+        }
+        match suggest_key(kind, key) {
+            Some(suggestion) => warn!("The key '{}' of job '{}' is not used by {} jobs, did you mean '{}'?", key, job, kind, suggestion),
+            None => warn!("The key '{}' of job '{}' is not used by {} jobs", key, job, kind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+
+    use crate::job::{ExecJobInfo, JobInfo, LocalJobInfo, RunJobInfo, ServiceRunJobInfo, JOB_KINDS};
+
+    use super::{edit_distance, kind_keys, schema, suggest_key};
+
+    /// Build a parameter map that sets every key of a kind to its example value
+    fn example_parameters(kind: &str) -> HashMap<String, Vec<String>> {
+        kind_keys(kind).unwrap().iter()
+            .map(|k| (k.name.to_string(), vec![k.example.to_string()]))
+            .collect()
+    }
+
+    #[test]
+    fn schema_matches_parsers() {
+        for kind in JOB_KINDS {
+            let mut parameters = example_parameters(kind);
+            let keys: BTreeSet<String> = parameters.keys().cloned().collect();
+            let job = match kind {
+                ExecJobInfo::LABEL => JobInfo::ExecJob(Box::new(ExecJobInfo::take_from(&mut parameters).unwrap())),
+                RunJobInfo::LABEL => JobInfo::RunJob(Box::new(RunJobInfo::take_from(&mut parameters).unwrap())),
+                LocalJobInfo::LABEL => JobInfo::LocalJob(Box::new(LocalJobInfo::take_from(&mut parameters).unwrap())),
+                ServiceRunJobInfo::LABEL => JobInfo::ServiceRunJob(Box::new(ServiceRunJobInfo::take_from(&mut parameters).unwrap())),
+                _ => unreachable!(),
+            };
+            // Every key of the schema is used by the kind's parser
+            assert!(parameters.is_empty(), "{}: keys of the schema left by the parser: {:?}", kind, parameters.keys());
+            // Every key the parser uses is written back by the job, so must be in the schema
+            let written: BTreeSet<String> = job.to_parameters().into_keys().filter(|k| k != "kind").collect();
+            assert!(written.is_subset(&keys), "{}: keys missing from the schema: {:?}", kind, written.difference(&keys));
+        }
+    }
+
+    #[test]
+    fn schema_describes_kinds() {
+        let schema = schema();
+        let kinds: Vec<&str> = schema["kinds"].as_array().unwrap().iter().map(|k| k["kind"].as_str().unwrap()).collect();
+        for kind in JOB_KINDS {
+            assert!(kinds.contains(&kind), "{} is missing from {:?}", kind, kinds);
+        }
+        let local = schema["kinds"].as_array().unwrap().iter().find(|k| k["kind"] == "job-local").unwrap();
+        assert_eq!(local["unsafe-from-labels"], true);
+        let command = local["keys"].as_array().unwrap().iter().find(|k| k["name"] == "command").unwrap();
+        assert_eq!(command["type"], "list");
+        assert_eq!(command["required"], true);
+        let exec = schema["kinds"].as_array().unwrap().iter().find(|k| k["kind"] == "job-exec").unwrap();
+        let tty = exec["keys"].as_array().unwrap().iter().find(|k| k["name"] == "tty").unwrap();
+        assert_eq!(tty["default"], "false");
+    }
+
+    #[test]
+    fn suggest_close_keys() {
+        assert_eq!(edit_distance("comand", "command"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(suggest_key("job-exec", "comand"), Some("command"));
+        assert_eq!(suggest_key("job-exec", "contianer"), Some("container"));
+        assert_eq!(suggest_key("job-local", "timout"), Some("timeout"));
+        assert_eq!(suggest_key("job-local", "volume"), None);
+        assert_eq!(suggest_key("job-unknown", "comand"), None);
+    }
+}
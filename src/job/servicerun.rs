@@ -1,37 +1,107 @@
-use std::{collections::HashMap, fmt::{Debug, Display, Formatter}};
+use std::{collections::{BTreeMap, HashMap}, fmt::{Debug, Display, Formatter}};
 
 use anyhow::Error;
 use bollard::Docker;
-use croner::Cron;
-use tracing::warn;
+use futures_util::future::BoxFuture;
 
 use crate::{job::common::UNKNOWN_CONTAINER_LABEL, require_one, take_one};
 
-use super::common::{schedule_to_cron, ExecInfo};
+use super::{common::{describe_commands, put_all, put_one, take_commands, take_schedule, Schedule, ExecInfo, JobOptions}, schema::{warn_unused_keys, KeySpec, KeyType}, Job};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct ServiceRunJobInfo {
     pub name: String,
-    pub schedule: Cron,
-    pub command: String,
+    pub schedule: Schedule,
+    /// The commands to run, one after the other
+    pub command: Vec<String>,
     pub image: Option<String>,
     pub user: Option<String>,
     pub network: Option<Vec<String>>,
     pub delete: bool,
     pub container: Option<String>,
     pub tty: bool,
+    pub options: JobOptions,
 }
 
 impl ServiceRunJobInfo {
-    pub const LABEL: &'static str = "job-service-run";
+    pub const LABEL: &str = "job-service-run";
+    /// The keys accepted by service run jobs, besides the ones shared by all kinds
+    pub const KEYS: &'static [KeySpec] = &[
+        KeySpec::new("command", KeyType::List, "touch /tmp/cfc").required(),
+        KeySpec::new("image", KeyType::String, "alpine:latest"),
+        KeySpec::new("user", KeyType::String, "nobody"),
+        KeySpec::new("network", KeyType::List, "my-network"),
+        KeySpec::new("delete", KeyType::Bool, "false").default("true"),
+        KeySpec::new("container", KeyType::String, "my-container"),
+        KeySpec::new("tty", KeyType::Bool, "true").default("false"),
+    ];
+
+    /// Build a job from the keys of its parameter map, leaving the keys it does not use
+    pub(crate) fn take_from(value: &mut HashMap<String, Vec<String>>) -> Result<Self, Error> {
+        let name = require_one!(value, "name").unwrap_or_else(|_| "".to_string());
+        Ok(ServiceRunJobInfo {
+            schedule: take_schedule(value, &name)?,
+            name,
+            command: take_commands(value, true)?,
+            image: take_one!(value, "image")?,
+            user: take_one!(value, "user")?,
+            network: value.remove("network"),
+            delete: take_one!(value, "delete")?.map_or(Ok(true), |t| t.parse().map_err(Error::new))?,
+            container: take_one!(value, "container")?,
+            tty: take_one!(value, "tty")?.map_or(Ok(false), |t| t.parse().map_err(Error::new))?,
+            options: JobOptions::take_from(value)?,
+        })
+    }
     pub async fn exec(self, _handle: &Docker) -> Result<ExecInfo, Error> {
         Err(Error::msg("message")) // TODO
     }
-    pub fn get_schedule(&self) -> Cron {
-        self.schedule.clone()
+}
+
+impl Job for ServiceRunJobInfo {
+    fn name(&self) -> &String {
+        &self.name
+    }
+    fn kind(&self) -> &str {
+        Self::LABEL
+    }
+    fn options(&self) -> &JobOptions {
+        &self.options
+    }
+    fn options_mut(&mut self) -> &mut JobOptions {
+        &mut self.options
     }
-    pub fn may_run_parallel(&self) -> bool {
-        true
+    fn commands(&self) -> &[String] {
+        &self.command
+    }
+    fn exec(&self, handle: &Docker) -> BoxFuture<'static, Result<ExecInfo, Error>> {
+        let (job, handle) = (self.clone(), handle.clone());
+        Box::pin(async move { ServiceRunJobInfo::exec(job, &handle).await })
+    }
+    fn clone_job(&self) -> Box<dyn Job> {
+        Box::new(self.clone())
+    }
+    fn to_parameters(&self) -> BTreeMap<String, Vec<String>> {
+        let mut map = BTreeMap::new();
+        put_one(&mut map, "name", Some(&self.name));
+        self.schedule.put_into(&mut map);
+        put_all(&mut map, "command", &self.command);
+        put_one(&mut map, "image", self.image.as_ref());
+        put_one(&mut map, "user", self.user.as_ref());
+        if let Some(network) = self.network.as_ref() {
+            map.insert("network".to_string(), network.clone());
+        }
+        put_one(&mut map, "delete", Some(self.delete));
+        put_one(&mut map, "container", self.container.as_ref());
+        put_one(&mut map, "tty", Some(self.tty));
+        self.options.put_into(&mut map);
+        map
+    }
+    fn describe_action(&self) -> String {
+        let target = self.image.as_ref().or(self.container.as_ref()).map_or(UNKNOWN_CONTAINER_LABEL, |s| s.as_str());
+        format!("run {} in a new {} service", describe_commands(&self.command), target)
+    }
+    fn schedule(&self) -> &Schedule {
+        &self.schedule
     }
 }
 
@@ -39,20 +109,8 @@ impl TryFrom<HashMap<String, Vec<String>>> for ServiceRunJobInfo {
     type Error = Error;
 
     fn try_from(mut value: HashMap<String, Vec<String>>) -> Result<Self, Self::Error> {
-        let job = ServiceRunJobInfo {
-            name: require_one!(value, "name").unwrap_or_else(|_| "".to_string()),
-            schedule: schedule_to_cron(&require_one!(value, "schedule")?.as_str())?,
-            command: require_one!(value, "command")?,
-            image: take_one!(value, "image")?,
-            user: take_one!(value, "user")?,
-            network: value.remove("network"),
-            delete: take_one!(value, "delete")?.map_or(Ok(true), |t| t.parse().map_err(|e| Error::new(e)))?,
-            container: take_one!(value, "container")?,
-            tty: take_one!(value, "tty")?.map_or(Ok(false), |t| t.parse().map_err(|e| Error::new(e)))?,
-        };
-        if !value.is_empty() {
-            warn!("The job key map has excess attributes that will not be used: {:?}", value.keys());
-        }
+        let job = Self::take_from(&mut value)?;
+        warn_unused_keys(Self::LABEL, &job.name, &value);
         Ok(job)
     }
 }
@@ -73,13 +131,15 @@ impl Debug for ServiceRunJobInfo {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ServiceRunJobInfo")
             .field("name", &self.name)
-            .field("schedule", &self.schedule.pattern.to_string())
+            .field("schedule", &self.schedule)
             .field("command", &self.command)
             .field("image", &self.image)
             .field("user", &self.user)
             .field("network", &self.network)
             .field("delete", &self.delete)
             .field("container", &self.container)
-            .field("tty", &self.tty).finish()
+            .field("tty", &self.tty)
+            .field("options", &self.options)
+            .finish()
     }
 }
@@ -24,6 +24,9 @@
 //!
 //! *Note:* The cron format does not have to contain the seconds specifier
 //!
+//! `@every` intervals may be written with unit suffixes (`1h30m`, `1h 30m`), as ISO8601 durations (`PT1H30M`)
+//! or as `HH:MM:SS` (`01:30:00`).
+//!
 //! You can configure four different kind of jobs:
 //!
 //! `job-exec`: Executed in a running container.
@@ -68,7 +71,19 @@
 //! Though both an executable and a library are made available, the library is only
 //! intended for consumption by the executable and its API should not be considered stable.
 
+pub mod clock;
 pub mod context;
+pub mod health;
+#[cfg(feature = "history")]
+pub mod history_db;
+#[cfg(feature = "http")]
+mod http;
 pub mod utils;
 pub mod job;
 pub mod loader;
+pub mod metrics;
+pub mod report;
+pub mod scheduler;
+pub mod slack;
+pub mod state;
+pub mod webhook;
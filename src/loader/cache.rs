@@ -0,0 +1,119 @@
+//! Cache of the job maps parsed from container labels
+use std::{collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet}, hash::{Hash, Hasher}};
+
+use anyhow::Result;
+
+/// The job maps parsed from a container's labels
+pub type ContainerJobs = HashMap<String, HashMap<String, Vec<String>>>;
+
+struct CacheEntry {
+    fingerprint: u64,
+    jobs: ContainerJobs,
+    last_used: u64,
+}
+
+/// Keeps the jobs parsed from each container's labels so that unchanged containers
+/// are not parsed again on every discovery pass.
+///
+/// Entries are keyed by container ID and invalidated when the container's creation
+/// date or labels change. The cache holds at most `capacity` containers and evicts
+/// the least recently used ones first.
+pub struct LabelCache {
+    entries: HashMap<String, CacheEntry>,
+    capacity: usize,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for LabelCache {
+    fn default() -> Self {
+        LabelCache::new(1024)
+    }
+}
+
+impl LabelCache {
+    pub fn new(capacity: usize) -> Self {
+        LabelCache {
+            entries: HashMap::new(),
+            capacity: capacity.max(1),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Compute the value used to detect changes of a container's metadata or of the parser's settings
+    pub fn fingerprint(created: i64, labels: &HashMap<String, String>, settings: impl Hash) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        created.hash(&mut hasher);
+        settings.hash(&mut hasher);
+        labels.iter().collect::<BTreeMap<_, _>>().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Get the jobs of a container, calling `parse` if the container is unknown or changed
+    pub fn get_or_parse(&mut self, id: &str, fingerprint: u64, parse: impl FnOnce() -> Result<ContainerJobs>) -> Result<ContainerJobs> {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(id).filter(|e| e.fingerprint == fingerprint) {
+            self.hits += 1;
+            entry.last_used = self.tick;
+            return Ok(entry.jobs.clone());
+        }
+        self.misses += 1;
+        let jobs = parse()?;
+        if !self.entries.contains_key(id) && self.entries.len() >= self.capacity {
+            if let Some(oldest) = self.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| k.clone()) {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(id.to_string(), CacheEntry { fingerprint, jobs: jobs.clone(), last_used: self.tick });
+        Ok(jobs)
+    }
+
+    /// Evict the containers that were not seen during the last discovery pass
+    pub fn retain(&mut self, seen: &HashSet<String>) {
+        self.entries.retain(|id, _| seen.contains(id));
+    }
+
+    /// The number of containers whose jobs were reused from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// The number of containers whose labels had to be parsed
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// The number of containers currently cached
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::{ContainerJobs, LabelCache};
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = LabelCache::new(2);
+        for id in ["a", "b", "a", "c"] {
+            cache.get_or_parse(id, 0, || Ok(ContainerJobs::new())).unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+        // b was the least recently used container
+        cache.get_or_parse("a", 0, || panic!("a should be cached")).unwrap();
+        cache.retain(&HashSet::from(["c".to_string()]));
+        assert_eq!(cache.len(), 1);
+        assert!(LabelCache::fingerprint(0, &HashMap::new(), ()) != LabelCache::fingerprint(1, &HashMap::new(), ()));
+    }
+}
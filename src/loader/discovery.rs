@@ -0,0 +1,232 @@
+//! Detection of the containers that start and stop while the daemon runs
+//!
+//! Container events are listened to when the container engine supports it. Some socket
+//! proxies and older engines refuse the events endpoint: the containers are then rescanned
+//! periodically, and subscribing to the events is attempted again from time to time so that
+//! a fixed proxy configuration is picked up without a restart.
+use std::fmt::{Display, Formatter};
+
+use anyhow::{Error, Result};
+use bollard::errors::Error as DockerError;
+use futures_util::{stream::BoxStream, StreamExt};
+use tokio::time::{interval, interval_at, Duration, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+
+use super::ContainerEvent;
+
+/// The default delay between two rescans of the containers when events are not supported
+pub const DEFAULT_RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often subscribing to the container events is attempted again while rescanning
+pub const EVENTS_RETRY_INTERVAL: Duration = Duration::from_secs(600);
+
+/// An event stream that ends this soon after the subscription was refused rather than interrupted
+const IMMEDIATE_END: Duration = Duration::from_secs(5);
+
+/// How the containers that start and stop are detected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscoveryMode {
+    /// The container engine's event stream is listened to
+    Events,
+    /// The containers are listed again periodically
+    Rescan(Duration),
+}
+
+impl Display for DiscoveryMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiscoveryMode::Events => write!(f, "container events"),
+            DiscoveryMode::Rescan(every) => write!(f, "periodic rescan every {}s", every.as_secs()),
+        }
+    }
+}
+
+/// What the daemon should do to keep the jobs declared in labels up-to-date
+#[derive(Clone, Debug, PartialEq)]
+pub enum Discovery {
+    /// A container started or stopped
+    Event(ContainerEvent),
+    /// The containers should be listed again as changes may have been missed
+    Rescan,
+}
+
+/// Whether an error means that the container engine does not support the events endpoint,
+/// e.g. when a socket proxy denies it
+fn is_unsupported(e: &Error) -> bool {
+    matches!(e.downcast_ref::<DockerError>(), Some(DockerError::DockerResponseServerError { status_code: 403 | 404 | 405 | 501, .. }))
+}
+
+type Subscribe = Box<dyn FnMut() -> Result<BoxStream<'static, Result<ContainerEvent>>> + Send>;
+
+/// Follows the containers that start and stop, with the container events or by rescanning
+/// them when the events are not supported
+pub struct LabelDiscovery {
+    subscribe: Subscribe,
+    events: Option<BoxStream<'static, Result<ContainerEvent>>>,
+    subscribed_at: Instant,
+    /// Whether the events already failed, in which case retries are only logged at debug level
+    fell_back: bool,
+    rescan_interval: Duration,
+    retry_interval: Duration,
+    rescan: Option<(Interval, Interval)>,
+}
+
+impl LabelDiscovery {
+    /// Start following the containers, `subscribe` is called to listen to their events
+    pub fn new(subscribe: impl FnMut() -> Result<BoxStream<'static, Result<ContainerEvent>>> + Send + 'static, rescan_interval: Duration, retry_interval: Duration) -> Self {
+        let mut discovery = LabelDiscovery {
+            subscribe: Box::new(subscribe),
+            events: None,
+            subscribed_at: Instant::now(),
+            fell_back: false,
+            rescan_interval,
+            retry_interval,
+            rescan: None,
+        };
+        if let Err(e) = discovery.subscribe() {
+            discovery.fall_back(&format!("the subscription failed: {}", e));
+        }
+        discovery
+    }
+
+    /// The way containers are currently detected
+    pub fn mode(&self) -> DiscoveryMode {
+        match self.rescan {
+            Some(_) => DiscoveryMode::Rescan(self.rescan_interval),
+            None => DiscoveryMode::Events,
+        }
+    }
+
+    fn subscribe(&mut self) -> Result<()> {
+        self.events = Some((self.subscribe)()?);
+        self.subscribed_at = Instant::now();
+        self.rescan = None;
+        Ok(())
+    }
+
+    fn fall_back(&mut self, reason: &str) {
+        match self.fell_back {
+            true => debug!("Container events are still not available, rescanning the containers every {}s: {}", self.rescan_interval.as_secs(), reason),
+            false => warn!(
+                "Container events are not available ({}), rescanning the containers every {}s instead and retrying every {}s",
+                reason, self.rescan_interval.as_secs(), self.retry_interval.as_secs(),
+            ),
+        }
+        self.fell_back = true;
+        self.events = None;
+        let mut rescan = interval(self.rescan_interval);
+        rescan.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut retry = interval_at(Instant::now() + self.retry_interval, self.retry_interval);
+        retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.rescan = Some((rescan, retry));
+    }
+
+    /// Wait for the next change to apply.
+    ///
+    /// This future may be dropped at any time without missing an event.
+    pub async fn next(&mut self) -> Discovery {
+        loop {
+            if let Some((rescan, retry)) = self.rescan.as_mut() {
+                tokio::select! {
+                    // A due retry wins so that the rescans can not delay it forever
+                    biased;
+                    _ = retry.tick() => match self.subscribe() {
+                        Ok(()) => {
+                            debug!("Subscribed to the container events again");
+                            // The containers that changed before the subscription would be missed
+                            return Discovery::Rescan;
+                        },
+                        Err(e) => debug!("Failed to subscribe to the container events again: {}", e),
+                    },
+                    _ = rescan.tick() => return Discovery::Rescan,
+                }
+                continue;
+            }
+            let Some(events) = self.events.as_mut() else {
+                return std::future::pending().await;
+            };
+            match events.next().await {
+                Some(Ok(event)) => {
+                    if self.fell_back {
+                        info!("Container events are available again, stopping the periodic rescan");
+                        self.fell_back = false;
+                    }
+                    return Discovery::Event(event);
+                },
+                Some(Err(e)) if is_unsupported(&e) => self.fall_back(&format!("the subscription was refused: {}", e)),
+                Some(Err(e)) => error!("Failed to receive container events: {}", e),
+                None if self.subscribed_at.elapsed() < IMMEDIATE_END => self.fall_back("the event stream ended immediately"),
+                None => self.fall_back("the event stream ended"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+    use anyhow::{Error, Result};
+    use bollard::errors::Error as DockerError;
+    use futures_util::{stream::{self, BoxStream}, StreamExt};
+    use tokio::time::{timeout, Duration};
+
+    use crate::loader::ContainerEvent;
+
+    use super::{Discovery, DiscoveryMode, LabelDiscovery};
+
+    fn refused() -> Result<ContainerEvent> {
+        Err(Error::new(DockerError::DockerResponseServerError { status_code: 403, message: "forbidden".to_string() }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rescan_when_events_are_refused() {
+        // The first subscriptions are refused by a proxy, whose configuration is then fixed
+        let subscriptions = Arc::new(AtomicUsize::new(0));
+        let counter = subscriptions.clone();
+        let mut discovery = LabelDiscovery::new(move || {
+            let stream: BoxStream<'static, Result<ContainerEvent>> = match counter.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => stream::once(async { refused() }).boxed(),
+                _ => stream::once(async { Ok(ContainerEvent::Started("a".to_string())) }).chain(stream::pending()).boxed(),
+            };
+            Ok(stream)
+        }, Duration::from_secs(60), Duration::from_secs(600));
+        assert_eq!(discovery.mode(), DiscoveryMode::Events);
+
+        // The refusal is detected and the rescan ticks take over, the first one immediately
+        assert_eq!(discovery.next().await, Discovery::Rescan);
+        assert_eq!(discovery.mode(), DiscoveryMode::Rescan(Duration::from_secs(60)));
+        let start = tokio::time::Instant::now();
+        for _ in 0..9 {
+            assert_eq!(discovery.next().await, Discovery::Rescan);
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(540));
+
+        // The retry subscribes again, which is refused once more
+        assert_eq!(discovery.next().await, Discovery::Rescan);
+        assert_eq!(subscriptions.load(Ordering::SeqCst), 2);
+        assert_eq!(discovery.next().await, Discovery::Rescan);
+        assert!(matches!(discovery.mode(), DiscoveryMode::Rescan(_)));
+
+        // Once the events are accepted, the rescans stop
+        for _ in 0..20 {
+            if discovery.mode() == DiscoveryMode::Events {
+                break;
+            }
+            discovery.next().await;
+        }
+        assert_eq!(discovery.mode(), DiscoveryMode::Events);
+        assert_eq!(discovery.next().await, Discovery::Event(ContainerEvent::Started("a".to_string())));
+        assert!(timeout(Duration::from_secs(3600), discovery.next()).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rescan_when_events_end() {
+        let mut discovery = LabelDiscovery::new(|| Ok(stream::empty().boxed()), Duration::from_secs(30), Duration::from_secs(600));
+        assert_eq!(discovery.next().await, Discovery::Rescan);
+        assert_eq!(discovery.mode(), DiscoveryMode::Rescan(Duration::from_secs(30)));
+
+        let discovery = LabelDiscovery::new(|| Err(Error::msg("no handle")), Duration::from_secs(30), Duration::from_secs(600));
+        assert_eq!(discovery.mode(), DiscoveryMode::Rescan(Duration::from_secs(30)));
+    }
+}
@@ -1,122 +1,667 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use anyhow::{Error, Result};
-use bollard::{container::ListContainersOptions, Docker};
+use bollard::{container::ListContainersOptions, secret::{EventMessage, EventMessageTypeEnum}, system::EventsOptions, Docker};
+use futures_util::{Stream, StreamExt};
 use json::{self, JsonValue};
 use tracing::{debug, error, trace, warn};
 
-use crate::job::LocalJobInfo;
+use crate::{job::{find_key, parse_secret_reference, registered_kind, LocalJobInfo, ANNOTATION_PREFIX, FILE_SECRET_BACKEND}, utils::Elided};
 
-pub async fn get_tagged_targets(handle: &Docker, label_prefixes: &Vec<String>, allow_unsafe_jobs: bool) -> Result<HashMap<String, HashMap<String, Vec<String>>>> {
+use super::{cache::{ContainerJobs, LabelCache}, ContainerEvent, COMPOSE_CONTAINER_NUMBER_LABEL, COMPOSE_PROJECT_LABEL, COMPOSE_SERVICE_LABEL, ORIGIN_KEY};
+
+/// Get the job maps declared in the labels of the running containers that are enabled with one
+/// of the prefixes and match the `filters` of the container list.
+///
+/// With `compose_services`, the replicas of a Compose service declare a single job for the
+/// service, see [`ServiceReplicas`].
+pub async fn get_tagged_targets(
+    handle: &Docker, label_prefixes: &Vec<String>, filters: &HashMap<String, Vec<String>>, compose_services: bool,
+    allow_unsafe_jobs: bool, max_value_length: usize, cache: &mut LabelCache,
+) -> Result<HashMap<String, HashMap<String, Vec<String>>>> {
     let mut container_idx: HashSet<String> = HashSet::new();
     let mut job_map: HashMap<String, HashMap<String, Vec<String>>> = HashMap::new();
+    let mut services = ServiceReplicas::default();
     for prefix in label_prefixes {
         let label_filter = format!("{prefix}.enabled=true");
-        debug!["Looking for containers with label {label_filter}"];
+        debug!["Looking for containers with label {label_filter} and filters {:?}", filters];
+        let mut filters = filters.clone();
+        filters.entry("label".into()).or_default().push(label_filter);
         let options = ListContainersOptions::<String> {
-            filters: HashMap::from([("label".into(), vec![label_filter])]),
+            filters,
             ..Default::default()
         };
-        let container_list;
-        match handle.list_containers(Some(options)).await {
-            Ok(l) => container_list = l,
+        let container_list = match handle.list_containers(Some(options)).await {
+            Ok(l) => l,
             Err(e) => {
                 error!("Failed to get container list: {}", e);
                 return Err(Error::msg("Failed to get container list"));
             }
-        }
+        };
         debug!("Found {} candidate containers", container_list.len());
         for container in container_list {
-            let container_id = container.id.as_ref().unwrap();
-            if container_idx.contains(container_id) {
+            let container_id = container.id.unwrap();
+            if container_idx.contains(&container_id) {
                 debug!["Skipping {} as it was already encountered", container_id];
                 continue;
             }
-            container_idx.insert(container_id.to_string());
-            debug!("On container {:?}", container);
-            if !container.labels.as_ref().is_some_and(|c| !c.is_empty()) {
+            let labels = container.labels.unwrap_or_default();
+            debug!("On container {} with labels {:?}", container_id, Elided(&labels));
+            container_idx.insert(container_id.clone());
+            if labels.is_empty() {
                 continue;
             }
-            for (key, value) in container.labels.as_ref().unwrap() {
-                let mut key_parts = key.split(".");
-                if key_parts.next().map_or(true, |p| !label_prefixes.contains(&p.to_string())) {
-                    trace!["Skipping label {} as it does not start with one of the expected prefix", key];
-                    continue;
-                }
-                let job_kind = key_parts.next().and_then(|k| Some(k.to_string()));
-                let job_name = key_parts.next().and_then(|n| Some(n.to_string()));
-                let job_parameter = key_parts.next().and_then(|p| Some(p.to_string()));
-                if job_kind.is_none() || job_name.is_none() || job_parameter.is_none() || key_parts.next().is_some() {
-                    trace!["Skipping label {} as its key does not contain the 4 expected parts", key];
-                    continue;
+            let name = container.names.as_ref().and_then(|n| n.first()).map(|n| n.trim_start_matches('/').to_string());
+            let fingerprint = LabelCache::fingerprint(container.created.unwrap_or_default(), &labels, (label_prefixes, allow_unsafe_jobs, max_value_length, &name));
+            let jobs = cache.get_or_parse(&container_id, fingerprint, || {
+                let mut jobs = parse_container_labels(&container_id, &labels, label_prefixes, allow_unsafe_jobs, max_value_length)?;
+                if let Some(name) = name.as_ref() {
+                    target_by_name(&mut jobs, &container_id, name);
                 }
-                let job_kind = job_kind.unwrap();
-                let job_name = job_name.unwrap();
-                let job_parameter = job_parameter.unwrap();
-                if !allow_unsafe_jobs {
-                    match job_kind.as_str() {
-                        LocalJobInfo::LABEL => {
-                            error!["Found local job declared in tags, however this is not allowed. Skipping label {}.", key];
-                            continue;
-                        },
-                        _ => {},
-                    }
+                if let Some(message) = explain_missing_jobs(&container_id, &labels, label_prefixes, &jobs) {
+                    warn!("{}", message);
                 }
-                // Start including the key
-                let job_key = format!["{}_{}_{}", container_id, job_kind, job_name];
-                if !job_map.contains_key(&job_key) {
-                    let mut initial_map = vec![
-                        ("kind".to_string(), vec![job_kind.clone()]),
-                        ("name".to_string(), vec![job_name.clone()]),
-                    ];
-                    if job_kind != LocalJobInfo::LABEL {
-                        initial_map.push(("container".to_string(), vec![container_id.clone()]));
-                    }
-                    job_map.insert(job_key.clone(), HashMap::from_iter(initial_map));
+                Ok(jobs)
+            })?;
+            match compose_services {
+                true => job_map.extend(services.add(&container_id, &labels, jobs).into_iter().flatten()),
+                false => job_map.extend(jobs),
+            }
+        }
+    }
+    job_map.extend(services.into_jobs());
+    cache.retain(&container_idx);
+    debug!("Label cache usage: {} hits, {} misses, {} containers cached", cache.hits(), cache.misses(), cache.len());
+    Ok(job_map)
+}
+
+/// Make the jobs declared in a container's labels target the container by its name instead of
+/// its id, so that they keep working once the container is recreated under the same name
+fn target_by_name(jobs: &mut ContainerJobs, container_id: &str, name: &str) {
+    for job in jobs.values_mut() {
+        if let Some(container) = job.get_mut("container").filter(|c| c.len() == 1 && c[0] == container_id) {
+            container[0] = name.to_string();
+        }
+    }
+}
+
+/// The jobs of the replicas of Compose services, of which a single one declares the jobs of its service.
+///
+/// The jobs of the replica with the lowest container number are kept, keyed by the service
+/// instead of the container so that a service scaled to several replicas declares each of its
+/// jobs once. These jobs target the kept replica.
+#[derive(Default)]
+struct ServiceReplicas {
+    /// The kept replica of each project and service: its container number and id, and its jobs
+    services: HashMap<(String, String), (u64, String, ContainerJobs)>,
+}
+
+impl ServiceReplicas {
+    /// Add the jobs of a container, which are returned as is when it is not part of a Compose service
+    fn add(&mut self, container_id: &str, labels: &HashMap<String, String>, jobs: ContainerJobs) -> Option<ContainerJobs> {
+        let (Some(project), Some(service)) = (labels.get(COMPOSE_PROJECT_LABEL), labels.get(COMPOSE_SERVICE_LABEL)) else {
+            return Some(jobs);
+        };
+        let number = labels.get(COMPOSE_CONTAINER_NUMBER_LABEL).and_then(|n| n.parse().ok()).unwrap_or(u64::MAX);
+        match self.services.get(&(project.clone(), service.clone())) {
+            Some((kept_number, kept_id, _)) if (*kept_number, kept_id.as_str()) < (number, container_id) => {
+                debug!("Ignoring the jobs of container {} as another replica of service {}/{} declares them", container_id, project, service);
+            },
+            _ => {
+                self.services.insert((project.clone(), service.clone()), (number, container_id.to_string(), jobs));
+            },
+        }
+        None
+    }
+
+    /// Get the jobs of the kept replicas, keyed by their service
+    fn into_jobs(self) -> ContainerJobs {
+        let mut job_map = ContainerJobs::new();
+        for ((project, service), (_, container_id, jobs)) in self.services {
+            for (key, mut job) in jobs {
+                let job_key = format!("{}_{}{}", project, service, key.strip_prefix(container_id.as_str()).unwrap_or(&key));
+                job.insert(ORIGIN_KEY.to_string(), vec![format!(
+                    "service {}/{} (container {})", project, service, container_id.chars().take(12).collect::<String>(),
+                )]);
+                job_map.insert(job_key, job);
+            }
+        }
+        job_map
+    }
+}
+
+/// Read a multi-valued label: a JSON array of strings is split in its values,
+/// anything else is used as a single raw value
+fn parse_label_list(value: &str) -> Vec<String> {
+    match json::parse(value) {
+        Ok(JsonValue::Array(items)) => items.iter()
+            .map(|i| i.as_str().map(|s| s.to_string()))
+            .collect::<Option<Vec<String>>>()
+            .unwrap_or_else(|| vec![value.to_owned()]),
+        _ => vec![value.to_owned()],
+    }
+}
+
+/// Extract the job maps declared in a container's labels
+///
+/// Values longer than `max_value_length` are rejected, which leaves their job incomplete.
+/// A job's commands may be listed with indexed labels (`command.1`, `command.2`...), which are
+/// run after the one set in the `command` label in the order of their index. Annotations are
+/// set with a fifth segment (`annotation.team`).
+pub(crate) fn parse_container_labels(container_id: &str, labels: &HashMap<String, String>, label_prefixes: &[String], allow_unsafe_jobs: bool, max_value_length: usize) -> Result<ContainerJobs> {
+    let mut job_map: ContainerJobs = HashMap::new();
+    let mut indexed_commands: HashMap<String, BTreeMap<u64, String>> = HashMap::new();
+    for (key, value) in labels {
+        let key_parts: Vec<&str> = key.split('.').collect();
+        if !label_prefixes.iter().any(|p| p == key_parts[0]) {
+            trace!["Skipping label {} as it does not start with one of the expected prefix", key];
+            continue;
+        }
+        let Some((job_kind, job_name, job_parameter, command_index)) = split_job_label(&key_parts[1..]) else {
+            trace!["Skipping label {} as its key does not contain the 4 expected parts", key];
+            continue;
+        };
+        let Some(kind_info) = registered_kind(&job_kind) else {
+            warn!["Skipping label {} as {} is not a supported job kind", key, job_kind];
+            continue;
+        };
+        if !allow_unsafe_jobs && kind_info.unsafe_from_labels {
+            error!["Found {} job declared in tags, however this is not allowed. Skipping label {}.", job_kind, key];
+            continue;
+        }
+        if !allow_unsafe_jobs && find_key(&job_kind, &job_parameter).is_some_and(|k| k.unsafe_from_labels) {
+            error!["Found the {} key declared in tags, however this is not allowed. Skipping label {}.", job_parameter, key];
+            continue;
+        }
+        if value.len() > max_value_length {
+            error![
+                "Skipping label {} on container {} as its value is {} bytes long, over the {} bytes limit: {:?}",
+                key, container_id, value.len(), max_value_length, Elided(value),
+            ];
+            continue;
+        }
+        // Start including the key
+        let job_key = format!["{}_{}_{}", container_id, job_kind, job_name];
+        if !job_map.contains_key(&job_key) {
+            let mut initial_map = vec![
+                ("kind".to_string(), vec![job_kind.clone()]),
+                ("name".to_string(), vec![job_name.clone()]),
+                (ORIGIN_KEY.to_string(), vec![format!("container {}", container_id.chars().take(12).collect::<String>())]),
+            ];
+            if job_kind != LocalJobInfo::LABEL {
+                initial_map.push(("container".to_string(), vec![container_id.to_string()]));
+            }
+            job_map.insert(job_key.clone(), HashMap::from_iter(initial_map));
+        }
+        let evt_info = job_map.get_mut(&job_key).unwrap();
+        if !evt_info.get("kind").unwrap().contains(&job_kind) {
+            error!["Found conflicting cron types for job {} (had '{}' but found '{}' in {})", job_name, evt_info.get("kind").unwrap().first().unwrap(), job_kind, key];
+            return Err(Error::msg("Conflicting cron types on label"));
+        }
+        if let Some(index) = Some(command_index).filter(|i| *i > 0) {
+            let commands = indexed_commands.entry(job_key).or_default();
+            match commands.get(&index) {
+                Some(command) if command != value => {
+                    warn!["Parameter is set more than once with different label prefixes (found on {})", key];
+                    return Err(Error::msg("Parameter set more than once has different values in its occurences"));
+                },
+                _ => { commands.insert(index, value.to_owned()); },
+            }
+            continue;
+        }
+        // FIXME: this is only required due to the fact that we allow the use of multiple prefix keys
+        if let Some(param_value) = evt_info.get(&job_parameter) {
+            if job_parameter == "container" && param_value.len() == 1 && param_value.contains(value) {
+                evt_info.remove("container");
+            } else {
+                warn!["Parameter is set more than once with different label prefixes (found on {})", key];
+                if !param_value.contains(value) {
+                    return Err(Error::msg("Parameter set more than once has different values in its occurences"));
                 }
-                let evt_info = job_map.get_mut(&job_key).unwrap();
-                if !evt_info.get("kind").unwrap().contains(&job_kind) {
-                    error!["Found conflicting cron types for job {} (had '{}' but found '{}' in {})", job_name, evt_info.get("kind").unwrap().first().unwrap(), job_kind, key];
-                    return Err(Error::msg("Conflicting cron types on label"));
+                continue;
+            }
+        }
+        if job_parameter == "environment" {
+            // Secrets are read with cfc's privileges: containers may only reference files, and only when trusted
+            let secret_backends: Vec<String> = parse_label_list(value).iter()
+                .filter_map(|entry| parse_secret_reference(entry).map(|(backend, _)| backend.to_string()))
+                .collect();
+            if secret_backends.iter().any(|b| b != FILE_SECRET_BACKEND) {
+                error!["Only the {} secret backend may be used in tags. Skipping label {}.", FILE_SECRET_BACKEND, key];
+                continue;
+            }
+            if !allow_unsafe_jobs && !secret_backends.is_empty() {
+                error!["Found a secret reference declared in tags, however this is not allowed. Skipping label {}.", key];
+                continue;
+            }
+        }
+        match job_parameter.as_str() {
+            "volume"|"network"|"environment" => {
+                evt_info.insert(job_parameter, parse_label_list(value));
+            },
+            _ => {evt_info.insert(job_parameter, vec![value.to_owned()]);},
+        }
+    }
+    for (job_key, commands) in indexed_commands {
+        if let Some(evt_info) = job_map.get_mut(&job_key) {
+            evt_info.entry("command".to_string()).or_default().extend(commands.into_values());
+        }
+    }
+    Ok(job_map)
+}
+
+/// Split the segments of a label key that follow its prefix in the job's kind, name, parameter
+/// and command index, `0` when the label does not set an indexed command.
+///
+/// The parameter is the last segment, or the last two for indexed commands (`command.1`) and
+/// annotations (`annotation.team`), and the job's name is made of the segments between the kind
+/// and the parameter, so that it may contain dots. The parameter of a job whose name contains
+/// dots must be one of its kind's keys, which rejects the keys that have extra segments.
+fn split_job_label(parts: &[&str]) -> Option<(String, String, String, u64)> {
+    let (kind, rest) = parts.split_first()?;
+    if rest.len() < 2 {
+        return None;
+    }
+    let (name, parameter, index) = match rest.split_at(rest.len() - 2) {
+        (name, ["command", index]) if !name.is_empty() && index.parse::<u64>().is_ok() => (name, "command".to_string(), index.parse().ok()?),
+        (name, ["annotation", annotation]) if !name.is_empty() => (name, format!("{}{}", ANNOTATION_PREFIX, annotation), 0),
+        _ => {
+            let (parameter, name) = rest.split_last()?;
+            if name.len() > 1 && find_key(kind, parameter).is_none() {
+                return None;
+            }
+            (name, parameter.to_string(), 0)
+        },
+    };
+    Some((kind.to_string(), name.join("."), parameter, index))
+}
+
+/// Count the labels that have the shape of a job label but whose prefix is not one of the
+/// expected prefixes, by prefix
+pub(crate) fn near_miss_labels(labels: &HashMap<String, String>, label_prefixes: &[String]) -> BTreeMap<String, usize> {
+    let mut near_misses: BTreeMap<String, usize> = BTreeMap::new();
+    for key in labels.keys() {
+        let parts: Vec<&str> = key.split('.').collect();
+        if parts.len() >= 4 && !label_prefixes.iter().any(|p| p == parts[0]) && registered_kind(parts[1]).is_some() {
+            *near_misses.entry(parts[0].to_string()).or_default() += 1;
+        }
+    }
+    near_misses
+}
+
+/// Explain why a container that is enabled for one of the expected prefixes has no job, when
+/// its job labels use another prefix (e.g. a service half-migrated from ofelia)
+pub(crate) fn explain_missing_jobs(container_id: &str, labels: &HashMap<String, String>, label_prefixes: &[String], jobs: &ContainerJobs) -> Option<String> {
+    if !jobs.is_empty() {
+        return None;
+    }
+    let enabled: Vec<&str> = label_prefixes.iter()
+        .filter(|p| labels.get(&format!("{}.enabled", p)).is_some_and(|v| v == "true"))
+        .map(|p| p.as_str())
+        .collect();
+    let near_misses = near_miss_labels(labels, label_prefixes);
+    if enabled.is_empty() || near_misses.is_empty() {
+        return None;
+    }
+    let found: Vec<String> = near_misses.iter().map(|(prefix, count)| format!("{} job labels with the {} prefix", count, prefix)).collect();
+    let other: Vec<&str> = near_misses.keys().map(|p| p.as_str()).collect();
+    Some(format!(
+        "Container {} is enabled with the {} prefix but declares no job with it, while it has {}: add {} to --prefix or rename these labels to use the {} prefix",
+        container_id.chars().take(12).collect::<String>(), enabled.join(", "), found.join(" and "), other.join(", "), enabled[0],
+    ))
+}
+
+/// Convert an engine event to a [`ContainerEvent`] if it concerns a container
+/// that has jobs enabled with one of the label prefixes
+fn to_container_event(event: EventMessage, label_prefixes: &[String]) -> Option<ContainerEvent> {
+    if event.typ != Some(EventMessageTypeEnum::CONTAINER) {
+        return None;
+    }
+    let actor = event.actor?;
+    let attributes = actor.attributes.unwrap_or_default();
+    let enabled = label_prefixes.iter()
+        .any(|p| attributes.get(&format!("{}.enabled", p)).is_some_and(|v| v == "true"));
+    if !enabled {
+        return None;
+    }
+    match event.action.as_deref() {
+        Some("start") => Some(ContainerEvent::Started(actor.id?)),
+        Some("die") => Some(ContainerEvent::Stopped(actor.id?)),
+        _ => None,
+    }
+}
+
+/// Listen to the start and stop events of the containers that have jobs enabled with one of the label prefixes
+pub fn container_events(handle: &Docker, label_prefixes: &[String]) -> impl Stream<Item = Result<ContainerEvent>> {
+    let options = EventsOptions::<String> {
+        filters: HashMap::from([
+            ("type".into(), vec!["container".into()]),
+            ("event".into(), vec!["start".into(), "die".into()]),
+        ]),
+        ..Default::default()
+    };
+    let label_prefixes = label_prefixes.to_vec();
+    handle.events(Some(options)).filter_map(move |event| {
+        let event = event.map(|e| to_container_event(e, &label_prefixes)).map_err(Error::new).transpose();
+        async move { event }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bollard::secret::{EventActor, EventMessage, EventMessageTypeEnum};
+
+    use crate::loader::cache::LabelCache;
+
+    use crate::job::{JobInfo, LocalJobInfo, JOB_KINDS};
+
+    use super::{explain_missing_jobs, near_miss_labels, parse_container_labels, parse_label_list, target_by_name, to_container_event, ContainerEvent, ServiceReplicas};
+
+    /// A small deterministic generator (xorshift64*) so that failures can be replayed from their seed
+    struct Gen(u64);
+
+    impl Gen {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545F4914F6CDD1D)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, values: &[&'a str]) -> &'a str {
+            values[self.below(values.len())]
+        }
+
+        /// An arbitrary string, biased towards the characters that are meaningful to the parser
+        fn string(&mut self, max_len: usize) -> String {
+            const CHARS: &[char] = &['.', '.', '"', '[', ']', ',', '\\', ' ', 'a', 'b', '-', '_', '0', 'é', '\u{0}', '{', '}'];
+            (0..self.below(max_len + 1)).map(|_| match self.below(4) {
+                0 => char::from_u32(self.next() as u32 % 0x2FFFF).unwrap_or('x'),
+                _ => CHARS[self.below(CHARS.len())],
+            }).collect()
+        }
+
+        /// A label key that is likely, but not guaranteed, to be well-formed
+        fn key(&mut self) -> String {
+            let parts = [
+                self.pick(&["cfc", "ofelia", "other", ""]).to_string(),
+                if self.below(4) == 0 { self.string(12) } else { self.pick(&JOB_KINDS).to_string() },
+                if self.below(4) == 0 { self.string(8) } else { self.pick(&["a", "b"]).to_string() },
+                if self.below(4) == 0 { self.string(8) } else { self.pick(&["schedule", "command", "environment", "volume", "network", "container"]).to_string() },
+            ];
+            let count = if self.below(4) == 0 { self.below(6) } else { 4 };
+            parts.iter().cycle().take(count).cloned().collect::<Vec<_>>().join(".")
+        }
+
+        /// A label value, sometimes a JSON array
+        fn value(&mut self) -> String {
+            match self.below(4) {
+                0 => format!("[{}]", (0..self.below(4)).map(|_| format!("{:?}", self.string(6))).collect::<Vec<_>>().join(",")),
+                1 => format!("[\"a\", {}]", self.pick(&["1", "null", "[\"b\"]", "\"c\""])),
+                _ => self.string(20),
+            }
+        }
+    }
+
+    #[test]
+    fn label_parser_properties() {
+        let prefixes = vec!["cfc".to_string(), "ofelia".to_string()];
+        for seed in 1..2000u64 {
+            let mut gen = Gen(seed);
+            let labels: HashMap<String, String> = (0..gen.below(8)).map(|_| (gen.key(), gen.value())).collect();
+            for allow_unsafe in [false, true] {
+                // The parser never panics, and only accepts known kinds
+                let Ok(jobs) = parse_container_labels("id", &labels, &prefixes, allow_unsafe, 1024) else { continue };
+                for job in jobs.values() {
+                    let kind = job.get("kind").and_then(|k| k.first()).expect("a job map has no kind");
+                    assert!(JOB_KINDS.contains(&kind.as_str()), "seed {}: unknown kind {:?}", seed, kind);
+                    assert!(allow_unsafe || kind != LocalJobInfo::LABEL, "seed {}: an unsafe job was accepted", seed);
+                    assert!(job.get("name").is_some_and(|n| n.len() == 1), "seed {}: the job has no name", seed);
                 }
-                // FIXME: this is only required due to the fact that we allow the use of multiple prefix keys
-                let param_value = evt_info.get(&job_parameter);
-                if param_value.is_some() {
-                    if job_parameter == "container" && evt_info.get("container").map_or(true, |v| v.len() == 1 && v.contains(value)) {
-                        evt_info.remove("container");
-                    } else {
-                        warn!["Parameter is set more than once with different label prefixes (found on {})", key];
-                        if !param_value.unwrap().contains(value) {
-                            return Err(Error::msg("Parameter set more than once has different values in its occurences"));
-                        }
+                // Every accepted label ends up in the job map of its kind and name
+                for (key, value) in &labels {
+                    let parts: Vec<&str> = key.split('.').collect();
+                    let [prefix, kind, name, parameter] = parts[..] else { continue };
+                    if !prefixes.iter().any(|p| p == prefix) || !JOB_KINDS.contains(&kind) || (!allow_unsafe && kind == LocalJobInfo::LABEL) {
                         continue;
                     }
-                }
-                match job_parameter.as_str() {
-                    "volume"|"network"|"environment" => {
-                        evt_info.insert(job_parameter, json::parse(value)
-                            .map_or_else(|e| Err(Error::new(e)), |j| {
-                                if let JsonValue::Array(v) = j {
-                                    let mut values = vec![];
-                                    for i in v {
-                                        if let JsonValue::String(s) = i {
-                                            values.push(s);
-                                        } else {
-                                            return Err(Error::msg(""));
-                                        }
-                                    }
-                                    return Ok(values);
-                                } else {
-                                    return Err(Error::msg(""));
-                                }
-                            })
-                            .unwrap_or_else(|_| vec![value.to_owned()])
-                        );
-                    },
-                    _ => {evt_info.insert(job_parameter, vec![value.to_owned()]);},
+                    let job = jobs.get(&format!("id_{}_{}", kind, name)).unwrap_or_else(|| panic!("seed {}: {} was dropped", seed, key));
+                    if parameter != "container" {
+                        let values = job.get(parameter).unwrap_or_else(|| panic!("seed {}: {} was dropped", seed, key));
+                        assert!(values.contains(value) || *values == parse_label_list(value), "seed {}: {} was not kept", seed, key);
+                    }
                 }
             }
         }
     }
-    Ok(job_map)
+
+    #[test]
+    fn label_list_values() {
+        let mut gen = Gen(42);
+        for _ in 0..2000 {
+            let value = gen.value();
+            let values = parse_label_list(&value);
+            // Either every item of a JSON array of strings, or the raw value
+            match json::parse(&value) {
+                Ok(json::JsonValue::Array(items)) if items.iter().all(|i| i.is_string()) => {
+                    assert_eq!(values, items.iter().map(|i| i.as_str().unwrap().to_string()).collect::<Vec<_>>());
+                },
+                _ => assert_eq!(values, vec![value]),
+            }
+        }
+    }
+
+    #[test]
+    fn reject_oversized_label_values() {
+        let prefixes = vec!["cfc".to_string()];
+        let huge = "x".repeat(8 << 20);
+        let labels = HashMap::from([
+            ("cfc.job-exec.a.schedule".to_string(), "@daily".to_string()),
+            ("cfc.job-exec.a.command".to_string(), huge.clone()),
+            ("cfc.job-exec.b.command".to_string(), "x".repeat(1024)),
+            ("other.config".to_string(), huge),
+        ]);
+        let mut jobs = parse_container_labels("id", &labels, &prefixes, false, 1024).unwrap();
+        // The oversized value is neither kept nor truncated
+        let stored: usize = jobs.values().flat_map(|j| j.values()).flatten().map(|v| v.len()).sum();
+        assert!(stored < 2048, "{} bytes were kept", stored);
+        assert_eq!(jobs["id_job-exec_b"]["command"][0].len(), 1024);
+        // The incomplete job fails its validation instead
+        let e = JobInfo::try_from(jobs.remove("id_job-exec_a").unwrap()).unwrap_err();
+        assert!(e.to_string().contains("command"), "{}", e);
+    }
+
+    #[test]
+    fn half_migrated_container() {
+        let prefixes = vec!["cfc".to_string()];
+        let labels: HashMap<String, String> = [
+            ("cfc.enabled", "true"),
+            ("ofelia.enabled", "true"),
+            ("ofelia.job-exec.backup.schedule", "@daily"),
+            ("ofelia.job-exec.backup.command", "backup.sh"),
+            ("ofelia.job-exec.backup.annotation.team", "ops"),
+            // Labels that do not have the shape of a job label are not near misses
+            ("com.docker.compose.project", "app"),
+            ("ofelia.job-exec.backup", "x"),
+        ].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let jobs = parse_container_labels("0123456789abcdef", &labels, &prefixes, false, 1024).unwrap();
+        assert!(jobs.is_empty());
+        assert_eq!(near_miss_labels(&labels, &prefixes).into_iter().collect::<Vec<_>>(), vec![("ofelia".to_string(), 3)]);
+        assert_eq!(
+            explain_missing_jobs("0123456789abcdef", &labels, &prefixes, &jobs).unwrap(),
+            "Container 0123456789ab is enabled with the cfc prefix but declares no job with it, while it has 3 job labels with the ofelia prefix: \
+            add ofelia to --prefix or rename these labels to use the cfc prefix",
+        );
+
+        // Nothing is reported once the prefix is expected, or when the container is not enabled for an expected prefix
+        let both = vec!["cfc".to_string(), "ofelia".to_string()];
+        let jobs = parse_container_labels("0123456789abcdef", &labels, &both, false, 1024).unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert!(explain_missing_jobs("0123456789abcdef", &labels, &both, &jobs).is_none());
+        let mut labels = labels;
+        labels.remove("cfc.enabled");
+        assert!(explain_missing_jobs("0123456789abcdef", &labels, &prefixes, &Default::default()).is_none());
+    }
+
+    #[test]
+    fn compose_replicas_declare_one_job() {
+        let prefixes = vec!["cfc".to_string()];
+        let replica = |number: &str| -> HashMap<String, String> {
+            [("cfc.enabled", "true"), ("cfc.job-exec.backup.schedule", "@daily"), ("cfc.job-exec.backup.command", "backup.sh"),
+                ("com.docker.compose.project", "app"), ("com.docker.compose.service", "db"), ("com.docker.compose.container-number", number)]
+                .iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        let mut services = ServiceReplicas::default();
+        for (id, number) in [("bbbbbbbbbbbbbbbb", "2"), ("aaaaaaaaaaaaaaaa", "1"), ("cccccccccccccccc", "3")] {
+            let labels = replica(number);
+            let jobs = parse_container_labels(id, &labels, &prefixes, false, 1024).unwrap();
+            assert!(services.add(id, &labels, jobs).is_none());
+        }
+        // Containers outside of Compose keep their own jobs
+        let mut standalone = replica("1");
+        standalone.remove("com.docker.compose.service");
+        let jobs = parse_container_labels("dddddddddddddddd", &standalone, &prefixes, false, 1024).unwrap();
+        assert_eq!(services.add("dddddddddddddddd", &standalone, jobs).unwrap().len(), 1);
+
+        // The service's job targets its first replica
+        let jobs = services.into_jobs();
+        assert_eq!(jobs.keys().collect::<Vec<_>>(), vec!["app_db_job-exec_backup"]);
+        let job = &jobs["app_db_job-exec_backup"];
+        assert_eq!(job["container"], vec!["aaaaaaaaaaaaaaaa"]);
+        assert_eq!(job[super::ORIGIN_KEY], vec!["service app/db (container aaaaaaaaaaaa)"]);
+    }
+
+    #[test]
+    fn jobs_target_containers_by_name() {
+        let labels: HashMap<String, String> = [
+            ("cfc.job-exec.backup.schedule", "@daily"), ("cfc.job-exec.backup.command", "backup.sh"),
+            ("cfc.job-local.host.schedule", "@daily"), ("cfc.job-local.host.command", "true"),
+        ].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let mut jobs = parse_container_labels("0123456789abcdef", &labels, &["cfc".to_string()], true, 1024).unwrap();
+        target_by_name(&mut jobs, "0123456789abcdef", "app-db-1");
+        assert_eq!(jobs["0123456789abcdef_job-exec_backup"]["container"], ["app-db-1"]);
+        assert!(!jobs["0123456789abcdef_job-local_host"].contains_key("container"));
+    }
+
+    #[test]
+    fn label_parser_regressions() {
+        let prefixes = vec!["cfc".to_string()];
+        let parse = |labels: &[(&str, &str)], allow_unsafe: bool| parse_container_labels(
+            "id", &labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(), &prefixes, allow_unsafe, 1024,
+        );
+        // Short strings are stored differently by the JSON parser
+        assert_eq!(parse_label_list(r#"["A=1", "B=a much longer value than thirty bytes"]"#), vec!["A=1", "B=a much longer value than thirty bytes"]);
+        // Arrays mixing strings and other values are not split
+        assert_eq!(parse_label_list(r#"["A=1", 2]"#), vec![r#"["A=1", 2]"#]);
+        assert_eq!(parse_label_list(r#""A=1""#), vec![r#""A=1""#]);
+        assert_eq!(parse_label_list("[\"unterminated"), vec!["[\"unterminated"]);
+        // Job names may contain dots, the parameter is read from the end of the key
+        let jobs = parse(&[
+            ("cfc.job-exec.db.backup.schedule", "@daily"), ("cfc.job-exec.db.backup.command.1", "ls"),
+            ("cfc.job-exec.db.backup.annotation.team", "storage"), ("cfc.job-exec.db.backup.command", "true"),
+        ], false).unwrap();
+        assert_eq!(jobs["id_job-exec_db.backup"]["name"], vec!["db.backup"]);
+        assert_eq!(jobs["id_job-exec_db.backup"]["command"], vec!["true", "ls"]);
+        assert_eq!(jobs["id_job-exec_db.backup"]["annotation.team"], vec!["storage"]);
+        assert!(parse(&[("cfc.job-exec..schedule", "@daily")], false).unwrap().len() == 1);
+        // Keys with extra segments are still malformed
+        for malformed in ["cfc.job-exec.backup", "cfc.job-exec.db.backup.unknown-key", "cfc.job-exec.backup.schedule.daily"] {
+            assert!(parse(&[(malformed, "@daily")], false).unwrap().is_empty(), "{}", malformed);
+        }
+        // Unknown and unsafe kinds are rejected
+        assert!(parse(&[("cfc.job-unknown.a.schedule", "@daily")], true).unwrap().is_empty());
+        assert!(parse(&[("cfc.job-local.a.schedule", "@daily")], false).unwrap().is_empty());
+        assert_eq!(parse(&[("cfc.job-local.a.schedule", "@daily")], true).unwrap().len(), 1);
+        // Unsafe keys of safe kinds are rejected
+        let jobs = parse(&[("cfc.job-exec.a.schedule", "@daily"), ("cfc.job-exec.a.pass-environment", "true")], false).unwrap();
+        assert!(!jobs["id_job-exec_a"].contains_key("pass-environment"));
+        let jobs = parse(&[("cfc.job-exec.a.schedule", "@daily"), ("cfc.job-exec.a.pass-environment", "true")], true).unwrap();
+        assert!(jobs["id_job-exec_a"].contains_key("pass-environment"));
+        // Hooks run on the host like local jobs
+        let jobs = parse(&[("cfc.job-exec.a.schedule", "@daily"), ("cfc.job-exec.a.on-error", "alert")], false).unwrap();
+        assert!(!jobs["id_job-exec_a"].contains_key("on-error"));
+        let jobs = parse(&[("cfc.job-exec.a.schedule", "@daily"), ("cfc.job-exec.a.on-error", "alert")], true).unwrap();
+        assert!(jobs["id_job-exec_a"].contains_key("on-error"));
+        // Webhooks are sent from cfc's network, to any address
+        #[cfg(feature = "webhook")]
+        {
+            let jobs = parse(&[("cfc.job-exec.a.schedule", "@daily"), ("cfc.job-exec.a.webhook-url", "http://relay:8080/cfc")], false).unwrap();
+            assert!(!jobs["id_job-exec_a"].contains_key("webhook-url"));
+            let jobs = parse(&[("cfc.job-exec.a.schedule", "@daily"), ("cfc.job-exec.a.webhook-url", "http://relay:8080/cfc")], true).unwrap();
+            assert!(jobs["id_job-exec_a"].contains_key("webhook-url"));
+        }
+        // Quotes in values are kept verbatim
+        let jobs = parse(&[("cfc.job-exec.a.command", r#"sh -c "echo 'a.b'""#)], false).unwrap();
+        assert_eq!(jobs["id_job-exec_a"]["command"], vec![r#"sh -c "echo 'a.b'""#]);
+        // Indexed commands follow the unindexed one in the order of their index
+        let jobs = parse(&[
+            ("cfc.job-exec.a.command.10", "third"), ("cfc.job-exec.a.command.2", "second"),
+            ("cfc.job-exec.a.command", "first"), ("cfc.job-exec.a.schedule.1", "@daily"),
+        ], false).unwrap();
+        assert_eq!(jobs["id_job-exec_a"]["command"], vec!["first", "second", "third"]);
+        assert!(!jobs["id_job-exec_a"].contains_key("schedule"));
+        assert!(parse(&[("cfc.job-exec.a.command.first", "ls")], false).unwrap().is_empty());
+        // A job may be disabled on its own, while the container stays enabled
+        let mut jobs = parse(&[("cfc.job-exec.a.command", "ls"), ("cfc.job-exec.a.schedule", "@daily"), ("cfc.job-exec.a.enabled", "false")], false).unwrap();
+        assert!(JobInfo::try_from(jobs.remove("id_job-exec_a").unwrap()).unwrap().options().disabled);
+        // Secrets may only be read from files, by trusted containers
+        let secret = |value: &str, allow_unsafe: bool| parse(&[("cfc.job-exec.a.environment", value)], allow_unsafe).unwrap()
+            .get("id_job-exec_a").is_some_and(|j| j.contains_key("environment"));
+        assert!(secret(r#"["A=1", "B=secret://file/run/secrets/b"]"#, true));
+        assert!(!secret(r#"["A=1", "B=secret://file/run/secrets/b"]"#, false));
+        assert!(!secret("B=secret://env/VAULT_TOKEN", true));
+        assert!(secret("B=not-a-secret://env/VAULT_TOKEN", false));
+    }
+
+    fn event(action: &str, labels: &[(&str, &str)]) -> EventMessage {
+        EventMessage {
+            typ: Some(EventMessageTypeEnum::CONTAINER),
+            action: Some(action.to_string()),
+            actor: Some(EventActor {
+                id: Some("abc".to_string()),
+                attributes: Some(labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>()),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reuse_unchanged_containers() {
+        let prefixes = vec!["cfc".to_string()];
+        let labels = |command: &str| HashMap::from([
+            ("cfc.enabled".to_string(), "true".to_string()),
+            ("cfc.job-exec.backup.schedule".to_string(), "@daily".to_string()),
+            ("cfc.job-exec.backup.command".to_string(), command.to_string()),
+        ]);
+        let mut cache = LabelCache::default();
+        let mut parsed = vec![];
+        for pass in [["a", "b", "c"].map(|id| (id, labels("true"))), [("a", labels("true")), ("b", labels("false")), ("c", labels("true"))]] {
+            for (id, labels) in pass {
+                let fingerprint = LabelCache::fingerprint(0, &labels, &prefixes);
+                let jobs = cache.get_or_parse(id, fingerprint, || {
+                    parsed.push(id);
+                    parse_container_labels(id, &labels, &prefixes, false, 1024)
+                }).unwrap();
+                assert_eq!(jobs.len(), 1);
+            }
+        }
+        assert_eq!(parsed, vec!["a", "b", "c", "b"]);
+        assert_eq!((cache.hits(), cache.misses()), (2, 4));
+    }
+
+    #[test]
+    fn filter_container_events() {
+        let prefixes = vec!["cfc".to_string(), "ofelia".to_string()];
+        assert_eq!(to_container_event(event("start", &[("ofelia.enabled", "true")]), &prefixes), Some(ContainerEvent::Started("abc".to_string())));
+        assert_eq!(to_container_event(event("die", &[("cfc.enabled", "true")]), &prefixes), Some(ContainerEvent::Stopped("abc".to_string())));
+        assert_eq!(to_container_event(event("start", &[("cfc.enabled", "false")]), &prefixes), None);
+        assert_eq!(to_container_event(event("start", &[("other.enabled", "true")]), &prefixes), None);
+        assert_eq!(to_container_event(event("pause", &[("cfc.enabled", "true")]), &prefixes), None);
+    }
 }
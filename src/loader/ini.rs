@@ -1,23 +1,83 @@
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap, fmt::Write};
 
 use anyhow::{Error, Result};
 use ini_core as ini;
 use regex::Regex;
 use tracing::{debug, trace, warn};
 
-pub fn parse_ini(payload: &String) -> Result<HashMap<String, HashMap<String, Vec<String>>>> {
+use super::{ordered_keys, ordered_sections, JobMaps, ParserLimits, ORIGIN_KEY};
+
+/// Join the lines that end with a backslash with the following line.
+///
+/// The backslash and the line break are replaced by a single space, and the indentation
+/// of the continuation line is dropped. Comments are never continued. The line breaks are
+/// restored after the joined line, so that the lines keep their number.
+fn join_continued_lines(payload: &str) -> Cow<'_, str> {
+    let is_continued = |line: &str| {
+        let line = line.trim();
+        line.ends_with('\\') && !line.starts_with([';', '#'])
+    };
+    if !payload.lines().any(is_continued) {
+        return Cow::Borrowed(payload);
+    }
+    let mut joined = String::with_capacity(payload.len());
+    let mut continued = false;
+    let mut joined_breaks = 0;
+    for line in payload.lines() {
+        let line = if continued { line.trim_start() } else { line };
+        match line.trim_end().strip_suffix('\\') {
+            Some(head) if !line.trim_start().starts_with([';', '#']) || continued => {
+                joined.push_str(head.trim_end());
+                joined.push(' ');
+                joined_breaks += 1;
+                continued = true;
+            },
+            _ => {
+                joined.push_str(line);
+                joined.push('\n');
+                joined.push_str(&"\n".repeat(joined_breaks));
+                joined_breaks = 0;
+                continued = false;
+            },
+        }
+    }
+    Cow::Owned(joined)
+}
+
+/// Remove the quotes around a value that is entirely quoted, which preserves its
+/// leading and trailing whitespace. Values that contain other quotes are kept as-is.
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value.strip_prefix(quote).and_then(|v| v.strip_suffix(quote)) {
+            if !inner.contains(quote) {
+                return inner;
+            }
+        }
+    }
+    value
+}
+
+/// Parse an INI configuration into a map of job parameter maps.
+///
+/// Values are trimmed unless they are quoted, and may span several lines by ending
+/// them with a backslash. The line of each job's section is set in its [`ORIGIN_KEY`].
+pub fn parse_ini(payload: &str, limits: &ParserLimits) -> Result<HashMap<String, HashMap<String, Vec<String>>>> {
     let mut current_section = "".to_string();
     let mut current_data = HashMap::new();
-    let mut parser = ini::Parser::new(payload.as_str());
-    while let Some(i) = parser.next() {
+    let payload = join_continued_lines(payload);
+    let parser = ini::Parser::new(&payload);
+    let re = Regex::new("^(?<kind>[^\\s]+)\\s*\"(?<name>[^\"]+)\"$").unwrap();
+    // The offset and line of the last section found
+    let mut line = (0, 1);
+    for i in parser {
         match i {
             ini::Item::Error(e) => {
                 return Err(Error::msg(e.to_string()));
             },
             ini::Item::Section(s) => {
+                ParserLimits::check("max-scalar-length", s.len(), limits.max_scalar_length)?;
                 current_section = s.trim().to_string();
                 debug!["Found ini config section {}", s];
-                let re = Regex::new("^(?<kind>[^\\s]+)\\s*\"(?<name>[^\"]+)\"$").unwrap();
                 let (section_kind, section_name): (String, String);
                 match re.captures(&current_section) {
                     Some(c) => {
@@ -43,21 +103,28 @@ pub fn parse_ini(payload: &String) -> Result<HashMap<String, HashMap<String, Vec
                         "Update your configuration files to only declare jobs once.",
                         ];
                 } else {
+                    ParserLimits::check("max-jobs", current_data.len() + 1, limits.max_jobs)?;
+                    // The section is a slice of the payload, its offset gives its line
+                    let offset = s.as_ptr() as usize - payload.as_ptr() as usize;
+                    line.1 += payload[line.0..offset].matches('\n').count();
+                    line.0 = offset;
                     current_data.insert(current_section.clone(), HashMap::from([
                         ("kind".to_string(), vec![section_kind]),
-                        ("name".to_string(), vec![section_name])
+                        ("name".to_string(), vec![section_name]),
+                        (ORIGIN_KEY.to_string(), vec![line.1.to_string()]),
                     ]));
                 }
             },
             ini::Item::SectionEnd => current_section = "".to_string(),
             ini::Item::Property(k, v) => {
                 let k = k.trim();
-                let v = v.map(|v| v.trim());
+                let v = v.map(|v| unquote(v.trim()));
+                ParserLimits::check("max-scalar-length", k.len().max(v.map_or(0, |v| v.len())), limits.max_scalar_length)?;
                 trace!["Found entry '{}' with value '{:?}'", k, v];
                 if current_section.is_empty() {
                     return Err(Error::msg(format!("Found property {} without a section", k)));
                 }
-                if v == None {
+                if v.is_none() {
                     warn!["Found property '{}' without a value, it will be ignored.", k];
                     continue;
                 }
@@ -73,3 +140,90 @@ pub fn parse_ini(payload: &String) -> Result<HashMap<String, HashMap<String, Vec
     }
     Ok(current_data)
 }
+
+/// Quote a value whose whitespace would be trimmed when read, if a quote allows it
+fn quote(value: &str) -> Cow<'_, str> {
+    if !value.is_empty() && value.trim() == value && unquote(value) == value {
+        return Cow::Borrowed(value);
+    }
+    match ['"', '\''].into_iter().find(|q| !value.contains(*q)) {
+        Some(quote) => Cow::Owned(format!("{0}{1}{0}", quote, value)),
+        None => Cow::Borrowed(value),
+    }
+}
+
+/// Write parsed job parameter maps as an INI configuration, the `global` section first and the
+/// jobs in the order they were declared
+pub fn write_ini(data: &JobMaps) -> String {
+    let mut content = String::new();
+    for (section, parameters) in ordered_sections(data) {
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        // Writing to a string can not fail
+        let _ = writeln!(content, "[{}]", section);
+        for key in ordered_keys(parameters) {
+            // The kind and name of a job are read from the section's header
+            let skip = match key.as_str() {
+                "kind" | "name" if section != "global" => 1,
+                _ => 0,
+            };
+            for value in parameters[key].iter().skip(skip) {
+                let _ = writeln!(content, "{} = {}", key, quote(value));
+            }
+        }
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::{ParserLimits, ORIGIN_KEY};
+
+    use super::{join_continued_lines, parse_ini};
+
+    #[test]
+    fn join_lines() {
+        assert_eq!(join_continued_lines("a = b\nc = d\n"), "a = b\nc = d\n");
+        assert_eq!(join_continued_lines("a = b \\\n    c \\\n  d\ne = f\n"), "a = b c d\n\n\ne = f\n");
+        // Comments are not continued, but a continued line may end in a comment-like line
+        assert_eq!(join_continued_lines("; a \\\nb = c\n"), "; a \\\nb = c\n");
+        assert_eq!(join_continued_lines("a = b \\\n# c\n"), "a = b # c\n\n");
+    }
+
+    #[test]
+    fn parse_values() {
+        let data = parse_ini(concat!(
+            "[job-exec \"backup\"]\n",
+            "command = pg_dump \\\n",
+            "    --format=c \\\n",
+            "    db\n",
+            "environment = FOO=bar\n",
+            "environment = \"  SPACED= a b  \"\n",
+            "user = 'root'\n",
+            "tty =   false   \n",
+            "container = \"a\" \"b\"\n",
+        ), &ParserLimits::default()).unwrap();
+        let job = &data["job-exec \"backup\""];
+        assert_eq!(job["command"], vec!["pg_dump --format=c db"]);
+        assert_eq!(job["environment"], vec!["FOO=bar", "  SPACED= a b  "]);
+        assert_eq!(job["user"], vec!["root"]);
+        assert_eq!(job["tty"], vec!["false"]);
+        assert_eq!(job["container"], vec!["\"a\" \"b\""]);
+    }
+
+    #[test]
+    fn section_lines() {
+        let data = parse_ini(concat!(
+            "; jobs\n",
+            "[job-exec \"a\"]\n",
+            "command = echo \\\n",
+            "    a\n",
+            "\n",
+            "[job-local \"b\"]\n",
+            "command = true\n",
+        ), &ParserLimits::default()).unwrap();
+        assert_eq!(data["job-exec \"a\""][ORIGIN_KEY], vec!["2"]);
+        assert_eq!(data["job-local \"b\""][ORIGIN_KEY], vec!["6"]);
+    }
+}
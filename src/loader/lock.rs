@@ -0,0 +1,161 @@
+//! Export and import of a fully-resolved job set.
+//!
+//! A lock file contains the normalized parameter map of every job, sorted so
+//! that the same job set always produces the same file, and the checksum of the job set.
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Error, Result};
+use serde_json::{json, Value};
+use tokio::fs;
+
+use crate::{job::{migrate_job, JobInfo, DEFAULT_CONFIG_VERSION}, utils::sha256_hex};
+
+/// The version of the lock file format
+pub const LOCK_VERSION: u64 = 1;
+
+/// Get the normalized parameter maps of the job set, in a stable order
+fn canonical_maps(jobs: &[JobInfo]) -> Vec<BTreeMap<String, Vec<String>>> {
+    let mut maps: Vec<BTreeMap<String, Vec<String>>> = jobs.iter().map(|j| j.to_parameters()).collect();
+    maps.sort();
+    maps
+}
+
+/// Get the checksum of sorted parameter maps
+fn maps_checksum(maps: &[BTreeMap<String, Vec<String>>]) -> String {
+    // Serializing maps and strings can not fail
+    let canonical = serde_json::to_string(maps).unwrap_or_default();
+    format!("sha256:{}", sha256_hex(canonical.as_bytes()))
+}
+
+/// Get the checksum of the job set's canonical form, e.g. `sha256:2c26b4...`.
+///
+/// It only depends on the jobs' effective parameters, not on the order or format in which they were declared.
+pub fn config_checksum(jobs: &[JobInfo]) -> String {
+    maps_checksum(&canonical_maps(jobs))
+}
+
+/// Serialize the job set in its canonical JSON form
+pub fn export_jobs(jobs: &[JobInfo]) -> Result<String> {
+    let lock = json!({"version": LOCK_VERSION, "checksum": config_checksum(jobs), "jobs": canonical_maps(jobs)});
+    let mut content = serde_json::to_string_pretty(&lock)?;
+    content.push('\n');
+    Ok(content)
+}
+
+/// Build the job set described in a lock file's content
+pub fn import_jobs(content: &str) -> Result<Vec<JobInfo>> {
+    let lock: Value = serde_json::from_str(content)
+        .map_err(|e| Error::msg(format!("The lock file is not valid JSON: {}", e)))?;
+    match lock.get("version").and_then(Value::as_u64) {
+        Some(LOCK_VERSION) => {},
+        Some(v) => return Err(Error::msg(format!("Unsupported lock file version {}, expected {}", v, LOCK_VERSION))),
+        None => return Err(Error::msg("The lock file has no version")),
+    }
+    let jobs = lock.get("jobs").and_then(Value::as_array)
+        .ok_or_else(|| Error::msg("The lock file has no job list"))?;
+    let mut maps = jobs.iter().enumerate().map(|(i, job)| {
+        serde_json::from_value::<BTreeMap<String, Vec<String>>>(job.clone())
+            .map_err(|e| Error::msg(format!("The job at index {} of the lock file is invalid: {}", i, e)))
+    }).collect::<Result<Vec<_>>>()?;
+    // Lock files written before checksums were introduced have none
+    if let Some(expected) = lock.get("checksum").and_then(Value::as_str) {
+        maps.sort();
+        let actual = maps_checksum(&maps);
+        if actual != expected {
+            return Err(Error::msg(format!("The lock file's checksum {} does not match the checksum of its jobs {}", expected, actual)));
+        }
+    }
+    // The jobs of the lock files exported before a key was renamed use its old name
+    maps.into_iter().map(|map| {
+        let mut map: HashMap<String, Vec<String>> = map.into_iter().collect();
+        let kind = map.get("kind").and_then(|k| k.first()).cloned().unwrap_or_default();
+        migrate_job(&kind, &mut map, DEFAULT_CONFIG_VERSION)?;
+        JobInfo::try_from(map)
+    }).collect()
+}
+
+/// Load the jobs from a lock file
+pub async fn load_lock(path: &str) -> Result<Vec<JobInfo>> {
+    let content = fs::read_to_string(path).await
+        .map_err(|e| Error::msg(format!("Failed to read lock file {}: {}", path, e)))?;
+    import_jobs(&content)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::job::JobInfo;
+
+    use super::{config_checksum, export_jobs, import_jobs};
+
+    fn job(values: &[(&str, &[&str])]) -> JobInfo {
+        JobInfo::try_from(values.iter()
+            .map(|(k, v)| (k.to_string(), v.iter().map(|s| s.to_string()).collect()))
+            .collect::<HashMap<String, Vec<String>>>()).unwrap()
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let jobs = vec![
+            job(&[("kind", &["job-local"]), ("name", &["local"]), ("schedule", &["@every 90m"]), ("command", &["echo 1"]),
+                ("environment", &["A=1", "B=2"]), ("sandbox", &["unshare"]), ("run-on-start", &["true"])]),
+            job(&[("kind", &["job-exec"]), ("name", &["exec"]), ("schedule", &["@hourly"]), ("command", &["echo 2"]),
+                ("container", &["c"]), ("max-runs", &["3"]), ("not-before", &["2024-01-01T00:00:00+02:00"])]),
+            job(&[("kind", &["job-run"]), ("name", &["run"]), ("schedule", &["0 0 * * * *"]), ("command", &["echo 3"]),
+                ("image", &["alpine"]), ("network", &["a", "b"]), ("volume", &["/a:/a"]), ("delete", &["false"])]),
+            job(&[("kind", &["job-service-run"]), ("name", &["service"]), ("schedule", &["@daily"]), ("command", &["echo 4"]),
+                ("image", &["alpine"])]),
+        ];
+        let exported = export_jobs(&jobs).unwrap();
+        let imported = import_jobs(&exported).unwrap();
+        assert_eq!(imported.len(), jobs.len());
+        for job in &jobs {
+            assert!(imported.contains(job), "{:?} was not imported", job);
+        }
+        // The export is canonical
+        let mut reversed = imported;
+        reversed.reverse();
+        assert_eq!(export_jobs(&reversed).unwrap(), exported);
+        assert!(exported.contains(&format!("\"checksum\": \"{}\"", config_checksum(&jobs))));
+    }
+
+    #[test]
+    fn checksum_is_canonical() {
+        let local = |schedule: &str| job(&[("kind", &["job-local"]), ("name", &["local"]), ("schedule", &[schedule]), ("command", &["echo 1"]),
+            ("environment", &["A=1"]), ("max-runs", &["3"])]);
+        let exec = job(&[("container", &["c"]), ("command", &["echo 2"]), ("schedule", &["@hourly"]), ("name", &["exec"]), ("kind", &["job-exec"])]);
+        let checksum = config_checksum(&[local("@every 90m"), exec.clone()]);
+        assert!(checksum.starts_with("sha256:") && checksum.len() == 71, "{}", checksum);
+        // The order of the jobs and of their keys does not matter
+        let reordered = job(&[("max-runs", &["3"]), ("environment", &["A=1"]), ("command", &["echo 1"]), ("schedule", &["@every 90m"]),
+            ("name", &["local"]), ("kind", &["job-local"])]);
+        assert_eq!(config_checksum(&[exec.clone(), reordered]), checksum);
+        // Any change of a value does
+        assert_ne!(config_checksum(&[local("@every 91m"), exec.clone()]), checksum);
+        assert_ne!(config_checksum(&[local("@every 90m")]), checksum);
+    }
+
+    #[test]
+    fn import_invalid_lock() {
+        assert!(import_jobs("[]").is_err());
+        assert!(import_jobs(r#"{"version": 2, "jobs": []}"#).is_err());
+        assert!(import_jobs(r#"{"version": 1, "jobs": [{"kind": "job-local"}]}"#).is_err());
+        assert!(import_jobs(r#"{"version": 1, "jobs": []}"#).unwrap().is_empty());
+        // The jobs were modified after the export
+        let exported = export_jobs(&[job(&[("kind", &["job-local"]), ("name", &["local"]), ("schedule", &["@hourly"]), ("command", &["echo 1"])])]).unwrap();
+        assert!(import_jobs(&exported.replace("echo 1", "echo 2")).is_err());
+    }
+
+    #[test]
+    fn import_renamed_keys() {
+        let local = job(&[("kind", &["job-local"]), ("name", &["local"]), ("schedule", &["@hourly"]), ("command", &["echo 1"]), ("working-dir", &["/tmp"])]);
+        let exported = export_jobs(std::slice::from_ref(&local)).unwrap();
+        // A lock exported before the key was renamed, whose checksum was computed on the old name
+        let old_job = serde_json::json!({"kind": ["job-local"], "name": ["local"], "schedule": ["@hourly"], "command": ["echo 1"], "dir": ["/tmp"]});
+        let checksum = super::maps_checksum(&[serde_json::from_value(old_job.clone()).unwrap()]);
+        let old = serde_json::json!({"version": 1, "checksum": checksum, "jobs": [old_job]});
+        assert_eq!(import_jobs(&old.to_string()).unwrap(), vec![local.clone()]);
+        assert_eq!(import_jobs(&exported).unwrap(), vec![local]);
+    }
+}
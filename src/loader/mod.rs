@@ -1,10 +1,12 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet}, fmt::{Display, Formatter}, io::Read, path::{Path, PathBuf}, str::FromStr, sync::{LazyLock, Mutex, OnceLock}, time::Instant};
 
 use anyhow::{Error, Result};
+use futures_util::stream::BoxStream;
 use tokio::fs;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
-use crate::{context::ApplicationContext, job::JobInfo};
+use self::{cache::LabelCache, discovery::{LabelDiscovery, EVENTS_RETRY_INTERVAL}, version::{check_min_version, CfcVersion, MIN_CFC_VERSION_KEY}};
+use crate::{context::ApplicationContext, job::{migrate_job, parse_config_version, resolve_schedule_alias, ExecJobInfo, JobInfo, LocalJobInfo, RunJobInfo, CONFIG_VERSION, DEFAULT_CONFIG_VERSION}, take_one, utils::Elided};
 
 #[cfg(feature = "labels")]
 pub mod docker;
@@ -12,59 +14,817 @@ pub mod docker;
 pub mod ini;
 #[cfg(feature = "yaml")]
 pub mod yaml;
+pub mod cache;
+pub mod discovery;
+pub mod lock;
+pub mod version;
+pub mod watch;
+
+/// The limits enforced when parsing configuration files that may come from untrusted sources
+#[derive(Clone, Debug)]
+pub struct ParserLimits {
+    /// The maximum size of a configuration file, in bytes
+    pub max_input_size: usize,
+    /// The maximum number of jobs declared in a configuration file
+    pub max_jobs: usize,
+    /// The maximum length of a key or value, in bytes
+    pub max_scalar_length: usize,
+    /// The maximum ratio between the number of values obtained by expanding YAML aliases and the number of values in the file
+    pub max_alias_expansion: usize,
+    /// The maximum length of a container label's value, in bytes
+    pub max_label_value_length: usize,
+}
+
+impl Default for ParserLimits {
+    fn default() -> Self {
+        ParserLimits {
+            max_input_size: 1024 * 1024,
+            max_jobs: 10_000,
+            max_scalar_length: 64 * 1024,
+            max_alias_expansion: 10,
+            max_label_value_length: 16 * 1024,
+        }
+    }
+}
+
+/// Returned when a configuration file exceeds one of the [`ParserLimits`]
+#[derive(Debug, PartialEq)]
+pub struct LimitExceeded {
+    /// The name of the exceeded limit
+    pub limit: &'static str,
+    pub max: usize,
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The configuration exceeds the {} limit ({})", self.limit, self.max)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+impl ParserLimits {
+    /// Fail with a [`LimitExceeded`] error if the value is over the maximum
+    pub(crate) fn check(limit: &'static str, value: usize, max: usize) -> Result<()> {
+        if value > max {
+            return Err(Error::new(LimitExceeded { limit, max }));
+        }
+        Ok(())
+    }
+}
+
+/// A change of state of a container that declares jobs in its labels
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContainerEvent {
+    Started(String),
+    Stopped(String),
+}
+
+/// Where a job's definition was read from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobOrigin {
+    /// A configuration file
+    File,
+    /// The labels of a container
+    Labels,
+}
+
+/// The network of the containers started by run jobs declared in labels that do not set one.
+///
+/// Without it, whoever can set labels could start a network-attached container.
+pub const LABEL_RUN_NETWORK: &str = "none";
+
+/// The label that holds the Compose project of a container
+pub const COMPOSE_PROJECT_LABEL: &str = "com.docker.compose.project";
+
+/// The label that holds the Compose service of a container
+pub const COMPOSE_SERVICE_LABEL: &str = "com.docker.compose.service";
+
+/// The label that holds the number of a Compose service's replica, starting from 1
+pub const COMPOSE_CONTAINER_NUMBER_LABEL: &str = "com.docker.compose.container-number";
+
+/// Parse a `--docker-filter` value in a filter of the container engine's container list.
+///
+/// The value is either one of the engine's filters (`name=db`, `label=env=prod`...) or
+/// `compose-project=<project>` and `compose-service=<service>`, which select the containers
+/// of a Compose project or service.
+pub fn parse_docker_filter(value: &str) -> Result<(String, String)> {
+    let Some((filter, argument)) = value.split_once('=').filter(|(f, a)| !f.trim().is_empty() && !a.is_empty()) else {
+        return Err(Error::msg(format!("Invalid docker filter '{}', expected <filter>=<value> (e.g. label=env=prod or compose-service=db)", value)));
+    };
+    Ok(match filter.trim() {
+        "compose-project" => ("label".to_string(), format!("{}={}", COMPOSE_PROJECT_LABEL, argument)),
+        "compose-service" => ("label".to_string(), format!("{}={}", COMPOSE_SERVICE_LABEL, argument)),
+        filter => (filter.to_string(), argument.to_string()),
+    })
+}
+
+/// The reserved key of the job maps that holds where the job was declared: the line of its
+/// definition as set by the file parsers, then prefixed with the file by the loader, or the container
+/// whose labels declare it.
+///
+/// It is removed before the jobs are built, and cannot be set in labels as it starts with a dot.
+pub const ORIGIN_KEY: &str = ".origin";
+
+/// Get the network used by the run jobs that do not set one, `None` for the container engine's default
+fn default_run_network(ctx: &ApplicationContext, origin: JobOrigin) -> Option<String> {
+    ctx.default_run_network.clone().or_else(|| (origin == JobOrigin::Labels).then(|| LABEL_RUN_NETWORK.to_string()))
+}
+
+/// Fail if several jobs of a normalized map have the same name, unless duplicates are allowed.
+/// The error lists where each of the definitions was declared.
+fn check_duplicate_names(map: &JobMaps, allow_duplicates: bool) -> Result<()> {
+    let origin = |parameters: &HashMap<String, Vec<String>>| parameters.get(ORIGIN_KEY)
+        .and_then(|o| o.first()).cloned().unwrap_or_else(|| "an unknown location".to_string());
+    let mut definitions: Vec<(&String, String)> = map.iter()
+        .map(|(key, parameters)| (parameters.get("name").and_then(|n| n.first()).unwrap_or(key), origin(parameters)))
+        .collect();
+    definitions.sort();
+    for pair in definitions.windows(2) {
+        let [(name, first), (other, second)] = pair else { continue };
+        if name != other {
+            continue;
+        }
+        if !allow_duplicates {
+            return Err(Error::msg(format!("Job '{}' is declared more than once, at {} and at {}", name, first, second)));
+        }
+        warn!("Job '{}' is declared more than once, at {} and at {}", name, first, second);
+    }
+    Ok(())
+}
+
+/// The edits already suggested to migrate the configuration, so that reloads do not repeat them
+static SUGGESTED_EDITS: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+/// Log a warning unless it was already logged
+fn warn_once(message: String) {
+    if SUGGESTED_EDITS.lock().unwrap_or_else(|e| e.into_inner()).insert(message.clone()) {
+        warn!("{}", message);
+    }
+}
+
+/// Take the version of the configuration format declared in a file's global section
+fn take_config_version(global: Option<&mut HashMap<String, Vec<String>>>) -> Result<u64> {
+    let Some(global) = global else {
+        return Ok(DEFAULT_CONFIG_VERSION);
+    };
+    take_one!(global, "version")?.map_or(Ok(DEFAULT_CONFIG_VERSION), |v| parse_config_version(&v))
+}
+
+/// Check the `min-cfc-version` declared in a file's global section against the running cfc,
+/// warning once if the configuration was written for an older release series
+fn check_min_cfc_version(global: Option<&HashMap<String, Vec<String>>>) -> Result<()> {
+    let Some(values) = global.and_then(|g| g.get(MIN_CFC_VERSION_KEY)) else {
+        return Ok(());
+    };
+    let [required] = values.as_slice() else {
+        return Err(Error::msg(format!("The global {} has too many values ({:?})", MIN_CFC_VERSION_KEY, values)));
+    };
+    if let Some(warning) = check_min_version(required, &CfcVersion::current())? {
+        warn_once(warning);
+    }
+    Ok(())
+}
+
+/// Rename the keys of a job written for an older version of the configuration format, warning
+/// once about each edit to make. Returns whether a key was renamed.
+fn migrate_parameters(key: &str, parameters: &mut HashMap<String, Vec<String>>, version: u64) -> Result<bool> {
+    let name = parameters.get("name").and_then(|n| n.first()).map_or(key, String::as_str).to_string();
+    let kind = parameters.get("kind").and_then(|k| k.first()).cloned().unwrap_or_default();
+    let renames = migrate_job(&kind, parameters, version)
+        .map_err(|e| Error::msg(format!("Invalid job '{}': {}", name, e)))?;
+    let origin = parameters.get(ORIGIN_KEY).and_then(|o| o.first()).map_or("an unknown location", String::as_str);
+    for rename in renames.iter() {
+        warn_once(format!(
+            "The key '{}' of job '{}' (at {}) was renamed to '{}' in configuration version {}, rename it",
+            rename.from, name, origin, rename.to, rename.version,
+        ));
+    }
+    Ok(!renames.is_empty())
+}
 
 /// Maps a normalized map to a JobInfo list. All keys set in the sub-HashMaps MUST be non-empty Vec.
-fn map_to_job(map: HashMap<String, HashMap<String, Vec<String>>>) -> Result<Vec<JobInfo>> {
+fn map_to_job(map: HashMap<String, HashMap<String, Vec<String>>>, ctx: &ApplicationContext, origin: JobOrigin) -> Result<Vec<JobInfo>> {
+    check_duplicate_names(&map, ctx.allow_duplicate_names)?;
     let mut retval = vec![];
     for (name, mut parameters) in map{
+        parameters.remove(ORIGIN_KEY);
         debug!["Create new job '{}'", name];
-        trace!["Create new job '{}' from {:?}", name, parameters];
+        trace!["Create new job '{}' from {:?}", name, Elided(&parameters)];
         if !parameters.contains_key("name") {
             parameters.insert("name".to_string(), vec![name.clone()]);
         }
+        if let Some(schedule) = parameters.get_mut("schedule") {
+            for s in schedule.iter_mut() {
+                *s = resolve_schedule_alias(s, &ctx.schedule_aliases)
+                    .map_err(|e| Error::msg(format!("Invalid schedule for job '{}': {}", name, e)))?;
+            }
+        }
+        if let Some(timezone) = ctx.timezone {
+            parameters.entry("timezone".to_string()).or_insert_with(|| vec![timezone.to_string()]);
+        }
+        if ctx.strict_schedule {
+            parameters.entry("strict-schedule".to_string()).or_insert_with(|| vec!["true".to_string()]);
+        }
+        let is_container_job = parameters.get("kind")
+            .is_some_and(|k| k.iter().any(|k| k == ExecJobInfo::LABEL || k == RunJobInfo::LABEL));
+        if ctx.pass_environment && is_container_job {
+            parameters.entry("pass-environment".to_string()).or_insert_with(|| vec!["true".to_string()]);
+        }
+        if let Some(network) = default_run_network(ctx, origin) {
+            if parameters.get("kind").is_some_and(|k| k.contains(&RunJobInfo::LABEL.to_string())) {
+                parameters.entry("network".to_string()).or_insert_with(|| vec![network]);
+            }
+        }
+        if let Some(url) = ctx.webhook_url.as_ref() {
+            parameters.entry("webhook-url".to_string()).or_insert_with(|| vec![url.to_string()]);
+        }
+        if let Some(sandbox) = ctx.sandbox {
+            if parameters.get("kind").is_some_and(|k| k.contains(&LocalJobInfo::LABEL.to_string())) {
+                parameters.entry("sandbox".to_string()).or_insert_with(|| vec![sandbox.to_string()]);
+            }
+        }
+        if let Some(size) = ctx.max_output_size {
+            if parameters.get("kind").is_some_and(|k| k.iter().any(|k| k == ExecJobInfo::LABEL || k == LocalJobInfo::LABEL)) {
+                parameters.entry("max-output-size".to_string()).or_insert_with(|| vec![size.to_string()]);
+            }
+        }
         match JobInfo::try_from(parameters) {
-            Ok(job) => {
-                trace!["Created new job {:?}", job];
+            Ok(mut job) => {
+                job.options_mut().from_labels = origin == JobOrigin::Labels;
+                trace!["Created new job {} from {:?}", job.name(), Elided(&job.to_parameters())];
                 retval.push(job);
             }
             Err(e) => return Err(e),
         }
     }
-    return Ok(retval);
+    Ok(retval)
 }
 
-fn load_file_content(content: &String, ext: &String) -> Result<HashMap<String, HashMap<String, Vec<String>>>> {
-    let r = Err(Error::msg("No compiled feature supports parsing files, try to use the --docker option to get configuration from labels"));
-    let is_ini = ext == "ini";
-    let is_yaml = ["yaml", "yml"].contains(&ext.as_str());
-    #[cfg(feature="ini")]
-    let r = if is_ini || !is_yaml {
-        r.or_else(|_| ini::parse_ini(content))
-    } else {r};
-    #[cfg(feature="yaml")]
-    let r = if is_yaml || !is_ini {
-        r.or_else(|_| yaml::parse_yaml(content))
-    } else {r};
-    r
+/// The parameter maps of the jobs read from a configuration file, by job name
+pub type JobMaps = HashMap<String, HashMap<String, Vec<String>>>;
+
+/// The configuration file formats known to the loader
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Ini,
+    Yaml,
 }
 
-pub async fn load_file(path: &String, mut _ctx: &ApplicationContext) -> Result<Vec<JobInfo>> {
-    fs::read(&path).await
-        .map_err(|e| Error::new(e))
-        .and_then(|bytes| String::from_utf8(bytes).map_err(|e| Error::new(e)))
-        .and_then(|c| load_file_content(&c, &path.split(".").last().unwrap().to_lowercase()))
-        .and_then(|mut map| {
-            // TODO: load global configs into ctx
-            map.remove("global");
-            Ok(map)
-        }).and_then(|map| map_to_job(map))
+impl ConfigFormat {
+    /// The formats supported by this build, in the order they are tried for unknown extensions
+    pub const ENABLED: &'static [ConfigFormat] = &[
+        #[cfg(feature = "ini")]
+        ConfigFormat::Ini,
+        #[cfg(feature = "yaml")]
+        ConfigFormat::Yaml,
+    ];
+
+    /// Get the format a file extension stands for
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "ini" => Some(ConfigFormat::Ini),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// The name of the crate feature that enables the format
+    pub fn feature(&self) -> &'static str {
+        match self {
+            ConfigFormat::Ini => "ini",
+            ConfigFormat::Yaml => "yaml",
+        }
+    }
+
+    fn parse(&self, _content: &str, _limits: &ParserLimits) -> Result<JobMaps> {
+        match self {
+            #[cfg(feature = "ini")]
+            ConfigFormat::Ini => ini::parse_ini(_content, _limits),
+            #[cfg(feature = "yaml")]
+            ConfigFormat::Yaml => yaml::parse_yaml(_content, _limits),
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::msg(format!("{} files are not supported by this build, the {} feature is disabled", self, self.feature()))),
+        }
+    }
+
+    fn write(&self, _map: &JobMaps) -> Result<String> {
+        match self {
+            #[cfg(feature = "ini")]
+            ConfigFormat::Ini => Ok(ini::write_ini(_map)),
+            #[cfg(feature = "yaml")]
+            ConfigFormat::Yaml => Ok(yaml::write_yaml(_map)),
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::msg(format!("{} files are not supported by this build, the {} feature is disabled", self, self.feature()))),
+        }
+    }
+}
+
+/// Get the sections of a parsed configuration in the order they were declared, the global section first
+#[cfg(any(feature = "ini", feature = "yaml"))]
+fn ordered_sections(map: &JobMaps) -> Vec<(&String, &HashMap<String, Vec<String>>)> {
+    let line = |parameters: &HashMap<String, Vec<String>>| parameters.get(ORIGIN_KEY)
+        .and_then(|o| o.first())
+        .and_then(|l| l.parse::<usize>().ok());
+    let mut sections: Vec<_> = map.iter().collect();
+    sections.sort_by_key(|(name, parameters)| (*name != "global", line(parameters), name.as_str()));
+    sections
+}
+
+/// Get the keys of a parsed section in the order they are written: its version, kind and name first
+#[cfg(any(feature = "ini", feature = "yaml"))]
+fn ordered_keys(parameters: &HashMap<String, Vec<String>>) -> Vec<&String> {
+    let mut keys: Vec<&String> = parameters.keys().filter(|k| *k != ORIGIN_KEY).collect();
+    keys.sort_by_key(|k| (["version", "kind", "name"].iter().position(|first| k == first).unwrap_or(3), k.as_str()));
+    keys
+}
+
+impl FromStr for ConfigFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Self::from_extension(&s.trim().to_lowercase()) {
+            Some(format) => Ok(format),
+            None => Err(Error::msg(format!("Unsupported configuration format '{}', expected one of ini or yaml", s))),
+        }
+    }
 }
 
-pub async fn load_labels(_ctx: &ApplicationContext) -> Result<Vec<JobInfo>> {
+impl Display for ConfigFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigFormat::Ini => write!(f, "INI"),
+            ConfigFormat::Yaml => write!(f, "YAML"),
+        }
+    }
+}
+
+/// Parse a configuration file's content, returning the format it was read as.
+///
+/// Files with a known extension are only parsed with the matching format, other files
+/// are parsed with the first enabled format that accepts them.
+pub fn load_file_content(content: &str, ext: &str, limits: &ParserLimits) -> Result<(ConfigFormat, JobMaps)> {
+    let candidates = match ConfigFormat::from_extension(ext) {
+        Some(format) => vec![format],
+        None if ConfigFormat::ENABLED.is_empty() => {
+            return Err(Error::msg("No compiled feature supports parsing files, try to use the --docker option to get configuration from labels"));
+        },
+        None => ConfigFormat::ENABLED.to_vec(),
+    };
+    parse_content(content, candidates, limits)
+}
+
+/// Parse a configuration file's content with a specific format, whatever its extension
+pub fn load_content_as(content: &str, format: ConfigFormat, limits: &ParserLimits) -> Result<JobMaps> {
+    parse_content(content, vec![format], limits).map(|(_, map)| map)
+}
+
+/// Parse a configuration with the first of the candidate formats that accepts it
+fn parse_content(content: &str, candidates: Vec<ConfigFormat>, limits: &ParserLimits) -> Result<(ConfigFormat, JobMaps)> {
+    ParserLimits::check("max-input-size", content.len(), limits.max_input_size)?;
+    let mut errors = vec![];
+    for format in candidates {
+        match format.parse(content, limits) {
+            Ok(map) => return Ok((format, map)),
+            Err(e) if e.is::<LimitExceeded>() => return Err(e),
+            Err(e) => {
+                debug!("The configuration could not be parsed as {}: {}", format, e);
+                errors.push((format, e));
+            },
+        }
+    }
+    if errors.len() == 1 {
+        return Err(errors.pop().unwrap().1);
+    }
+    let reasons: Vec<String> = errors.iter().map(|(format, e)| format!("as {}: {}", format, e)).collect();
+    Err(Error::msg(format!("The configuration could not be parsed {}", reasons.join(", nor "))))
+}
+
+/// The path that stands for the standard input when used as configuration path
+pub const STDIN_PATH: &str = "-";
+
+/// The configuration read from the standard input, kept to be parsed again on reloads
+static STDIN_CONTENT: OnceLock<Result<String, String>> = OnceLock::new();
+
+/// Read the configuration from the standard input, only consuming it the first time
+fn read_stdin(limits: &ParserLimits) -> Result<String> {
+    STDIN_CONTENT.get_or_init(|| {
+        let mut bytes = vec![];
+        // Read one byte past the limit to detect oversized inputs without reading them whole
+        std::io::stdin().lock().take(u64::try_from(limits.max_input_size).unwrap_or(u64::MAX).saturating_add(1)).read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())
+            .and_then(|_| String::from_utf8(bytes).map_err(|e| e.to_string()))
+    }).clone().map_err(|e| Error::msg(format!("Failed to read the configuration from the standard input: {}", e)))
+}
+
+/// Read and parse a single configuration file, or the standard input.
+/// Unless a format is provided, it is guessed from the file's extension.
+async fn read_config_file(path: &Path, format: Option<ConfigFormat>, limits: &ParserLimits) -> Result<(ConfigFormat, JobMaps)> {
+    let start_time = Instant::now();
+    let content = if path == Path::new(STDIN_PATH) {
+        read_stdin(limits)?
+    } else {
+        let size = fs::metadata(&path).await.map_err(Error::new)?.len();
+        ParserLimits::check("max-input-size", usize::try_from(size).unwrap_or(usize::MAX), limits.max_input_size)?;
+        fs::read(&path).await
+            .map_err(Error::new)
+            .and_then(|bytes| String::from_utf8(bytes).map_err(Error::new))?
+    };
+    let (format, map) = match format {
+        Some(format) => (format, load_content_as(&content, format, limits)?),
+        None => {
+            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+            load_file_content(&content, &ext, limits)?
+        },
+    };
+    debug!["Parsed configuration file {} as {} in {:?}", path.display(), format, start_time.elapsed()];
+    Ok((format, map))
+}
+
+/// List the configuration files of a directory, sorted by name.
+/// Only the files with an extension matching a configuration format are kept.
+async fn list_config_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut entries = fs::read_dir(dir).await.map_err(Error::new)?;
+    while let Some(entry) = entries.next_entry().await.map_err(Error::new)? {
+        let path = entry.path();
+        let known_format = path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| ConfigFormat::from_extension(&e.to_lowercase()))
+            .is_some();
+        if known_format && fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+            files.push(path);
+        } else {
+            trace!("Ignoring {} in configuration directory {}", path.display(), dir.display());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Get the configuration files found at a path, and whether the path is a directory
+async fn config_files(path: &Path) -> Result<(bool, Vec<PathBuf>)> {
+    let from_dir = path != Path::new(STDIN_PATH) && fs::metadata(path).await.map_err(Error::new)?.is_dir();
+    if !from_dir {
+        return Ok((false, vec![path.to_path_buf()]));
+    }
+    let files = list_config_files(path).await?;
+    debug!("Found {} configuration files in {}", files.len(), path.display());
+    Ok((true, files))
+}
+
+/// Load the jobs declared in a configuration file, in all the configuration files of a directory,
+/// or in the standard input if the path is [`STDIN_PATH`].
+///
+/// A job name may only be declared once across the files of a directory unless duplicate names
+/// are allowed, the `global` sections of the files are applied in the order of the file names.
+pub async fn load_file(path: &String, ctx: &mut ApplicationContext) -> Result<Vec<JobInfo>> {
+    let (from_dir, files) = config_files(Path::new(path)).await?;
+    let mut maps = Vec::with_capacity(files.len());
+    for file in files.iter() {
+        let in_file = |e: Error| if from_dir { Error::msg(format!("Failed to load {}: {}", file.display(), e)) } else { e };
+        let (_, map) = read_config_file(file, ctx.config_format, &ctx.parser_limits).await.map_err(in_file)?;
+        // Checked in all the files before any job is read, so that an outdated cfc reports it first
+        check_min_cfc_version(map.get("global")).map_err(in_file)?;
+        maps.push(map);
+    }
+    let mut jobs = JobMaps::new();
+    for (file, mut map) in files.iter().zip(maps) {
+        let in_file = |e: Error| if from_dir { Error::msg(format!("Failed to load {}: {}", file.display(), e)) } else { e };
+        let version = take_config_version(map.get_mut("global")).map_err(in_file)?;
+        if let Some(mut global) = map.remove("global") {
+            global.remove(ORIGIN_KEY);
+            global.remove(MIN_CFC_VERSION_KEY);
+            ctx.apply_global(global)?;
+        }
+        let file_name = match file == Path::new(STDIN_PATH) {
+            true => "the standard input".to_string(),
+            false => file.display().to_string(),
+        };
+        let mut migrated = false;
+        for (key, mut values) in map {
+            let origin = match values.remove(ORIGIN_KEY).and_then(|o| o.into_iter().next()) {
+                Some(line) => format!("{}:{}", file_name, line),
+                None => file_name.clone(),
+            };
+            // The same section of two files can not be kept apart, whether duplicate names are allowed or not
+            if let Some(other) = jobs.get(&key) {
+                let name = values.get("name").and_then(|n| n.first()).unwrap_or(&key);
+                let other = other.get(ORIGIN_KEY).and_then(|o| o.first()).map_or("", String::as_str);
+                return Err(Error::msg(format!("Job '{}' is declared more than once, at {} and at {}", name, other, origin)));
+            }
+            values.insert(ORIGIN_KEY.to_string(), vec![origin]);
+            migrated |= migrate_parameters(&key, &mut values, version)?;
+            jobs.insert(key, values);
+        }
+        if migrated {
+            warn_once(format!(
+                "{} is read as configuration version {}, set `version = {}` in its global section once its keys are renamed \
+                (`cfc validate --migrate` prints the migrated configuration)", file_name, version, CONFIG_VERSION,
+            ));
+        }
+    }
+    ParserLimits::check("max-jobs", jobs.len(), ctx.parser_limits.max_jobs)?;
+    map_to_job(jobs, ctx, JobOrigin::File)
+}
+
+/// Get the content of a configuration file, or of the files of a directory, with the keys
+/// renamed since the version they declare migrated to the current version of the format.
+///
+/// Each file is returned along with its path, in the format it is written in. Its comments
+/// and the order of the keys of its jobs are not preserved.
+pub async fn migrate_config(path: &String, ctx: &ApplicationContext) -> Result<Vec<(PathBuf, String)>> {
+    let (from_dir, files) = config_files(Path::new(path)).await?;
+    let mut migrated = vec![];
+    for file in files {
+        let in_file = |e: Error| if from_dir { Error::msg(format!("Failed to migrate {}: {}", file.display(), e)) } else { e };
+        let (format, mut map) = read_config_file(&file, ctx.config_format, &ctx.parser_limits).await.map_err(in_file)?;
+        check_min_cfc_version(map.get("global")).map_err(in_file)?;
+        let version = take_config_version(map.get_mut("global")).map_err(in_file)?;
+        for (key, parameters) in map.iter_mut().filter(|(key, _)| *key != "global") {
+            let kind = parameters.get("kind").and_then(|k| k.first()).cloned().unwrap_or_default();
+            migrate_job(&kind, parameters, version)
+                .map_err(|e| in_file(Error::msg(format!("Invalid job '{}': {}", key, e))))?;
+        }
+        map.entry("global".to_string()).or_default().insert("version".to_string(), vec![CONFIG_VERSION.to_string()]);
+        let content = format.write(&map).map_err(in_file)?;
+        migrated.push((file, content));
+    }
+    Ok(migrated)
+}
+
+/// Load the jobs declared in the labels of the running containers.
+/// Containers whose metadata did not change since they were put in the cache are not parsed again.
+pub async fn load_labels(_ctx: &ApplicationContext, _cache: &mut LabelCache) -> Result<Vec<JobInfo>> {
     #[cfg(feature = "labels")]
-    let jobs = docker::get_tagged_targets(&_ctx.get_handle()?, &_ctx.label_prefixes, _ctx.unsafe_labels).await
-        .and_then(|map| map_to_job(map));
+    let jobs = docker::get_tagged_targets(&_ctx.get_handle()?, &_ctx.label_prefixes, &_ctx.docker_filters, _ctx.compose_services, _ctx.unsafe_labels, _ctx.parser_limits.max_label_value_length, _cache).await
+        .and_then(|mut map| {
+            // Labels do not declare a version, the current key names and the older ones are both accepted
+            for (key, parameters) in map.iter_mut() {
+                migrate_parameters(key, parameters, DEFAULT_CONFIG_VERSION)?;
+            }
+            map_to_job(map, _ctx, JobOrigin::Labels)
+        });
     #[cfg(not(feature = "labels"))]
     let jobs = Err(Error::msg("No compiled feature supports parsing labels, try to use file parsing"));
     jobs
 }
+
+/// Follow the containers that may change the jobs declared in labels, with their events or by
+/// rescanning them every `rescan_interval` if the container engine does not support events
+pub fn follow_labels(ctx: &ApplicationContext, rescan_interval: std::time::Duration) -> LabelDiscovery {
+    let handle = ctx.get_handle().map_err(|e| e.to_string());
+    let _label_prefixes = ctx.label_prefixes.clone();
+    let subscribe = move || -> Result<BoxStream<'static, Result<ContainerEvent>>> {
+        let _handle = handle.clone().map_err(Error::msg)?;
+        #[cfg(feature = "labels")]
+        let events = Ok(Box::pin(docker::container_events(&_handle, &_label_prefixes)) as BoxStream<_>);
+        #[cfg(not(feature = "labels"))]
+        let events = Err(Error::msg("No compiled feature supports parsing labels, try to use file parsing"));
+        events
+    };
+    LabelDiscovery::new(subscribe, rescan_interval, EVENTS_RETRY_INTERVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{load_file_content, LimitExceeded, ParserLimits};
+    #[cfg(all(feature = "ini", feature = "yaml"))]
+    use crate::context::ApplicationContext;
+
+    fn assert_limit(content: &str, ext: &str, limit: &str) {
+        let start_time = Instant::now();
+        let e = load_file_content(content, ext, &ParserLimits::default()).unwrap_err();
+        assert!(start_time.elapsed() < Duration::from_secs(2), "Parsing took {:?}", start_time.elapsed());
+        assert_eq!(e.downcast_ref::<LimitExceeded>().map(|l| l.limit), Some(limit), "Unexpected error {}", e);
+    }
+
+    #[test]
+    fn parser_limits() {
+        let limits = ParserLimits::default();
+        for ext in ["ini", "yaml", "conf"] {
+            assert_limit(&"#".repeat(limits.max_input_size + 1), ext, "max-input-size");
+        }
+        let long_value = "a".repeat(limits.max_scalar_length + 1);
+        if cfg!(feature = "ini") {
+            assert_limit(&format!("[job-local \"a\"]\ncommand = {}\n", long_value), "ini", "max-scalar-length");
+        }
+        if cfg!(feature = "yaml") {
+            assert_limit(&format!("a:\n  command: {}\n", long_value), "yaml", "max-scalar-length");
+        }
+        if cfg!(feature = "ini") {
+            let many_ini_jobs: String = (0..=limits.max_jobs).map(|i| format!("[job-local \"{}\"]\n", i)).collect();
+            assert_limit(&many_ini_jobs, "ini", "max-jobs");
+        }
+        if cfg!(feature = "yaml") {
+            let many_yaml_jobs: String = (0..=limits.max_jobs).map(|i| format!("j{}: {{}}\n", i)).collect();
+            assert_limit(&many_yaml_jobs, "yaml", "max-jobs");
+        }
+    }
+
+    #[cfg(all(feature = "ini", feature = "yaml"))]
+    #[test]
+    fn config_format_override() {
+        use super::{load_content_as, ConfigFormat};
+
+        assert_eq!(" YAML".parse::<ConfigFormat>().unwrap(), ConfigFormat::Yaml);
+        assert_eq!("yml".parse::<ConfigFormat>().unwrap(), ConfigFormat::Yaml);
+        assert!("toml".parse::<ConfigFormat>().unwrap_err().to_string().contains("'toml'"));
+        let yaml = "a:\n  kind: job-local\n";
+        assert!(load_content_as(yaml, ConfigFormat::Yaml, &ParserLimits::default()).unwrap().contains_key("a"));
+        // The forced format does not fall back to the other ones
+        assert!(load_content_as(yaml, ConfigFormat::Ini, &ParserLimits::default()).is_err());
+    }
+
+    #[cfg(all(feature = "ini", feature = "yaml"))]
+    #[tokio::test]
+    async fn load_config_directory() {
+        let dir = std::env::temp_dir().join(format!("cfc-conf.d-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.ini"), "[job-local \"a\"]\nschedule = @hourly\ncommand = true\n").unwrap();
+        std::fs::write(dir.join("b.yml"), "b:\n  kind: job-local\n  schedule: '@daily'\n  command: 'true'\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a configuration file").unwrap();
+        let path = dir.to_string_lossy().to_string();
+
+        let mut jobs: Vec<String> = super::load_file(&path, &mut ApplicationContext::default()).await.unwrap()
+            .iter().map(|j| j.name().to_string()).collect();
+        jobs.sort();
+        assert_eq!(jobs, vec!["a", "b"]);
+
+        std::fs::write(dir.join("c.yaml"), "a:\n  kind: job-local\n  schedule: '@daily'\n  command: 'false'\n").unwrap();
+        let e = super::load_file(&path, &mut ApplicationContext::default()).await.unwrap_err().to_string();
+        assert!(e.contains("'a'") && e.contains("a.ini:1") && e.contains("c.yaml:1"), "{}", e);
+        let mut ctx = ApplicationContext { allow_duplicate_names: true, ..Default::default() };
+        assert_eq!(super::load_file(&path, &mut ctx).await.unwrap().len(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(feature = "ini", feature = "yaml"))]
+    #[tokio::test]
+    async fn migrate_v1_configurations() {
+        use super::{load_content_as, ConfigFormat, JobMaps, ORIGIN_KEY};
+
+        let normalized = |content: &str, format: ConfigFormat| -> JobMaps {
+            let mut map = load_content_as(content, format, &ParserLimits::default()).unwrap();
+            map.values_mut().for_each(|parameters| { parameters.remove(ORIGIN_KEY); });
+            map
+        };
+        let fixtures = [
+            (ConfigFormat::Ini, "ini", concat!(
+                "[global]\ntimezone = UTC\n\n",
+                "[job-local \"a\"]\nschedule = @hourly\ncommand = echo a\ndir = /tmp\nenvironment = \"  SPACED  \"\n\n",
+                "[job-exec \"b\"]\nschedule = @daily\ncontainer = db\ncommand = [\"echo\", \"a b\"]\ndir = /app\n",
+            ), concat!(
+                "[global]\nversion = 2\ntimezone = UTC\n\n",
+                "[job-local \"a\"]\nschedule = @hourly\ncommand = echo a\nworking-dir = /tmp\nenvironment = \"  SPACED  \"\n\n",
+                "[job-exec \"b\"]\nschedule = @daily\ncontainer = db\ncommand = [\"echo\", \"a b\"]\nworking-dir = /app\n",
+            )),
+            (ConfigFormat::Yaml, "yaml", concat!(
+                "global:\n  timezone: UTC\n",
+                "a:\n  kind: job-local\n  schedule: '@hourly'\n  command: [[echo, a b], 'echo \"c\"']\n  dir: /tmp\n  annotations: {team: ops}\n",
+            ), concat!(
+                "global:\n  version: 2\n  timezone: UTC\n",
+                "a:\n  kind: job-local\n  schedule: '@hourly'\n  command: ['[\"echo\",\"a b\"]', 'echo \"c\"']\n  working-dir: /tmp\n  annotation.team: ops\n",
+            )),
+        ];
+        let dir = std::env::temp_dir().join(format!("cfc-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (format, ext, v1, v2) in fixtures {
+            let (v1_path, v2_path) = (dir.join(format!("v1.{}", ext)), dir.join(format!("v2.{}", ext)));
+            std::fs::write(&v1_path, v1).unwrap();
+            std::fs::write(&v2_path, v2).unwrap();
+            let ctx = ApplicationContext::default();
+            let migrated = super::migrate_config(&v1_path.to_string_lossy().to_string(), &ctx).await.unwrap();
+            assert_eq!(migrated.len(), 1);
+            assert_eq!(normalized(&migrated[0].1, format), normalized(v2, format), "{}", migrated[0].1);
+            // The migrated configuration is stable
+            std::fs::write(&v1_path, &migrated[0].1).unwrap();
+            assert_eq!(super::migrate_config(&v1_path.to_string_lossy().to_string(), &ctx).await.unwrap()[0].1, migrated[0].1);
+            // The v1 and v2 configurations describe the same jobs
+            std::fs::write(&v1_path, v1).unwrap();
+            let mut v1_jobs = super::load_file(&v1_path.to_string_lossy().to_string(), &mut ApplicationContext::default()).await.unwrap();
+            let mut v2_jobs = super::load_file(&v2_path.to_string_lossy().to_string(), &mut ApplicationContext::default()).await.unwrap();
+            v1_jobs.sort_by(|a, b| a.name().cmp(b.name()));
+            v2_jobs.sort_by(|a, b| a.name().cmp(b.name()));
+            assert_eq!(v1_jobs, v2_jobs);
+        }
+        let future = dir.join("v3.ini");
+        std::fs::write(&future, "[global]\nversion = 3\n").unwrap();
+        let e = super::load_file(&future.to_string_lossy().to_string(), &mut ApplicationContext::default()).await.unwrap_err();
+        assert_eq!(e.to_string(), "this cfc (config version 2) cannot read version 3");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn docker_filters() {
+        use super::parse_docker_filter;
+
+        let filter = |value: &str| parse_docker_filter(value).map(|(f, v)| format!("{} {}", f, v));
+        assert_eq!(filter("label=env=prod").unwrap(), "label env=prod");
+        assert_eq!(filter("name=db").unwrap(), "name db");
+        assert_eq!(filter("compose-project=app").unwrap(), "label com.docker.compose.project=app");
+        assert_eq!(filter("compose-service=db").unwrap(), "label com.docker.compose.service=db");
+        assert!(filter("compose-service").is_err());
+        assert!(filter("=db").is_err());
+        assert!(filter("name=").is_err());
+    }
+
+    #[cfg(feature = "ini")]
+    #[tokio::test]
+    async fn check_min_cfc_version_first() {
+        let dir = std::env::temp_dir().join(format!("cfc-min-version-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.ini"), "[job-local \"a\"]\nschedule = @hourly\ncommand = true\nunknown-key = 1\n").unwrap();
+        std::fs::write(dir.join("b.ini"), "[global]\nmin-cfc-version = 999.0\n").unwrap();
+        let path = dir.to_string_lossy().to_string();
+        // The version is checked before the invalid job of the first file is read
+        let e = super::load_file(&path, &mut ApplicationContext::default()).await.unwrap_err().to_string();
+        assert!(e.contains("requires cfc 999.0.0") && e.contains(super::version::CFC_VERSION), "{}", e);
+        assert!(super::migrate_config(&path, &ApplicationContext::default()).await.is_err());
+
+        std::fs::write(dir.join("a.ini"), "[job-local \"a\"]\nschedule = @hourly\ncommand = true\n").unwrap();
+        std::fs::write(dir.join("b.ini"), format!("[global]\nmin-cfc-version = {}\n", super::version::CFC_VERSION)).unwrap();
+        assert_eq!(super::load_file(&path, &mut ApplicationContext::default()).await.unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "ini")]
+    #[test]
+    fn reject_duplicate_names() {
+        use super::{check_duplicate_names, load_content_as, ConfigFormat};
+
+        let content = "[job-exec \"backup\"]\ncontainer = db\ncommand = true\n\n[job-run \"backup\"]\nimage = alpine\n";
+        let map = load_content_as(content, ConfigFormat::Ini, &ParserLimits::default()).unwrap();
+        let e = check_duplicate_names(&map, false).unwrap_err().to_string();
+        assert_eq!(e, "Job 'backup' is declared more than once, at 1 and at 5");
+        assert!(check_duplicate_names(&map, true).is_ok());
+        let content = "[job-exec \"a\"]\ncontainer = db\ncommand = true\n[job-run \"b\"]\nimage = alpine\n";
+        assert!(check_duplicate_names(&load_content_as(content, ConfigFormat::Ini, &ParserLimits::default()).unwrap(), false).is_ok());
+    }
+
+    #[cfg(all(feature = "ini", feature = "yaml", feature = "labels"))]
+    #[test]
+    fn annotations_reach_reports() {
+        use std::collections::HashMap;
+
+        use super::{docker::parse_container_labels, load_content_as, map_to_job, ConfigFormat, JobOrigin};
+        use crate::{job::ExecutionReport, report::JobRunReport};
+
+        let ctx = ApplicationContext::default();
+        let limits = ParserLimits::default();
+        let ini = "[job-exec \"a\"]\nschedule = @hourly\ncontainer = db\ncommand = true\nannotation.team = ops\nannotation.run-book = http://wiki/a\n";
+        let yaml = "a:\n  kind: job-exec\n  schedule: \"@hourly\"\n  container: db\n  command: \"true\"\n  annotations:\n    team: ops\n    run-book: http://wiki/a\n";
+        let labels = HashMap::from([
+            ("cfc.job-exec.a.schedule".to_string(), "@hourly".to_string()),
+            ("cfc.job-exec.a.command".to_string(), "true".to_string()),
+            ("cfc.job-exec.a.annotation.team".to_string(), "ops".to_string()),
+            ("cfc.job-exec.a.annotation.run-book".to_string(), "http://wiki/a".to_string()),
+        ]);
+        let maps = [
+            (load_content_as(ini, ConfigFormat::Ini, &limits).unwrap(), JobOrigin::File),
+            (load_content_as(yaml, ConfigFormat::Yaml, &limits).unwrap(), JobOrigin::File),
+            (parse_container_labels("db", &labels, &["cfc".to_string()], false, 1024).unwrap(), JobOrigin::Labels),
+        ];
+        for (map, origin) in maps {
+            let job = map_to_job(map, &ctx, origin).unwrap().pop().unwrap();
+            assert_eq!(job.options().from_labels, origin == JobOrigin::Labels);
+            // Annotations are kept out of the action's definition
+            assert_eq!(job.describe_action(), "exec 'true' in db");
+            let payload = serde_json::to_value(JobRunReport::new(&job, Ok(ExecutionReport::default()), 0.0)).unwrap();
+            assert_eq!(payload["annotations"], serde_json::json!({"team": "ops", "run-book": "http://wiki/a"}), "{}", payload);
+        }
+    }
+
+    #[test]
+    fn default_run_network_by_origin() {
+        use std::collections::HashMap;
+
+        use super::{map_to_job, JobOrigin};
+        use crate::context::ApplicationContext;
+
+        let networks = |ctx: &ApplicationContext, origin: JobOrigin, network: Option<&str>| {
+            let mut parameters: HashMap<String, Vec<String>> = HashMap::from([
+                ("kind".to_string(), vec!["job-run".to_string()]),
+                ("schedule".to_string(), vec!["@hourly".to_string()]),
+                ("image".to_string(), vec!["alpine".to_string()]),
+            ]);
+            if let Some(network) = network {
+                parameters.insert("network".to_string(), vec![network.to_string()]);
+            }
+            let job = map_to_job(HashMap::from([("job".to_string(), parameters)]), ctx, origin).unwrap().pop().unwrap();
+            job.to_parameters().get("network").cloned()
+        };
+        let ctx = ApplicationContext::default();
+        assert_eq!(networks(&ctx, JobOrigin::Labels, None), Some(vec!["none".to_string()]));
+        assert_eq!(networks(&ctx, JobOrigin::File, None), None);
+        // Explicit networks always win
+        assert_eq!(networks(&ctx, JobOrigin::Labels, Some("backend")), Some(vec!["backend".to_string()]));
+        assert_eq!(networks(&ctx, JobOrigin::File, Some("backend")), Some(vec!["backend".to_string()]));
+        let ctx = ApplicationContext { default_run_network: Some("isolated".to_string()), ..Default::default() };
+        assert_eq!(networks(&ctx, JobOrigin::Labels, None), Some(vec!["isolated".to_string()]));
+        assert_eq!(networks(&ctx, JobOrigin::File, None), Some(vec!["isolated".to_string()]));
+        assert_eq!(networks(&ctx, JobOrigin::Labels, Some("backend")), Some(vec!["backend".to_string()]));
+    }
+}
@@ -0,0 +1,167 @@
+//! The version of cfc a configuration is written for
+//!
+//! A configuration may declare the oldest cfc that understands it with the `min-cfc-version`
+//! key of its `global` section. It is checked before any job is read: an older cfc refuses the
+//! configuration instead of running part of it, and a cfc that is several releases ahead warns
+//! that the configuration may need a review. Versions are compared with semver precedence.
+use std::{cmp::Ordering, fmt::{Display, Formatter}, str::FromStr};
+
+use anyhow::{Error, Result};
+
+/// The key of the global section that declares the oldest cfc that can read the configuration
+pub const MIN_CFC_VERSION_KEY: &str = "min-cfc-version";
+
+/// The version of the running cfc
+pub const CFC_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A semantic version, whose minor and patch numbers default to 0 when omitted (e.g. `0.4`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CfcVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// The pre-release identifiers, a pre-release precedes the release of the same version
+    pub pre: Vec<String>,
+}
+
+impl CfcVersion {
+    /// The version of the running cfc
+    pub fn current() -> Self {
+        CFC_VERSION.parse().expect("The crate version is a semantic version")
+    }
+
+    /// Whether `self` is a later release series than `other`: a later major version, or a
+    /// later minor version while the major version is 0
+    fn is_series_after(&self, other: &CfcVersion) -> bool {
+        self.major > other.major || (self.major == 0 && other.major == 0 && self.minor > other.minor)
+    }
+}
+
+/// Parse a numeric identifier, which has no leading zero
+fn parse_number(value: &str) -> Option<u64> {
+    match value.len() > 1 && value.starts_with('0') {
+        true => None,
+        false => value.parse().ok().filter(|_| value.bytes().all(|b| b.is_ascii_digit())),
+    }
+}
+
+impl FromStr for CfcVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::msg(format!("Invalid cfc version '{}', expected a semantic version such as 0.4 or 1.2.3", s));
+        // Build metadata does not take part in the precedence
+        let version = s.trim().split_once('+').map_or(s.trim(), |(version, _)| version);
+        let (release, pre) = match version.split_once('-') {
+            Some((release, pre)) => {
+                let pre: Vec<String> = pre.split('.').map(str::to_string).collect();
+                let valid = |id: &String| !id.is_empty() && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                    && (!id.bytes().all(|b| b.is_ascii_digit()) || parse_number(id).is_some());
+                if !pre.iter().all(valid) {
+                    return Err(invalid());
+                }
+                (release, pre)
+            },
+            None => (version, vec![]),
+        };
+        let numbers = release.split('.').map(parse_number).collect::<Option<Vec<u64>>>().ok_or_else(invalid)?;
+        match numbers[..] {
+            [major] => Ok(CfcVersion { major, minor: 0, patch: 0, pre }),
+            [major, minor] => Ok(CfcVersion { major, minor, patch: 0, pre }),
+            [major, minor, patch] => Ok(CfcVersion { major, minor, patch, pre }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Display for CfcVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            write!(f, "-{}", self.pre.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for CfcVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let release = (self.major, self.minor, self.patch).cmp(&(other.major, other.minor, other.patch));
+        let pre = match (self.pre.is_empty(), other.pre.is_empty()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            // Numeric identifiers are compared as numbers and precede the alphanumeric ones
+            (false, false) => self.pre.iter().map(|id| (parse_number(id).is_none(), parse_number(id), id))
+                .cmp(other.pre.iter().map(|id| (parse_number(id).is_none(), parse_number(id), id))),
+        };
+        release.then(pre)
+    }
+}
+
+impl PartialOrd for CfcVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Check that the `running` cfc can read a configuration that declares `min-cfc-version = required`.
+///
+/// An error naming both versions is returned when the running cfc is older. Otherwise, a warning
+/// is returned when it belongs to a later release series, whose changes the configuration may not
+/// account for.
+pub fn check_min_version(required: &str, running: &CfcVersion) -> Result<Option<String>> {
+    let required: CfcVersion = required.parse()
+        .map_err(|e| Error::msg(format!("Invalid global {} value: {}", MIN_CFC_VERSION_KEY, e)))?;
+    if *running < required {
+        return Err(Error::msg(format!(
+            "The configuration requires cfc {} or later ({}) but this cfc is version {}, update it before loading this configuration",
+            required, MIN_CFC_VERSION_KEY, running,
+        )));
+    }
+    Ok(running.is_series_after(&required).then(|| format!(
+        "The configuration was written for cfc {} ({}) but this cfc is version {}, review it for the changes of the newer releases",
+        required, MIN_CFC_VERSION_KEY, running,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_min_version, CfcVersion};
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!("0.4".parse::<CfcVersion>().unwrap(), CfcVersion { major: 0, minor: 4, patch: 0, pre: vec![] });
+        assert_eq!("1.2.3-rc.1+build.5".parse::<CfcVersion>().unwrap().to_string(), "1.2.3-rc.1");
+        CfcVersion::current();
+        for malformed in ["", "v1.2", "1.2.3.4", "1..2", "01.2", "1.2-", "1.2-rc..1", "1.2-01", "latest"] {
+            assert!(malformed.parse::<CfcVersion>().is_err(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn compare_versions() {
+        let versions = ["0.9.0", "1.0.0-alpha", "1.0.0-alpha.1", "1.0.0-alpha.beta", "1.0.0-beta.2", "1.0.0-beta.11", "1.0.0", "1.0.1", "1.10"];
+        let parsed: Vec<CfcVersion> = versions.iter().map(|v| v.parse().unwrap()).collect();
+        assert!(parsed.windows(2).all(|w| w[0] < w[1]), "{:?}", parsed);
+        assert_eq!("1.0".parse::<CfcVersion>().unwrap().cmp(&"1.0.0+build".parse().unwrap()), std::cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn check_min_cfc_version() {
+        let running: CfcVersion = "0.4.2".parse().unwrap();
+        // Older
+        let e = check_min_version("0.5", &running).unwrap_err().to_string();
+        assert!(e.contains("0.5.0") && e.contains("0.4.2"), "{}", e);
+        assert!(check_min_version("0.4.3", &running).is_err());
+        // Equal or newer within the same release series
+        assert_eq!(check_min_version("0.4.2", &running).unwrap(), None);
+        assert_eq!(check_min_version("0.4", &running).unwrap(), None);
+        // Much newer
+        assert!(check_min_version("0.3", &running).unwrap().is_some_and(|w| w.contains("0.3.0") && w.contains("0.4.2")));
+        assert!(check_min_version("1.2", &"2.0.0".parse().unwrap()).unwrap().is_some());
+        assert_eq!(check_min_version("1.2", &"1.9.0".parse().unwrap()).unwrap(), None);
+        // Malformed
+        assert!(check_min_version("0.4.x", &running).unwrap_err().to_string().contains("min-cfc-version"));
+    }
+}
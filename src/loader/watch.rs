@@ -0,0 +1,102 @@
+//! Detection of configuration file changes
+use std::{path::PathBuf, time::SystemTime};
+
+use tokio::time::{sleep, Duration};
+use tracing::trace;
+
+/// The state of a file used to detect changes. `None` if the file could not be read.
+type FileStamp = Option<(SystemTime, u64)>;
+
+/// Polls a file's modification time and size to detect changes.
+/// When watching a directory, the latest modification time and total size of its files are used.
+///
+/// Successive changes are debounced: a change is only reported once the file
+/// stayed untouched for the debounce duration, so that an editor writing a file
+/// in several steps only triggers a single notification.
+pub struct FileWatcher {
+    path: PathBuf,
+    last: FileStamp,
+    interval: Duration,
+    debounce: Duration,
+}
+
+impl FileWatcher {
+    /// Start watching a file, using its current state as reference
+    pub fn new(path: impl Into<PathBuf>, interval: Duration, debounce: Duration) -> Self {
+        let path = path.into();
+        let last = Self::stamp(&path);
+        FileWatcher { path, last, interval, debounce }
+    }
+
+    fn stamp(path: &PathBuf) -> FileStamp {
+        let metadata = std::fs::metadata(path).ok()?;
+        let mut stamp = (metadata.modified().ok()?, metadata.len());
+        if metadata.is_dir() {
+            stamp.1 = 0;
+            for entry in std::fs::read_dir(path).ok()?.flatten() {
+                if let Some((modified, len)) = entry.metadata().ok().and_then(|m| Some((m.modified().ok()?, m.len()))) {
+                    stamp = (stamp.0.max(modified), stamp.1 + len);
+                }
+            }
+        }
+        Some(stamp)
+    }
+
+    /// Wait until the file changes and stops changing.
+    ///
+    /// This future may be dropped at any time without missing a change.
+    pub async fn changed(&mut self) {
+        loop {
+            sleep(self.interval).await;
+            let mut current = Self::stamp(&self.path);
+            if current == self.last {
+                continue;
+            }
+            trace!("Detected a change of {}, waiting for it to settle", self.path.display());
+            loop {
+                sleep(self.debounce).await;
+                let settled = Self::stamp(&self.path);
+                if settled == current {
+                    break;
+                }
+                current = settled;
+            }
+            self.last = current;
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{self, File}, io::Write};
+
+    use tokio::time::{timeout, Duration};
+
+    use super::FileWatcher;
+
+    #[tokio::test]
+    async fn detect_debounced_changes() {
+        let path = std::env::temp_dir().join(format!("cfc-watch-{}.ini", std::process::id()));
+        fs::write(&path, "[job-local \"a\"]\n").unwrap();
+        let mut watcher = FileWatcher::new(&path, Duration::from_millis(10), Duration::from_millis(50));
+
+        assert!(timeout(Duration::from_millis(100), watcher.changed()).await.is_err());
+
+        let writer = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                for _ in 0..5 {
+                    let mut f = File::options().append(true).open(&path).unwrap();
+                    f.write_all(b"# edit\n").unwrap();
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            })
+        };
+        timeout(Duration::from_secs(2), watcher.changed()).await.unwrap();
+        writer.await.unwrap();
+        // All the writes were merged in a single notification
+        assert!(timeout(Duration::from_millis(100), watcher.changed()).await.is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,80 +1,415 @@
-use std::collections::HashMap;
+use std::{collections::{hash_map::Entry, HashMap}, fmt::Write};
 
 use anyhow::{Error, Result};
-use saphyr_parser::{Event, Parser};
-use tracing::warn;
+use saphyr_parser::{Event, Marker, Parser, ScanError};
+use tracing::{debug, warn};
 
-pub fn parse_yaml(payload: &String) -> Result<HashMap<String, HashMap<String, Vec<String>>>> {
-    let mut parser = Parser::new_from_str(payload.as_str());
+use crate::job::ANNOTATION_PREFIX;
+
+use super::{ordered_keys, ordered_sections, JobMaps, ParserLimits, ORIGIN_KEY};
+
+/// The maximum nesting of the YAML nodes, which is well over what job definitions need
+const MAX_NESTING: usize = 16;
+
+/// The job keys whose values may be written as a dict, which is flattened into `KEY=VALUE` values
+const MAPPING_KEYS: [&str; 1] = ["environment"];
+
+/// The job key whose nested arrays are commands given as a list of arguments
+const COMMAND_KEY: &str = "command";
+
+/// The job key whose dict is turned into `annotation.KEY` keys
+const ANNOTATIONS_KEY: &str = "annotations";
+
+/// Top-level keys with this prefix hold shared settings rather than jobs, as in docker compose files
+const EXTENSION_PREFIX: &str = "x-";
+
+/// A node of the YAML document, along with the position at which it starts
+#[derive(Clone, Debug)]
+enum Node {
+    Scalar(String, Marker),
+    Sequence(Vec<Node>, Marker),
+    Mapping(Vec<(Node, Node)>, Marker),
+}
+
+impl Node {
+    fn marker(&self) -> Marker {
+        match self {
+            Node::Scalar(_, m) | Node::Sequence(_, m) | Node::Mapping(_, m) => *m,
+        }
+    }
+
+    /// The number of scalars in the node and its children
+    fn values(&self) -> usize {
+        match self {
+            Node::Scalar(_, _) => 1,
+            Node::Sequence(items, _) => items.iter().map(Node::values).sum(),
+            Node::Mapping(entries, _) => entries.iter().map(|(k, v)| k.values() + v.values()).sum(),
+        }
+    }
+}
+
+/// Builds the document's nodes from the parser's events, expanding aliases as they are found
+struct NodeLoader<'a, I> {
+    events: I,
+    limits: &'a ParserLimits,
+    /// The anchored nodes, along with their number of values
+    anchors: HashMap<usize, (Node, usize)>,
+    values: usize,
+    expanded_values: usize,
+    depth: usize,
+}
+
+impl<I: Iterator<Item = Result<(Event, Marker), ScanError>>> NodeLoader<'_, I> {
+    /// Load the next node, or return `None` at the end of the current sequence, mapping or stream
+    fn next_node(&mut self) -> Result<Option<Node>> {
+        loop {
+            let Some(token) = self.events.next() else {
+                return Err(Error::msg("The YAML parser ended unexpectedly"));
+            };
+            let (event, marker) = token.map_err(Error::new)?;
+            let (node, anchor) = match event {
+                Event::StreamStart | Event::DocumentStart | Event::DocumentEnd | Event::Nothing => continue,
+                Event::StreamEnd | Event::SequenceEnd | Event::MappingEnd => return Ok(None),
+                Event::Alias(id) => {
+                    let Some((node, values)) = self.anchors.get(&id) else {
+                        return Err(Error::msg(format!("Found an alias to an unknown anchor (at line {} column {})", marker.line(), marker.col())));
+                    };
+                    // Bound the size of the expanded document to prevent alias bombs
+                    self.expanded_values += values;
+                    ParserLimits::check("max-alias-expansion", self.expanded_values.div_ceil(self.values.max(1)), self.limits.max_alias_expansion)?;
+                    return Ok(Some(node.clone()));
+                },
+                Event::Scalar(value, _, anchor, _) => {
+                    ParserLimits::check("max-scalar-length", value.len(), self.limits.max_scalar_length)?;
+                    self.values += 1;
+                    (Node::Scalar(value, marker), anchor)
+                },
+                Event::SequenceStart(_, _) | Event::MappingStart(_, _) if self.depth >= MAX_NESTING => {
+                    return Err(Error::msg(format!["Yaml dict is too deeply nested at line {}, column {} in file", marker.line(), marker.col()]));
+                },
+                Event::SequenceStart(anchor, _) => {
+                    self.depth += 1;
+                    let mut items = vec![];
+                    while let Some(item) = self.next_node()? {
+                        items.push(item);
+                    }
+                    self.depth -= 1;
+                    (Node::Sequence(items, marker), anchor)
+                },
+                Event::MappingStart(anchor, _) => {
+                    self.depth += 1;
+                    let mut entries = vec![];
+                    while let Some(key) = self.next_node()? {
+                        let value = self.next_node()?
+                            .ok_or_else(|| Error::msg(format!("Unexpected dict key without a value (at line {} column {})", marker.line(), marker.col())))?;
+                        entries.push((key, value));
+                    }
+                    self.depth -= 1;
+                    (Node::Mapping(entries, marker), anchor)
+                },
+            };
+            if anchor != 0 {
+                self.anchors.insert(anchor, (node.clone(), node.values()));
+            }
+            return Ok(Some(node));
+        }
+    }
+}
+
+/// Parse a YAML configuration into a map of job parameter maps.
+///
+/// Aliases are replaced by the node their anchor points to. A job may also pull the keys of
+/// one or several dicts with the `<<` merge key: as in YAML's merge key specification, the
+/// keys set in the job itself override the merged ones, and when several dicts are merged
+/// the first one that sets a key wins. Top-level keys starting with `x-` are not jobs and may
+/// be used to declare the anchored settings. The line of each job's key is set in its [`ORIGIN_KEY`].
+pub fn parse_yaml(payload: &str, limits: &ParserLimits) -> Result<HashMap<String, HashMap<String, Vec<String>>>> {
+    let mut loader = NodeLoader {
+        events: Parser::new_from_str(payload),
+        limits,
+        anchors: HashMap::new(),
+        values: 0,
+        expanded_values: 0,
+        depth: 0,
+    };
     let mut data = HashMap::new();
-    let mut current_depth = -1;
-    let mut is_vec_context = false;
-    let mut current_job_name = "".to_string();
-    let mut current_job_key = "".to_string();
-    while let Some(token) = parser.next() {
-        if token.is_err() {
-            return Err(Error::new(token.unwrap_err()));
+    // Each document declares its own jobs
+    while let Some(document) = loader.next_node()? {
+        let Node::Mapping(jobs, _) = document else {
+            let marker = document.marker();
+            return Err(Error::msg(format!("The YAML configuration must be a dict of jobs (at line {} column {})", marker.line(), marker.col())));
+        };
+        for (name, job) in jobs {
+            let Node::Scalar(name, marker) = name else {
+                let marker = name.marker();
+                return Err(Error::msg(format!["Unexpected dict without a key at line {}, column {} in file", marker.line(), marker.col()]));
+            };
+            if name.starts_with(EXTENSION_PREFIX) {
+                debug!("Skipping the top-level key '{}' as it is not a job", name);
+                continue;
+            }
+            let entries = match job {
+                Node::Mapping(entries, _) => entries,
+                Node::Scalar(_, m) => return Err(Error::msg(format!("Unexpected scalar in dict, a dict was was expected (at line {} col {})", m.line(), m.col()))),
+                Node::Sequence(_, m) => return Err(Error::msg(format!("Arrays may only be used at depth 2 in YAML configuration (at line {} column {})", m.line(), m.col()))),
+            };
+            if data.contains_key(&name) {
+                warn!("The key '{}' appears several times in a single dict, this may produce unexpected results and is not supported. Please fix your YAML configuration (ar line {} col {})", name, marker.line(), marker.col());
+            } else {
+                ParserLimits::check("max-jobs", data.len() + 1, limits.max_jobs)?;
+            }
+            let parameters = data.entry(name)
+                .or_insert_with(|| HashMap::from([(ORIGIN_KEY.to_string(), vec![marker.line().to_string()])]));
+            add_job_parameters(parameters, entries)?;
         }
-        let (event, marker) = token.unwrap();
-        match event {
-            Event::DocumentStart | Event::DocumentEnd | Event::Nothing | Event::StreamStart => {},
-            Event::Alias(_) => {
-                warn!("Found an alias in the YAML file. Their use is not supported at the moment (as line {} column {})", marker.line(), marker.col());
-            },
-            Event::Scalar(value, _, _, _) => {
-                match current_depth {
-                    0 => {
-                        if !current_job_name.is_empty() {
-                            return Err(Error::msg(format!("Unexpected scalar in dict, a dict was was expected (at line {} col {})", marker.line(), marker.col())));
-                        }
-                        if data.contains_key(&value) {
-                            warn!("The key '{}' appears several times in a single dict, this may produce unexpected results and is not supported. Please fix your YAML configuration (ar line {} col {})", value, marker.line(), marker.col());
-                        } else {
-                            data.insert(value, HashMap::new());
-                        }
-                    },
-                    1 => {
-                        let current_subdict = data.get_mut(&current_job_name).unwrap();
-                        if current_job_key.is_empty() {
-                            if current_subdict.contains_key(&value) {
-                                warn!("The key '{}' appears several times in a single dict, this may produce unexpected results and is not supported. Please fix your YAML configuration (at line {} col {})", value, marker.line(), marker.col());
-                            } else {
-                                current_subdict.insert(value, vec![]);
-                            }
-                        } else {
-                            current_subdict.get_mut(&current_job_key).unwrap().push(value);
-                        }
-                    },
-                    _ => return Err(Error::msg(format!("Unhandled error while parsing yaml file (at line {} column {}): Unexpected scalar", marker.line(), marker.col()))),
-                }
-            },
-            Event::SequenceStart(_, _) => {
-                if current_depth != 1 || is_vec_context {
-                    return Err(Error::msg(format!("Arrays may only be used at depth 2 in YAML configuration (at line {} column {})", marker.line(), marker.col())))
-                }
-                is_vec_context = true;
-            },
-            Event::SequenceEnd => is_vec_context = false,
-            Event::MappingStart(_, _) => {
-                current_depth += 1;
-                match current_depth {
-                    0 => {},
-                    1 => assert!(!current_job_name.is_empty()),
-                    _ => return Err(Error::msg(format!["Yaml dict is too deeply nested at line {}, column {} in file", marker.line(), marker.col()])),
-                }
-            },
-            Event::MappingEnd => {
-                current_depth -= 1;
-                match current_depth {
-                    0 => current_job_key = "".to_string(),
-                    1 => current_job_name = "".to_string(),
-                    _ => {},
+    }
+    Ok(data)
+}
+
+/// Add the keys of a job's dict to its parameter map, resolving merge keys
+fn add_job_parameters(parameters: &mut HashMap<String, Vec<String>>, entries: Vec<(Node, Node)>) -> Result<()> {
+    let mut merged: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, value) in entries {
+        let Node::Scalar(key, marker) = key else {
+            let marker = key.marker();
+            return Err(Error::msg(format!["Yaml dict is too deeply nested at line {}, column {} in file", marker.line(), marker.col()]));
+        };
+        if key == "<<" {
+            let merged_dicts = match value {
+                Node::Sequence(items, _) => items,
+                value => vec![value],
+            };
+            for dict in merged_dicts {
+                let Node::Mapping(merged_entries, _) = dict else {
+                    let marker = dict.marker();
+                    return Err(Error::msg(format!("Only dicts may be merged into a job (at line {} column {})", marker.line(), marker.col())));
+                };
+                let mut dict_parameters = HashMap::new();
+                add_job_parameters(&mut dict_parameters, merged_entries)?;
+                for (key, values) in dict_parameters {
+                    merged.entry(key).or_insert(values);
                 }
+            }
+            continue;
+        }
+        if key == ANNOTATIONS_KEY {
+            let Node::Mapping(entries, _) = value else {
+                let m = value.marker();
+                return Err(Error::msg(format!("The annotations of a job must be a dict (at line {} column {})", m.line(), m.col())));
+            };
+            for (name, annotation) in mapping_pairs(entries)? {
+                insert_values(parameters, format!("{}{}", ANNOTATION_PREFIX, name), vec![annotation], marker);
+            }
+            continue;
+        }
+        let values = match value {
+            Node::Sequence(items, _) if key == COMMAND_KEY => command_values(items)?,
+            Node::Mapping(entries, _) if MAPPING_KEYS.contains(&key.as_str()) => {
+                mapping_pairs(entries)?.into_iter().map(|(key, value)| format!("{}={}", key, value)).collect()
             },
-            Event::StreamEnd => {
-                return Ok(data);
-            },
+            value => job_values(value)?,
+        };
+        insert_values(parameters, key, values, marker);
+    }
+    for (key, values) in merged {
+        parameters.entry(key).or_insert(values);
+    }
+    Ok(())
+}
+
+/// Add values to a job key, warning if the key was already set
+fn insert_values(parameters: &mut HashMap<String, Vec<String>>, key: String, values: Vec<String>, marker: Marker) {
+    match parameters.entry(key) {
+        Entry::Vacant(e) => {
+            e.insert(values);
+        },
+        Entry::Occupied(mut e) => {
+            warn!("The key '{}' appears several times in a single dict, this may produce unexpected results and is not supported. Please fix your YAML configuration (at line {} col {})", e.key(), marker.line(), marker.col());
+            e.get_mut().extend(values);
+        },
+    }
+}
+
+/// Get the keys and values of a dict of scalars
+fn mapping_pairs(entries: Vec<(Node, Node)>) -> Result<Vec<(String, String)>> {
+    entries.into_iter().map(|entry| match entry {
+        (Node::Scalar(key, _), Node::Scalar(value, _)) => Ok((key, value)),
+        (Node::Scalar(_, _), Node::Sequence(_, m)) => Err(Error::msg(format!("Arrays may only be used at depth 2 in YAML configuration (at line {} column {})", m.line(), m.col()))),
+        (key, value) => {
+            let m = if matches!(key, Node::Scalar(_, _)) { value.marker() } else { key.marker() };
+            Err(Error::msg(format!["Yaml dict is too deeply nested at line {}, column {} in file", m.line(), m.col()]))
+        },
+    }).collect()
+}
+
+/// Get the commands of a job, a command given as an array of arguments is kept as a JSON array
+fn command_values(items: Vec<Node>) -> Result<Vec<String>> {
+    items.into_iter().map(|item| match item {
+        Node::Sequence(arguments, _) => {
+            let arguments = arguments.into_iter().map(|argument| match argument {
+                Node::Scalar(argument, _) => Ok(argument),
+                argument => {
+                    let m = argument.marker();
+                    Err(Error::msg(format!("The arguments of a command must be scalars (at line {} column {})", m.line(), m.col())))
+                },
+            }).collect::<Result<Vec<_>>>()?;
+            Ok(serde_json::Value::from(arguments).to_string())
+        },
+        item => job_values(item)?.pop().ok_or_else(|| Error::msg("Unexpected empty command")),
+    }).collect()
+}
+
+/// Get the values of a job key, which is either a scalar or an array of scalars
+fn job_values(value: Node) -> Result<Vec<String>> {
+    match value {
+        Node::Scalar(value, _) => Ok(vec![value]),
+        Node::Sequence(items, _) => items.into_iter().map(|item| match item {
+            Node::Scalar(value, _) => Ok(value),
+            Node::Sequence(_, m) => Err(Error::msg(format!("Arrays may only be used at depth 2 in YAML configuration (at line {} column {})", m.line(), m.col()))),
+            Node::Mapping(_, m) => Err(Error::msg(format!["Yaml dict is too deeply nested at line {}, column {} in file", m.line(), m.col()])),
+        }).collect(),
+        Node::Mapping(_, m) => Err(Error::msg(format!["Yaml dict is too deeply nested at line {}, column {} in file", m.line(), m.col()])),
+    }
+}
+
+/// Write a key, quoting it unless it is a plain word
+fn yaml_key(key: &str) -> String {
+    let plain = key.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || "-_.@".contains(c));
+    if plain { key.to_string() } else { yaml_string(key) }
+}
+
+/// Write a scalar, as a double-quoted string whose escapes are the same as JSON's unless it is a plain word
+fn yaml_string(value: &str) -> String {
+    let plain = value.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '/')
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=".contains(c));
+    if plain { value.to_string() } else { serde_json::Value::from(value).to_string() }
+}
+
+/// Write a value of a job key, commands given as a list of arguments are written as a nested list
+fn yaml_value(key: &str, value: &str) -> String {
+    match serde_json::from_str::<Vec<String>>(value) {
+        Ok(arguments) if key == COMMAND_KEY => format!("[{}]", arguments.iter().map(|a| yaml_string(a)).collect::<Vec<_>>().join(", ")),
+        _ => yaml_string(value),
+    }
+}
+
+/// Write parsed job parameter maps as a YAML configuration, the `global` dict first and the
+/// jobs in the order they were declared
+pub fn write_yaml(data: &JobMaps) -> String {
+    let mut content = String::new();
+    for (section, parameters) in ordered_sections(data) {
+        let keys = ordered_keys(parameters);
+        // Writing to a string can not fail
+        if keys.is_empty() {
+            let _ = writeln!(content, "{}: {{}}", yaml_key(section));
+            continue;
+        }
+        let _ = writeln!(content, "{}:", yaml_key(section));
+        for key in keys {
+            match parameters[key].as_slice() {
+                [] => { let _ = writeln!(content, "  {}: []", yaml_key(key)); },
+                // A single nested list would be read as a list of commands
+                [value] if key != COMMAND_KEY || !value.starts_with('[') => { let _ = writeln!(content, "  {}: {}", yaml_key(key), yaml_string(value)); },
+                values => {
+                    let _ = writeln!(content, "  {}:", yaml_key(key));
+                    for value in values {
+                        let _ = writeln!(content, "    - {}", yaml_value(key, value));
+                    }
+                },
+            }
+        }
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::loader::{LimitExceeded, ParserLimits, ORIGIN_KEY};
+
+    use super::parse_yaml;
+
+    fn parse(payload: &str) -> HashMap<String, HashMap<String, Vec<String>>> {
+        parse_yaml(payload, &ParserLimits::default()).unwrap()
+    }
+
+    #[test]
+    fn parse_jobs() {
+        let data = parse("a:\n  kind: job-local\n  environment: [A=1, B=2]\nb: {}\n");
+        assert_eq!(data["a"]["kind"], vec!["job-local"]);
+        assert_eq!(data["a"]["environment"], vec!["A=1", "B=2"]);
+        // Only the line of the job's key is known of an empty job
+        assert_eq!(data["a"][ORIGIN_KEY], vec!["1"]);
+        assert_eq!(data["b"].keys().collect::<Vec<_>>(), vec![ORIGIN_KEY]);
+        assert_eq!(data["b"][ORIGIN_KEY], vec!["4"]);
+        assert!(parse("").is_empty());
+        for invalid in ["a: 1\n", "a: [1]\n", "a:\n  b:\n    c: 1\n", "a:\n  b: [[1]]\n", "- a\n", &"[".repeat(100)] {
+            assert!(parse_yaml(invalid, &ParserLimits::default()).is_err(), "{:?}", invalid);
         }
     }
-    return Err(Error::msg("The YAML parser ended unexpectedly"))
+
+    #[test]
+    fn parse_environment_mapping() {
+        let data = parse("a:\n  environment:\n    FOO: bar\n    BAZ: 'a=b c'\n    EMPTY: ''\nb:\n  environment: {FOO: bar}\n");
+        assert_eq!(data["a"]["environment"], vec!["FOO=bar", "BAZ=a=b c", "EMPTY="]);
+        assert_eq!(data["b"]["environment"], vec!["FOO=bar"]);
+        // The list form is still supported
+        assert_eq!(parse("a:\n  environment:\n    - FOO=bar\n")["a"]["environment"], vec!["FOO=bar"]);
+        for invalid in ["a:\n  environment:\n    FOO:\n      BAR: 1\n", "a:\n  environment:\n    FOO: [1]\n", "a:\n  command: {FOO: bar}\n"] {
+            assert!(parse_yaml(invalid, &ParserLimits::default()).is_err(), "{:?}", invalid);
+        }
+        let e = parse_yaml("a:\n  environment:\n    FOO:\n      BAR: 1\n", &ParserLimits::default()).unwrap_err();
+        assert!(e.to_string().contains("too deeply nested"), "{}", e);
+    }
+
+    #[test]
+    fn parse_argv_commands() {
+        let data = parse("a:\n  command:\n    - [echo, 'a b', \"c\\\"d\"]\n    - echo e\n");
+        assert_eq!(data["a"]["command"], vec![r#"["echo","a b","c\"d"]"#, "echo e"]);
+        for invalid in ["a:\n  command: [[echo, [a]]]\n", "a:\n  command: [[echo, {a: b}]]\n", "a:\n  schedule: [[a]]\n"] {
+            assert!(parse_yaml(invalid, &ParserLimits::default()).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn resolve_aliases() {
+        let data = parse(concat!(
+            "x-defaults: &defaults\n  kind: job-local\n  schedule: '@hourly'\n  environment: [A=1]\n",
+            "x-extra: &extra\n  schedule: '@daily'\n  dir: /tmp\n",
+            "a:\n  <<: *defaults\n  command: &cmd echo a\n",
+            // Keys set in the job override merged ones, wherever the merge key is
+            "b:\n  schedule: '@weekly'\n  <<: *defaults\n  command: *cmd\n",
+            // The first merged dict that sets a key wins
+            "c:\n  <<: [*extra, *defaults]\n  command: echo c\n",
+            "d: *extra\n",
+        ));
+        assert_eq!(data["a"]["kind"], vec!["job-local"]);
+        assert_eq!(data["a"]["schedule"], vec!["@hourly"]);
+        assert_eq!(data["a"]["environment"], vec!["A=1"]);
+        assert!(!data["a"].contains_key("<<"));
+        assert_eq!(data["b"]["schedule"], vec!["@weekly"]);
+        assert_eq!(data["b"]["command"], vec!["echo a"]);
+        assert_eq!(data["c"]["schedule"], vec!["@daily"]);
+        assert_eq!(data["c"]["dir"], vec!["/tmp"]);
+        assert_eq!(data["c"]["kind"], vec!["job-local"]);
+        assert_eq!(data["d"]["dir"], vec!["/tmp"]);
+        assert_eq!(data.len(), 4);
+
+        assert!(parse_yaml("a:\n  <<: *unknown\n", &ParserLimits::default()).is_err());
+        assert!(parse_yaml("a:\n  <<: [1]\n", &ParserLimits::default()).is_err());
+    }
+
+    #[test]
+    fn limit_alias_expansion() {
+        let bomb = concat!(
+            "a: &a [x, x, x, x, x, x, x, x, x, x]\n",
+            "b: &b [*a, *a, *a, *a, *a, *a, *a, *a, *a, *a]\n",
+            "c: &c [*b, *b, *b, *b, *b, *b, *b, *b, *b, *b]\n",
+            "d: [*c, *c, *c, *c, *c, *c, *c, *c, *c, *c]\n",
+        );
+        let e = parse_yaml(bomb, &ParserLimits::default()).unwrap_err();
+        assert_eq!(e.downcast_ref::<LimitExceeded>().unwrap().limit, "max-alias-expansion");
+    }
 }
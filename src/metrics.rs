@@ -0,0 +1,328 @@
+//! Execution metrics exposed in the Prometheus text format
+//!
+//! The metrics are always collected, the HTTP server that exposes them is only
+//! available with the `metrics` feature.
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+use anyhow::Error;
+use serde::Serialize;
+
+use crate::{job::{running_executions, ExecInfo, ExecutionReport}, scheduler::SpawnBudget};
+
+/// The upper bounds of the execution duration histogram's buckets, in seconds
+pub const DURATION_BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0, 14400.0];
+
+/// The maximum number of annotations exported as labels, which bounds the cardinality of the metrics
+pub const MAX_ANNOTATION_LABELS: usize = 8;
+
+/// The outcome of an execution, as reported in the `result` label
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunResult {
+    /// The job's command succeeded
+    Success,
+    /// The job's command returned a non-zero value
+    Failure,
+    /// The execution was terminated as it exceeded its timeout
+    Timeout,
+    /// The job's command could not be run
+    Error,
+}
+
+impl RunResult {
+    /// Get the result of an execution
+    pub fn of(result: &Result<ExecInfo, Error>) -> Self {
+        match result {
+            Ok(ExecInfo::Report(r)) => Self::of_report(r),
+            Ok(_) => RunResult::Success,
+            Err(_) => RunResult::Error,
+        }
+    }
+
+    /// Get the result of an execution that ran the job's command
+    pub fn of_report(report: &ExecutionReport) -> Self {
+        match report {
+            r if r.timed_out => RunResult::Timeout,
+            r if r.retval != 0 => RunResult::Failure,
+            _ => RunResult::Success,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            RunResult::Success => "success",
+            RunResult::Failure => "failure",
+            RunResult::Timeout => "timeout",
+            RunResult::Error => "error",
+        }
+    }
+}
+
+/// The distribution of the execution durations of a job
+#[derive(Default)]
+struct Histogram {
+    /// The number of observations in each bucket, not cumulated
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = DURATION_BUCKETS.iter().position(|b| value <= *b) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+struct Metrics {
+    /// The number of executions by job, kind and result
+    runs: BTreeMap<(String, String, RunResult), u64>,
+    /// The execution durations by job and kind
+    durations: BTreeMap<(String, String), Histogram>,
+    /// The annotations of each scheduled job
+    annotations: BTreeMap<String, BTreeMap<String, String>>,
+    /// The annotations exported as labels
+    annotation_labels: Vec<String>,
+    /// The number of executions skipped by job because the spawn budget was exhausted
+    spawns_skipped: BTreeMap<String, u64>,
+    /// The budget of containers created per hour, if any
+    spawn_budget: Option<SpawnBudget>,
+    /// The number of executions by job whose command produced no output in time
+    stalls: BTreeMap<String, u64>,
+}
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    runs: BTreeMap::new(), durations: BTreeMap::new(), annotations: BTreeMap::new(), annotation_labels: vec![],
+    spawns_skipped: BTreeMap::new(), spawn_budget: None, stalls: BTreeMap::new(),
+});
+
+/// The checksum of the configuration the daemon currently runs
+static CONFIG_CHECKSUM: Mutex<Option<String>> = Mutex::new(None);
+
+/// Set the checksum of the configuration the daemon runs, returning the previous one
+pub fn set_config_checksum(checksum: String) -> Option<String> {
+    CONFIG_CHECKSUM.lock().unwrap_or_else(|e| e.into_inner()).replace(checksum)
+}
+
+/// Get the checksum of the configuration the daemon runs, if it was set
+pub fn config_checksum() -> Option<String> {
+    CONFIG_CHECKSUM.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Record the end of a job's execution
+pub fn record_execution(job: &str, kind: &str, result: RunResult, duration: Duration) {
+    let mut metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+    *metrics.runs.entry((job.to_string(), kind.to_string(), result)).or_default() += 1;
+    metrics.durations.entry((job.to_string(), kind.to_string())).or_default().observe(duration.as_secs_f64());
+}
+
+/// Set the budget of containers created per hour whose use is exported
+pub fn set_spawn_budget(budget: Option<SpawnBudget>) {
+    METRICS.lock().unwrap_or_else(|e| e.into_inner()).spawn_budget = budget;
+}
+
+/// Record an execution skipped because the budget of containers created per hour was exhausted
+pub fn record_spawn_skipped(job: &str) {
+    *METRICS.lock().unwrap_or_else(|e| e.into_inner()).spawns_skipped.entry(job.to_string()).or_default() += 1;
+}
+
+/// Record an execution whose command produced no output within its job's `first-output-timeout`
+pub fn record_stall(job: &str) {
+    *METRICS.lock().unwrap_or_else(|e| e.into_inner()).stalls.entry(job.to_string()).or_default() += 1;
+}
+
+/// Set the annotations that are exported as labels of the `cfc_job_annotations` metric
+pub fn set_annotation_labels(names: Vec<String>) -> Result<(), Error> {
+    if names.len() > MAX_ANNOTATION_LABELS {
+        return Err(Error::msg(format!("At most {} annotations may be exported as metric labels", MAX_ANNOTATION_LABELS)));
+    }
+    if let Some(name) = names.iter().find(|n| n.is_empty() || !n.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')) {
+        return Err(Error::msg(format!("Invalid annotation name '{}', expected letters, digits, '-' and '_'", name)));
+    }
+    METRICS.lock().unwrap_or_else(|e| e.into_inner()).annotation_labels = names;
+    Ok(())
+}
+
+/// Record the annotations of a scheduled job
+pub fn record_annotations(job: &str, annotations: &BTreeMap<String, String>) {
+    let mut metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+    match annotations.is_empty() {
+        true => metrics.annotations.remove(job),
+        false => metrics.annotations.insert(job.to_string(), annotations.clone()),
+    };
+}
+
+/// Escape a label value as required by the Prometheus text format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Render the metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    let metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+
+    out.push_str("# HELP cfc_job_runs_total The number of ended job executions.\n# TYPE cfc_job_runs_total counter\n");
+    for ((job, kind, result), count) in metrics.runs.iter() {
+        let _ = writeln!(out, "cfc_job_runs_total{{job=\"{}\",kind=\"{}\",result=\"{}\"}} {}", escape(job), escape(kind), result.as_str(), count);
+    }
+
+    out.push_str("# HELP cfc_job_duration_seconds The duration of the job executions.\n# TYPE cfc_job_duration_seconds histogram\n");
+    for ((job, kind), histogram) in metrics.durations.iter() {
+        let labels = format!("job=\"{}\",kind=\"{}\"", escape(job), escape(kind));
+        let mut cumulated = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+            cumulated += count;
+            let _ = writeln!(out, "cfc_job_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, cumulated);
+        }
+        let _ = writeln!(out, "cfc_job_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, histogram.count);
+        let _ = writeln!(out, "cfc_job_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+        let _ = writeln!(out, "cfc_job_duration_seconds_count{{{}}} {}", labels, histogram.count);
+    }
+
+    if !metrics.annotation_labels.is_empty() {
+        out.push_str("# HELP cfc_job_annotations The annotations of the jobs, as labels.\n# TYPE cfc_job_annotations gauge\n");
+        for (job, annotations) in metrics.annotations.iter() {
+            let labels: String = metrics.annotation_labels.iter()
+                .filter_map(|name| Some(format!(",annotation_{}=\"{}\"", name.replace('-', "_"), escape(annotations.get(name)?))))
+                .collect();
+            if !labels.is_empty() {
+                let _ = writeln!(out, "cfc_job_annotations{{job=\"{}\"{}}} 1", escape(job), labels);
+            }
+        }
+    }
+
+    if let Some(budget) = metrics.spawn_budget.as_ref() {
+        out.push_str("# HELP cfc_spawn_budget_limit The maximum number of containers created per hour.\n# TYPE cfc_spawn_budget_limit gauge\n");
+        let _ = writeln!(out, "cfc_spawn_budget_limit {}", budget.limit());
+        out.push_str("# HELP cfc_spawn_budget_used The number of containers created in the last hour.\n# TYPE cfc_spawn_budget_used gauge\n");
+        let _ = writeln!(out, "cfc_spawn_budget_used {}", budget.used(tokio::time::Instant::now()));
+        out.push_str("# HELP cfc_spawns_skipped_total The executions skipped as the budget of containers was exhausted.\n# TYPE cfc_spawns_skipped_total counter\n");
+        for (job, count) in metrics.spawns_skipped.iter() {
+            let _ = writeln!(out, "cfc_spawns_skipped_total{{job=\"{}\"}} {}", escape(job), count);
+        }
+    }
+
+    if !metrics.stalls.is_empty() {
+        out.push_str("# HELP cfc_job_stalls_total The executions whose command produced no output in time.\n# TYPE cfc_job_stalls_total counter\n");
+        for (job, count) in metrics.stalls.iter() {
+            let _ = writeln!(out, "cfc_job_stalls_total{{job=\"{}\"}} {}", escape(job), count);
+        }
+    }
+
+    if let Some(checksum) = config_checksum() {
+        out.push_str("# HELP cfc_config_info The checksum of the configuration the daemon runs.\n# TYPE cfc_config_info gauge\n");
+        let _ = writeln!(out, "cfc_config_info{{checksum=\"{}\"}} 1", escape(&checksum));
+    }
+
+    let mut running: BTreeMap<String, u64> = BTreeMap::new();
+    for (job, _) in running_executions() {
+        *running.entry(job).or_default() += 1;
+    }
+    out.push_str("# HELP cfc_job_running The number of running executions of a job.\n# TYPE cfc_job_running gauge\n");
+    for (job, count) in running {
+        let _ = writeln!(out, "cfc_job_running{{job=\"{}\"}} {}", escape(&job), count);
+    }
+    out
+}
+
+#[cfg(feature = "metrics")]
+pub use server::MetricsServer;
+
+#[cfg(feature = "metrics")]
+mod server {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use anyhow::Result;
+    use hyper::StatusCode;
+
+    use crate::http::HttpServer;
+
+    /// A minimal HTTP server that only answers `GET /metrics`
+    pub struct MetricsServer {
+        server: HttpServer,
+    }
+
+    impl MetricsServer {
+        /// Listen on an address
+        pub async fn bind(addr: SocketAddr) -> Result<Self> {
+            let handler = Arc::new(|path: String| -> futures_util::future::BoxFuture<'static, _> {
+                Box::pin(async move { (path == "/metrics").then(|| (StatusCode::OK, super::render())) })
+            });
+            Ok(MetricsServer { server: HttpServer::bind(addr, "metrics", "text/plain; version=0.0.4; charset=utf-8", handler).await? })
+        }
+
+        /// Get the address the server listens on
+        pub fn local_addr(&self) -> Result<SocketAddr> {
+            self.server.local_addr()
+        }
+
+        /// Answer requests until the future is dropped
+        pub async fn run(self) {
+            self.server.run().await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::{escape, record_annotations, record_execution, render, set_annotation_labels, set_config_checksum, RunResult};
+
+    #[test]
+    fn render_metrics() {
+        record_execution("render \"a\"", "job-local", RunResult::Success, Duration::from_millis(300));
+        record_execution("render \"a\"", "job-local", RunResult::Success, Duration::from_secs(2));
+        record_execution("render \"a\"", "job-local", RunResult::Timeout, Duration::from_secs(20));
+        let out = render();
+        assert!(out.contains("cfc_job_runs_total{job=\"render \\\"a\\\"\",kind=\"job-local\",result=\"success\"} 2\n"), "{}", out);
+        assert!(out.contains("cfc_job_runs_total{job=\"render \\\"a\\\"\",kind=\"job-local\",result=\"timeout\"} 1\n"), "{}", out);
+        let labels = "job=\"render \\\"a\\\"\",kind=\"job-local\"";
+        // The buckets are cumulative
+        assert!(out.contains(&format!("cfc_job_duration_seconds_bucket{{{},le=\"0.1\"}} 0\n", labels)), "{}", out);
+        assert!(out.contains(&format!("cfc_job_duration_seconds_bucket{{{},le=\"0.5\"}} 1\n", labels)), "{}", out);
+        assert!(out.contains(&format!("cfc_job_duration_seconds_bucket{{{},le=\"5\"}} 2\n", labels)), "{}", out);
+        assert!(out.contains(&format!("cfc_job_duration_seconds_bucket{{{},le=\"+Inf\"}} 3\n", labels)), "{}", out);
+        assert!(out.contains(&format!("cfc_job_duration_seconds_count{{{}}} 3\n", labels)), "{}", out);
+        assert!(out.contains("# TYPE cfc_job_running gauge\n"));
+        set_config_checksum("sha256:00".to_string());
+        assert!(render().contains("cfc_config_info{checksum=\"sha256:00\"} 1\n"));
+        assert_eq!(escape("a\\b\nc"), "a\\\\b\\nc");
+    }
+
+    #[test]
+    fn render_annotations() {
+        let annotations = BTreeMap::from([("team".to_string(), "ops".to_string()), ("run-book".to_string(), "http://wiki/a".to_string()), ("owner".to_string(), "x".to_string())]);
+        record_annotations("annotated", &annotations);
+        record_annotations("other", &BTreeMap::from([("owner".to_string(), "y".to_string())]));
+        set_annotation_labels(vec!["team".to_string(), "run-book".to_string()]).unwrap();
+        let out = render();
+        // Only the allowed annotations are exported, and jobs without any are left out
+        assert!(out.contains("cfc_job_annotations{job=\"annotated\",annotation_team=\"ops\",annotation_run_book=\"http://wiki/a\"} 1\n"), "{}", out);
+        assert!(!out.contains("owner") && !out.contains("job=\"other\""), "{}", out);
+        assert!(set_annotation_labels((0..9).map(|i| format!("a{}", i)).collect()).is_err());
+        assert!(set_annotation_labels(vec!["a.b".to_string()]).is_err());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn serve_metrics() {
+        let server = super::MetricsServer::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let task = tokio::spawn(server.run());
+        record_execution("served", "job-exec", RunResult::Error, Duration::from_secs(1));
+        let request = |method: &'static str, path: &'static str| crate::http::request(addr, method, path);
+        let response = request("GET", "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("cfc_job_runs_total{job=\"served\",kind=\"job-exec\",result=\"error\"} 1\n"), "{}", response);
+        assert!(request("GET", "/").await.starts_with("HTTP/1.1 404 "));
+        assert!(request("POST", "/metrics").await.starts_with("HTTP/1.1 405 "));
+        let response = request("HEAD", "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\n\r\n"), "{}", response);
+        task.abort();
+    }
+}
@@ -0,0 +1,217 @@
+//! Detailed reports of one-off job executions, written for batch pipelines
+use std::{collections::BTreeMap, path::Path, time::{Duration, Instant}};
+
+use anyhow::{Error, Result};
+use bollard::Docker;
+use serde::Serialize;
+
+use crate::{job::{ErrorCategory, ExecInfo, ExecutionReport, JobInfo}, metrics::RunResult};
+
+/// The version of the report's layout, increased when it changes in an incompatible way
+pub const REPORT_VERSION: u64 = 1;
+
+/// The number of bytes kept from the end of each output stream of an execution
+pub const OUTPUT_TAIL_LENGTH: usize = 4096;
+
+/// Keep the last bytes of an output, without splitting a character
+pub(crate) fn tail(output: &mut Option<String>, max: usize) -> bool {
+    let Some(output) = output.as_mut() else { return false };
+    if output.len() <= max {
+        return false;
+    }
+    let mut start = output.len() - max;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    output.drain(..start);
+    true
+}
+
+/// The report of an execution of a job
+#[derive(Clone, Debug, Serialize)]
+pub struct JobRunReport {
+    pub name: String,
+    pub kind: String,
+    pub result: RunResult,
+    pub duration_seconds: f64,
+    /// The execution's report, with the output streams reduced to their tail
+    #[serde(flatten)]
+    pub report: Option<ExecutionReport>,
+    /// Whether the output streams were truncated
+    pub truncated: bool,
+    /// The reason why the job could not be run
+    pub error: Option<String>,
+    /// The likely cause of the error, if any
+    pub error_category: Option<String>,
+    /// The checksum of the configuration the daemon ran, only set for scheduled executions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_checksum: Option<String>,
+    /// The annotations of the job
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl JobRunReport {
+    /// Describe the result of an execution
+    pub fn new(job: &JobInfo, result: Result<ExecutionReport>, duration_seconds: f64) -> Self {
+        let mut report = match result {
+            Ok(report) => Self::build(job.name(), job.kind(), Ok(report), duration_seconds),
+            Err(e) => Self::build(job.name(), job.kind(), Err(&e), duration_seconds),
+        };
+        report.annotations = job.options().annotations.clone();
+        report
+    }
+
+    /// Describe the result of a scheduled execution
+    pub fn of_execution(name: &str, kind: &str, annotations: &BTreeMap<String, String>, result: &Result<ExecInfo>, duration: Duration) -> Self {
+        let result = match result {
+            Ok(ExecInfo::Report(report)) => Ok(report.clone()),
+            Ok(_) => Ok(ExecutionReport::default()),
+            Err(e) => Err(e),
+        };
+        let mut report = Self::build(name, kind, result, duration.as_secs_f64());
+        report.config_checksum = crate::metrics::config_checksum();
+        report.annotations = annotations.clone();
+        report
+    }
+
+    fn build(name: &str, kind: &str, result: Result<ExecutionReport, &Error>, duration_seconds: f64) -> Self {
+        let (run_result, report, error, error_category) = match result {
+            Ok(report) => (RunResult::of_report(&report), Some(report), None, None),
+            Err(e) => (RunResult::Error, None, Some(e.to_string()), Some(ErrorCategory::of(e).to_string())),
+        };
+        let mut entry = JobRunReport {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            result: run_result,
+            duration_seconds,
+            report,
+            truncated: false,
+            error,
+            error_category,
+            config_checksum: None,
+            annotations: BTreeMap::new(),
+        };
+        if let Some(report) = entry.report.as_mut() {
+            let stdout_truncated = tail(&mut report.stdout, OUTPUT_TAIL_LENGTH);
+            let stderr_truncated = tail(&mut report.stderr, OUTPUT_TAIL_LENGTH);
+            entry.truncated = stdout_truncated || stderr_truncated;
+        }
+        entry
+    }
+
+    /// Run a job once and describe the execution
+    pub async fn run(job: JobInfo, handle: &Docker) -> Self {
+        let start_time = Instant::now();
+        let summary = job.clone();
+        let result = job.run_once(handle).await;
+        JobRunReport::new(&summary, result, start_time.elapsed().as_secs_f64())
+    }
+}
+
+/// The number of executions of each result in a report
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RunCounts {
+    pub total: usize,
+    pub success: usize,
+    pub failure: usize,
+    pub timeout: usize,
+    pub error: usize,
+}
+
+/// The report of the executions of a batch of jobs
+#[derive(Clone, Debug, Serialize)]
+pub struct BatchReport {
+    pub version: u64,
+    pub jobs: Vec<JobRunReport>,
+    pub counts: RunCounts,
+}
+
+impl BatchReport {
+    pub fn new(jobs: Vec<JobRunReport>) -> Self {
+        let mut counts = RunCounts { total: jobs.len(), ..Default::default() };
+        for job in jobs.iter() {
+            match job.result {
+                RunResult::Success => counts.success += 1,
+                RunResult::Failure => counts.failure += 1,
+                RunResult::Timeout => counts.timeout += 1,
+                RunResult::Error => counts.error += 1,
+            }
+        }
+        BatchReport { version: REPORT_VERSION, jobs, counts }
+    }
+
+    /// Write the report as JSON.
+    ///
+    /// The report is written to a temporary file in the same directory first, so that
+    /// readers never see a partially written report.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        let file_name = path.file_name().ok_or_else(|| Error::msg(format!("The report path {} is not a file", path.display())))?;
+        let temporary = path.with_file_name(format!(".{}.{}.tmp", file_name.to_string_lossy(), std::process::id()));
+        std::fs::write(&temporary, content)
+            .and_then(|_| std::fs::rename(&temporary, path))
+            .inspect_err(|_| { let _ = std::fs::remove_file(&temporary); })
+            .map_err(|e| Error::msg(format!("Failed to write the report file {}: {}", path.display(), e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bollard::Docker;
+
+    use crate::job::JobInfo;
+
+    use super::{tail, BatchReport, JobRunReport, RunCounts};
+
+    fn job(name: &str, command: &str) -> JobInfo {
+        JobInfo::try_from(HashMap::from([
+            ("kind".to_string(), vec!["job-local".to_string()]),
+            ("name".to_string(), vec![name.to_string()]),
+            ("schedule".to_string(), vec!["@yearly".to_string()]),
+            ("command".to_string(), vec![command.to_string()]),
+        ])).unwrap()
+    }
+
+    #[test]
+    fn keep_output_tail() {
+        let mut output = Some("abcdé".to_string());
+        assert!(tail(&mut output, 2));
+        // The tail starts after the split character
+        assert_eq!(output.as_deref(), Some("é"));
+        assert!(!tail(&mut output, 2));
+        assert!(!tail(&mut None, 2));
+    }
+
+    #[tokio::test]
+    async fn write_batch_report() {
+        let handle = Docker::connect_with_http_defaults().unwrap();
+        let report = BatchReport::new(vec![
+            JobRunReport::run(job("passing", "echo done"), &handle).await,
+            JobRunReport::run(job("failing", "sh -c 'echo broken >&2; exit 3'"), &handle).await,
+        ]);
+        assert_eq!(report.counts, RunCounts { total: 2, success: 1, failure: 1, ..Default::default() });
+        let path = std::env::temp_dir().join(format!("cfc-report-{}.json", std::process::id()));
+        report.write(&path).unwrap();
+
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written["version"], 1);
+        assert_eq!(written["counts"], serde_json::json!({"total": 2, "success": 1, "failure": 1, "timeout": 0, "error": 0}));
+        let passing = &written["jobs"][0];
+        assert_eq!(passing["name"], "passing");
+        assert_eq!(passing["kind"], "job-local");
+        assert_eq!(passing["result"], "success");
+        assert_eq!(passing["retval"], 0);
+        assert_eq!(passing["stdout"], "done\n");
+        assert_eq!(passing["error"], serde_json::Value::Null);
+        let failing = &written["jobs"][1];
+        assert_eq!(failing["result"], "failure");
+        assert_eq!(failing["retval"], 3);
+        assert_eq!(failing["stderr"], "broken\n");
+        assert_eq!(failing["timed_out"], false);
+        assert!(failing["duration_seconds"].as_f64().unwrap() >= 0.0);
+    }
+}
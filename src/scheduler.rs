@@ -0,0 +1,700 @@
+//! Supervision of the jobs run by the daemon
+use std::{collections::{HashMap, VecDeque}, num::NonZeroUsize, panic::AssertUnwindSafe, sync::{Arc, Mutex}};
+
+use anyhow::Error;
+use bollard::Docker;
+use futures_util::FutureExt;
+use tokio::{sync::watch, task::{AbortHandle, JoinSet}, time::{sleep_until, timeout, Duration, Instant}};
+use tracing::{debug, error, info, warn};
+
+use crate::{clock::ClockJump, job::{panic_message, ExecutionHistory, ExecutionPermits, GroupLocks, JobControl, JobInfo, JobState, StopDaemonError}, loader::cache::LabelCache};
+
+/// The delay before the first restart of a job whose scheduler failed
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum delay between the restarts of a job whose scheduler keeps failing
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How often the jobs that are still running are logged while stopping
+const STOP_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the scheduler of a job removed by a reload is kept paused in case the job is added back
+pub const REMOVAL_GRACE: Duration = Duration::from_secs(5);
+
+/// The rolling window over which the containers created by the jobs are counted
+pub const SPAWN_WINDOW: Duration = Duration::from_secs(3600);
+
+/// The value returned by a job's scheduling task when it ends
+pub type JobResult = Result<Option<bool>, Error>;
+
+/// Get the delay before restarting a job's failed scheduler after it was restarted `restarts` times
+fn restart_delay(restarts: u32) -> Duration {
+    RESTART_BACKOFF.saturating_mul(2u32.saturating_pow(restarts)).min(MAX_RESTART_BACKOFF)
+}
+
+#[derive(Debug)]
+struct SpawnWindow {
+    limit: usize,
+    /// The creation times of the containers created in the current window, oldest first
+    spawns: VecDeque<Instant>,
+    /// The number of executions skipped since the daemon started
+    skipped: u64,
+    /// Whether the budget was exhausted since the last execution it allowed
+    exhausted: bool,
+}
+
+impl SpawnWindow {
+    fn expire(&mut self, now: Instant) {
+        while self.spawns.front().is_some_and(|t| now.saturating_duration_since(*t) >= SPAWN_WINDOW) {
+            self.spawns.pop_front();
+        }
+    }
+}
+
+/// Caps the number of containers created by `job-run` and `job-service-run` jobs over
+/// a rolling window of [`SPAWN_WINDOW`], shared by all jobs
+#[derive(Clone, Debug)]
+pub struct SpawnBudget(Arc<Mutex<SpawnWindow>>);
+
+impl SpawnBudget {
+    pub fn new(limit: NonZeroUsize) -> Self {
+        SpawnBudget(Arc::new(Mutex::new(SpawnWindow { limit: limit.get(), spawns: VecDeque::with_capacity(limit.get()), skipped: 0, exhausted: false })))
+    }
+
+    /// Record an execution of a job that creates a container, return false if it must be skipped
+    /// because the budget is exhausted.
+    ///
+    /// The first execution skipped after the budget was exhausted is notified.
+    pub fn try_spawn(&self, job: &str, now: Instant) -> bool {
+        let mut window = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        window.expire(now);
+        if window.spawns.len() < window.limit {
+            window.spawns.push_back(now);
+            window.exhausted = false;
+            return true;
+        }
+        window.skipped += 1;
+        error!("Skipping the run of job {} as {} containers were already created in the last {}s", job, window.limit, SPAWN_WINDOW.as_secs());
+        if !std::mem::replace(&mut window.exhausted, true) {
+            #[cfg(feature = "webhook")]
+            crate::slack::notify_message(format!(
+                ":warning: The budget of {} containers per hour is exhausted, the runs of job {} and of the other jobs that create containers are skipped",
+                window.limit, job,
+            ));
+        }
+        false
+    }
+
+    /// The maximum number of containers created in the window
+    pub fn limit(&self) -> usize {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).limit
+    }
+
+    /// The number of containers created in the window that ends at `now`
+    pub fn used(&self, now: Instant) -> usize {
+        let mut window = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        window.expire(now);
+        window.spawns.len()
+    }
+
+    /// The number of executions skipped since the daemon started
+    pub fn skipped(&self) -> u64 {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).skipped
+    }
+}
+
+/// A job removed by a reload whose scheduling task is kept paused until its grace window ends
+struct RetiredJob {
+    name: String,
+    handles: Vec<AbortHandle>,
+    definition: Option<JobInfo>,
+    restarts: Option<u32>,
+    history: Option<ExecutionHistory>,
+    state: watch::Sender<JobState>,
+    /// The state the job is put back in if it is added back
+    resumed_state: JobState,
+    /// When the job's scheduling task is aborted
+    deadline: Instant,
+}
+
+/// Keeps track of the scheduling task of every running job.
+///
+/// Jobs are identified by their [fingerprint][`JobInfo::fingerprint`] so that
+/// a configuration reload only restarts the jobs whose definition changed.
+/// The scheduler of a removed job is paused for a [grace window][`Scheduler::set_removal_grace`]
+/// before it is stopped, so that a job removed and added back by successive reloads keeps its
+/// running executions and its schedule instead of being triggered again.
+pub struct Scheduler {
+    handle: Docker,
+    set: JoinSet<(String, JobResult)>,
+    jobs: HashMap<String, (String, Vec<AbortHandle>)>,
+    /// The definition of the running jobs, used to restart them
+    definitions: HashMap<String, JobInfo>,
+    /// The number of times each job was restarted after its scheduler failed
+    restarts: HashMap<String, u32>,
+    /// The last executions of the running jobs, kept across the restarts of their scheduler
+    histories: HashMap<String, ExecutionHistory>,
+    /// Whether the running jobs are paused, kept across the restarts of their scheduler
+    states: HashMap<String, watch::Sender<JobState>>,
+    /// The state of the jobs started afterwards
+    default_state: JobState,
+    /// The jobs removed by a reload that are stopped once their grace window ends
+    retired: HashMap<String, RetiredJob>,
+    removal_grace: Duration,
+    restart_failed: bool,
+    label_cache: LabelCache,
+    stop: watch::Sender<bool>,
+    /// Shared by the executions of all jobs to limit how many run at the same time
+    execution_permits: Option<ExecutionPermits>,
+    /// Shared by all jobs so that the executions of a group's jobs never overlap
+    group_locks: GroupLocks,
+    clock_jumps: watch::Sender<Option<ClockJump>>,
+    /// Whether the jobs started afterwards only log their executions
+    dry_run: bool,
+    /// Shared by the jobs that create containers to cap how many they create per hour
+    spawn_budget: Option<SpawnBudget>,
+}
+
+impl Scheduler {
+    pub fn new(handle: Docker) -> Self {
+        Scheduler {
+            handle,
+            set: JoinSet::new(),
+            jobs: HashMap::new(),
+            definitions: HashMap::new(),
+            restarts: HashMap::new(),
+            histories: HashMap::new(),
+            states: HashMap::new(),
+            default_state: JobState::Active,
+            retired: HashMap::new(),
+            removal_grace: REMOVAL_GRACE,
+            restart_failed: true,
+            label_cache: LabelCache::default(),
+            stop: watch::channel(false).0,
+            execution_permits: None,
+            group_locks: GroupLocks::default(),
+            clock_jumps: watch::channel(None).0,
+            dry_run: false,
+            spawn_budget: None,
+        }
+    }
+
+    /// The cache of the jobs parsed from container labels, kept across reloads
+    pub fn label_cache(&mut self) -> &mut LabelCache {
+        &mut self.label_cache
+    }
+
+    /// Set whether jobs whose scheduler failed are restarted, which is the default.
+    ///
+    /// Restarts are delayed by an exponential backoff, so that a job that keeps failing
+    /// does not monopolize the daemon.
+    pub fn set_restart_failed(&mut self, restart: bool) {
+        self.restart_failed = restart;
+    }
+
+    /// Set how long the scheduler of a job removed by a reload is kept paused, [`REMOVAL_GRACE`] by default.
+    ///
+    /// A job that is added back within this window, with the same definition, resumes its
+    /// scheduler where it was. With a zero grace, removed jobs are stopped right away.
+    pub fn set_removal_grace(&mut self, grace: Duration) {
+        self.removal_grace = grace;
+    }
+
+    /// Limit the number of executions that run at the same time across the jobs started afterwards
+    pub fn set_execution_permits(&mut self, permits: Option<ExecutionPermits>) {
+        self.execution_permits = permits;
+    }
+
+    /// Set the locks of the jobs' mutual-exclusion groups, shared with the jobs started afterwards
+    pub fn set_group_locks(&mut self, groups: GroupLocks) {
+        self.group_locks = groups;
+    }
+
+    /// Cap the number of containers created per hour by the jobs started afterwards
+    pub fn set_spawn_budget(&mut self, budget: Option<SpawnBudget>) {
+        self.spawn_budget = budget;
+    }
+
+    /// The budget of containers created per hour, if any
+    pub fn spawn_budget(&self) -> Option<&SpawnBudget> {
+        self.spawn_budget.as_ref()
+    }
+
+    /// Set whether the jobs started afterwards only log what they would execute when triggered
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Notify the running jobs that the wall clock jumped, so that they re-arm their timers
+    pub fn clock_jumped(&self, jump: ClockJump) {
+        self.clock_jumps.send_replace(Some(jump));
+    }
+
+    /// Pause or resume the jobs named `name`, returning the number of jobs that changed state
+    pub fn set_job_state(&self, name: &str, state: JobState) -> usize {
+        self.definitions.iter()
+            .filter(|(_, job)| job.name() == name)
+            .filter_map(|(fingerprint, _)| self.states.get(fingerprint))
+            .filter(|sender| sender.send_replace(state) != state)
+            .count()
+    }
+
+    /// Pause or resume all the jobs, including the ones started afterwards.
+    ///
+    /// Returns the number of running jobs that changed state.
+    pub fn set_state(&mut self, state: JobState) -> usize {
+        self.default_state = state;
+        self.retired.values_mut().for_each(|job| job.resumed_state = state);
+        self.states.values().filter(|sender| sender.send_replace(state) != state).count()
+    }
+
+    /// Start scheduling a job, unless it is disabled
+    pub fn start(&mut self, job: JobInfo) {
+        if job.options().disabled {
+            info!("Job {} is disabled and will not be scheduled", job.name());
+            return;
+        }
+        self.spawn(job, Duration::ZERO);
+    }
+
+    /// Start scheduling a job after a delay
+    fn spawn(&mut self, job: JobInfo, delay: Duration) {
+        let fingerprint = job.fingerprint();
+        let name = job.name().clone();
+        let handle = self.handle.clone();
+        let task_fingerprint = fingerprint.clone();
+        let control = JobControl::new(self.stop.subscribe(), self.clock_jumps.subscribe())
+            .with_permits(self.execution_permits.clone())
+            .with_groups(self.group_locks.clone())
+            .with_dry_run(self.dry_run)
+            .with_spawn_budget(self.spawn_budget.clone())
+            .with_history(self.histories.entry(fingerprint.clone()).or_insert_with(|| ExecutionHistory::new(job.options().history_size())).clone())
+            .with_state(self.states.entry(fingerprint.clone()).or_insert_with(|| watch::channel(self.default_state).0).subscribe());
+        self.definitions.entry(fingerprint.clone()).or_insert_with(|| job.clone());
+        let abort_handle = self.set.spawn(async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            let result = AssertUnwindSafe(job.start_until(handle, control)).catch_unwind().await
+                .unwrap_or_else(|p| Err(Error::msg(format!("The job's scheduler panicked: {}", panic_message(p.as_ref())))));
+            (task_fingerprint, result)
+        });
+        self.jobs.entry(fingerprint).or_insert_with(|| (name, vec![])).1.push(abort_handle);
+    }
+
+    /// Replace the running jobs with the provided ones.
+    ///
+    /// Jobs whose definition did not change keep running without interruption,
+    /// jobs that are not part of the new list are stopped and new jobs are started.
+    pub fn reload(&mut self, jobs: Vec<JobInfo>) {
+        let mut new_jobs: HashMap<String, Vec<JobInfo>> = HashMap::new();
+        for job in jobs {
+            if job.options().disabled {
+                debug!("Job {} is disabled and will not be scheduled", job.name());
+                continue;
+            }
+            new_jobs.entry(job.fingerprint()).or_default().push(job);
+        }
+        for fingerprint in new_jobs.keys() {
+            self.resume_retired(fingerprint);
+        }
+        let stale: Vec<String> = self.jobs.keys()
+            .filter(|f| !new_jobs.contains_key(*f))
+            .cloned()
+            .collect();
+        for fingerprint in stale {
+            self.retire(&fingerprint);
+        }
+        for (fingerprint, jobs) in new_jobs {
+            let running = self.jobs.get(&fingerprint).map_or(0, |(_, h)| h.len());
+            for job in jobs.into_iter().skip(running) {
+                info!("Starting job {}", job.name());
+                self.start(job);
+            }
+        }
+    }
+
+    /// Stop a job removed from the configuration, after the removal grace window if there is one
+    fn retire(&mut self, fingerprint: &str) {
+        let definition = self.definitions.remove(fingerprint);
+        let restarts = self.restarts.remove(fingerprint);
+        let history = self.histories.remove(fingerprint);
+        let state = self.states.remove(fingerprint);
+        let Some((name, handles)) = self.jobs.remove(fingerprint) else {
+            return;
+        };
+        let Some(state) = state.filter(|_| !self.removal_grace.is_zero() && !*self.stop.borrow()) else {
+            info!("Stopping job {} as it is not part of the configuration anymore", name);
+            handles.iter().for_each(|h| h.abort());
+            return;
+        };
+        info!("Pausing job {} as it is not part of the configuration anymore, it is stopped in {:?} unless it is added back", name, self.removal_grace);
+        let resumed_state = state.send_replace(JobState::Paused);
+        let deadline = Instant::now() + self.removal_grace;
+        self.retired.insert(fingerprint.to_string(), RetiredJob { name, handles, definition, restarts, history, state, resumed_state, deadline });
+    }
+
+    /// Resume the scheduler of a job that is added back within its removal grace window
+    fn resume_retired(&mut self, fingerprint: &str) {
+        let Some(job) = self.retired.remove(fingerprint) else {
+            return;
+        };
+        info!("Resuming job {} as it was added back, its running executions and schedule are kept", job.name);
+        job.state.send_replace(job.resumed_state);
+        let fingerprint = fingerprint.to_string();
+        if let Some(definition) = job.definition {
+            self.definitions.insert(fingerprint.clone(), definition);
+        }
+        if let Some(restarts) = job.restarts {
+            self.restarts.insert(fingerprint.clone(), restarts);
+        }
+        if let Some(history) = job.history {
+            self.histories.insert(fingerprint.clone(), history);
+        }
+        self.states.insert(fingerprint.clone(), job.state);
+        self.jobs.insert(fingerprint, (job.name, job.handles));
+    }
+
+    /// Stop the removed jobs whose grace window ended
+    fn expire_retired(&mut self, now: Instant) {
+        let expired: Vec<String> = self.retired.iter().filter(|(_, job)| job.deadline <= now).map(|(f, _)| f.clone()).collect();
+        for fingerprint in expired {
+            if let Some(job) = self.retired.remove(&fingerprint) {
+                info!("Stopping job {} as it was not added back to the configuration", job.name);
+                job.handles.iter().for_each(|h| h.abort());
+            }
+        }
+    }
+
+    /// Wait for the next job to end its scheduling and return its name and result.
+    ///
+    /// The removed jobs whose grace window ends in the meantime are stopped.
+    /// Returns `None` once no job is running anymore.
+    pub async fn join_next(&mut self) -> Option<(String, JobResult)> {
+        loop {
+            let expiry = self.retired.values().map(|job| job.deadline).min();
+            let joined = tokio::select! {
+                joined = self.set.join_next() => joined?,
+                _ = sleep_until(expiry.unwrap_or_else(Instant::now)), if expiry.is_some() => {
+                    self.expire_retired(Instant::now());
+                    continue;
+                },
+            };
+            match joined {
+                Ok((fingerprint, result)) => {
+                    let name = match (self.jobs.get_mut(&fingerprint), self.retired.get_mut(&fingerprint)) {
+                        (Some((name, handles)), _) | (None, Some(RetiredJob { name, handles, .. })) => {
+                            let name = name.clone();
+                            handles.retain(|h| !h.is_finished());
+                            name
+                        },
+                        (None, None) => fingerprint.clone(),
+                    };
+                    if self.retired.get(&fingerprint).is_some_and(|job| job.handles.is_empty()) {
+                        self.retired.remove(&fingerprint);
+                    }
+                    match result.as_ref() {
+                        // The daemon is about to stop, restarting the job would trigger it again
+                        Err(e) if e.is::<StopDaemonError>() => {},
+                        Err(e) => self.restart_after_failure(&fingerprint, e),
+                        Ok(_) => {},
+                    }
+                    if self.jobs.get(&fingerprint).is_some_and(|(_, handles)| handles.is_empty()) {
+                        self.jobs.remove(&fingerprint);
+                        self.definitions.remove(&fingerprint);
+                        self.restarts.remove(&fingerprint);
+                        self.histories.remove(&fingerprint);
+                        self.states.remove(&fingerprint);
+                    }
+                    return Some((name, result));
+                },
+                Err(e) if e.is_cancelled() => debug!("A stopped job's scheduler ended"),
+                Err(e) => return Some(("UNKNOWN".to_string(), Err(Error::new(e)))),
+            }
+        }
+    }
+
+    /// Schedule the restart of a job whose scheduler failed, unless restarts are disabled
+    fn restart_after_failure(&mut self, fingerprint: &str, error: &Error) {
+        if !self.restart_failed || *self.stop.borrow() {
+            return;
+        }
+        let Some(job) = self.definitions.get(fingerprint).cloned() else {
+            return;
+        };
+        let restarts = self.restarts.entry(fingerprint.to_string()).or_default();
+        let delay = restart_delay(*restarts);
+        *restarts += 1;
+        warn!("The scheduler of job {} failed ({}), restarting it in {:?} (restart #{})", job.name(), error, delay, restarts);
+        self.spawn(job, delay);
+    }
+
+    /// Whether any job is currently scheduled
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    /// Get the jobs whose scheduling task is running
+    pub fn jobs(&self) -> impl Iterator<Item = &JobInfo> {
+        self.definitions.values()
+    }
+
+    /// Get the jobs whose scheduling task is running along with a read handle to their execution history
+    pub fn histories(&self) -> impl Iterator<Item = (&JobInfo, &ExecutionHistory)> {
+        self.definitions.iter().filter_map(|(fingerprint, job)| Some((job, self.histories.get(fingerprint)?)))
+    }
+
+    /// Get the names of the jobs whose scheduling task is running
+    pub fn running_jobs(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.jobs.values().map(|(name, _)| name.clone()).collect();
+        names.sort();
+        names
+    }
+
+    /// Stop triggering the jobs and wait for their running executions to end.
+    ///
+    /// The jobs that are still running are logged periodically, and aborted after `grace`.
+    pub async fn stop(&mut self, grace: Duration) {
+        self.stop.send_replace(true);
+        let deadline = Instant::now() + grace;
+        loop {
+            let wait = STOP_PROGRESS_INTERVAL.min(deadline.saturating_duration_since(Instant::now()));
+            match timeout(wait, self.join_next()).await {
+                Ok(Some((name, result))) => debug!("The job {} stopped: {:?}", name, result),
+                Ok(None) => break,
+                Err(_) if Instant::now() >= deadline => {
+                    warn!("Aborting the jobs that are still running after {:?}: {:?}", grace, self.running_jobs());
+                    break;
+                },
+                Err(_) => info!(
+                    "Waiting up to {}s for the running executions of jobs {:?} to end",
+                    deadline.saturating_duration_since(Instant::now()).as_secs_f32().round(), self.running_jobs(),
+                ),
+            }
+        }
+        self.shutdown().await;
+    }
+
+    /// Abort all jobs and wait for their termination
+    pub async fn shutdown(&mut self) {
+        self.jobs.clear();
+        self.retired.clear();
+        self.definitions.clear();
+        self.restarts.clear();
+        self.histories.clear();
+        self.set.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bollard::Docker;
+    use tokio::time::{sleep, timeout, Duration, Instant};
+
+    use crate::job::{JobInfo, JobState};
+
+    use super::{restart_delay, Scheduler, SpawnBudget, SPAWN_WINDOW};
+
+    #[tokio::test(start_paused = true)]
+    async fn spawn_budget_window() {
+        let budget = SpawnBudget::new(3.try_into().unwrap());
+        let start = Instant::now();
+        assert!(budget.try_spawn("a", start));
+        sleep(Duration::from_secs(600)).await;
+        assert!(budget.try_spawn("a", Instant::now()));
+        assert!(budget.try_spawn("b", Instant::now()));
+        assert!(!budget.try_spawn("a", Instant::now()));
+        assert_eq!((budget.used(Instant::now()), budget.skipped()), (3, 1));
+
+        // The first spawn leaves the window exactly one hour after it happened
+        sleep(SPAWN_WINDOW - Duration::from_secs(600) - Duration::from_millis(1)).await;
+        assert!(!budget.try_spawn("a", Instant::now()));
+        sleep(Duration::from_millis(1)).await;
+        assert_eq!(budget.used(Instant::now()), 2);
+        assert!(budget.try_spawn("a", Instant::now()));
+        assert!(!budget.try_spawn("b", Instant::now()));
+        assert_eq!(budget.skipped(), 3);
+
+        // The budget is fully available again once a whole window elapsed
+        sleep(SPAWN_WINDOW).await;
+        assert_eq!(budget.used(Instant::now()), 0);
+        assert!((0..3).all(|_| budget.try_spawn("a", Instant::now())));
+        assert_eq!(Instant::now() - start, SPAWN_WINDOW * 2);
+    }
+
+    fn local_job(name: &str, schedule: &str) -> JobInfo {
+        local_job_with(name, schedule, "true", false)
+    }
+
+    fn local_job_with(name: &str, schedule: &str, command: &str, run_on_start: bool) -> JobInfo {
+        JobInfo::try_from(HashMap::from([
+            ("kind".to_string(), vec!["job-local".to_string()]),
+            ("name".to_string(), vec![name.to_string()]),
+            ("command".to_string(), vec![command.to_string()]),
+            ("schedule".to_string(), vec![schedule.to_string()]),
+            ("run-on-start".to_string(), vec![run_on_start.to_string()]),
+        ])).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_unchanged_jobs() {
+        let mut scheduler = Scheduler::new(Docker::connect_with_http_defaults().unwrap());
+        scheduler.start(local_job("kept", "@hourly"));
+        scheduler.start(local_job("changed", "@hourly"));
+
+        scheduler.reload(vec![local_job("kept", "@hourly"), local_job("changed", "@daily"), local_job("added", "@daily")]);
+        assert_eq!(scheduler.jobs.len(), 3);
+        // The stopped job's task remains in the set until it is joined, the kept job was not restarted
+        assert_eq!(scheduler.set.len(), 4);
+        assert!(!scheduler.jobs.contains_key(&local_job("changed", "@hourly").fingerprint()));
+        scheduler.shutdown().await;
+        assert!(scheduler.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disabled_jobs_are_not_scheduled() {
+        let disabled = |name: &str| {
+            let mut parameters: HashMap<String, Vec<String>> = local_job(name, "@hourly").to_parameters().into_iter().collect();
+            parameters.insert("kind".to_string(), vec!["job-local".to_string()]);
+            parameters.insert("enabled".to_string(), vec!["false".to_string()]);
+            JobInfo::try_from(parameters).unwrap()
+        };
+        let mut scheduler = Scheduler::new(Docker::connect_with_http_defaults().unwrap());
+        scheduler.start(disabled("a"));
+        scheduler.start(local_job("b", "@hourly"));
+        assert_eq!(scheduler.jobs.len(), 1);
+        // Disabling a running job stops it on reload
+        scheduler.reload(vec![disabled("a"), disabled("b")]);
+        assert!(scheduler.jobs.is_empty());
+        scheduler.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_jobs() {
+        let mut scheduler = Scheduler::new(Docker::connect_with_http_defaults().unwrap());
+        scheduler.start(local_job("cleanup", "@hourly"));
+        scheduler.start(local_job("backup", "@hourly"));
+        assert_eq!(scheduler.set_job_state("cleanup", JobState::Paused), 1);
+        assert_eq!(scheduler.set_job_state("cleanup", JobState::Paused), 0);
+        assert_eq!(scheduler.set_state(JobState::Paused), 1);
+        // Jobs started while all jobs are paused start paused
+        scheduler.reload(vec![local_job("cleanup", "@hourly"), local_job("backup", "@hourly"), local_job("added", "@daily")]);
+        assert_eq!(*scheduler.states[&local_job("added", "@daily").fingerprint()].borrow(), JobState::Paused);
+        assert_eq!(scheduler.set_state(JobState::Active), 3);
+        assert_eq!(scheduler.set_job_state("unknown", JobState::Paused), 0);
+        scheduler.shutdown().await;
+    }
+
+    /// Replace the scheduling task of a job with one that fails right away
+    fn fail_scheduler(scheduler: &mut Scheduler, job: &JobInfo) {
+        let fingerprint = job.fingerprint();
+        let (_, handles) = scheduler.jobs.get_mut(&fingerprint).unwrap();
+        handles.drain(..).for_each(|h| h.abort());
+        let task_fingerprint = fingerprint.clone();
+        handles.push(scheduler.set.spawn(async move { (task_fingerprint, Err(anyhow::Error::msg("boom"))) }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restart_failed_schedulers() {
+        let mut scheduler = Scheduler::new(Docker::connect_with_http_defaults().unwrap());
+        let job = local_job("flaky", "@yearly");
+        scheduler.start(job.clone());
+        for restarts in 1..=3 {
+            fail_scheduler(&mut scheduler, &job);
+            let (name, result) = scheduler.join_next().await.unwrap();
+            assert_eq!(name, "flaky");
+            assert!(result.is_err());
+            // The job is restarted after a growing delay
+            assert_eq!(scheduler.running_jobs(), vec!["flaky"]);
+            assert_eq!(scheduler.restarts[&job.fingerprint()], restarts);
+        }
+        assert_eq!([0, 1, 2, 20].map(restart_delay), [1, 2, 4, 300].map(Duration::from_secs));
+        scheduler.shutdown().await;
+
+        // Without restarts, the failed job is forgotten
+        let mut scheduler = Scheduler::new(Docker::connect_with_http_defaults().unwrap());
+        scheduler.set_restart_failed(false);
+        scheduler.start(job.clone());
+        fail_scheduler(&mut scheduler, &job);
+        assert!(scheduler.join_next().await.unwrap().1.is_err());
+        assert!(scheduler.running_jobs().is_empty());
+        assert!(scheduler.join_next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn removed_jobs_added_back_keep_running() {
+        let trace = std::env::temp_dir().join(format!("cfc-readd-{}", std::process::id()));
+        let _ = std::fs::remove_file(&trace);
+        let job = local_job_with("flapping", "@yearly", &format!("sh -c 'echo start >> {0}; sleep 0.5; echo end >> {0}'", trace.display()), true);
+        let mut scheduler = Scheduler::new(Docker::connect_with_http_defaults().unwrap());
+        scheduler.start(job.clone());
+        sleep(Duration::from_millis(100)).await;
+
+        // The job is removed and added back while its run-on-start execution is running
+        scheduler.reload(vec![]);
+        assert!(scheduler.running_jobs().is_empty());
+        assert_eq!(*scheduler.retired[&job.fingerprint()].state.borrow(), JobState::Paused);
+        scheduler.reload(vec![job.clone()]);
+        assert_eq!(scheduler.running_jobs(), vec!["flapping"]);
+        assert_eq!(*scheduler.states[&job.fingerprint()].borrow(), JobState::Active);
+        sleep(Duration::from_millis(800)).await;
+        // The execution was neither aborted nor triggered again, and is recorded in the kept history
+        assert_eq!(std::fs::read_to_string(&trace).unwrap(), "start\nend\n");
+        assert_eq!(scheduler.histories[&job.fingerprint()].entries().len(), 1);
+        assert_eq!(scheduler.set.len(), 1);
+        scheduler.shutdown().await;
+        std::fs::remove_file(&trace).unwrap();
+    }
+
+    #[tokio::test]
+    async fn removed_jobs_are_stopped_after_their_grace() {
+        let trace = std::env::temp_dir().join(format!("cfc-expire-{}", std::process::id()));
+        let _ = std::fs::remove_file(&trace);
+        let job = local_job_with("removed", "@yearly", &format!("sh -c 'echo run >> {}'", trace.display()), true);
+        let mut scheduler = Scheduler::new(Docker::connect_with_http_defaults().unwrap());
+        scheduler.set_removal_grace(Duration::from_millis(200));
+        scheduler.start(job.clone());
+        sleep(Duration::from_millis(100)).await;
+
+        scheduler.reload(vec![]);
+        let started = Instant::now();
+        assert!(timeout(Duration::from_secs(5), scheduler.join_next()).await.unwrap().is_none());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(scheduler.retired.is_empty() && scheduler.is_empty());
+        // Once stopped, the job added back starts afresh
+        scheduler.reload(vec![job]);
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(std::fs::read_to_string(&trace).unwrap(), "run\nrun\n");
+        scheduler.shutdown().await;
+        std::fs::remove_file(&trace).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_waits_for_running_executions() {
+        let marker = std::env::temp_dir().join(format!("cfc-stop-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let mut scheduler = Scheduler::new(Docker::connect_with_http_defaults().unwrap());
+        scheduler.start(local_job_with("slow", "@yearly", &format!("sh -c 'sleep 0.3; touch {}'", marker.display()), true));
+        scheduler.start(local_job("idle", "@yearly"));
+        sleep(Duration::from_millis(100)).await;
+
+        let started = Instant::now();
+        scheduler.stop(Duration::from_secs(5)).await;
+        assert!(marker.exists(), "the running execution was aborted");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(scheduler.is_empty());
+        std::fs::remove_file(&marker).unwrap();
+
+        // Executions that exceed the grace period are aborted
+        let mut scheduler = Scheduler::new(Docker::connect_with_http_defaults().unwrap());
+        scheduler.start(local_job_with("stuck", "@yearly", "sleep 10", true));
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(scheduler.running_jobs(), vec!["stuck"]);
+        let started = Instant::now();
+        scheduler.stop(Duration::from_millis(200)).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(scheduler.is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+//! Notification of failed job executions to a Slack incoming webhook
+//!
+//! Repeated failures of a job are collapsed: once a failure was notified, the next ones
+//! within the collapse window are only counted and mentioned in the next notification.
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+
+use anyhow::Error;
+
+use crate::{report::{tail, JobRunReport}, webhook::WebhookUrl};
+
+/// The default delay during which the repeated failures of a job are collapsed
+pub const DEFAULT_COLLAPSE_WINDOW: Duration = Duration::from_secs(600);
+
+/// The number of bytes of stderr included in a notification
+const STDERR_TAIL_LENGTH: usize = 1024;
+
+/// Decides which failures of each job are notified
+#[derive(Debug)]
+pub struct FailureThrottle {
+    window: Duration,
+    /// The time of the last notification of each job and the number of failures collapsed since
+    jobs: HashMap<String, (Instant, u64)>,
+}
+
+impl FailureThrottle {
+    pub fn new(window: Duration) -> Self {
+        FailureThrottle { window, jobs: HashMap::new() }
+    }
+
+    /// Record a failure of a job, return the number of failures collapsed since the last
+    /// notification if this one should be notified
+    pub fn record(&mut self, job: &str, now: Instant) -> Option<u64> {
+        match self.jobs.get_mut(job) {
+            Some((last, collapsed)) if now.saturating_duration_since(*last) < self.window => {
+                *collapsed += 1;
+                None
+            },
+            Some((last, collapsed)) => {
+                *last = now;
+                Some(std::mem::take(collapsed))
+            },
+            None => {
+                self.jobs.insert(job.to_string(), (now, 0));
+                Some(0)
+            },
+        }
+    }
+}
+
+/// Escape the characters that Slack interprets in message texts
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Format the Slack message describing a failed execution
+pub fn format_failure(report: &JobRunReport, collapsed: u64) -> String {
+    let name = escape(&report.name);
+    let mut text = match (report.report.as_ref(), report.error.as_ref()) {
+        (Some(r), _) if r.timed_out => format!(":x: Job *{}* timed out after {:.1} seconds", name, report.duration_seconds),
+        (Some(r), _) => format!(":x: Job *{}* failed with exit code {}", name, r.retval),
+        (None, Some(e)) => format!(":x: Job *{}* could not be run: {}", name, escape(e)),
+        (None, None) => format!(":x: Job *{}* failed", name),
+    };
+    if collapsed > 0 {
+        text.push_str(&format!(" ({} more failures since the last notification)", collapsed));
+    }
+    let mut stderr = report.report.as_ref().and_then(|r| r.stderr.clone());
+    tail(&mut stderr, STDERR_TAIL_LENGTH);
+    if let Some(stderr) = stderr.filter(|s| !s.trim().is_empty()) {
+        text.push_str(&format!("\n```{}```", escape(stderr.trim_end()).replace("```", "'''")));
+    }
+    text
+}
+
+struct Slack {
+    // Only read when notifications can be delivered
+    #[cfg_attr(not(feature = "webhook"), allow(dead_code))]
+    url: WebhookUrl,
+    throttle: FailureThrottle,
+}
+
+static SLACK: Mutex<Option<Slack>> = Mutex::new(None);
+
+/// Set the incoming webhook that failures are notified to, or stop notifying them.
+///
+/// The collapsed failures are kept when the window does not change.
+pub fn configure(url: Option<WebhookUrl>, window: Duration) -> Result<(), Error> {
+    if url.is_some() && !cfg!(feature = "webhook") {
+        return Err(Error::msg("Slack notifications require cfc to be built with the webhook feature"));
+    }
+    let mut slack = SLACK.lock().unwrap_or_else(|e| e.into_inner());
+    *slack = match (url, slack.take()) {
+        (Some(url), Some(current)) if current.throttle.window == window => Some(Slack { url, throttle: current.throttle }),
+        (Some(url), _) => Some(Slack { url, throttle: FailureThrottle::new(window) }),
+        (None, _) => None,
+    };
+    Ok(())
+}
+
+/// Notify the failure of an execution in the background, unless it is collapsed
+#[cfg(feature = "webhook")]
+pub fn notify_failure(report: JobRunReport) {
+    use tracing::{debug, warn};
+
+    let (url, collapsed) = {
+        let mut slack = SLACK.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(slack) = slack.as_mut() else { return };
+        match slack.throttle.record(&report.name, Instant::now()) {
+            Some(collapsed) => (slack.url.clone(), collapsed),
+            None => return debug!("Not notifying the failure of job {} to Slack as it failed recently", report.name),
+        }
+    };
+    let body = serde_json::json!({ "text": format_failure(&report, collapsed) }).to_string();
+    crate::webhook::deliver(url, body, move |_, result| match result {
+        Ok(status) if (200..300).contains(&status) => debug!("Notified the failure of job {} to Slack", report.name),
+        Ok(status) => warn!("The Slack notification of job {} was refused with status {}", report.name, status),
+        Err(e) => warn!("Failed to notify the failure of job {} to Slack: {}", report.name, e),
+    });
+}
+
+/// Notify a message that is not tied to an execution in the background, it is never collapsed
+#[cfg(feature = "webhook")]
+pub fn notify_message(text: String) {
+    use tracing::{debug, warn};
+
+    let Some(url) = SLACK.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|s| s.url.clone()) else { return };
+    let body = serde_json::json!({ "text": text }).to_string();
+    crate::webhook::deliver(url, body, move |_, result| match result {
+        Ok(status) if (200..300).contains(&status) => debug!("Notified a message to Slack"),
+        Ok(status) => warn!("The Slack notification was refused with status {}", status),
+        Err(e) => warn!("Failed to notify a message to Slack: {}", e),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{job::ExecutionReport, report::JobRunReport, metrics::RunResult};
+
+    use super::{format_failure, FailureThrottle};
+
+    #[test]
+    fn collapse_repeated_failures() {
+        let mut throttle = FailureThrottle::new(Duration::from_secs(600));
+        let start = Instant::now();
+        assert_eq!(throttle.record("a", start), Some(0));
+        assert_eq!(throttle.record("a", start + Duration::from_secs(60)), None);
+        assert_eq!(throttle.record("b", start + Duration::from_secs(60)), Some(0));
+        assert_eq!(throttle.record("a", start + Duration::from_secs(120)), None);
+        assert_eq!(throttle.record("a", start + Duration::from_secs(600)), Some(2));
+        assert_eq!(throttle.record("a", start + Duration::from_secs(1199)), None);
+    }
+
+    #[test]
+    fn format_failure_messages() {
+        let report = |report: Option<ExecutionReport>, error: Option<&str>| JobRunReport {
+            name: "<backup>".to_string(),
+            kind: "job-exec".to_string(),
+            result: RunResult::Failure,
+            duration_seconds: 2.5,
+            report,
+            truncated: false,
+            error: error.map(|e| e.to_string()),
+            error_category: None,
+            config_checksum: None,
+            annotations: Default::default(),
+        };
+        let failed = ExecutionReport { retval: 3, stderr: Some(format!("{}disk full\n", "x".repeat(2000))), ..Default::default() };
+        let text = format_failure(&report(Some(failed), None), 0);
+        assert!(text.starts_with(":x: Job *&lt;backup&gt;* failed with exit code 3\n```"), "{}", text);
+        assert!(text.ends_with("disk full```"), "{}", text);
+        assert!(text.len() < 1100);
+        assert_eq!(format_failure(&report(Some(ExecutionReport::timeout()), None), 4), ":x: Job *&lt;backup&gt;* timed out after 2.5 seconds (4 more failures since the last notification)");
+        assert_eq!(format_failure(&report(None, Some("No such container")), 0), ":x: Job *&lt;backup&gt;* could not be run: No such container");
+    }
+}
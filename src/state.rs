@@ -0,0 +1,280 @@
+//! Publication of the last run of each job in a container volume
+//!
+//! Dashboards that already read the container engine's metadata can not read the labels of the
+//! jobs' last runs, as labels can not change once a container is created. Instead, the last run
+//! of each job is written as a small JSON file to a named volume (`cfc.state` by default) that
+//! other containers may mount read-only.
+//!
+//! The container engine can not write to a volume directly: the files are uploaded to a helper
+//! container that mounts the volume and is never started.
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use anyhow::{Error, Result};
+use bollard::{container::{Config, CreateContainerOptions, UploadToContainerOptions}, models::HostConfig, volume::CreateVolumeOptions, Docker};
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::{job::{prepare_image, ExecInfo, PullPolicy}, metrics::RunResult, utils::sha256_hex};
+
+/// The volume the last runs are written to by default
+pub const DEFAULT_STATE_VOLUME: &str = "cfc.state";
+
+/// The image of the helper container, only used to mount the volume
+pub const DEFAULT_STATE_IMAGE: &str = "busybox:latest";
+
+/// Where the volume is mounted in the helper container
+const STATE_MOUNT: &str = "/state";
+
+/// The file written to check that the volume can be written
+const PROBE_FILE: &str = ".cfc-writable";
+
+/// The last run of a job, as written to its file
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LastRun {
+    pub name: String,
+    pub kind: String,
+    pub result: RunResult,
+    /// The exit code of the run, `None` if the job could not be run
+    pub exit_code: Option<i64>,
+    /// The reason why the job could not be run
+    pub error: Option<String>,
+    pub scheduled_at: Option<String>,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub duration_seconds: f64,
+    /// The number of runs of the job that failed in a row, this one included
+    pub consecutive_failures: u64,
+}
+
+impl LastRun {
+    /// Describe the result of a run, or return `None` if nothing was executed (e.g. a skipped run)
+    pub fn of_execution(name: &str, kind: &str, result: &Result<ExecInfo>, duration: Duration) -> Option<Self> {
+        let report = match result {
+            Ok(ExecInfo::Report(report)) => Some(report),
+            Ok(_) => return None,
+            Err(_) => None,
+        };
+        Some(LastRun {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            result: RunResult::of(result),
+            exit_code: report.map(|r| r.retval),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            scheduled_at: report.and_then(|r| r.scheduled_at).map(|d| d.to_rfc3339()),
+            started_at: report.and_then(|r| r.started_at).map(|d| d.to_rfc3339()),
+            finished_at: report.and_then(|r| r.finished_at).map(|d| d.to_rfc3339()),
+            duration_seconds: duration.as_secs_f64(),
+            consecutive_failures: report.map_or(0, |r| r.consecutive_failures),
+        })
+    }
+}
+
+/// Get the name of the file holding the last run of a job, the characters that are not
+/// allowed in a file name are replaced with `_`
+///
+/// As different job names may be sanitized to the same name (e.g. `a/b` and `a_b`), it ends with
+/// the first characters of the SHA-256 of the job's name.
+pub fn state_file_name(job: &str) -> String {
+    let name: String = job.chars().map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' }).collect();
+    format!("{}-{}.json", name.trim_start_matches('.'), &sha256_hex(job.as_bytes())[..8])
+}
+
+/// A place where the files of the last runs are written
+pub trait StateStore: Send + Sync {
+    /// Create the store if needed and check that it can be written
+    fn ensure(&self) -> BoxFuture<'_, Result<()>>;
+
+    /// Create or replace a file of the store
+    fn write(&self, file: String, content: Vec<u8>) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Writes the files to a named volume of the container engine
+pub struct VolumeStateStore {
+    handle: Docker,
+    volume: String,
+    /// The image of the helper container
+    image: String,
+}
+
+impl VolumeStateStore {
+    pub fn new(handle: Docker, volume: &str, image: &str) -> Self {
+        VolumeStateStore { handle, volume: volume.to_string(), image: image.to_string() }
+    }
+
+    /// The name of the helper container that mounts the volume
+    pub fn helper(&self) -> String {
+        format!("{}.writer", self.volume)
+    }
+
+    /// Create the helper container, unless it already exists
+    async fn ensure_helper(&self) -> Result<()> {
+        match self.handle.inspect_container(&self.helper(), None).await {
+            Ok(_) => return Ok(()),
+            Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => {},
+            Err(e) => return Err(Error::msg(format!("Failed to inspect the container {}: {}", self.helper(), e))),
+        }
+        let owner = format!("the state volume {}", self.volume);
+        prepare_image(&self.handle, &self.image, PullPolicy::Missing, &owner).await?;
+        let config = Config {
+            image: Some(self.image.clone()),
+            cmd: Some(vec!["true".to_string()]),
+            labels: Some(HashMap::from([("cfc.state-writer".to_string(), self.volume.clone())])),
+            host_config: Some(HostConfig { binds: Some(vec![format!("{}:{}", self.volume, STATE_MOUNT)]), ..Default::default() }),
+            ..Default::default()
+        };
+        let options = CreateContainerOptions { name: self.helper(), platform: None };
+        self.handle.create_container(Some(options), config).await
+            .map_err(|e| Error::msg(format!("Failed to create the container {} that writes to {}: {}", self.helper(), owner, e)))?;
+        info!("Created the container {} to write the last runs of the jobs to the volume {}", self.helper(), self.volume);
+        Ok(())
+    }
+}
+
+/// Pack a file in a tar archive, as expected by the container engine's archive endpoint
+fn archive(file: &str, content: &[u8]) -> Result<Vec<u8>> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp().try_into().unwrap_or_default());
+    let mut builder = tar::Builder::new(vec![]);
+    builder.append_data(&mut header, file, content)?;
+    Ok(builder.into_inner()?)
+}
+
+impl StateStore for VolumeStateStore {
+    fn ensure(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let options = CreateVolumeOptions {
+                name: self.volume.clone(),
+                labels: HashMap::from([("cfc.state".to_string(), "true".to_string())]),
+                ..Default::default()
+            };
+            // The volume is returned as is if it already exists
+            self.handle.create_volume(options).await
+                .map_err(|e| Error::msg(format!("Failed to create the state volume {}: {}", self.volume, e)))?;
+            self.ensure_helper().await?;
+            self.write(PROBE_FILE.to_string(), vec![]).await
+                .map_err(|e| Error::msg(format!("The state volume {} can not be written: {}", self.volume, e)))
+        })
+    }
+
+    fn write(&self, file: String, content: Vec<u8>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let options = UploadToContainerOptions { path: STATE_MOUNT.to_string(), ..Default::default() };
+            self.handle.upload_to_container(&self.helper(), Some(options), archive(&file, &content)?.into()).await
+                .map_err(|e| Error::msg(format!("Failed to write {} to the volume {}: {}", file, self.volume, e)))
+        })
+    }
+}
+
+static STORE: Mutex<Option<Arc<dyn StateStore>>> = Mutex::new(None);
+
+/// Ensure a store and publish the last runs of the jobs to it, or stop publishing them
+pub async fn configure(store: Option<Arc<dyn StateStore>>) -> Result<()> {
+    if let Some(store) = &store {
+        store.ensure().await?;
+    }
+    *STORE.lock().unwrap_or_else(|e| e.into_inner()) = store;
+    Ok(())
+}
+
+/// Write the last run of a job to the configured store in the background
+///
+/// Only the jobs discovered from the labels of a container are published, the jobs of the
+/// configuration files are left out.
+pub fn publish(name: &str, kind: &str, from_labels: bool, result: &Result<ExecInfo>, duration: Duration) {
+    let Some(store) = STORE.lock().unwrap_or_else(|e| e.into_inner()).clone() else { return };
+    publish_to(store, name, kind, from_labels, result, duration);
+}
+
+/// Write the last run of a job to a store in the background, see [`publish`]
+fn publish_to(store: Arc<dyn StateStore>, name: &str, kind: &str, from_labels: bool, result: &Result<ExecInfo>, duration: Duration) -> Option<JoinHandle<()>> {
+    if !from_labels {
+        return None;
+    }
+    let run = LastRun::of_execution(name, kind, result, duration)?;
+    Some(tokio::spawn(async move {
+        let content = match serde_json::to_vec_pretty(&run) {
+            Ok(content) => content,
+            Err(e) => return warn!("Failed to serialize the last run of job {}: {}", run.name, e),
+        };
+        match store.write(state_file_name(&run.name), content).await {
+            Ok(()) => debug!("Published the last run of job {}", run.name),
+            Err(e) => warn!("Failed to publish the last run of job {}: {}", run.name, e),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{Arc, Mutex}, time::Duration};
+
+    use anyhow::{Error, Result};
+    use futures_util::future::BoxFuture;
+
+    use crate::{job::{ExecInfo, ExecutionReport}, utils::sha256_hex};
+
+    use super::{archive, state_file_name, StateStore};
+
+    /// Records the calls it receives instead of writing anything
+    #[derive(Default)]
+    struct RecordingStore {
+        calls: Mutex<Vec<(String, Option<String>)>>,
+    }
+
+    impl StateStore for RecordingStore {
+        fn ensure(&self) -> BoxFuture<'_, Result<()>> {
+            self.calls.lock().unwrap().push(("ensure".to_string(), None));
+            Box::pin(async { Ok(()) })
+        }
+
+        fn write(&self, file: String, content: Vec<u8>) -> BoxFuture<'_, Result<()>> {
+            self.calls.lock().unwrap().push((file, Some(String::from_utf8(content).unwrap())));
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_last_runs() {
+        let store = Arc::new(RecordingStore::default());
+        let publish = |name: &str, from_labels: bool, result: &Result<ExecInfo>, duration: Duration| {
+            super::publish_to(store.clone(), name, "job-exec", from_labels, result, duration)
+        };
+        let report = ExecutionReport { retval: 3, consecutive_failures: 2, ..Default::default() };
+        let written = [
+            publish("state/backup", true, &Ok(ExecInfo::Report(report.clone())), Duration::from_millis(1500)),
+            publish("state/broken", true, &Err(Error::msg("No such container")), Duration::ZERO),
+        ];
+        for handle in written {
+            handle.unwrap().await.unwrap();
+        }
+        assert!(publish("state/skipped", true, &Ok(ExecInfo::Skipped), Duration::ZERO).is_none());
+        // The jobs of the configuration files are not published
+        assert!(publish("state/file", false, &Ok(ExecInfo::Report(report)), Duration::ZERO).is_none());
+
+        let calls = store.calls.lock().unwrap().clone();
+        assert_eq!(calls.len(), 2, "{:?}", calls);
+        let written = |job: &str| calls.iter().find(|(f, _)| *f == state_file_name(job)).and_then(|(_, c)| c.clone()).unwrap();
+        let backup: serde_json::Value = serde_json::from_str(&written("state/backup")).unwrap();
+        assert_eq!(backup["result"], "failure");
+        assert_eq!(backup["exit_code"], 3);
+        assert_eq!(backup["consecutive_failures"], 2);
+        assert_eq!(backup["duration_seconds"], 1.5);
+        let broken: serde_json::Value = serde_json::from_str(&written("state/broken")).unwrap();
+        assert_eq!((&broken["result"], &broken["error"]), (&"error".into(), &"No such container".into()));
+    }
+
+    #[test]
+    fn name_state_files() {
+        assert_eq!(state_file_name("db.backup"), format!("db.backup-{}.json", &sha256_hex(b"db.backup")[..8]));
+        assert!(state_file_name("../etc/passwd").starts_with("_etc_passwd-"));
+        // Names that are sanitized the same way still get different files
+        assert_ne!(state_file_name("a/b"), state_file_name("a_b"));
+        let archive = archive("a.json", b"{}").unwrap();
+        let mut entries = tar::Archive::new(archive.as_slice());
+        let entry = entries.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!((entry.path().unwrap().to_str().unwrap(), entry.size()), ("a.json", 2));
+    }
+}
@@ -1,3 +1,7 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::{Debug, Formatter}};
+
+use sha2::{Digest, Sha256};
+
 /// Check whether the current application is running
 /// from within a container
 /// 
@@ -12,3 +16,94 @@
 pub fn is_docker_env() -> bool {
     std::fs::metadata("/.dockerenv").is_ok()
 }
+
+/// The number of bytes of a value that are kept when it is elided in logs
+pub const ELIDED_LENGTH: usize = 256;
+
+/// Wraps a value so that its Debug output elides the strings longer than [`ELIDED_LENGTH`].
+///
+/// Used to log job parameter maps, whose values may be arbitrarily large.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::collections::HashMap;
+/// use cfc::utils::Elided;
+/// let map = HashMap::from([("command".to_string(), vec!["x".repeat(10_000)])]);
+/// assert!(format!("{:?}", Elided(&map)).len() < 400);
+/// ```
+pub struct Elided<'a, T: ?Sized>(pub &'a T);
+
+impl Debug for Elided<'_, str> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.0.len() <= ELIDED_LENGTH {
+            return write!(f, "{:?}", self.0);
+        }
+        let mut end = ELIDED_LENGTH;
+        while !self.0.is_char_boundary(end) {
+            end -= 1;
+        }
+        write!(f, "{:?}... ({} bytes elided)", &self.0[..end], self.0.len() - end)
+    }
+}
+
+impl Debug for Elided<'_, String> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Elided(self.0.as_str()).fmt(f)
+    }
+}
+
+impl<T> Debug for Elided<'_, Vec<T>> where for<'b> Elided<'b, T>: Debug {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.0.iter().map(Elided)).finish()
+    }
+}
+
+impl<K: Debug, V> Debug for Elided<'_, HashMap<K, V>> where for<'b> Elided<'b, V>: Debug {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.0.iter().map(|(k, v)| (k, Elided(v)))).finish()
+    }
+}
+
+impl<K: Debug, V> Debug for Elided<'_, BTreeMap<K, V>> where for<'b> Elided<'b, V>: Debug {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.0.iter().map(|(k, v)| (k, Elided(v)))).finish()
+    }
+}
+
+/// Compute the SHA-256 digest of some data, formatted as lowercase hexadecimal
+///
+/// # Examples
+///
+/// ```rust
+/// use cfc::utils::sha256_hex;
+/// assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+/// ```
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{sha256_hex, Elided, ELIDED_LENGTH};
+
+    #[test]
+    fn sha256_digests() {
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(sha256_hex(&[b'a'; 1000]), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+    }
+
+    #[test]
+    fn elide_long_values() {
+        assert_eq!(format!("{:?}", Elided("short")), "\"short\"");
+        let long = "é".repeat(ELIDED_LENGTH);
+        let elided = format!("{:?}", Elided(long.as_str()));
+        assert!(elided.ends_with(&format!("... ({} bytes elided)", ELIDED_LENGTH)), "{}", elided);
+        let map = BTreeMap::from([("a", vec!["b".to_string(), "c".repeat(1 << 20)])]);
+        let elided = format!("{:?}", Elided(&map));
+        assert!(elided.starts_with("{\"a\": [\"b\", \"ccc") && elided.len() < 2 * ELIDED_LENGTH, "{}", elided);
+    }
+}
@@ -0,0 +1,49 @@
+//! Startup of the daemon on configurations that can not be loaded.
+#![cfg(all(feature = "bin", feature = "ini"))]
+use std::process::{Command, Output};
+
+const JOB: &str = "[job-local \"a\"]\nschedule = @hourly\ncommand = true\n";
+
+/// Start the daemon on a configuration directory holding the given files
+fn start_daemon(name: &str, files: &[(&str, &str)], args: &[&str]) -> Output {
+    let dir = std::env::temp_dir().join(format!("cfc-daemon-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (file, content) in files {
+        std::fs::write(dir.join(file), content).unwrap();
+    }
+    let output = Command::new(env!("CARGO_BIN_EXE_cfc"))
+        .args(["--config", dir.to_str().unwrap(), "daemon"])
+        .args(args)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    output
+}
+
+#[test]
+fn reject_configuration_over_limits() {
+    let output = start_daemon("limits", &[("jobs.ini", JOB)], &["--max-config-size", "8"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("exceeds the max-input-size limit"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn reject_duplicate_job_names() {
+    let output = start_daemon("duplicates", &[("first.ini", JOB), ("second.ini", JOB)], &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("Job 'a' is declared more than once"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn reject_newer_configuration() {
+    let config = format!("[global]\nmin-cfc-version = 999.0\n\n{}", JOB);
+    let output = start_daemon("version", &[("jobs.ini", &config)], &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("The configuration requires cfc 999.0.0 or later"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
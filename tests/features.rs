@@ -0,0 +1,84 @@
+//! Behavior of the loaders under each combination of the `ini`, `yaml` and `labels` features.
+//!
+//! Run with e.g. `cargo test --no-default-features --features ini --test features`
+//! to check a single combination.
+use cfc::{context::ApplicationContext, loader::{cache::LabelCache, load_file_content, load_labels, ConfigFormat, ParserLimits}};
+
+const INI: &str = "[job-local \"a\"]\nschedule = @hourly\ncommand = true\n";
+const YAML: &str = "a:\n  kind: job-local\n  schedule: '@hourly'\n  command: 'true'\n";
+
+fn load(content: &str, ext: &str) -> anyhow::Result<ConfigFormat> {
+    load_file_content(content, ext, &ParserLimits::default()).map(|(format, _)| format)
+}
+
+#[test]
+fn enabled_formats() {
+    assert_eq!(ConfigFormat::ENABLED.contains(&ConfigFormat::Ini), cfg!(feature = "ini"));
+    assert_eq!(ConfigFormat::ENABLED.contains(&ConfigFormat::Yaml), cfg!(feature = "yaml"));
+}
+
+#[cfg(all(feature = "ini", feature = "yaml"))]
+#[test]
+fn extension_matched_parser_is_preferred() {
+    assert_eq!(load(INI, "ini").unwrap(), ConfigFormat::Ini);
+    assert_eq!(load(YAML, "yaml").unwrap(), ConfigFormat::Yaml);
+    assert_eq!(load(YAML, "yml").unwrap(), ConfigFormat::Yaml);
+    // A matched extension does not fall back to the other format
+    assert!(load(YAML, "ini").is_err());
+    assert!(load(INI, "yaml").is_err());
+    // Unknown extensions are tried with every format
+    assert_eq!(load(INI, "conf").unwrap(), ConfigFormat::Ini);
+    assert_eq!(load(YAML, "conf").unwrap(), ConfigFormat::Yaml);
+    let e = load("[not closed\n", "conf").unwrap_err().to_string();
+    assert!(e.contains("as INI") && e.contains("nor as YAML"), "{}", e);
+}
+
+#[cfg(all(feature = "ini", not(feature = "yaml")))]
+#[test]
+fn ini_only_rejects_yaml() {
+    assert_eq!(load(INI, "ini").unwrap(), ConfigFormat::Ini);
+    assert_eq!(load(INI, "conf").unwrap(), ConfigFormat::Ini);
+    let e = load(YAML, "yaml").unwrap_err().to_string();
+    assert_eq!(e, "YAML files are not supported by this build, the yaml feature is disabled");
+}
+
+#[cfg(all(feature = "yaml", not(feature = "ini")))]
+#[test]
+fn yaml_only_falls_back_to_yaml() {
+    assert_eq!(load(YAML, "yaml").unwrap(), ConfigFormat::Yaml);
+    assert_eq!(load(YAML, "conf").unwrap(), ConfigFormat::Yaml);
+    assert!(load(INI, "conf").is_err());
+    let e = load(INI, "ini").unwrap_err().to_string();
+    assert_eq!(e, "INI files are not supported by this build, the ini feature is disabled");
+}
+
+#[cfg(not(any(feature = "ini", feature = "yaml")))]
+#[test]
+fn no_file_format() {
+    let e = load(INI, "conf").unwrap_err().to_string();
+    assert!(e.contains("--docker"), "{}", e);
+    let e = load(INI, "ini").unwrap_err().to_string();
+    assert!(e.contains("the ini feature is disabled"), "{}", e);
+    let e = load(YAML, "yaml").unwrap_err().to_string();
+    assert!(e.contains("the yaml feature is disabled"), "{}", e);
+}
+
+#[cfg(not(feature = "labels"))]
+#[tokio::test]
+async fn labels_disabled() {
+    let e = load_labels(&ApplicationContext::default(), &mut LabelCache::default()).await.unwrap_err().to_string();
+    assert_eq!(e, "No compiled feature supports parsing labels, try to use file parsing");
+}
+
+#[cfg(feature = "labels")]
+#[tokio::test]
+async fn labels_enabled() {
+    // Without a reachable engine, the error comes from the label loader itself
+    let ctx = ApplicationContext {
+        socket: Some("/nonexistent/docker.sock".to_string()),
+        label_prefixes: vec!["cfc".to_string()],
+        ..Default::default()
+    };
+    let e = load_labels(&ctx, &mut LabelCache::default()).await.unwrap_err().to_string();
+    assert!(!e.contains("No compiled feature"), "{}", e);
+}
@@ -0,0 +1,36 @@
+//! Round trip of the last runs through a real state volume.
+//!
+//! Needs a running container engine, run with `cargo test --test state_volume -- --ignored`.
+use bollard::{container::{Config, CreateContainerOptions, LogOutput, LogsOptions, RemoveContainerOptions, WaitContainerOptions}, models::HostConfig, Docker};
+use cfc::state::{StateStore, VolumeStateStore, DEFAULT_STATE_IMAGE};
+use futures_util::StreamExt;
+
+#[tokio::test]
+#[ignore = "needs a container engine"]
+async fn read_state_from_another_container() {
+    let handle = Docker::connect_with_local_defaults().unwrap();
+    let volume = format!("cfc.state-test-{}", std::process::id());
+    let store = VolumeStateStore::new(handle.clone(), &volume, DEFAULT_STATE_IMAGE);
+    store.ensure().await.unwrap();
+    store.write("backup.json".to_string(), br#"{"result":"success"}"#.to_vec()).await.unwrap();
+
+    let reader = format!("{}.reader", volume);
+    let config = Config {
+        image: Some(DEFAULT_STATE_IMAGE.to_string()),
+        cmd: Some(vec!["cat".to_string(), "/state/backup.json".to_string()]),
+        host_config: Some(HostConfig { binds: Some(vec![format!("{}:/state:ro", volume)]), ..Default::default() }),
+        ..Default::default()
+    };
+    handle.create_container(Some(CreateContainerOptions { name: reader.as_str(), platform: None }), config).await.unwrap();
+    handle.start_container::<String>(&reader, None).await.unwrap();
+    handle.wait_container(&reader, None::<WaitContainerOptions<String>>).next().await.unwrap().unwrap();
+    let logs = handle.logs(&reader, Some(LogsOptions::<String> { stdout: true, ..Default::default() }))
+        .filter_map(|l| async move { match l { Ok(LogOutput::StdOut { message }) => Some(String::from_utf8_lossy(&message).into_owned()), _ => None } })
+        .collect::<String>().await;
+
+    for container in [reader, store.helper()] {
+        handle.remove_container(&container, Some(RemoveContainerOptions { force: true, ..Default::default() })).await.unwrap();
+    }
+    handle.remove_volume(&volume, None).await.unwrap();
+    assert_eq!(logs, r#"{"result":"success"}"#);
+}